@@ -0,0 +1,281 @@
+//! An HTTP server exposing the latest results of all targets.
+//!
+//! [LatestResults] is a [ScrapeObserver] that keeps the outcome of the latest
+//! scrape of every named target. [serve] exposes them on `/metrics` in the
+//! Prometheus exposition format, such that debugbunny doubles as a simple
+//! exporter on hosts that lack one:
+//!
+//! - `debugbunny_up`: whether the latest scrape succeeded,
+//! - `debugbunny_scrape_duration_seconds`: how long it took,
+//! - `debugbunny_derived`: the numeric [derived fields](crate::derive) of the
+//!   latest successful scrape, labeled by `field`.
+//!
+//! Further, `/last/<target>` returns the body of the latest successful scrape
+//! of a target, such that the latest snapshot can be fetched with `curl`
+//! instead of reassembling it from the log. The time of the scrape is in the
+//! `Last-Modified` header. Like for [derived fields](crate::derive), the body
+//! of a command is its stdout and the body of structured output is JSON.
+//!
+//! ```no_run
+//! # use debugbunny::{admin::{serve, LatestResults}, debugbunny::DebugBunny, result_processor::LogOutputWriter};
+//! # async fn run() -> std::io::Result<()> {
+//! let latest = LatestResults::default();
+//! let debugbunny = DebugBunny::builder()
+//!     .observer(latest.clone())
+//!     .start_scraping(vec![], LogOutputWriter::new(tokio::io::stderr()))
+//!     .await
+//!     .unwrap();
+//! let listener = tokio::net::TcpListener::bind("127.0.0.1:9123").await?;
+//! tokio::spawn(serve(listener, latest));
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::Write,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use bytes::Bytes;
+use http::{header, HeaderValue, Request, Response, StatusCode};
+use http_body_util::Full;
+use hyper::{body::Incoming, server::conn::http1, service::service_fn};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+
+use crate::{
+    config::ScrapeTargetConfig,
+    derive::{body, derive_fields},
+    observer::ScrapeObserver,
+    scrape_target::{ScrapeOk, ScrapeResult},
+};
+
+/// The latest results of all named targets. Clones share the results.
+#[derive(Clone, Default)]
+pub struct LatestResults {
+    targets: Arc<Mutex<BTreeMap<String, Latest>>>,
+}
+
+struct Latest {
+    up: bool,
+    duration: Duration,
+    derived: BTreeMap<String, f64>,
+    /// Kept if later scrapes fail.
+    body: Option<LastBody>,
+}
+
+#[derive(Clone)]
+struct LastBody {
+    at: SystemTime,
+    data: Bytes,
+    content_type: HeaderValue,
+}
+
+impl LastBody {
+    fn new(ok: &ScrapeOk) -> Self {
+        let (data, content_type) = match ok {
+            ScrapeOk::HttpResponse(r) => (
+                r.body().clone(),
+                r.headers().get(header::CONTENT_TYPE).cloned(),
+            ),
+            ScrapeOk::CommandResponse(_) => (
+                Bytes::from(body(ok).into_owned()),
+                Some(HeaderValue::from_static("text/plain; charset=utf-8")),
+            ),
+            ScrapeOk::Structured(_) => (
+                Bytes::from(body(ok).into_owned()),
+                Some(HeaderValue::from_static("application/json")),
+            ),
+        };
+        Self {
+            at: SystemTime::now(),
+            data,
+            content_type: content_type
+                .unwrap_or(HeaderValue::from_static("application/octet-stream")),
+        }
+    }
+}
+
+impl ScrapeObserver for LatestResults {
+    fn on_finish(
+        &self,
+        config: &ScrapeTargetConfig,
+        duration: Duration,
+        result: &ScrapeResult<ScrapeOk>,
+    ) {
+        let Some(name) = &config.name else {
+            return;
+        };
+        let derived = match result {
+            Ok(ok) => derive_fields(config, ok)
+                .into_iter()
+                .filter_map(|(k, v)| Some((k, v.as_f64()?)))
+                .collect(),
+            Err(_) => BTreeMap::new(),
+        };
+        let mut targets = self.targets.lock().unwrap();
+        let body = match result {
+            Ok(ok) => Some(LastBody::new(ok)),
+            Err(_) => targets.get(name).and_then(|l| l.body.clone()),
+        };
+        let latest = Latest {
+            up: result.is_ok(),
+            duration,
+            derived,
+            body,
+        };
+        targets.insert(name.clone(), latest);
+    }
+}
+
+impl LatestResults {
+    /// The results in the Prometheus text exposition format.
+    pub fn render_metrics(&self) -> String {
+        let targets = self.targets.lock().unwrap();
+        let mut out = String::new();
+        out.push_str("# HELP debugbunny_up Whether the latest scrape of the target succeeded.\n");
+        out.push_str("# TYPE debugbunny_up gauge\n");
+        for (name, l) in targets.iter() {
+            let _ = writeln!(
+                out,
+                "debugbunny_up{{target=\"{}\"}} {}",
+                escape(name),
+                u8::from(l.up)
+            );
+        }
+        out.push_str("# HELP debugbunny_scrape_duration_seconds The duration of the latest scrape of the target.\n");
+        out.push_str("# TYPE debugbunny_scrape_duration_seconds gauge\n");
+        for (name, l) in targets.iter() {
+            let _ = writeln!(
+                out,
+                "debugbunny_scrape_duration_seconds{{target=\"{}\"}} {}",
+                escape(name),
+                l.duration.as_secs_f64()
+            );
+        }
+        out.push_str("# HELP debugbunny_derived Numeric fields derived from the latest scrape.\n");
+        out.push_str("# TYPE debugbunny_derived gauge\n");
+        for (name, l) in targets.iter() {
+            for (field, v) in &l.derived {
+                let _ = writeln!(
+                    out,
+                    "debugbunny_derived{{target=\"{}\",field=\"{}\"}} {v}",
+                    escape(name),
+                    escape(field)
+                );
+            }
+        }
+        out
+    }
+}
+
+/// Escape a label value.
+fn escape(v: &str) -> String {
+    v.replace('\\', r"\\")
+        .replace('"', "\\\"")
+        .replace('\n', r"\n")
+}
+
+/// Serve the latest results on `listener` until accepting a connection fails.
+pub async fn serve(listener: TcpListener, latest: LatestResults) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let latest = latest.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let r = handle(&latest, req);
+                async move { Ok::<_, Infallible>(r) }
+            });
+            if let Err(e) = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+            {
+                tracing::debug!(error = %e, %peer, "admin connection failed");
+            }
+        });
+    }
+}
+
+fn handle(latest: &LatestResults, req: Request<Incoming>) -> Response<Full<Bytes>> {
+    let path = req.uri().path();
+    let last = path.strip_prefix("/last/").and_then(|target| {
+        let target = percent_encoding::percent_decode_str(target).decode_utf8_lossy();
+        let targets = latest.targets.lock().unwrap();
+        targets.get(target.as_ref())?.body.clone()
+    });
+    match (path, last) {
+        ("/metrics", _) => Response::builder()
+            .header(header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Full::new(latest.render_metrics().into())),
+        (_, Some(last)) => Response::builder()
+            .header(header::CONTENT_TYPE, last.content_type)
+            .header(header::LAST_MODIFIED, httpdate::fmt_http_date(last.at))
+            .body(Full::new(last.data)),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::default()),
+    }
+    .expect("valid response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{Action, Pattern, ScrapeTargetBuilder},
+        derive::DerivedField,
+        scrape_target::ScrapeErr,
+    };
+
+    #[tokio::test]
+    async fn latest_results_are_served() {
+        let ok = ScrapeTargetBuilder::new()
+            .name("ok")
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .derived(
+                "answer",
+                DerivedField::new(Pattern::new(r#""a":(\d+)"#).unwrap()),
+            )
+            .build();
+        let failing = ScrapeTargetBuilder::new()
+            .name("fail\"ing")
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let latest = LatestResults::default();
+        let body = ScrapeOk::Structured(serde_json::json!({ "a": 42 }));
+        latest.on_finish(&ok, Duration::from_millis(500), &Ok(body));
+        latest.on_finish(&failing, Duration::ZERO, &Err(ScrapeErr::Cancelled));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, latest));
+        let metrics = reqwest::get(format!("http://{addr}/metrics"))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+
+        assert!(metrics.contains("debugbunny_up{target=\"ok\"} 1\n"));
+        assert!(metrics.contains("debugbunny_up{target=\"fail\\\"ing\"} 0\n"));
+        assert!(metrics.contains("debugbunny_scrape_duration_seconds{target=\"ok\"} 0.5\n"));
+        assert!(metrics.contains("debugbunny_derived{target=\"ok\",field=\"answer\"} 42\n"));
+
+        let last = reqwest::get(format!("http://{addr}/last/ok"))
+            .await
+            .unwrap();
+        assert!(last.headers().contains_key(header::LAST_MODIFIED));
+        assert_eq!(r#"{"a":42}"#, last.text().await.unwrap());
+        // The target failed before a body was recorded.
+        let missing = reqwest::get(format!("http://{addr}/last/fail%22ing"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, missing.status());
+    }
+}
@@ -0,0 +1,98 @@
+//! Flagging bodies whose size deviates from the usual.
+//!
+//! A sudden change of the size of an output, e.g. an error page instead of
+//! the metrics or an exploding process list, is a cheap signal that something
+//! changed drastically. With [SizeAnomalies], a
+//! [LogOutputWriter](crate::result_processor::LogOutputWriter) keeps a rolling
+//! baseline of the body sizes of each target and sets
+//! [ScrapeCallRepr::anomalous_size](crate::result_processor::ScrapeCallRepr::anomalous_size)
+//! if a body is larger or smaller than the baseline by more than a factor.
+//! The baseline is the median of the recent sizes, such that a single outlier
+//! does not shift it.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+/// Tracks the recent body sizes of each target. Clones share their state.
+/// See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct SizeAnomalies {
+    factor: f64,
+    window: usize,
+    min_samples: usize,
+    targets: Arc<Mutex<HashMap<String, VecDeque<usize>>>>,
+}
+
+impl SizeAnomalies {
+    /// Flag bodies more than `factor` times larger or smaller than the
+    /// baseline.
+    ///
+    /// # Panics
+    ///
+    /// If `factor` is not greater than 1.
+    pub fn new(factor: f64) -> Self {
+        assert!(factor > 1.0, "the factor must be greater than 1");
+        Self {
+            factor,
+            window: 20,
+            min_samples: 5,
+            targets: Default::default(),
+        }
+    }
+
+    /// The number of recent bodies the baseline is computed from. Defaults
+    /// to 20.
+    pub fn window(mut self, n: usize) -> Self {
+        self.window = n.max(1);
+        self
+    }
+
+    /// The number of bodies a target needs before its bodies are flagged.
+    /// Defaults to 5.
+    pub fn min_samples(mut self, n: usize) -> Self {
+        self.min_samples = n.max(1);
+        self
+    }
+
+    /// Record the size of a body of `target`. Returns whether it deviates
+    /// from the baseline of the previous bodies.
+    pub fn observe(&self, target: &str, size: usize) -> bool {
+        let mut targets = self.targets.lock().unwrap();
+        let sizes = targets.entry(target.to_string()).or_default();
+        let anomalous = sizes.len() >= self.min_samples && {
+            let mut sorted: Vec<_> = sizes.iter().copied().collect();
+            sorted.sort_unstable();
+            // Empty bodies would make any ratio infinite.
+            let baseline = sorted[sorted.len() / 2].max(1) as f64;
+            let size = size.max(1) as f64;
+            size / baseline > self.factor || baseline / size > self.factor
+        };
+        sizes.push_back(size);
+        if sizes.len() > self.window {
+            sizes.pop_front();
+        }
+        anomalous
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deviations_from_the_median_are_flagged() {
+        let a = SizeAnomalies::new(4.0).window(5).min_samples(3);
+        let flagged: Vec<_> = [100, 110, 5000, 90, 105, 400, 20, 100]
+            .into_iter()
+            .map(|s| a.observe("t", s))
+            .collect();
+        // Too few samples for the first outlier, the median ignores it later.
+        assert_eq!(
+            vec![false, false, false, false, false, false, true, false],
+            flagged
+        );
+        assert!(!a.observe("other", 1_000_000));
+    }
+}
@@ -0,0 +1,83 @@
+//! The record that starts the output of each run.
+//!
+//! When scraping starts, a [Banner] is passed to every processor, see
+//! [ScrapeResultProcessor::process_banner](crate::result_processor::ScrapeResultProcessor::process_banner).
+//! It names the version of debugbunny, the host and a fingerprint of the
+//! effective config, such that every log segment is self-describing and hosts
+//! whose config drifted from the rest of the fleet stand out in the logs.
+
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, TimestampSecondsWithFrac};
+use sha2::{Digest, Sha256};
+
+use crate::config::ScrapeTargetConfig;
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Banner {
+    /// The version of debugbunny.
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[serde_as(as = "TimestampSecondsWithFrac<f64>")]
+    pub started_at: SystemTime,
+    /// The SHA-256 of the targets as JSON, hex encoded. Equal configs have
+    /// equal fingerprints regardless of how they were written, e.g. with
+    /// groups or environment variables.
+    pub config_sha256: String,
+    pub targets: usize,
+}
+
+impl Banner {
+    pub fn new(configs: &[ScrapeTargetConfig]) -> Self {
+        let json = serde_json::to_vec(configs).expect("can't fail");
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            host: host_name(),
+            started_at: SystemTime::now(),
+            config_sha256: hex::encode(Sha256::digest(json)),
+            targets: configs.len(),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn host_name() -> Option<String> {
+    let name = rustix::system::uname()
+        .nodename()
+        .to_str()
+        .ok()?
+        .to_string();
+    Some(name).filter(|n| !n.is_empty())
+}
+
+#[cfg(not(unix))]
+fn host_name() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::config::{Action, ScrapeTargetBuilder};
+
+    #[test]
+    fn fingerprints_depend_on_the_config_only() {
+        let target = |interval| {
+            ScrapeTargetBuilder::new()
+                .name("t")
+                .interval(Duration::from_secs(interval))
+                .action(Action::SelfStatus)
+                .build()
+        };
+        let a = Banner::new(&[target(1)]);
+        assert_eq!(a.config_sha256, Banner::new(&[target(1)]).config_sha256);
+        assert_ne!(a.config_sha256, Banner::new(&[target(2)]).config_sha256);
+        assert_eq!(64, a.config_sha256.len());
+        assert_eq!(env!("CARGO_PKG_VERSION"), a.version);
+    }
+}
@@ -0,0 +1,180 @@
+//! Writing to blocking sinks.
+//!
+//! A [LogOutputWriter](crate::result_processor::LogOutputWriter) writes to an
+//! `AsyncWrite`. Synchronous logging frameworks, e.g. appenders that write to
+//! files or sockets with `std::io`, can be used through a [BlockingWriter],
+//! see [LogOutputWriter::blocking](crate::result_processor::LogOutputWriter::blocking).
+//! Writes are buffered and handed to a blocking thread when the buffer is
+//! flushed or full, such that the io-threads never block on the sink.
+
+use std::{
+    future::Future,
+    io::{self, Write},
+    mem,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use tokio::{io::AsyncWrite, task::JoinHandle};
+
+/// The size at which the buffer is written even if it was not flushed.
+const CAPACITY: usize = 64 * 1024;
+
+/// Adapts a [Write] to [AsyncWrite]. See the [module docs](self).
+pub struct BlockingWriter<W> {
+    /// `None` while a blocking write is in progress.
+    writer: Option<W>,
+    buf: Vec<u8>,
+    pending: Option<Pending<W>>,
+}
+
+struct Pending<W> {
+    task: JoinHandle<(W, Vec<u8>, io::Result<()>)>,
+    flush: bool,
+}
+
+// The writer is never pinned.
+impl<W> Unpin for BlockingWriter<W> {}
+
+impl<W> BlockingWriter<W>
+where
+    W: Write + Send + 'static,
+{
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Some(writer),
+            buf: Vec::new(),
+            pending: None,
+        }
+    }
+
+    /// Write the buffer, and flush the writer if `flush`, on a blocking thread.
+    fn start(&mut self, flush: bool) {
+        let mut writer = self.writer.take().expect("no write in progress");
+        let mut buf = mem::take(&mut self.buf);
+        let task = tokio::task::spawn_blocking(move || {
+            let mut r = writer.write_all(&buf);
+            if flush {
+                r = r.and_then(|()| writer.flush());
+            }
+            buf.clear();
+            (writer, buf, r)
+        });
+        self.pending = Some(Pending { task, flush });
+    }
+
+    /// Wait for the write in progress, if any. Returns whether it flushed.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        let Some(pending) = &mut self.pending else {
+            return Poll::Ready(Ok(false));
+        };
+        let (writer, buf, r) =
+            ready!(Pin::new(&mut pending.task).poll(cx)).expect("Could not join blocking code!");
+        let flushed = pending.flush;
+        self.pending = None;
+        self.writer = Some(writer);
+        self.buf = buf;
+        Poll::Ready(r.map(|()| flushed))
+    }
+}
+
+impl<W> AsyncWrite for BlockingWriter<W>
+where
+    W: Write + Send + 'static,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        if this.buf.len() >= CAPACITY {
+            this.start(false);
+            ready!(this.poll_pending(cx))?;
+        }
+        this.buf.extend_from_slice(data);
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if ready!(this.poll_pending(cx))? {
+            return Poll::Ready(Ok(()));
+        }
+        this.start(true);
+        ready!(this.poll_pending(cx))?;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+    use crate::{
+        config::{Action, ScrapeTargetBuilder},
+        result_processor::{LogOutputWriter, ScrapeResultProcessor},
+        scrape_target::ScrapeOk,
+    };
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<(Vec<u8>, usize)>>);
+
+    impl Write for Shared {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().0.extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.lock().unwrap().1 += 1;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_are_buffered_until_flushed() {
+        let shared = Shared::default();
+        let mut w = BlockingWriter::new(shared.clone());
+        w.write_all(b"a\n").await.unwrap();
+        w.write_all(b"b\n").await.unwrap();
+        assert!(shared.0.lock().unwrap().0.is_empty());
+
+        w.flush().await.unwrap();
+        assert_eq!((b"a\nb\n".to_vec(), 1), *shared.0.lock().unwrap());
+
+        let large = vec![b'x'; CAPACITY + 1];
+        w.write_all(&large).await.unwrap();
+        w.write_all(b"\n").await.unwrap();
+        assert_eq!(4 + CAPACITY + 1, shared.0.lock().unwrap().0.len());
+        assert_eq!(1, shared.0.lock().unwrap().1);
+    }
+
+    #[tokio::test]
+    async fn log_output_writer_writes_to_blocking_writers() {
+        let shared = Shared::default();
+        let w = LogOutputWriter::blocking(shared.clone());
+        let config = ScrapeTargetBuilder::new()
+            .interval(std::time::Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let ok = ScrapeOk::Structured(serde_json::json!({ "a": 1 }));
+        w.process(&config, Ok(ok)).await.unwrap();
+
+        let out = shared.0.lock().unwrap().0.clone();
+        let lines: Vec<serde_json::Value> = out
+            .split(|b| *b == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(|l| serde_json::from_slice(l).unwrap())
+            .collect();
+        assert_eq!(2, lines.len());
+    }
+}
@@ -0,0 +1,159 @@
+//! Storing bodies as files instead of writing them to the log.
+//!
+//! On hosts without a way to ship logs off the machine, e.g. air-gapped ones,
+//! someone collects the diagnostics by hand. Bodies chunked into the log are
+//! of little use there. With a [BodySpool], a
+//! [LogOutputWriter](crate::result_processor::LogOutputWriter) stores each
+//! body in a file of its own and the record of the call only refers to it
+//! (see [SpooledBody]). The file holds the payload as it would have been
+//! chunked, i.e. compressed with zstd and encrypted if a key is set. Its
+//! digest is the id of the body in the record.
+//!
+//! The spool is pruned after every body written, oldest bodies first. If a
+//! body cannot be stored, it is chunked into the log as usual.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+
+const EXTENSION: &str = "body";
+
+/// A directory of bodies. See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct BodySpool {
+    dir: PathBuf,
+    max_bytes: Option<u64>,
+    max_age: Option<Duration>,
+    /// Held while storing a body and pruning, such that writers sharing the
+    /// spool do not delete each other's bodies or race for the same files.
+    lock: Arc<Mutex<()>>,
+}
+
+/// A body that was stored in a [BodySpool] instead of the log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SpooledBody {
+    pub path: PathBuf,
+    pub size: u64,
+    /// The id of the key the body is encrypted with, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+}
+
+impl BodySpool {
+    /// The directory is created with the first body, if needed.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self {
+            dir: dir.into(),
+            max_bytes: None,
+            max_age: None,
+            lock: Arc::default(),
+        }
+    }
+
+    /// Delete the oldest bodies once the bodies in the spool take up more
+    /// than `n` bytes. The latest body is always kept.
+    pub fn max_bytes(mut self, n: u64) -> Self {
+        self.max_bytes = Some(n);
+        self
+    }
+
+    /// Delete bodies that are older than `d`.
+    pub fn max_age(mut self, d: Duration) -> Self {
+        self.max_age = Some(d);
+        self
+    }
+
+    /// Store `data` under `name` and prune the spool. Blocks.
+    pub(crate) fn store(
+        &self,
+        name: &str,
+        data: &[u8],
+        key_id: Option<String>,
+    ) -> io::Result<SpooledBody> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(name).with_extension(EXTENSION);
+        fs::write(&path, data)?;
+        // The body is stored, so failing to prune must not fail the call.
+        if let Err(e) = self.prune(&path) {
+            tracing::warn!(error = %e, dir = ?self.dir, "could not prune body spool");
+        }
+        Ok(SpooledBody {
+            path,
+            size: data.len() as u64,
+            key_id,
+        })
+    }
+
+    /// Delete the oldest bodies but `keep`. Bodies that are gone already,
+    /// e.g. because someone else cleaned up the directory, are skipped.
+    fn prune(&self, keep: &Path) -> io::Result<()> {
+        if self.max_bytes.is_none() && self.max_age.is_none() {
+            return Ok(());
+        }
+        let mut bodies = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().map_or(true, |e| e != EXTENSION) {
+                continue;
+            }
+            let meta = match entry.metadata() {
+                Ok(meta) => meta,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            bodies.push((meta.modified()?, meta.len(), path));
+        }
+        // Newest first.
+        bodies.sort_by_key(|(modified, _, _)| std::cmp::Reverse(*modified));
+        let now = SystemTime::now();
+        let mut total = 0;
+        for (modified, len, path) in bodies {
+            total += len;
+            let too_old = self
+                .max_age
+                .is_some_and(|max| now.duration_since(modified).unwrap_or_default() > max);
+            let too_much = self.max_bytes.is_some_and(|max| total > max);
+            if path != keep && (too_old || too_much) {
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_bodies_are_pruned() {
+        let dir = std::env::temp_dir().join(format!("debugbunny-spool-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("unrelated"), [0; 100]).unwrap();
+        let spool = BodySpool::new(&dir).max_bytes(150);
+        for name in ["a", "b", "c"] {
+            let body = spool.store(name, &[1; 100], None).unwrap();
+            assert_eq!(100, body.size);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let mut left: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        left.sort();
+        assert_eq!(vec!["c.body", "unrelated"], left);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
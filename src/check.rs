@@ -0,0 +1,225 @@
+//! Assertions on the results of a target.
+//!
+//! A target may define named [Check]s, e.g. that the status of a response is
+//! 200 or that a derived field stays below a threshold. They are evaluated
+//! after every call, and their outcomes are emitted as the `checks` of the
+//! record of the call, such that the output can be verified automatically
+//! without decoding the bodies. All checks of a failed call fail.
+
+use std::collections::BTreeMap;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    config::{Pattern, ScrapeTargetConfig},
+    derive::body,
+    scrape_target::ScrapeOk,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+#[serde(tag = "type")]
+pub enum Check {
+    /// The status code of an HTTP response equals the given one.
+    Status { equals: u16 },
+    /// The exit code of a command equals the given one.
+    ExitCode { equals: i32 },
+    /// The body matches the regular expression. See [crate::derive] for what
+    /// the body of each kind of output is.
+    BodyMatches {
+        #[schemars(with = "String")]
+        pattern: Pattern,
+    },
+    /// A derived or promoted field is a number within the given bounds, which
+    /// are exclusive.
+    Threshold {
+        field: String,
+        #[serde(
+            default,
+            deserialize_with = "crate::config::deserialize_opt_number",
+            skip_serializing_if = "Option::is_none"
+        )]
+        below: Option<f64>,
+        #[serde(
+            default,
+            deserialize_with = "crate::config::deserialize_opt_number",
+            skip_serializing_if = "Option::is_none"
+        )]
+        above: Option<f64>,
+    },
+}
+
+// Equality is total, as NaN bounds are rejected.
+impl Eq for Check {}
+
+/// The outcome of a [Check] for a single call.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CheckOutcome {
+    pub name: String,
+    pub passed: bool,
+    /// The value the check was evaluated against, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub actual: Option<Value>,
+}
+
+impl Check {
+    pub fn status(equals: u16) -> Self {
+        Self::Status { equals }
+    }
+
+    pub fn exit_code(equals: i32) -> Self {
+        Self::ExitCode { equals }
+    }
+
+    pub fn body_matches(pattern: Pattern) -> Self {
+        Self::BodyMatches { pattern }
+    }
+
+    /// # Panics
+    ///
+    /// If `threshold` is NaN.
+    pub fn below<S: ToString>(field: S, threshold: f64) -> Self {
+        assert!(!threshold.is_nan(), "the threshold must be a number");
+        Self::Threshold {
+            field: field.to_string(),
+            below: Some(threshold),
+            above: None,
+        }
+    }
+
+    /// # Panics
+    ///
+    /// If `threshold` is NaN.
+    pub fn above<S: ToString>(field: S, threshold: f64) -> Self {
+        assert!(!threshold.is_nan(), "the threshold must be a number");
+        Self::Threshold {
+            field: field.to_string(),
+            below: None,
+            above: Some(threshold),
+        }
+    }
+
+    /// Returns whether the check passed, and the value it was evaluated
+    /// against. `fields` looks up derived and promoted fields.
+    fn evaluate<'a>(
+        &self,
+        ok: &ScrapeOk,
+        fields: impl Fn(&str) -> Option<&'a Value>,
+    ) -> (bool, Option<Value>) {
+        match (self, ok) {
+            (Self::Status { equals }, ScrapeOk::HttpResponse(r)) => {
+                let status = r.status().as_u16();
+                (status == *equals, Some(status.into()))
+            }
+            (Self::ExitCode { equals }, ScrapeOk::CommandResponse(o)) => match o.status.code() {
+                Some(code) => (code == *equals, Some(code.into())),
+                // Killed by a signal.
+                None => (false, None),
+            },
+            (Self::Status { .. } | Self::ExitCode { .. }, _) => (false, None),
+            (Self::BodyMatches { pattern }, ok) => (pattern.regex().is_match(&body(ok)), None),
+            (
+                Self::Threshold {
+                    field,
+                    below,
+                    above,
+                },
+                _,
+            ) => {
+                let Some(v) = fields(field) else {
+                    return (false, None);
+                };
+                let passed = v.as_f64().is_some_and(|n| {
+                    below.map_or(true, |b| n < b) && above.map_or(true, |a| n > a)
+                });
+                (passed, Some(v.clone()))
+            }
+        }
+    }
+}
+
+/// Evaluate the checks of `config` against the result of a call. `ok` is
+/// `None` if the call failed.
+pub fn evaluate_checks(
+    config: &ScrapeTargetConfig,
+    ok: Option<&ScrapeOk>,
+    derived: &BTreeMap<String, Value>,
+    promoted: &BTreeMap<String, Value>,
+) -> Vec<CheckOutcome> {
+    config
+        .checks
+        .iter()
+        .map(|(name, check)| {
+            let (passed, actual) = match ok {
+                Some(ok) => check.evaluate(ok, |f| derived.get(f).or_else(|| promoted.get(f))),
+                None => (false, None),
+            };
+            CheckOutcome {
+                name: name.clone(),
+                passed,
+                actual,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::config::{Action, ScrapeTargetBuilder};
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        let check = |pattern| {
+            let v = serde_json::json!({ "type": "BodyMatches", "pattern": pattern });
+            serde_json::from_value::<Check>(v)
+        };
+        assert!(check("(unclosed").is_err());
+        assert!(check("^up").is_ok());
+    }
+
+    #[test]
+    fn checks_pass_or_fail() {
+        let config = ScrapeTargetBuilder::new()
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .check("ok", Check::status(200))
+            .check("exit", Check::exit_code(0))
+            .check("healthy", Check::body_matches(Pattern::new("^up").unwrap()))
+            .check("fast", Check::below("latency", 0.5))
+            .check("missing", Check::above("nope", 0.0))
+            .build();
+        let r = http::Response::builder()
+            .status(200)
+            .body(Bytes::from_static(b"up and running"))
+            .unwrap();
+        let ok = ScrapeOk::HttpResponse(r);
+        let derived = BTreeMap::from([("latency".to_string(), serde_json::json!(0.7))]);
+
+        let outcomes = evaluate_checks(&config, Some(&ok), &derived, &BTreeMap::new());
+        let passed: Vec<_> = outcomes
+            .iter()
+            .map(|o| (o.name.as_str(), o.passed))
+            .collect();
+        assert_eq!(
+            vec![
+                ("exit", false),
+                ("fast", false),
+                ("healthy", true),
+                ("missing", false),
+                ("ok", true)
+            ],
+            passed
+        );
+        assert_eq!(Some(serde_json::json!(0.7)), outcomes[1].actual);
+        assert_eq!(Some(serde_json::json!(200)), outcomes[4].actual);
+
+        let failed = evaluate_checks(&config, None, &derived, &BTreeMap::new());
+        assert!(failed.iter().all(|o| !o.passed));
+    }
+}
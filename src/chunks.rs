@@ -1,10 +1,13 @@
 //! A helper construct to chunk up a contiguous byte array or treat a vector of
 //! chunks as a single contiguous byte string. In either case, additional
 //! allocations are avoided.
-use std::{borrow::Cow, io::Read};
+use std::{fmt::Display, io::Read, str::FromStr};
 
+use base64::Engine;
+
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
-use serde_with::serde_as;
+use serde_with::{DeserializeFromStr, SerializeDisplay};
 use sha2::Digest;
 use thiserror::Error;
 
@@ -22,13 +25,175 @@ use thiserror::Error;
 /// of a json-object, this leaves 200 bytes for additional metadata.
 pub const DEFAULT_CHUNK_SIZE: usize = 2922;
 
-#[serde_as]
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
-pub struct Id(#[serde_as(as = "serde_with::hex::Hex")] [u8; 32]);
+/// The algorithm used to compute the [Id] of a chunked byte string.
+#[derive(
+    Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestAlgorithm {
+    #[default]
+    Sha256,
+    /// Considerably faster than SHA-256 on CPUs without SHA extensions.
+    Blake3,
+}
+
+impl DigestAlgorithm {
+    fn hasher(self) -> Hasher {
+        match self {
+            Self::Sha256 => Hasher::Sha256(Default::default()),
+            Self::Blake3 => Hasher::Blake3(Default::default()),
+        }
+    }
+}
+
+/// How the data of a chunk is represented in its record. The text encodings
+/// keep records valid JSON, while [ChunkEncoding::Raw] avoids their overhead
+/// for sinks that accept arbitrary bytes.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChunkEncoding {
+    /// 4 characters per 3 bytes.
+    #[default]
+    Base64,
+    /// 5 characters per 4 bytes, using the alphabet of
+    /// [Z85](https://rfc.zeromq.org/spec/32/). Unlike Z85, data of any length
+    /// is supported: a trailing group of `n` bytes is encoded as `n + 1`
+    /// characters.
+    Base85,
+    /// 2 characters per byte.
+    Hex,
+    /// The data as is, on the line following the record of the chunk. The
+    /// length of the data is given by the record, as the data may contain
+    /// line breaks.
+    Raw,
+}
+
+const Z85: &[u8; 85] =
+    b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ.-:+=^!/*?&<>()[]{}@%$#";
+
+impl ChunkEncoding {
+    /// The chunk size for which an encoded chunk is about as long as a chunk
+    /// of [DEFAULT_CHUNK_SIZE] encoded as base64.
+    pub fn chunk_size(self) -> usize {
+        match self {
+            Self::Base64 => DEFAULT_CHUNK_SIZE,
+            Self::Base85 => DEFAULT_CHUNK_SIZE * 4 / 3 * 4 / 5,
+            Self::Hex => DEFAULT_CHUNK_SIZE * 4 / 3 / 2,
+            Self::Raw => DEFAULT_CHUNK_SIZE * 4 / 3,
+        }
+    }
+
+    /// # Panics
+    ///
+    /// For [ChunkEncoding::Raw], which has no textual representation.
+    pub fn encode(self, data: &[u8]) -> String {
+        match self {
+            Self::Base64 => base64::engine::general_purpose::STANDARD.encode(data),
+            Self::Base85 => data
+                .chunks(4)
+                .flat_map(|group| {
+                    let mut padded = [0; 4];
+                    padded[..group.len()].copy_from_slice(group);
+                    let mut v = u32::from_be_bytes(padded);
+                    let mut digits = [0; 5];
+                    for d in digits.iter_mut().rev() {
+                        *d = Z85[(v % 85) as usize];
+                        v /= 85;
+                    }
+                    digits.into_iter().take(group.len() + 1)
+                })
+                .map(char::from)
+                .collect(),
+            Self::Hex => hex::encode(data),
+            Self::Raw => panic!("raw chunks are not encoded as text"),
+        }
+    }
+
+    /// The inverse of [ChunkEncoding::encode]. `None` if `s` is not a valid
+    /// encoding.
+    pub fn decode(self, s: &str) -> Option<Vec<u8>> {
+        match self {
+            Self::Base64 => base64::engine::general_purpose::STANDARD.decode(s).ok(),
+            Self::Base85 => {
+                let mut data = Vec::with_capacity(s.len() * 4 / 5);
+                for group in s.as_bytes().chunks(5) {
+                    if group.len() < 2 {
+                        return None;
+                    }
+                    // Missing digits are padded with the highest digit, such
+                    // that truncating the value yields the original bytes.
+                    let mut v: u64 = 0;
+                    for i in 0..5 {
+                        let digit = match group.get(i) {
+                            Some(c) => Z85.iter().position(|z| z == c)?,
+                            None => 84,
+                        };
+                        v = v * 85 + digit as u64;
+                    }
+                    let v = u32::try_from(v).ok()?;
+                    data.extend_from_slice(&v.to_be_bytes()[..group.len() - 1]);
+                }
+                Some(data)
+            }
+            Self::Hex => hex::decode(s).ok(),
+            Self::Raw => None,
+        }
+    }
+}
+
+enum Hasher {
+    Sha256(sha2::Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Sha256(h) => h.update(data),
+            Self::Blake3(h) => {
+                h.update(data);
+            }
+        }
+    }
+
+    fn finalize(self) -> Id {
+        match self {
+            Self::Sha256(h) => Id::new(DigestAlgorithm::Sha256, h.finalize().into()),
+            Self::Blake3(h) => Id::new(DigestAlgorithm::Blake3, h.finalize().into()),
+        }
+    }
+}
+
+/// The digest of a chunked byte string.
+///
+/// The string representation of a SHA-256 id is the hex-encoded digest. For
+/// other algorithms, the digest is prefixed by the name of the algorithm, e.g.
+/// `blake3:…`. Thus, decoders that only know SHA-256 ids keep working.
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, SerializeDisplay, DeserializeFromStr,
+)]
+pub struct Id {
+    algorithm: DigestAlgorithm,
+    digest: [u8; 32],
+}
+
+impl Id {
+    pub fn new(algorithm: DigestAlgorithm, digest: [u8; 32]) -> Self {
+        Self { algorithm, digest }
+    }
+
+    pub fn algorithm(&self) -> DigestAlgorithm {
+        self.algorithm
+    }
+
+    pub fn digest(&self) -> &[u8; 32] {
+        &self.digest
+    }
+}
 
 impl From<[u8; 32]> for Id {
     fn from(value: [u8; 32]) -> Self {
-        Self(value)
+        Self::new(DigestAlgorithm::Sha256, value)
     }
 }
 
@@ -36,20 +201,56 @@ impl From<&[u8]> for Id {
     fn from(value: &[u8]) -> Self {
         let mut id = [0u8; 32];
         id[0..].copy_from_slice(&value[..32]);
-        Self(id)
+        Self::from(id)
+    }
+}
+
+impl Display for Id {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.algorithm {
+            DigestAlgorithm::Sha256 => {}
+            DigestAlgorithm::Blake3 => f.write_str("blake3:")?,
+        }
+        f.write_str(&hex::encode(self.digest))
     }
 }
 
-pub struct Chunks<'a> {
+impl FromStr for Id {
+    type Err = ChunksError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, digest) = match s.split_once(':') {
+            None => (DigestAlgorithm::Sha256, s),
+            Some(("blake3", digest)) => (DigestAlgorithm::Blake3, digest),
+            Some(_) => return Err(ChunksError::InvalidId),
+        };
+        let mut id = [0u8; 32];
+        hex::decode_to_slice(digest, &mut id).map_err(|_| ChunksError::InvalidId)?;
+        Ok(Self::new(algorithm, id))
+    }
+}
+
+pub struct Chunks {
     id: Id,
     chunk_size: usize,
-    data: ChunksData<'a>,
+    data: ChunksData,
 }
 
-impl<'a> Chunks<'a> {
-    pub fn new<T: Into<Vec<u8>>>(data: T, chunk_size: usize) -> Self {
-        let data: Vec<u8> = data.into();
-        let id = (*sha2::Sha256::digest(&data)).into();
+impl Chunks {
+    pub fn new<T: Into<Bytes>>(data: T, chunk_size: usize) -> Self {
+        Self::with_digest(data, chunk_size, DigestAlgorithm::Sha256)
+    }
+
+    /// Like [Chunks::new], but the id is computed with the given algorithm.
+    pub fn with_digest<T: Into<Bytes>>(
+        data: T,
+        chunk_size: usize,
+        algorithm: DigestAlgorithm,
+    ) -> Self {
+        let data: Bytes = data.into();
+        let mut hasher = algorithm.hasher();
+        hasher.update(&data);
+        let id = hasher.finalize();
         Self {
             id,
             chunk_size,
@@ -59,7 +260,16 @@ impl<'a> Chunks<'a> {
 
     /// Create a Chunks-object from chunks. The `remaining`-field will be
     /// overriden with the actual remaining bytes.
-    pub fn from_chunks(v: Vec<Chunk<'a>>) -> Result<Chunks<'a>, ChunksError> {
+    pub fn from_chunks(v: Vec<Chunk>) -> Result<Chunks, ChunksError> {
+        Self::from_chunks_with_digest(v, DigestAlgorithm::Sha256)
+    }
+
+    /// Like [Chunks::from_chunks], but the id is computed with the given
+    /// algorithm, usually the one of the id the chunks were recorded with.
+    pub fn from_chunks_with_digest(
+        v: Vec<Chunk>,
+        algorithm: DigestAlgorithm,
+    ) -> Result<Chunks, ChunksError> {
         let mut r_iter = v.iter().rev();
         let chunk_size = v.first().map(|c| c.data.len()).unwrap_or(0);
         if r_iter.clone().skip(1).any(|c| c.data.len() != chunk_size) {
@@ -75,9 +285,9 @@ impl<'a> Chunks<'a> {
             Ok(())
         })?;
 
-        let mut hasher = sha2::Sha256::new();
-        v.iter().for_each(|c| hasher.update(c.data.as_ref()));
-        let id = (*hasher.finalize()).into();
+        let mut hasher = algorithm.hasher();
+        v.iter().for_each(|c| hasher.update(&c.data));
+        let id = hasher.finalize();
 
         Ok(Self {
             id,
@@ -86,20 +296,51 @@ impl<'a> Chunks<'a> {
         })
     }
 
-    pub fn iter(&self) -> Box<dyn Iterator<Item = Chunk<'_>> + '_ + Send> {
+    /// Like [Chunks::from_chunks], but the chunks may be given in any order,
+    /// e.g. as read from a log pipeline that does not preserve ordering. The
+    /// chunks are ordered by their `remaining`-field and duplicates are
+    /// dropped. Missing chunks are detected like by [Chunks::from_chunks],
+    /// except for missing leading chunks, which only show in the id.
+    pub fn from_unordered_chunks(v: Vec<Chunk>) -> Result<Chunks, ChunksError> {
+        Self::from_unordered_chunks_with_digest(v, DigestAlgorithm::Sha256)
+    }
+
+    /// See [Chunks::from_unordered_chunks] and
+    /// [Chunks::from_chunks_with_digest].
+    pub fn from_unordered_chunks_with_digest(
+        mut v: Vec<Chunk>,
+        algorithm: DigestAlgorithm,
+    ) -> Result<Chunks, ChunksError> {
+        v.sort_by_key(|c| std::cmp::Reverse(c.remaining));
+        v.dedup_by(|a, b| a.remaining == b.remaining && a.data == b.data);
+        Self::from_chunks_with_digest(v, algorithm)
+    }
+
+    /// Whether `chunks`, in any order and possibly with duplicates, cover a
+    /// byte string of length `len`.
+    pub fn is_complete(chunks: &[Chunk], len: usize) -> bool {
+        let mut seen: Vec<_> = chunks.iter().map(|c| (c.remaining, c.data.len())).collect();
+        seen.sort_unstable();
+        seen.dedup_by_key(|(remaining, _)| *remaining);
+        seen.first().is_some_and(|(r, l)| r == l)
+            && seen.last().is_some_and(|(r, _)| *r == len)
+            && seen.iter().map(|(_, l)| l).sum::<usize>() == len
+    }
+
+    /// Iterate over the chunks. The data of each chunk is a cheap slice of
+    /// the underlying buffer, i.e. no bytes are copied.
+    pub fn iter(&self) -> Box<dyn Iterator<Item = Chunk> + '_ + Send> {
         match &self.data {
-            ChunksData::Chunked(vs) => Box::new(vs.iter().map(|c| Chunk {
-                data: Cow::from(c.data.as_ref()),
-                remaining: c.remaining,
-            })),
+            ChunksData::Chunked(vs) => Box::new(vs.iter().cloned()),
             ChunksData::Contiguous(d) => {
                 let total_len = d.len();
+                let chunk_size = self.chunk_size;
                 Box::new(
-                    d.chunks(self.chunk_size)
-                        .enumerate()
-                        .map(move |(idx, w)| Chunk {
-                            remaining: total_len - total_len.min(idx * self.chunk_size),
-                            data: w.into(),
+                    (0..total_len)
+                        .step_by(chunk_size.max(1))
+                        .map(move |start| Chunk {
+                            remaining: total_len - start,
+                            data: d.slice(start..total_len.min(start + chunk_size)),
                         }),
                 )
             }
@@ -121,6 +362,21 @@ impl<'a> Chunks<'a> {
     pub fn chunk_size(&self) -> usize {
         self.chunk_size
     }
+
+    /// The length of the byte string.
+    pub fn total_size(&self) -> usize {
+        match &self.data {
+            ChunksData::Chunked(vs) => vs.first().map_or(0, |c| c.remaining),
+            ChunksData::Contiguous(d) => d.len(),
+        }
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        match &self.data {
+            ChunksData::Chunked(vs) => vs.len(),
+            ChunksData::Contiguous(d) => d.len().div_ceil(self.chunk_size.max(1)),
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -129,18 +385,26 @@ pub enum ChunksError {
     ChunksSizeMismatch,
     #[error("At least one value of a 'remaining'-field is invalid.")]
     InvalidRemainingValue,
+    #[error("Invalid chunk id.")]
+    InvalidId,
 }
 
-impl From<Vec<u8>> for Chunks<'_> {
+impl From<Vec<u8>> for Chunks {
     fn from(v: Vec<u8>) -> Self {
         Self::new(v, DEFAULT_CHUNK_SIZE)
     }
 }
 
+impl From<Bytes> for Chunks {
+    fn from(v: Bytes) -> Self {
+        Self::new(v, DEFAULT_CHUNK_SIZE)
+    }
+}
+
 pub struct ChunksRead<'a> {
     offset: usize,
     chunk_size: usize,
-    chunks: &'a Chunks<'a>,
+    chunks: &'a Chunks,
 }
 
 impl<'a> Read for ChunksRead<'a> {
@@ -154,10 +418,11 @@ impl<'a> Read for ChunksRead<'a> {
                 Ok(actual_len)
             }
             ChunksData::Chunked(c) => {
-                if c.is_empty() {
+                let idx = self.offset / self.chunk_size;
+                // The last chunk may be as large as the others.
+                if idx >= c.len() {
                     return Ok(0);
                 }
-                let idx = self.offset / self.chunk_size;
                 let chunk_offset = self.offset - self.chunk_size * idx;
                 let src = c[idx].data.as_ref();
                 assert!(src.len() >= chunk_offset);
@@ -171,29 +436,41 @@ impl<'a> Read for ChunksRead<'a> {
     }
 }
 
-enum ChunksData<'a> {
-    Contiguous(Vec<u8>),
-    Chunked(Vec<Chunk<'a>>),
+enum ChunksData {
+    Contiguous(Bytes),
+    Chunked(Vec<Chunk>),
 }
 
+/// A single chunk. Cloning a chunk is cheap, as the data is reference counted.
 #[derive(Debug, Clone)]
-pub struct Chunk<'a> {
+pub struct Chunk {
     pub remaining: usize,
-    pub data: Cow<'a, [u8]>,
-}
-
-impl<'a> Chunk<'a> {
-    pub fn into_owned(self) -> Chunk<'static> {
-        Chunk {
-            data: Cow::from(self.data.into_owned()),
-            remaining: self.remaining,
-        }
-    }
+    pub data: Bytes,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[test]
+    fn text_encodings_round_trip() {
+        let data: Vec<u8> = (0..=255).rev().collect();
+        for encoding in [
+            ChunkEncoding::Base64,
+            ChunkEncoding::Base85,
+            ChunkEncoding::Hex,
+        ] {
+            for len in [0, 1, 2, 3, 4, 5, 255, 256] {
+                let encoded = encoding.encode(&data[..len]);
+                assert_eq!(Some(&data[..len]), encoding.decode(&encoded).as_deref());
+            }
+        }
+        assert_eq!(
+            "HelloWorld",
+            ChunkEncoding::Base85.encode(&[0x86, 0x4F, 0xD2, 0x6F, 0xB5, 0x59, 0xF7, 0x5B])
+        );
+        assert_eq!(None, ChunkEncoding::Base85.decode("#####"));
+    }
+
     #[test]
     fn split_and_contiguous_has_same_id() {
         let data: Vec<_> = (0..7654).map(|x| (x % 256) as u8).collect();
@@ -202,13 +479,32 @@ mod tests {
         let id0 = chunks0.id();
         assert_ne!(id0, Id::from([0u8; 32]));
 
-        let chunks1 =
-            Chunks::from_chunks(chunks0.iter().map(|x| x.clone().into_owned()).collect()).unwrap();
+        let chunks1 = Chunks::from_chunks(chunks0.iter().collect()).unwrap();
         let id1 = chunks1.id();
 
         assert_eq!(id0, id1);
     }
 
+    #[test]
+    fn ids_encode_the_digest_algorithm() {
+        let data: Vec<_> = (0..7654).map(|x| (x % 256) as u8).collect();
+        let chunks = Chunks::with_digest(data, DEFAULT_CHUNK_SIZE, DigestAlgorithm::Blake3);
+        let id = chunks.id();
+        let rechunked =
+            Chunks::from_chunks_with_digest(chunks.iter().collect(), id.algorithm()).unwrap();
+        assert_eq!(id, rechunked.id());
+
+        let s = serde_json::to_string(&id).unwrap();
+        assert!(s.starts_with("\"blake3:"));
+        assert_eq!(id, serde_json::from_str(&s).unwrap());
+
+        // SHA-256 ids are plain hex strings.
+        let id = Id::from([0xab; 32]);
+        let s = serde_json::to_string(&id).unwrap();
+        assert_eq!(format!("\"{}\"", "ab".repeat(32)), s);
+        assert_eq!(id, serde_json::from_str(&s).unwrap());
+    }
+
     #[test]
     fn split_and_contiguous_have_same_content() {
         let data: Vec<_> = (0..7654).map(|x| (x % 256) as u8).collect();
@@ -217,12 +513,55 @@ mod tests {
         let mut buf0: Vec<u8> = vec![];
         std::io::copy(&mut chunks0.reader(), &mut buf0).unwrap();
 
-        let chunks1 =
-            Chunks::from_chunks(chunks0.iter().map(|x| x.clone().into_owned()).collect()).unwrap();
+        let chunks1 = Chunks::from_chunks(chunks0.iter().collect()).unwrap();
         let mut buf1: Vec<u8> = vec![];
         std::io::copy(&mut chunks1.reader(), &mut buf1).unwrap();
 
         assert_ne!(0, buf1.len());
         assert_eq!(buf0, buf1);
     }
+
+    #[test]
+    fn unordered_chunks_are_reassembled() {
+        let data: Vec<_> = (0..7654).map(|x| (x % 256) as u8).collect();
+        let chunks0 = Chunks::new(data.clone(), 1000);
+        let mut chunks: Vec<_> = chunks0.iter().collect();
+        chunks.reverse();
+        chunks.swap(2, 5);
+        chunks.push(chunks[3].clone());
+        assert!(Chunks::is_complete(&chunks, data.len()));
+        assert!(!Chunks::is_complete(&chunks[1..], data.len()));
+        assert!(!Chunks::is_complete(&chunks[..1], data.len()));
+
+        let chunks1 = Chunks::from_unordered_chunks(chunks.clone()).unwrap();
+        assert_eq!(chunks0.id(), chunks1.id());
+        assert_eq!((7654, 8), (chunks0.total_size(), chunks0.chunk_count()));
+        assert_eq!((7654, 8), (chunks1.total_size(), chunks1.chunk_count()));
+        let mut buf = vec![];
+        chunks1.reader().read_to_end(&mut buf).unwrap();
+        assert_eq!(data, buf);
+
+        chunks.remove(4);
+        assert!(matches!(
+            Chunks::from_unordered_chunks(chunks),
+            Err(ChunksError::InvalidRemainingValue)
+        ));
+    }
+
+    #[test]
+    fn contiguous_chunks_share_buffer() {
+        let data: Vec<_> = (0..7654).map(|x| (x % 256) as u8).collect();
+        let chunks = Chunks::from(data);
+        let ChunksData::Contiguous(d) = &chunks.data else {
+            panic!("Expected contiguous data")
+        };
+
+        let mut offset = 0;
+        for c in chunks.iter() {
+            assert_eq!(d[offset..].as_ptr(), c.data.as_ptr());
+            assert_eq!(d.len() - offset, c.remaining);
+            offset += c.data.len();
+        }
+        assert_eq!(d.len(), offset);
+    }
 }
@@ -1,13 +1,372 @@
 //! A scrape service that executes commands and collects their output.
+//!
+//! If a call is dropped, e.g. because it timed out, the command is killed. On
+//! Windows, the command is assigned to a job object, such that processes
+//! spawned by the command are killed as well.
+//!
+//! How a command is run is described by a [CommandSpec], which is both the
+//! command part of the config and a builder for commands in code.
 
-use std::process::Stdio;
+use std::{
+    collections::BTreeMap,
+    env, io,
+    ops::{Deref, DerefMut, Range},
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, SystemTime},
+};
 
-use tokio::process::Command;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationSecondsWithFrac};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    process::{Child, Command},
+    time::Instant,
+};
+use tracing::debug;
 
-use crate::scrape_target::{FutureScrapeResult, ScrapeOk, ScrapeService};
+use crate::{
+    parse::OutputParser,
+    scrape_target::{FutureScrapeResult, ScrapeOk, ScrapeResult, ScrapeService},
+};
+
+/// The most bytes of output read at once, such that a long line is charged to
+/// the memory budget while it is being read. See [crate::memory].
+const MAX_READ: u64 = 64 * 1024;
+
+#[cfg(target_os = "linux")]
+mod linux;
+#[cfg(windows)]
+mod windows;
+
+/// The output of a command. In addition to the complete stdout and stderr, the
+/// lines of both streams are recorded in the order they were read, such that
+/// the relative ordering of e.g. progress messages and errors is retained.
+#[derive(Debug, Clone)]
+pub struct CommandOutput {
+    pub status: ExitStatus,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub lines: Vec<OutputLine>,
+    pub usage: ResourceUsage,
+    /// Stdout as parsed by the [OutputParser] of the command, if any. See
+    /// [crate::parse].
+    pub parsed: Option<serde_json::Value>,
+}
+
+/// The resources consumed by a command, including the processes it waited
+/// for.
+#[serde_as]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ResourceUsage {
+    /// From spawning the command until it exited.
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub wall_time: Duration,
+    /// The CPU time spent in user mode. Only available on Linux, like the
+    /// system time and the peak RSS.
+    #[serde_as(as = "Option<DurationSecondsWithFrac<f64>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_time: Option<Duration>,
+    #[serde_as(as = "Option<DurationSecondsWithFrac<f64>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system_time: Option<Duration>,
+    /// The peak resident set size of the largest process.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_rss_bytes: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// A line of output. To avoid copying the output, a line refers to a range of
+/// [CommandOutput::stdout] or [CommandOutput::stderr].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputLine {
+    /// The time at which the line was read.
+    pub timestamp: SystemTime,
+    pub stream: Stream,
+    pub range: Range<usize>,
+}
+
+impl CommandOutput {
+    /// The interleaved lines of stdout and stderr.
+    pub fn lines(&self) -> impl Iterator<Item = (&OutputLine, &[u8])> {
+        self.lines.iter().map(|l| {
+            let buf = match l.stream {
+                Stream::Stdout => &self.stdout,
+                Stream::Stderr => &self.stderr,
+            };
+            (l, &buf[l.range.clone()])
+        })
+    }
+
+    /// Drop the first `n` bytes of stdout.
+    fn truncate_stdout_front(&mut self, n: usize) {
+        self.stdout.drain(..n);
+        self.lines.retain_mut(|l| {
+            if l.stream == Stream::Stderr {
+                return true;
+            }
+            l.range = l.range.start.max(n) - n..l.range.end.saturating_sub(n);
+            !l.range.is_empty()
+        });
+    }
+}
+
+/// The stdout of the previous call of a command that only emits new output.
+pub type OutputCursor = Arc<StdMutex<Vec<u8>>>;
+
+/// How to run a command. See the [module docs](self).
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct CommandSpec {
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Run `command` as a command line of the platform shell (`sh -c` on
+    /// Unix, `cmd /C` on Windows). `args` are appended to the command line
+    /// without any quoting.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub shell: bool,
+    /// Variables set in addition to the environment of debugbunny.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// The working directory. Defaults to the one of debugbunny.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<PathBuf>,
+    /// Written to stdin, which is closed afterwards. Without it, stdin is
+    /// empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stdin: Option<String>,
+    /// Run as the given user. Requires the privileges to do so. Unix only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    /// Run with the given group. Requires the privileges to do so. Unix only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gid: Option<u32>,
+    #[serde(default, skip_serializing_if = "ResourceLimits::is_unlimited")]
+    pub limits: ResourceLimits,
+    /// If a call is dropped, first ask the command to exit with `SIGTERM` and
+    /// only kill it if it is still running after the given duration. Without
+    /// it, the command is killed right away. Unix only.
+    #[serde_as(as = "Option<DurationSecondsWithFrac<f64>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kill_grace_period: Option<Duration>,
+}
+
+/// Resource limits of a command (`setrlimit`). Unix only.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub struct ResourceLimits {
+    /// CPU time in seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_seconds: Option<u64>,
+    /// Size of the address space in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_bytes: Option<u64>,
+    /// Number of open file descriptors.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub open_files: Option<u64>,
+}
+
+impl CommandSpec {
+    pub fn new<S: ToString>(command: S) -> Self {
+        Self {
+            command: command.to_string(),
+            args: vec![],
+            shell: false,
+            env: BTreeMap::new(),
+            cwd: None,
+            stdin: None,
+            uid: None,
+            gid: None,
+            limits: ResourceLimits::default(),
+            kill_grace_period: None,
+        }
+    }
+
+    /// A command line that is run by the platform shell.
+    pub fn shell<S: ToString>(command_line: S) -> Self {
+        Self {
+            shell: true,
+            ..Self::new(command_line)
+        }
+    }
+
+    pub fn arg<S: ToString>(mut self, arg: S) -> Self {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    pub fn args<I: IntoIterator<Item = S>, S: ToString>(mut self, args: I) -> Self {
+        self.args.extend(args.into_iter().map(|a| a.to_string()));
+        self
+    }
+
+    pub fn env<K: ToString, V: ToString>(mut self, key: K, value: V) -> Self {
+        self.env.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn cwd<P: Into<PathBuf>>(mut self, cwd: P) -> Self {
+        self.cwd = Some(cwd.into());
+        self
+    }
+
+    pub fn stdin<S: ToString>(mut self, stdin: S) -> Self {
+        self.stdin = Some(stdin.to_string());
+        self
+    }
+
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.uid = Some(uid);
+        self
+    }
+
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.gid = Some(gid);
+        self
+    }
+
+    pub fn limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn kill_grace_period(mut self, d: Duration) -> Self {
+        self.kill_grace_period = Some(d);
+        self
+    }
+
+    fn build(&self) -> Command {
+        let mut cmd = if self.shell {
+            let line = std::iter::once(&self.command).chain(&self.args).cloned();
+            shell_command(&line.collect::<Vec<_>>().join(" "))
+        } else {
+            let mut cmd = Command::new(&self.command);
+            cmd.args(&self.args);
+            cmd
+        };
+        cmd.envs(&self.env);
+        if let Some(cwd) = &self.cwd {
+            cmd.current_dir(cwd);
+        }
+        cmd.stdin(Stdio::null());
+        #[cfg(unix)]
+        {
+            if let Some(uid) = self.uid {
+                cmd.uid(uid);
+            }
+            if let Some(gid) = self.gid {
+                cmd.gid(gid);
+            }
+            if !self.limits.is_unlimited() {
+                let limits = self.limits;
+                // SAFETY: the closure only makes `setrlimit` syscalls, which
+                // are async-signal-safe, and does not allocate.
+                unsafe {
+                    cmd.pre_exec(move || limits.apply());
+                }
+            }
+        }
+        cmd
+    }
+}
+
+impl ResourceLimits {
+    pub fn is_unlimited(&self) -> bool {
+        *self == Self::default()
+    }
+
+    #[cfg(unix)]
+    fn apply(&self) -> io::Result<()> {
+        use rustix::process::{setrlimit, Resource, Rlimit};
+
+        for (resource, limit) in [
+            (Resource::Cpu, self.cpu_seconds),
+            (Resource::As, self.memory_bytes),
+            (Resource::Nofile, self.open_files),
+        ] {
+            if let Some(limit) = limit {
+                let limit = Rlimit {
+                    current: Some(limit),
+                    maximum: Some(limit),
+                };
+                setrlimit(resource, limit)?;
+            }
+        }
+        Ok(())
+    }
+}
 
 pub struct CommandScrapeService<T> {
     command_constr: T,
+    /// The stdout of the previous call, if only new output is to be emitted.
+    previous_stdout: Option<OutputCursor>,
+    stdin: Option<Arc<[u8]>>,
+    kill_grace_period: Option<Duration>,
+    parser: Option<OutputParser>,
+}
+
+/// Kills the child when dropped, unless it exited. See
+/// [CommandSpec::kill_grace_period].
+struct KillOnDrop {
+    child: Option<Child>,
+    grace_period: Option<Duration>,
+}
+
+impl Deref for KillOnDrop {
+    type Target = Child;
+
+    fn deref(&self) -> &Child {
+        self.child.as_ref().expect("only taken on drop")
+    }
+}
+
+impl DerefMut for KillOnDrop {
+    fn deref_mut(&mut self) -> &mut Child {
+        self.child.as_mut().expect("only taken on drop")
+    }
+}
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let Some(mut child) = self.child.take() else {
+            return;
+        };
+        if matches!(child.try_wait(), Ok(Some(_))) {
+            return;
+        }
+        #[cfg(unix)]
+        if let (Some(grace_period), Some(pid), Ok(runtime)) = (
+            self.grace_period,
+            child
+                .id()
+                .and_then(|id| rustix::process::Pid::from_raw(id as i32)),
+            tokio::runtime::Handle::try_current(),
+        ) {
+            use rustix::process::{kill_process, Signal};
+
+            if kill_process(pid, Signal::TERM).is_ok() {
+                runtime.spawn(async move {
+                    if tokio::time::timeout(grace_period, child.wait())
+                        .await
+                        .is_err()
+                    {
+                        debug!("command did not exit within its grace period");
+                        let _ = child.kill().await;
+                    }
+                });
+                return;
+            }
+        }
+        let _ = child.start_kill();
+    }
 }
 
 impl<T> CommandScrapeService<T>
@@ -15,22 +374,117 @@ where
     T: Fn() -> Command + 'static,
 {
     pub fn new(command_constr: T) -> Self {
-        Self { command_constr }
+        Self {
+            command_constr,
+            previous_stdout: None,
+            stdin: None,
+            kill_grace_period: None,
+            parser: None,
+        }
+    }
+
+    /// For commands with append-only output (e.g. `dmesg`), only emit the
+    /// part of stdout that is new compared to the previous call.
+    pub fn only_new_output(mut self) -> Self {
+        self.previous_stdout = Some(Default::default());
+        self
+    }
+
+    /// Like [Self::only_new_output], but compare to the given cursor, e.g.
+    /// the output of a previous run of debugbunny. The cursor is updated
+    /// after every call.
+    pub fn only_new_output_after(mut self, cursor: OutputCursor) -> Self {
+        self.previous_stdout = Some(cursor);
+        self
+    }
+
+    /// Parse stdout into [CommandOutput::parsed]. Combined with
+    /// [Self::only_new_output], only the new output is parsed.
+    pub fn parse_output(mut self, parser: OutputParser) -> Self {
+        self.parser = Some(parser);
+        self
     }
 }
 
-pub fn new_from_config(
-    cmd: String,
-    args: Vec<String>,
-) -> CommandScrapeService<impl Fn() -> Command + 'static> {
-    let f = move || {
-        let mut cmd = Command::new(cmd.clone());
-        args.iter().for_each(|a| {
-            cmd.arg(a);
-        });
+/// Run commands as described by `spec`.
+pub fn new_from_spec(spec: CommandSpec) -> CommandScrapeService<impl Fn() -> Command + 'static> {
+    #[cfg(not(unix))]
+    if spec.uid.is_some() || spec.gid.is_some() || !spec.limits.is_unlimited() {
+        tracing::warn!(command = spec.command, "user, group and limits are ignored");
+    }
+    let stdin = spec.stdin.as_ref().map(|s| Arc::from(s.as_bytes()));
+    let kill_grace_period = spec.kill_grace_period;
+    CommandScrapeService {
+        stdin,
+        kill_grace_period,
+        ..CommandScrapeService::new(move || spec.build())
+    }
+}
+
+/// Resolve `command` like spawning it would: a command containing a path
+/// separator is taken as a path, any other command is looked up on `PATH`.
+/// Returns `None` if there is no executable file for `command`.
+pub fn find_executable(command: &str) -> Option<PathBuf> {
+    let path = Path::new(command);
+    if path.components().count() > 1 {
+        return is_executable(path).then(|| path.to_path_buf());
+    }
+    env::split_paths(&env::var_os("PATH")?)
+        .flat_map(|dir| candidates(dir.join(command)))
+        .find(|p| is_executable(p))
+}
+
+#[cfg(unix)]
+fn candidates(path: PathBuf) -> Vec<PathBuf> {
+    vec![path]
+}
+
+#[cfg(windows)]
+fn candidates(path: PathBuf) -> Vec<PathBuf> {
+    let extensions = env::var("PATHEXT").unwrap_or_else(|_| ".COM;.EXE;.BAT;.CMD".into());
+    std::iter::once(path.clone())
+        .chain(extensions.split(';').map(|ext| {
+            let mut p = path.clone().into_os_string();
+            p.push(ext);
+            PathBuf::from(p)
+        }))
+        .collect()
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    path.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(windows)]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Run `command_line` using the platform shell.
+pub fn new_shell(command_line: String) -> CommandScrapeService<impl Fn() -> Command + 'static> {
+    new_from_spec(CommandSpec::shell(command_line))
+}
+
+fn shell_command(command_line: &str) -> Command {
+    #[cfg(windows)]
+    let mut cmd = {
+        let mut cmd = Command::new("cmd");
+        // `cmd` has its own quoting rules, so pass the line verbatim.
+        cmd.arg("/C").raw_arg(command_line);
+        cmd
+    };
+    #[cfg(not(windows))]
+    let mut cmd = {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg(command_line);
         cmd
     };
-    CommandScrapeService::new(f)
+    cmd.stdin(Stdio::null());
+    cmd
 }
 
 impl<T> ScrapeService for CommandScrapeService<T>
@@ -40,21 +494,159 @@ where
     type Response = ScrapeOk;
     fn call(&mut self) -> FutureScrapeResult<ScrapeOk> {
         let mut command = (self.command_constr)();
-        command.kill_on_drop(true);
         command.stdout(Stdio::piped());
         command.stderr(Stdio::piped());
+        if self.stdin.is_some() {
+            command.stdin(Stdio::piped());
+        }
+        // Should the runtime shut down before a terminated child exited.
+        command.kill_on_drop(true);
+        let previous_stdout = self.previous_stdout.clone();
+        let stdin = self.stdin.clone();
+        let grace_period = self.kill_grace_period;
+        let parser = self.parser.clone();
         Box::pin(async move {
-            let child = command.spawn()?;
-            let output = child.wait_with_output().await?;
+            crate::memory::admit().await;
+            debug!(command = ?command.as_std(), "spawning command");
+            let started = Instant::now();
+            let mut child = KillOnDrop {
+                child: Some(command.spawn()?),
+                grace_period,
+            };
+            #[cfg(windows)]
+            let _job = windows::KillOnDropJob::assign(&child)?;
+            let input = child.stdin.take().zip(stdin);
+            let write_input = async move {
+                if let Some((mut pipe, data)) = input {
+                    // The command may exit without reading its input.
+                    let _ = pipe.write_all(&data).await;
+                }
+            };
+            let (_, output) = tokio::join!(write_input, collect_output(&mut child, started));
+            let mut output = output?;
+            debug!(status = %output.status, lines = output.lines.len(), "command exited");
+            if let Some(previous_stdout) = previous_stdout {
+                let mut previous = previous_stdout.lock().unwrap();
+                let new_len = new_suffix(&previous, &output.stdout).len();
+                let current = output.stdout.clone();
+                output.truncate_stdout_front(current.len() - new_len);
+                *previous = current;
+            }
+            if let Some(parser) = parser {
+                match parser.parse(&output.stdout) {
+                    Ok(v) => output.parsed = Some(v),
+                    Err(e) => tracing::warn!(error = %e, "could not parse output"),
+                }
+            }
             Ok(ScrapeOk::CommandResponse(output))
         })
     }
 }
 
+/// Read stdout and stderr of `child` line by line until both are closed, then
+/// wait for the child to exit. The output is charged to the memory budget as
+/// it is read.
+async fn collect_output(child: &mut Child, started: Instant) -> ScrapeResult<CommandOutput> {
+    let mut stdout_reader = BufReader::new(child.stdout.take().expect("stdout is piped"));
+    let mut stderr_reader = BufReader::new(child.stderr.take().expect("stderr is piped"));
+    let (mut stdout, mut stderr, mut lines) = (vec![], vec![], vec![]);
+    // The start of the current line and whether the stream is still open.
+    let mut stdout_state = (0, true);
+    let mut stderr_state = (0, true);
+    let mut charged = 0;
+    while stdout_state.1 || stderr_state.1 {
+        // `read_until` appends partially read data to the buffer if it is
+        // cancelled, so the next call simply continues the line.
+        let mut stdout_chunk = (&mut stdout_reader).take(MAX_READ);
+        let mut stderr_chunk = (&mut stderr_reader).take(MAX_READ);
+        let (stream, n) = tokio::select! {
+            n = stdout_chunk.read_until(b'\n', &mut stdout), if stdout_state.1 => (Stream::Stdout, n?),
+            n = stderr_chunk.read_until(b'\n', &mut stderr), if stderr_state.1 => (Stream::Stderr, n?),
+        };
+        // Including data read by a cancelled call.
+        crate::memory::charge(stdout.len() + stderr.len() - charged)?;
+        charged = stdout.len() + stderr.len();
+        let (state, buf) = match stream {
+            Stream::Stdout => (&mut stdout_state, &stdout),
+            Stream::Stderr => (&mut stderr_state, &stderr),
+        };
+        if n == 0 {
+            state.1 = false;
+        }
+        // A line is complete once its end or the end of the stream is read.
+        if buf.len() > state.0 && (n == 0 || buf.ends_with(b"\n")) {
+            lines.push(OutputLine {
+                timestamp: SystemTime::now(),
+                stream,
+                range: state.0..buf.len(),
+            });
+            state.0 = buf.len();
+        }
+    }
+    let mut usage = ResourceUsage::default();
+    #[cfg(target_os = "linux")]
+    if let Some(pid) = child.id() {
+        match linux::wait_for_usage(pid).await {
+            Ok(u) => {
+                usage.user_time = Some(u.user_time);
+                usage.system_time = Some(u.system_time);
+                usage.max_rss_bytes = Some(u.max_rss_bytes);
+            }
+            Err(e) => debug!(error = %e, "could not get resource usage"),
+        }
+    }
+    let status = child.wait().await?;
+    usage.wall_time = started.elapsed();
+    Ok(CommandOutput {
+        status,
+        stdout,
+        stderr,
+        lines,
+        usage,
+        parsed: None,
+    })
+}
+
+/// Returns the part of `current` that was not contained in `previous`.
+///
+/// Append-only outputs may be truncated at the front (e.g. the kernel ring
+/// buffer). Thus, we look for the first line of `previous` such that
+/// `current` starts with everything from that line on. If there is no such
+/// line, `current` is returned as a whole.
+fn new_suffix<'a>(previous: &[u8], current: &'a [u8]) -> &'a [u8] {
+    let line_starts = std::iter::once(0).chain(
+        previous
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| **b == b'\n')
+            .map(|(i, _)| i + 1),
+    );
+    for start in line_starts {
+        if start == previous.len() {
+            break;
+        }
+        let overlap = &previous[start..];
+        if current.starts_with(overlap) {
+            return &current[overlap.len()..];
+        }
+    }
+    current
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[cfg(unix)]
+    #[test]
+    fn executables_are_found() {
+        assert!(find_executable("sh").is_some());
+        assert!(find_executable("/bin/sh").is_some());
+        assert!(find_executable("no-such-command-for-debugbunny").is_none());
+        let manifest = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        assert!(find_executable(manifest).is_none());
+    }
+
     #[tokio::test]
     async fn simple_command_execution() {
         let mut cmd_s = CommandScrapeService::new(echo);
@@ -69,7 +661,159 @@ mod tests {
             .any(|w| w == expected_string.as_bytes()));
     }
 
+    #[test]
+    fn only_new_lines_are_emitted() {
+        assert_eq!(b"c\n", new_suffix(b"a\nb\n", b"a\nb\nc\n"));
+        // The head of the output has been dropped.
+        assert_eq!(b"c\nd\n", new_suffix(b"a\nb\n", b"b\nc\nd\n"));
+        // The output has been reset.
+        assert_eq!(b"x\n", new_suffix(b"a\nb\n", b"x\n"));
+        assert_eq!(b"a\n", new_suffix(b"", b"a\n"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stdout_and_stderr_are_interleaved() {
+        let mut cmd_s = CommandScrapeService::new(|| {
+            let mut cmd = Command::new("sh");
+            cmd.args(["-c", "echo a; sleep 0.1; echo b >&2; sleep 0.1; echo c"]);
+            cmd
+        });
+        let ScrapeOk::CommandResponse(output) = cmd_s.call().await.unwrap() else {
+            panic!("Invalid response")
+        };
+        let lines: Vec<_> = output.lines().map(|(l, s)| (l.stream, s)).collect();
+        assert_eq!(
+            vec![
+                (Stream::Stdout, &b"a\n"[..]),
+                (Stream::Stderr, b"b\n"),
+                (Stream::Stdout, b"c\n"),
+            ],
+            lines
+        );
+        assert_eq!(b"a\nc\n", output.stdout.as_slice());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn resource_usage_is_recorded() {
+        let mut cmd_s = new_shell(
+            "i=0; while [ $i -lt 100000 ]; do i=$((i+1)); done; sleep 0.1; exit 3".to_string(),
+        );
+        let ScrapeOk::CommandResponse(output) = cmd_s.call().await.unwrap() else {
+            panic!("Invalid response")
+        };
+        // The child is still reaped, so the exit status is intact.
+        assert_eq!(Some(3), output.status.code());
+        let usage = output.usage;
+        assert!(usage.wall_time >= Duration::from_millis(100));
+        assert!(usage.user_time.unwrap() + usage.system_time.unwrap() > Duration::ZERO);
+        assert!(usage.max_rss_bytes.unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn output_is_parsed() {
+        let mut cmd_s =
+            new_shell("echo a=1&& echo b=x".to_string()).parse_output(OutputParser::key_value());
+        let ScrapeOk::CommandResponse(output) = cmd_s.call().await.unwrap() else {
+            panic!("Invalid response")
+        };
+        assert_eq!(Some(serde_json::json!({ "a": 1, "b": "x" })), output.parsed);
+    }
+
+    #[tokio::test]
+    async fn shell_command_line() {
+        let mut cmd_s = new_shell("echo a&& echo b".to_string());
+        let ScrapeOk::CommandResponse(output) = cmd_s.call().await.unwrap() else {
+            panic!("Invalid response")
+        };
+        let lines: Vec<_> = output
+            .lines()
+            .map(|(_, l)| String::from_utf8_lossy(l).trim_end().to_string())
+            .collect();
+        assert_eq!(vec!["a", "b"], lines);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn specs_set_up_the_environment() {
+        let spec = CommandSpec::shell("echo $GREETING; pwd; cat")
+            .env("GREETING", "hi")
+            .cwd("/")
+            .stdin("input");
+        let ScrapeOk::CommandResponse(output) = new_from_spec(spec).call().await.unwrap() else {
+            panic!("Invalid response")
+        };
+        assert_eq!(b"hi\n/\ninput", output.stdout.as_slice());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn dropped_commands_are_terminated_gracefully() {
+        let marker = std::env::temp_dir().join(format!("debugbunny-term-{}", std::process::id()));
+        let _ = std::fs::remove_file(&marker);
+        let spec = CommandSpec::shell(format!(
+            "trap 'echo > {}; kill $!; exit 0' TERM; sleep 10 & wait",
+            marker.display()
+        ))
+        .kill_grace_period(Duration::from_secs(5));
+        let mut s = new_from_spec(spec);
+        let call = tokio::time::timeout(Duration::from_millis(200), s.call());
+        assert!(call.await.is_err());
+
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !marker.exists() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(marker.exists());
+        std::fs::remove_file(marker).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn in_flight_commands_are_killed_when_cancelled() {
+        use crate::scrape_target::{ScrapeErr, ScrapeTarget};
+
+        let pid_file = std::env::temp_dir().join(format!("debugbunny-pid-{}", std::process::id()));
+        let _ = std::fs::remove_file(&pid_file);
+        let s = new_shell(format!("echo $$ > {}; exec sleep 30", pid_file.display()));
+        let (cancel, cancelled) = tokio::sync::watch::channel(());
+        let mut st = ScrapeTarget::new_with_cancel(s, Duration::from_secs(60), cancelled);
+        let call = tokio::spawn(async move { st.scheduled.call().await });
+        while std::fs::read_to_string(&pid_file).map_or(true, |p| !p.ends_with('\n')) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        std::fs::remove_file(pid_file).unwrap();
+
+        cancel.send(()).unwrap();
+        let r = tokio::time::timeout(Duration::from_secs(1), call)
+            .await
+            .unwrap();
+        assert!(matches!(r.unwrap(), Err(ScrapeErr::Cancelled)));
+        // Killed, i.e. gone or a zombie until it is reaped.
+        let stat = format!("/proc/{}/stat", pid.trim());
+        let deadline = Instant::now() + Duration::from_secs(2);
+        let alive = || {
+            std::fs::read_to_string(&stat).is_ok_and(|s| {
+                s.rsplit_once(") ")
+                    .is_some_and(|(_, r)| !r.starts_with('Z'))
+            })
+        };
+        while alive() && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(!alive());
+    }
+
     fn echo() -> Command {
+        #[cfg(windows)]
+        let mut cmd = {
+            let mut cmd = Command::new("cmd");
+            cmd.args(["/C", "echo"]);
+            cmd
+        };
+        #[cfg(not(windows))]
         let mut cmd = Command::new("echo");
         cmd.arg("test");
         cmd
@@ -0,0 +1,58 @@
+//! Resource usage of commands.
+
+use std::{io, mem, time::Duration};
+
+/// The CPU times and the peak RSS of a child, including the processes it
+/// waited for.
+pub(super) struct Usage {
+    pub user_time: Duration,
+    pub system_time: Duration,
+    pub max_rss_bytes: u64,
+}
+
+/// Wait for the child with the given pid to exit and return its resource
+/// usage.
+///
+/// tokio reaps the child when it is waited for, so `wait4` cannot be used.
+/// Instead, the child is waited for without reaping it (`WNOWAIT`). Unlike
+/// the libc wrapper, the `waitid` syscall reports the resource usage of the
+/// zombie in that case.
+pub(super) async fn wait_for_usage(pid: u32) -> io::Result<Usage> {
+    tokio::task::spawn_blocking(move || {
+        // SAFETY: both structs are plain data for which all-zeros is valid.
+        let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+        let mut usage: libc::rusage = unsafe { mem::zeroed() };
+        loop {
+            // SAFETY: the pointers are valid for the duration of the call.
+            let r = unsafe {
+                libc::syscall(
+                    libc::SYS_waitid,
+                    libc::P_PID,
+                    pid as libc::id_t,
+                    &mut info as *mut libc::siginfo_t,
+                    libc::WEXITED | libc::WNOWAIT,
+                    &mut usage as *mut libc::rusage,
+                )
+            };
+            if r == 0 {
+                break;
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+        Ok(Usage {
+            user_time: duration(usage.ru_utime),
+            system_time: duration(usage.ru_stime),
+            // In kilobytes on Linux.
+            max_rss_bytes: usage.ru_maxrss as u64 * 1024,
+        })
+    })
+    .await
+    .expect("Could not join blocking code!")
+}
+
+fn duration(t: libc::timeval) -> Duration {
+    Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000)
+}
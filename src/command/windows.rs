@@ -0,0 +1,65 @@
+//! Job objects to kill the whole process tree of a command.
+
+use std::{io, mem, ptr};
+
+use tokio::process::Child;
+use windows_sys::Win32::{
+    Foundation::{CloseHandle, HANDLE},
+    System::JobObjects::{
+        AssignProcessToJobObject, CreateJobObjectW, JobObjectExtendedLimitInformation,
+        SetInformationJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+        JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+    },
+};
+
+/// A job object that kills all processes assigned to it once it is dropped.
+///
+/// Processes spawned by the child before it has been assigned to the job are
+/// not part of the job. As the child is assigned right after spawning it,
+/// this is unlikely in practice.
+pub(super) struct KillOnDropJob(HANDLE);
+
+// The handle is only used to close the job.
+unsafe impl Send for KillOnDropJob {}
+
+impl KillOnDropJob {
+    pub(super) fn assign(child: &Child) -> io::Result<Self> {
+        let Some(process) = child.raw_handle() else {
+            // The child has already exited.
+            return Ok(Self(ptr::null_mut()));
+        };
+        // SAFETY: All pointers passed are valid for the duration of the calls
+        // and the job handle is owned by the returned value.
+        unsafe {
+            let job = CreateJobObjectW(ptr::null(), ptr::null());
+            if job.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            let job = Self(job);
+            let mut info: JOBOBJECT_EXTENDED_LIMIT_INFORMATION = mem::zeroed();
+            info.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+            let ok = SetInformationJobObject(
+                job.0,
+                JobObjectExtendedLimitInformation,
+                &info as *const _ as *const _,
+                mem::size_of_val(&info) as u32,
+            );
+            if ok == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if AssignProcessToJobObject(job.0, process as HANDLE) == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(job)
+        }
+    }
+}
+
+impl Drop for KillOnDropJob {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            // SAFETY: The handle is owned by `self` and closed only once.
+            unsafe { CloseHandle(self.0) };
+        }
+    }
+}
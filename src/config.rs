@@ -1,12 +1,45 @@
-use std::{str::FromStr, time::Duration};
+use std::{
+    collections::{BTreeMap, HashSet},
+    net::IpAddr,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
 
-use reqwest::{Method, Url};
+use reqwest::{Method, StatusCode};
+use schemars::JsonSchema;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
-use serde_with::{serde_as, DurationSeconds};
+use serde_with::{serde_as, DurationSeconds, TryFromInto};
+use tokio_rustls::rustls::pki_types::ServerName;
 
+use crate::{
+    check::Check,
+    command::CommandSpec,
+    debugbunny::{DebugBunny, ProcessorErrorPolicy},
+    derive::DerivedField,
+    parse::OutputParser,
+    preflight::PreflightReport,
+    template::{UrlTemplate, Variables},
+};
+
+/// The timeout of targets that do not specify one.
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The configuration of a set of scrape targets.
+///
+/// When deserializing, targets that belong to a group inherit the interval,
+/// timeout and labels of their group, unless they specify them themselves.
+/// Thus, the targets of a deserialized config are fully resolved.
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(try_from = "RawConfig")]
 pub struct Config {
     pub scrape_targets: Vec<ScrapeTargetConfig>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub groups: BTreeMap<String, GroupConfig>,
+    /// Values for the placeholders in URL templates. See [Variables].
+    #[serde(skip_serializing_if = "Variables::is_empty")]
+    pub variables: Variables,
 }
 
 impl Config {
@@ -17,67 +50,1341 @@ impl Config {
     pub fn add_target(&mut self, t: ScrapeTargetConfig) {
         self.scrape_targets.push(t);
     }
+
+    pub fn add_group<S: ToString>(&mut self, name: S, g: GroupConfig) {
+        self.groups.insert(name.to_string(), g);
+    }
+
+    /// Load a config from a JSON file. With the `yaml` feature, files ending
+    /// in `.yaml` or `.yml` are read as YAML, with the same structure.
+    ///
+    /// Before parsing, environment variables of the form `${VAR}` or
+    /// `${VAR:-default}` are expanded. Within double-quoted strings, the values
+    /// are escaped as needed. Elsewhere, they are inserted verbatim, e.g. a number in
+    /// `"interval": ${INTERVAL}`. `$$` yields a literal `$`.
+    ///
+    /// The file may contain a list of glob patterns under the key `include`,
+    /// e.g. `"include": ["targets.d/*.json"]`. Relative patterns are resolved
+    /// against the directory of the including file. Each included file has the
+    /// same structure as the main config, in either format, and is merged into it: Targets are
+    /// appended, while groups and variables must not be defined more than once.
+    /// Likewise, target names must be unique across all files.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        Self::load_impl(path.as_ref(), false)
+    }
+
+    /// Like [Config::load], but unknown fields are rejected instead of being
+    /// ignored. The error suggests the closest known field, if any. E.g., a
+    /// misspelled `timout` would otherwise silently fall back to the default
+    /// timeout.
+    pub fn load_strict<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        Self::load_impl(path.as_ref(), true)
+    }
+
+    fn load_impl(path: &Path, strict: bool) -> Result<Self, ConfigError> {
+        let v = load_value(path, &mut vec![])?;
+        if strict {
+            let schema = serde_json::to_value(Self::json_schema()).expect("can't fail");
+            check_unknown_fields(&v, &schema, &schema, "config")?;
+        }
+        serde_json::from_value(v).map_err(|e| ConfigError::Parse(path.to_owned(), e))
+    }
+
+    /// Call each target once and report problems that would keep it from
+    /// being scraped. See [crate::debugbunny::DebugBunnyBuilder::preflight].
+    pub async fn preflight(&self) -> PreflightReport {
+        DebugBunny::builder()
+            .variables(self.variables.clone())
+            .preflight(&self.scrape_targets)
+            .await
+    }
+
+    /// Keep only the targets that are enabled by the tags, such that one
+    /// config can serve hosts with different roles. Tags are
+    /// [LabelSelector]s, e.g. `role=database`. If `only` is not empty, a
+    /// target must match at least one of its tags. A target that matches any
+    /// tag of `exclude` is dropped.
+    pub fn retain_tagged(&mut self, only: &[LabelSelector], exclude: &[LabelSelector]) {
+        self.scrape_targets.retain(|t| {
+            let included = only.is_empty() || only.iter().any(|s| s.matches(t));
+            included && !exclude.iter().any(|s| s.matches(t))
+        });
+    }
+
+    /// The JSON schema of config files as accepted by [Config::load].
+    pub fn json_schema() -> schemars::Schema {
+        schemars::schema_for!(ConfigFile)
+    }
+}
+
+/// A debugbunny config file.
+//
+// This type only exists to derive the JSON schema, see [Config::load] for the
+// semantics.
+#[allow(dead_code)]
+#[derive(JsonSchema)]
+#[schemars(title = "Config")]
+struct ConfigFile {
+    /// Glob patterns of further config files to merge into this one.
+    #[serde(default)]
+    include: Vec<String>,
+    scrape_targets: Vec<ScrapeTargetConfig>,
+    #[serde(default)]
+    groups: BTreeMap<String, GroupConfig>,
+    /// Values for the placeholders in URL templates.
+    #[serde(default)]
+    variables: Variables,
+    /// Tags that enable targets, see [Config::retain_tagged].
+    #[serde(default)]
+    only: Vec<String>,
+    /// Tags that disable targets, see [Config::retain_tagged].
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Target refers to unknown group '{0}'")]
+    UnknownGroup(String),
+    #[error("Invalid scrape target: {0}")]
+    InvalidTarget(#[from] serde_json::Error),
+    #[error("'{0}' is defined more than once")]
+    Duplicate(String),
+    #[error("Could not read {0}")]
+    Io(PathBuf, #[source] std::io::Error),
+    #[error("Could not parse {0}: {1}")]
+    Parse(PathBuf, #[source] serde_json::Error),
+    #[cfg(feature = "yaml")]
+    #[error("Could not parse {0}: {1}")]
+    ParseYaml(PathBuf, #[source] serde_yaml_ng::Error),
+    #[error("{0} is a YAML file, which requires the `yaml` feature")]
+    YamlNotSupported(PathBuf),
+    #[error("Invalid include pattern")]
+    Pattern(#[from] glob::PatternError),
+    #[error("Could not expand include pattern")]
+    Glob(#[from] glob::GlobError),
+    #[error("{0} includes itself")]
+    IncludeCycle(PathBuf),
+    #[error("Environment variable '{0}' is not set and has no default")]
+    UnsetVariable(String),
+    #[error("Invalid tag")]
+    Tag(#[from] LabelSelectorError),
+    #[error("Unterminated variable reference '${{{0}'")]
+    UnterminatedVariable(String),
+    #[error("Invalid TLS server name '{0}'")]
+    InvalidServerName(String),
+    #[error("Invalid field path '{0}'")]
+    InvalidFieldPath(String),
+    #[error("Promoted field '{0}' collides with a key of the record")]
+    ReservedField(String),
+    #[error("The timeout of a streamed response must exceed flush_every ({0:?})")]
+    StreamTimeout(Duration),
+    #[error("Unknown field '{field}' in {path}{}", did_you_mean(.suggestion))]
+    UnknownField {
+        path: String,
+        field: String,
+        suggestion: Option<String>,
+    },
+}
+
+fn did_you_mean(suggestion: &Option<String>) -> String {
+    suggestion
+        .as_ref()
+        .map(|s| format!(", did you mean '{s}'?"))
+        .unwrap_or_default()
+}
+
+/// Check that all keys of the objects in `v` are known to `schema`. `root` is
+/// the schema that contains the definitions `$ref`s point to.
+fn check_unknown_fields(
+    v: &serde_json::Value,
+    schema: &serde_json::Value,
+    root: &serde_json::Value,
+    path: &str,
+) -> Result<(), ConfigError> {
+    use serde_json::Value;
+    let schema = resolve_ref(schema, root);
+    let schema = match schema.get("oneOf").or_else(|| schema.get("anyOf")) {
+        Some(Value::Array(alternatives)) => match select_alternative(v, alternatives, root) {
+            Some(s) => s,
+            // Type errors are reported when deserializing.
+            None => return Ok(()),
+        },
+        _ => schema,
+    };
+
+    match v {
+        Value::Object(o) => {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            let additional = schema.get("additionalProperties").filter(|a| a.is_object());
+            for (k, child) in o {
+                let child_path = format!("{path}.{k}");
+                match (properties.and_then(|p| p.get(k)), additional) {
+                    (Some(s), _) | (None, Some(s)) => {
+                        check_unknown_fields(child, s, root, &child_path)?
+                    }
+                    (None, None) => {
+                        let suggestion = properties.and_then(|p| closest_match(k, p.keys()));
+                        return Err(ConfigError::UnknownField {
+                            path: path.to_string(),
+                            field: k.clone(),
+                            suggestion,
+                        });
+                    }
+                }
+            }
+        }
+        Value::Array(a) => {
+            if let Some(items) = schema.get("items") {
+                for (i, child) in a.iter().enumerate() {
+                    check_unknown_fields(child, items, root, &format!("{path}[{i}]"))?;
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn resolve_ref<'a>(
+    schema: &'a serde_json::Value,
+    root: &'a serde_json::Value,
+) -> &'a serde_json::Value {
+    match schema.get("$ref").and_then(|r| r.as_str()) {
+        Some(r) => r
+            .strip_prefix('#')
+            .and_then(|p| root.pointer(p))
+            .unwrap_or(schema),
+        None => schema,
+    }
+}
+
+/// Select the alternative of a `oneOf`/`anyOf` that describes `v`: The first
+/// object schema whose constant properties (e.g. the tag of an enum) match.
+fn select_alternative<'a>(
+    v: &serde_json::Value,
+    alternatives: &'a [serde_json::Value],
+    root: &'a serde_json::Value,
+) -> Option<&'a serde_json::Value> {
+    alternatives
+        .iter()
+        .map(|a| resolve_ref(a, root))
+        .filter(|a| a.get("properties").is_some() || a.get("additionalProperties").is_some())
+        .find(|a| {
+            a.get("properties")
+                .and_then(|p| p.as_object())
+                .into_iter()
+                .flatten()
+                .filter_map(|(k, p)| Some((k, p.get("const")?)))
+                .all(|(k, c)| v.get(k) == Some(c))
+        })
+}
+
+/// The candidate closest to `s` in terms of edit distance, if it is close
+/// enough to be a plausible typo.
+fn closest_match<'a, I: Iterator<Item = &'a String>>(s: &str, candidates: I) -> Option<String> {
+    candidates
+        .map(|c| (edit_distance(s, c), c))
+        .filter(|(d, c)| *d <= 2.max(c.len() / 3))
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, c)| c.clone())
+}
+
+/// The Levenshtein distance between `a` and `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut cur = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let subst = prev[j] + usize::from(ca != *cb);
+            cur[j + 1] = subst.min(prev[j + 1] + 1).min(cur[j] + 1);
+        }
+        prev = cur;
+    }
+    prev[b.len()]
+}
+
+/// Read the file at `path` and recursively merge all included files into it.
+/// `stack` contains the files that are currently being loaded.
+fn load_value(path: &Path, stack: &mut Vec<PathBuf>) -> Result<serde_json::Value, ConfigError> {
+    let io_err = |e| ConfigError::Io(path.to_owned(), e);
+    let parse_err = |e| ConfigError::Parse(path.to_owned(), e);
+    let canonical = path.canonicalize().map_err(io_err)?;
+    if stack.contains(&canonical) {
+        return Err(ConfigError::IncludeCycle(path.to_owned()));
+    }
+
+    let text = std::fs::read_to_string(path).map_err(io_err)?;
+    let text = interpolate_env(&text)?;
+    let mut v = parse(path, &text)?;
+    let includes: Vec<String> = match v.as_object_mut().and_then(|o| o.remove("include")) {
+        Some(i) => serde_json::from_value(i).map_err(parse_err)?,
+        None => vec![],
+    };
+
+    stack.push(canonical);
+    let base = path.parent().unwrap_or(Path::new("."));
+    for pattern in includes {
+        let pattern = base.join(pattern);
+        let mut paths = glob::glob(&pattern.to_string_lossy())?.collect::<Result<Vec<_>, _>>()?;
+        // Make the order of the targets independent of the file system.
+        paths.sort();
+        for p in paths {
+            let included = load_value(&p, stack)?;
+            merge_values(&mut v, included)?;
+        }
+    }
+    stack.pop();
+    Ok(v)
+}
+
+/// Parse the contents of the config file at `path`, as YAML if its extension
+/// says so, and as JSON otherwise.
+fn parse(path: &Path, text: &str) -> Result<serde_json::Value, ConfigError> {
+    let yaml = path.extension().is_some_and(|e| e == "yaml" || e == "yml");
+    if !yaml {
+        return serde_json::from_str(text).map_err(|e| ConfigError::Parse(path.to_owned(), e));
+    }
+    #[cfg(feature = "yaml")]
+    return serde_yaml_ng::from_str(text).map_err(|e| ConfigError::ParseYaml(path.to_owned(), e));
+    #[cfg(not(feature = "yaml"))]
+    Err(ConfigError::YamlNotSupported(path.to_owned()))
+}
+
+/// Expand `${VAR}` and `${VAR:-default}` with the values of environment
+/// variables. The default is used if the variable is unset or empty. Values
+/// are escaped within the strings of the JSON text `s`.
+fn interpolate_env(s: &str) -> Result<String, ConfigError> {
+    let mut res = String::with_capacity(s.len());
+    let mut in_string = false;
+    let mut rest = s;
+    while let Some(start) = rest.find(['$', '"', '\\']) {
+        res.push_str(&rest[..start]);
+        let tail = &rest[start + 1..];
+        if rest[start..].starts_with('"') {
+            in_string = !in_string;
+            res.push('"');
+            rest = tail;
+        } else if rest[start..].starts_with('\\') {
+            // Copy escape sequences as they are, e.g. `\"`.
+            let n = tail.chars().next().map_or(0, char::len_utf8);
+            res.push('\\');
+            res.push_str(&tail[..n]);
+            rest = &tail[n..];
+        } else if let Some(tail) = tail.strip_prefix('$') {
+            res.push('$');
+            rest = tail;
+        } else if let Some(tail) = tail.strip_prefix('{') {
+            let end = tail
+                .find('}')
+                .ok_or_else(|| ConfigError::UnterminatedVariable(tail.to_string()))?;
+            let (name, default) = match tail[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&tail[..end], None),
+            };
+            let value = std::env::var(name).ok().filter(|v| !v.is_empty());
+            match (value, default) {
+                (Some(v), _) if in_string => {
+                    let quoted = serde_json::Value::String(v).to_string();
+                    res.push_str(&quoted[1..quoted.len() - 1]);
+                }
+                (Some(v), _) => res.push_str(&v),
+                (None, Some(d)) => res.push_str(d),
+                (None, None) => return Err(ConfigError::UnsetVariable(name.to_string())),
+            }
+            rest = &tail[end + 1..];
+        } else {
+            res.push('$');
+            rest = tail;
+        }
+    }
+    res.push_str(rest);
+    Ok(res)
+}
+
+/// Merge `from` into `into`: Arrays are concatenated and objects are merged.
+/// A key must not be defined in both objects.
+fn merge_values(into: &mut serde_json::Value, from: serde_json::Value) -> Result<(), ConfigError> {
+    use serde_json::Value;
+    let (Value::Object(into), Value::Object(from)) = (into, from) else {
+        return Ok(());
+    };
+    for (k, v) in from {
+        match (into.get_mut(&k), v) {
+            (None, v) => {
+                into.insert(k, v);
+            }
+            (Some(Value::Array(a)), Value::Array(b)) => a.extend(b),
+            (Some(Value::Object(a)), Value::Object(b)) => {
+                for (kk, vv) in b {
+                    if a.contains_key(&kk) {
+                        return Err(ConfigError::Duplicate(kk));
+                    }
+                    a.insert(kk, vv);
+                }
+            }
+            _ => return Err(ConfigError::Duplicate(k)),
+        }
+    }
+    Ok(())
+}
+
+/// The config as it is written down, i.e. before the group defaults have been
+/// applied to the targets.
+#[derive(Deserialize)]
+struct RawConfig {
+    scrape_targets: Vec<serde_json::Value>,
+    #[serde(default)]
+    groups: BTreeMap<String, GroupConfig>,
+    #[serde(default)]
+    variables: Variables,
+    #[serde(default)]
+    only: Vec<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+}
+
+impl TryFrom<RawConfig> for Config {
+    type Error = ConfigError;
+
+    fn try_from(raw: RawConfig) -> Result<Self, Self::Error> {
+        let scrape_targets = raw
+            .scrape_targets
+            .into_iter()
+            .map(|mut t| {
+                if let Some(name) = t.get("group").and_then(|g| g.as_str()) {
+                    let g = raw
+                        .groups
+                        .get(name)
+                        .ok_or_else(|| ConfigError::UnknownGroup(name.to_string()))?;
+                    apply_group_defaults(&mut t, g);
+                }
+                let t: ScrapeTargetConfig = serde_json::from_value(t)?;
+                t.validate()?;
+                Ok(t)
+            })
+            .collect::<Result<Vec<ScrapeTargetConfig>, ConfigError>>()?;
+
+        let mut names = HashSet::new();
+        if let Some(name) = scrape_targets
+            .iter()
+            .filter_map(|t| t.name.as_ref())
+            .find(|n| !names.insert(*n))
+        {
+            return Err(ConfigError::Duplicate(name.clone()));
+        }
+
+        let parse = |tags: &[String]| {
+            tags.iter()
+                .map(|t| t.parse())
+                .collect::<Result<Vec<LabelSelector>, _>>()
+        };
+        let mut config = Self {
+            scrape_targets,
+            groups: raw.groups,
+            variables: raw.variables,
+        };
+        config.retain_tagged(&parse(&raw.only)?, &parse(&raw.exclude)?);
+        Ok(config)
+    }
+}
+
+/// Copy all fields of the group that are missing in the target. Labels are
+/// merged, with the labels of the target taking precedence.
+fn apply_group_defaults(target: &mut serde_json::Value, g: &GroupConfig) {
+    use serde_json::Value;
+    let (Value::Object(target), Ok(Value::Object(defaults))) = (target, serde_json::to_value(g))
+    else {
+        // Invalid targets are reported when deserializing them.
+        return;
+    };
+    for (k, v) in defaults {
+        match (target.get_mut(&k), v) {
+            (None, v) => {
+                target.insert(k, v);
+            }
+            (Some(Value::Object(labels)), Value::Object(group_labels)) => {
+                for (lk, lv) in group_labels {
+                    labels.entry(lk).or_insert(lv);
+                }
+            }
+            _ => {}
+        }
+    }
 }
 
 #[serde_as]
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[schemars(transform = interval_may_be_inherited)]
 pub struct ScrapeTargetConfig {
-    /// todo(dsd): replace this with a string represention.
-    #[serde_as(as = "DurationSeconds<u64>")]
-    pub interval: Duration,
+    /// An optional name of the target. Names must be unique within a config.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// In seconds, or `"continuous"` for a zero interval: The next call
+    /// starts as soon as the previous one finished, e.g. for targets that are
+    /// samplers themselves like `vmstat 1 10`. Timeouts and cancellation
+    /// still apply, and failed calls are retried with a delay that grows up
+    /// to 30 seconds. An interval of `0` is rejected, continuous targets must
+    /// be explicit.
+    ///
+    /// A range `{ "min": 30, "max": 90 }` waits a uniformly random time
+    /// within the range before each call, such that the calls do not
+    /// synchronize with periodic behavior of the observed system.
+    // todo(dsd): replace this with a string represention.
+    #[serde_as(as = "TryFromInto<IntervalRepr>")]
+    pub interval: Interval,
     pub timeout: Option<Duration>,
+    /// The timeout of unscheduled calls, e.g. for on-demand snapshots that
+    /// warrant a longer budget. Defaults to `timeout`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unscheduled_timeout: Option<Duration>,
     pub action: Action,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// The name of the sink the results of this target are routed to. If
+    /// unset, results go to the default processor. See
+    /// [crate::debugbunny::DebugBunnyBuilder::sink].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sink: Option<String>,
+    /// Run before every scrape of the target.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre: Option<Hook>,
+    /// Run after every scrape of the target, also if the scrape or the `pre`
+    /// hook failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post: Option<Hook>,
+    /// Fields extracted from the output of successful scrapes. See
+    /// [crate::derive].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub derived: BTreeMap<String, DerivedField>,
+    /// Paths of values in JSON output that are emitted as top-level keys of
+    /// the record of a call, e.g. `$.status.leader`, such that they are
+    /// searchable without decoding the body. See [crate::derive].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub promote_fields: Vec<String>,
+    /// Assertions evaluated after every call, whose outcomes are recorded
+    /// along with the result. See [crate::check].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub checks: BTreeMap<String, Check>,
+    /// Stop calling the target for a while after repeated failures. See
+    /// [crate::scrape_target::CircuitBreaker].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// Targets sharing an interval have their first calls spread across the
+    /// interval. Synchronized targets are not staggered, but called at the
+    /// start, e.g. to correlate their results.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub synchronized: bool,
+    /// Stop calling the target for good after this many consecutive failed
+    /// scheduled calls, such that a permanently broken target does not fill
+    /// the logs. A final [ScrapeErr::Disabled](crate::scrape_target::ScrapeErr::Disabled)
+    /// result is processed and published when the target is disabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_consecutive_failures: Option<NonZeroU32>,
+    /// Intervals that apply during parts of the day instead of `interval`,
+    /// e.g. to scrape more often during business hours. The first profile
+    /// whose window contains the current time applies. Windows follow the
+    /// local time of the host, including daylight saving time, unless a
+    /// profile names its own time zone.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub interval_profiles: Vec<IntervalProfile>,
+    /// What to do if a result of the target cannot be processed. Overrides
+    /// the policies of the builder, see
+    /// [DebugBunnyBuilder::processor_error_policy](crate::debugbunny::DebugBunnyBuilder::processor_error_policy).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub processor_error_policy: Option<ProcessorErrorPolicy>,
+}
+
+impl ScrapeTargetConfig {
+    /// Check what deserializing cannot, such that an invalid target is
+    /// rejected when the config is loaded instead of when it is started.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if let Action::TlsHandshake {
+            host, server_name, ..
+        } = &self.action
+        {
+            let name = server_name.as_ref().unwrap_or(host);
+            if ServerName::try_from(name.as_str()).is_err() {
+                return Err(ConfigError::InvalidServerName(name.clone()));
+            }
+        }
+        if let Action::Http {
+            stream: Some(stream),
+            ..
+        } = &self.action
+        {
+            // Otherwise, the data collected so far is dropped with the call.
+            let timeout = self.timeout.unwrap_or(DEFAULT_TIMEOUT);
+            let unscheduled_timeout = self.unscheduled_timeout.unwrap_or(timeout);
+            if timeout.min(unscheduled_timeout) <= stream.flush_every {
+                return Err(ConfigError::StreamTimeout(stream.flush_every));
+            }
+        }
+        for path in &self.promote_fields {
+            crate::derive::validate_path(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// See [crate::scrape_target::CircuitBreaker].
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct CircuitBreakerConfig {
+    /// The number of consecutive failed calls that open the circuit.
+    pub failures: u32,
+    /// How long the circuit stays open before the target is called again.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub open_for: Duration,
+}
+
+/// A command line that is run by the platform shell before or after a scrape,
+/// e.g. to enable verbose logging of a service while its debug endpoint is
+/// scraped. A failing hook does not prevent the scrape, but is reported as an
+/// error result of the target.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct Hook {
+    pub command: String,
+    /// Defaults to 10 seconds.
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<Duration>,
+}
+
+impl Hook {
+    pub fn new<S: ToString>(command_line: S) -> Self {
+        Self {
+            command: command_line.to_string(),
+            timeout: None,
+        }
+    }
+
+    pub fn timeout(mut self, d: Duration) -> Self {
+        self.timeout = Some(d);
+        self
+    }
+}
+
+/// In a config file, the interval of a target may be inherited from its group.
+fn interval_may_be_inherited(schema: &mut schemars::Schema) {
+    if let Some(serde_json::Value::Array(required)) = schema.get_mut("required") {
+        required.retain(|r| r != "interval");
+    }
+}
+
+/// The time between the starts of two scheduled calls of a target, picked
+/// uniformly from `min..=max` for every call. See
+/// [ScrapeTargetConfig::interval].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Interval {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl Interval {
+    pub fn fixed(d: Duration) -> Self {
+        Self { min: d, max: d }
+    }
+
+    /// # Panics
+    ///
+    /// If `min` is greater than `max`.
+    pub fn random(min: Duration, max: Duration) -> Self {
+        assert!(min <= max, "the interval range is empty");
+        Self { min, max }
+    }
+
+    pub fn is_random(&self) -> bool {
+        self.min != self.max
+    }
+}
+
+impl From<Duration> for Interval {
+    fn from(d: Duration) -> Self {
+        Self::fixed(d)
+    }
+}
+
+/// An interval as written down, see [ScrapeTargetConfig::interval].
+#[derive(Serialize, Deserialize, Clone, Copy, JsonSchema)]
+#[serde(untagged)]
+enum IntervalRepr {
+    Seconds(u64),
+    Named(NamedInterval),
+    Range { min: u64, max: u64 },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum NamedInterval {
+    Continuous,
+}
+
+impl TryFrom<IntervalRepr> for Interval {
+    type Error = String;
+
+    fn try_from(i: IntervalRepr) -> Result<Self, Self::Error> {
+        match i {
+            IntervalRepr::Seconds(0) | IntervalRepr::Range { max: 0, .. } => {
+                Err(r#"an interval must be positive, or "continuous""#.to_string())
+            }
+            IntervalRepr::Seconds(s) => Ok(Self::fixed(Duration::from_secs(s))),
+            IntervalRepr::Named(NamedInterval::Continuous) => Ok(Self::fixed(Duration::ZERO)),
+            IntervalRepr::Range { min, max } if min <= max => Ok(Self::random(
+                Duration::from_secs(min),
+                Duration::from_secs(max),
+            )),
+            IntervalRepr::Range { min, max } => {
+                Err(format!("interval minimum {min} exceeds maximum {max}"))
+            }
+        }
+    }
+}
+
+impl From<Interval> for IntervalRepr {
+    fn from(i: Interval) -> Self {
+        if i.is_random() {
+            IntervalRepr::Range {
+                min: i.min.as_secs(),
+                max: i.max.as_secs(),
+            }
+        } else if i.min.is_zero() {
+            IntervalRepr::Named(NamedInterval::Continuous)
+        } else {
+            IntervalRepr::Seconds(i.min.as_secs())
+        }
+    }
+}
+
+/// A time window in which a target is called at a different interval, see
+/// [ScrapeTargetConfig::interval_profiles].
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+pub struct IntervalProfile {
+    /// Inclusive.
+    #[schemars(with = "String")]
+    pub from: TimeOfDay,
+    /// Exclusive. A window whose end is not after its start wraps around
+    /// midnight, e.g. `"20:00"` to `"06:00"`. Equal bounds cover the whole
+    /// day.
+    #[schemars(with = "String")]
+    pub to: TimeOfDay,
+    /// See [ScrapeTargetConfig::interval].
+    #[serde_as(as = "TryFromInto<IntervalRepr>")]
+    pub interval: Interval,
+    /// The time zone of `from` and `to`, e.g. `"Europe/Berlin"`. Defaults to
+    /// the local time zone of the host.
+    #[schemars(with = "Option<String>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<TimeZone>,
+}
+
+impl IntervalProfile {
+    pub fn new(from: TimeOfDay, to: TimeOfDay, interval: impl Into<Interval>) -> Self {
+        Self {
+            from,
+            to,
+            interval: interval.into(),
+            timezone: None,
+        }
+    }
+
+    /// Evaluate the window in `timezone` instead of the local time zone.
+    pub fn timezone(mut self, timezone: TimeZone) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
+
+    /// Whether the window contains `t`.
+    pub fn contains(&self, t: TimeOfDay) -> bool {
+        if self.from < self.to {
+            self.from <= t && t < self.to
+        } else {
+            self.from <= t || t < self.to
+        }
+    }
+
+    /// Whether the window contains the point in time `t`.
+    pub fn contains_time(&self, t: SystemTime) -> bool {
+        self.contains(TimeOfDay::at(t, self.timezone.as_ref()))
+    }
+}
+
+/// A time zone of the IANA database, written as its name, e.g.
+/// `"America/New_York"`. It is looked up when the config is loaded.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeZone {
+    name: String,
+    tz: jiff::tz::TimeZone,
+}
+
+impl TimeZone {
+    pub fn new(name: &str) -> Result<Self, jiff::Error> {
+        Ok(Self {
+            name: name.to_string(),
+            tz: jiff::tz::TimeZone::get(name)?,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl PartialEq for TimeZone {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for TimeZone {}
+
+impl TryFrom<String> for TimeZone {
+    type Error = jiff::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::new(&s)
+    }
+}
+
+impl From<TimeZone> for String {
+    fn from(tz: TimeZone) -> Self {
+        tz.name
+    }
+}
+
+/// A time of day with minute precision, written as `"HH:MM"`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay {
+    minutes: u16,
+}
+
+impl TimeOfDay {
+    /// # Panics
+    ///
+    /// If `hour` or `minute` are out of range.
+    pub fn new(hour: u8, minute: u8) -> Self {
+        assert!(hour < 24 && minute < 60, "invalid time of day");
+        Self {
+            minutes: hour as u16 * 60 + minute as u16,
+        }
+    }
+
+    /// The time of day of `t` in `timezone`, or in the local time zone of
+    /// the host.
+    pub fn at(t: SystemTime, timezone: Option<&TimeZone>) -> Self {
+        let t = jiff::Timestamp::try_from(t).unwrap_or(jiff::Timestamp::UNIX_EPOCH);
+        let t = match timezone {
+            Some(timezone) => t.to_zoned(timezone.tz.clone()),
+            None => t.to_zoned(jiff::tz::TimeZone::system()),
+        };
+        Self::new(t.hour() as u8, t.minute() as u8)
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid time of day '{s}', expected HH:MM");
+        let (h, m) = s.split_once(':').ok_or_else(invalid)?;
+        let h: u8 = h.parse().map_err(|_| invalid())?;
+        let m: u8 = m.parse().map_err(|_| invalid())?;
+        if h >= 24 || m >= 60 {
+            return Err(invalid());
+        }
+        Ok(Self::new(h, m))
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(t: TimeOfDay) -> Self {
+        format!("{:02}:{:02}", t.minutes / 60, t.minutes % 60)
+    }
+}
+
+/// A regular expression, written as a string and compiled when the config is
+/// loaded. It matches bytes, such that output that is not valid UTF-8 can be
+/// matched, too.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct Pattern(regex::bytes::Regex);
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Self, regex::Error> {
+        regex::bytes::Regex::new(pattern).map(Self)
+    }
+
+    pub fn regex(&self) -> &regex::bytes::Regex {
+        &self.0
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Eq for Pattern {}
+
+impl TryFrom<String> for Pattern {
+    type Error = regex::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::new(&s)
+    }
+}
+
+impl From<Pattern> for String {
+    fn from(p: Pattern) -> Self {
+        p.as_str().to_string()
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+/// Defaults shared by all targets of a group.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq, JsonSchema)]
+pub struct GroupConfig {
+    #[serde_as(as = "Option<TryFromInto<IntervalRepr>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<Interval>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<Duration>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sink: Option<String>,
+}
+
+/// Selects targets by their labels, e.g. `component=network,env!=test`. A
+/// target is selected if it matches all comma-separated terms, which are
+/// `key=value`, `key!=value`, `key` (the label is set) and `!key` (the label
+/// is not set). The empty selector selects all targets.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LabelSelector {
+    terms: Vec<LabelTerm>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum LabelTerm {
+    Equals(String, String),
+    NotEquals(String, String),
+    Exists(String),
+    Missing(String),
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid label selector term '{0}'")]
+pub struct LabelSelectorError(String);
+
+impl LabelSelector {
+    pub fn matches(&self, c: &ScrapeTargetConfig) -> bool {
+        self.terms.iter().all(|t| match t {
+            LabelTerm::Equals(k, v) => c.labels.get(k) == Some(v),
+            LabelTerm::NotEquals(k, v) => c.labels.get(k) != Some(v),
+            LabelTerm::Exists(k) => c.labels.contains_key(k),
+            LabelTerm::Missing(k) => !c.labels.contains_key(k),
+        })
+    }
+}
+
+impl FromStr for LabelSelector {
+    type Err = LabelSelectorError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let valid_key = |k: &str| !k.is_empty() && !k.contains(['=', '!']);
+        let terms = s
+            .split(',')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(|t| {
+                let term = if let Some((k, v)) = t.split_once("!=") {
+                    LabelTerm::NotEquals(k.trim().to_string(), v.trim().to_string())
+                } else if let Some((k, v)) = t.split_once('=') {
+                    LabelTerm::Equals(k.trim().to_string(), v.trim().to_string())
+                } else if let Some(k) = t.strip_prefix('!') {
+                    LabelTerm::Missing(k.trim().to_string())
+                } else {
+                    LabelTerm::Exists(t.to_string())
+                };
+                let key = match &term {
+                    LabelTerm::Equals(k, _)
+                    | LabelTerm::NotEquals(k, _)
+                    | LabelTerm::Exists(k)
+                    | LabelTerm::Missing(k) => k,
+                };
+                if valid_key(key) {
+                    Ok(term)
+                } else {
+                    Err(LabelSelectorError(t.to_string()))
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { terms })
+    }
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
 #[serde(tag = "type")]
 pub enum Action {
     Http {
         // xxx(dsd): potentially, we could use serde_with trick here, but I got
         // tired of fiddling around with it.
-        #[serde(skip_serializing_if = "Option::is_none")]
+        /// Defaults to `GET`. `HEAD` and `OPTIONS` requests are probes whose
+        /// body is never read, see [crate::http::HeadersOnly].
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         #[serde(serialize_with = "serialize_opt_method")]
         #[serde(deserialize_with = "deserialize_opt_method")]
+        #[schemars(with = "Option<String>")]
         method: Option<Method>,
-        url: Url,
+        url: UrlTemplate,
+        /// Tried in order if the request to `url` fails.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        fallback_urls: Vec<UrlTemplate>,
+        /// Query parameters appended to the URLs, encoded as needed. Values
+        /// may contain placeholders, see [crate::template].
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        query: BTreeMap<String, String>,
+        /// If set, a dedicated client is built for this target. Otherwise, the
+        /// target uses a client that is shared with the other targets.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client: Option<Box<HttpClientConfig>>,
+        /// If non-empty, a response with a status code not contained in this
+        /// list is treated as an error.
+        #[serde_as(as = "Vec<TryFromInto<u16>>")]
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        expect_status: Vec<StatusCode>,
+        /// Emit the response of an endpoint that streams, e.g. server-sent
+        /// events, in parts instead of waiting for its end.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        stream: Option<HttpStreamConfig>,
+        /// Connect to the given vsock address instead of the host of the URL,
+        /// e.g. to scrape a guest VM from its hypervisor host. The URL still
+        /// names the path and the `Host` header. Linux only.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        vsock: Option<VsockAddr>,
     },
     Command {
-        command: String,
-        args: Vec<String>,
+        #[serde(flatten)]
+        spec: CommandSpec,
+        /// Only emit the part of stdout that is new compared to the previous
+        /// run. Useful for commands with append-only output, e.g. `dmesg`.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        only_new_output: bool,
+        /// Parse stdout, e.g. as CSV, and emit the result instead of the
+        /// lines of stdout. See [crate::parse].
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        parse: Option<OutputParser>,
+    },
+    /// Emit the state of debugbunny itself: uptime, memory usage and the
+    /// status of all targets.
+    SelfStatus,
+    /// Emit the number of calls, their durations and the size of their output
+    /// for every target since the previous call of this target. See
+    /// [crate::summary].
+    Summary,
+    /// Report the usage of the file systems of the given mount points. See
+    /// [crate::disk].
+    DiskUsage { mount_points: Vec<PathBuf> },
+    /// Snapshot the counters of the given network interfaces, or of all
+    /// interfaces if none are given. See [crate::netdev].
+    NetworkInterfaces {
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        interfaces: Vec<String>,
+        /// Emit the deltas since the previous scrape instead of the absolute
+        /// values.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        deltas: bool,
+    },
+    /// List processes. See [crate::process].
+    Processes {
+        /// A regular expression the name of listed processes must match.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        #[schemars(with = "Option<String>")]
+        name: Option<Pattern>,
+        /// Only list the processes with the largest resident set size.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        top: Option<usize>,
+    },
+    /// Sample the metrics of the tokio runtime debugbunny runs on. See
+    /// [crate::runtime_metrics]. Requires the `runtime-metrics` feature.
+    #[cfg(feature = "runtime-metrics")]
+    RuntimeMetrics,
+    /// Perform a TCP and TLS handshake without sending a request, and record
+    /// its timing, the negotiated parameters and the certificate fingerprint.
+    /// See [crate::tls].
+    TlsHandshake {
+        host: String,
+        /// Defaults to 443.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        port: Option<u16>,
+        /// The name sent via SNI and verified against the certificate.
+        /// Defaults to `host`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        server_name: Option<String>,
     },
 }
 
 impl Action {
-    pub fn http(url: Url) -> Self {
-        Self::Http { method: None, url }
+    pub fn http<U: Into<UrlTemplate>>(url: U) -> Self {
+        Self::Http {
+            method: None,
+            url: url.into(),
+            fallback_urls: vec![],
+            query: BTreeMap::new(),
+            client: None,
+            expect_status: vec![],
+            stream: None,
+            vsock: None,
+        }
     }
 
-    pub fn http_with_method(url: Url, method: Method) -> Self {
+    pub fn http_with_method<U: Into<UrlTemplate>>(url: U, method: Method) -> Self {
         Self::Http {
             method: Some(method),
-            url,
+            url: url.into(),
+            fallback_urls: vec![],
+            query: BTreeMap::new(),
+            client: None,
+            expect_status: vec![],
+            stream: None,
+            vsock: None,
         }
     }
 
+    pub fn http_with_client<U: Into<UrlTemplate>>(url: U, client: HttpClientConfig) -> Self {
+        Self::Http {
+            method: None,
+            url: url.into(),
+            fallback_urls: vec![],
+            query: BTreeMap::new(),
+            client: Some(Box::new(client)),
+            expect_status: vec![],
+            stream: None,
+            vsock: None,
+        }
+    }
+
+    /// Fall back to the given URLs if the primary URL fails. Has no effect on
+    /// non-HTTP actions.
+    pub fn fallback_urls<U: Into<UrlTemplate>>(mut self, urls: Vec<U>) -> Self {
+        if let Self::Http { fallback_urls, .. } = &mut self {
+            *fallback_urls = urls.into_iter().map(Into::into).collect();
+        }
+        self
+    }
+
+    /// Append a query parameter to the URLs. Has no effect on non-HTTP
+    /// actions.
+    pub fn query<K: ToString, V: ToString>(mut self, key: K, value: V) -> Self {
+        if let Self::Http { query, .. } = &mut self {
+            query.insert(key.to_string(), value.to_string());
+        }
+        self
+    }
+
+    /// Treat responses with a status code other than the given ones as
+    /// errors. Has no effect on non-HTTP actions.
+    pub fn expect_status(mut self, status: Vec<StatusCode>) -> Self {
+        if let Self::Http { expect_status, .. } = &mut self {
+            *expect_status = status;
+        }
+        self
+    }
+
+    /// Emit the response in parts, see [HttpStreamConfig]. Has no effect on
+    /// non-HTTP actions.
+    pub fn stream(mut self, config: HttpStreamConfig) -> Self {
+        if let Self::Http { stream, .. } = &mut self {
+            *stream = Some(config);
+        }
+        self
+    }
+
+    /// Send the requests over vsock, see [VsockAddr]. Has no effect on
+    /// non-HTTP actions.
+    pub fn vsock(mut self, addr: VsockAddr) -> Self {
+        if let Self::Http { vsock, .. } = &mut self {
+            *vsock = Some(addr);
+        }
+        self
+    }
+
     pub fn command(command: String) -> Self {
-        Self::Command {
-            command,
-            args: vec![],
+        CommandSpec::new(command).into()
+    }
+
+    pub fn tls_handshake<S: ToString>(host: S, port: u16) -> Self {
+        Self::TlsHandshake {
+            host: host.to_string(),
+            port: Some(port),
+            server_name: None,
         }
     }
 
+    /// A command line that is run by the platform shell.
+    pub fn shell<S: ToString>(command_line: S) -> Self {
+        CommandSpec::shell(command_line).into()
+    }
+
     pub fn command_with_args<S: ToString, T: ToString>(command: S, args: Vec<T>) -> Self {
-        let command = command.to_string();
-        let args = args.iter().map(ToString::to_string).collect();
-        Self::Command { command, args }
+        CommandSpec::new(command).args(args).into()
+    }
+
+    /// Only emit new output of a command. Has no effect on non-command
+    /// actions.
+    pub fn only_new_output(mut self) -> Self {
+        if let Self::Command {
+            only_new_output, ..
+        } = &mut self
+        {
+            *only_new_output = true;
+        }
+        self
+    }
+
+    /// Parse the output of a command. Has no effect on non-command actions.
+    pub fn parse_output(mut self, parser: OutputParser) -> Self {
+        if let Self::Command { parse, .. } = &mut self {
+            *parse = Some(parser);
+        }
+        self
+    }
+}
+
+impl From<CommandSpec> for Action {
+    fn from(spec: CommandSpec) -> Self {
+        Self::Command {
+            spec,
+            only_new_output: false,
+            parse: None,
+        }
+    }
+}
+
+/// Settings of the HTTP client used by a single target. Unset fields fall back
+/// to the defaults of [reqwest::ClientBuilder].
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq, JsonSchema)]
+pub struct HttpClientConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_max_idle_per_host: Option<usize>,
+    /// `true` forces HTTP/2 (with prior knowledge), `false` forces HTTP/1.
+    /// Builds without the `http2` feature always use HTTP/1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub http2: Option<bool>,
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp_keepalive: Option<Duration>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    /// Static host to IP overrides that bypass name resolution.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub resolve: BTreeMap<String, IpAddr>,
+    /// If set, resolved addresses are cached for the given duration. Stale
+    /// entries are used if the resolver fails. See [crate::dns::CachingResolver].
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns_cache_ttl: Option<Duration>,
+    /// Restrict connections to the given address family.
+    #[serde(default, skip_serializing_if = "IpFamily::is_any")]
+    pub ip_family: IpFamily,
+    /// `false` captures compressed bodies verbatim instead of transparently
+    /// decompressing gzip and brotli responses. The `Content-Encoding` of the
+    /// response is recorded alongside the body. Builds without the
+    /// `compression` feature never decompress.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub decompress: Option<bool>,
+    /// Overrides the `Accept-Encoding` header of requests, e.g. `identity` to
+    /// ask for an uncompressed response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accept_encoding: Option<String>,
+    /// Abort reading the body of a response if no data arrives for the given
+    /// duration, e.g. due to a half-open connection. See
+    /// [crate::scrape_target::ScrapeErr::Stalled].
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stall_timeout: Option<Duration>,
+}
+
+/// Reading a streamed response in parts, see
+/// [HttpScrapeTarget::with_streaming](crate::http::HttpScrapeTarget::with_streaming).
+/// Best combined with a continuous interval, such that the parts follow each
+/// other without gaps.
+#[serde_as]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub struct HttpStreamConfig {
+    /// How long a connection is read before a new request is sent.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub window: Duration,
+    /// How long a call collects data before emitting it.
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub flush_every: Duration,
+}
+
+/// The address of a vsock port. The context id (CID) identifies the VM: 2 is
+/// the host, guests have the CIDs assigned by their hypervisor.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub struct VsockAddr {
+    pub cid: u32,
+    pub port: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    V4,
+    V6,
+    #[default]
+    Any,
+}
+
+impl IpFamily {
+    pub fn is_any(&self) -> bool {
+        *self == Self::Any
     }
 }
 
 #[derive(Default, Debug)]
 pub struct ScrapeTargetBuilder {
-    interval: Option<Duration>,
+    name: Option<String>,
+    interval: Option<Interval>,
     timeout: Option<Duration>,
+    unscheduled_timeout: Option<Duration>,
     action: Option<Action>,
+    group: Option<String>,
+    labels: BTreeMap<String, String>,
+    sink: Option<String>,
+    pre: Option<Hook>,
+    post: Option<Hook>,
+    derived: BTreeMap<String, DerivedField>,
+    promote_fields: Vec<String>,
+    checks: BTreeMap<String, Check>,
+    circuit_breaker: Option<CircuitBreakerConfig>,
+    synchronized: bool,
+    max_consecutive_failures: Option<NonZeroU32>,
+    interval_profiles: Vec<IntervalProfile>,
+    processor_error_policy: Option<ProcessorErrorPolicy>,
 }
 
 impl ScrapeTargetBuilder {
@@ -85,8 +1392,47 @@ impl ScrapeTargetBuilder {
         Self::default()
     }
 
+    /// A builder for a target of the given group, initialized with the
+    /// defaults of the group.
+    pub fn in_group<S: ToString>(name: S, g: &GroupConfig) -> Self {
+        Self {
+            name: None,
+            interval: g.interval,
+            timeout: g.timeout,
+            unscheduled_timeout: None,
+            action: None,
+            group: Some(name.to_string()),
+            labels: g.labels.clone(),
+            sink: g.sink.clone(),
+            pre: None,
+            post: None,
+            derived: BTreeMap::new(),
+            promote_fields: Vec::new(),
+            checks: BTreeMap::new(),
+            circuit_breaker: None,
+            synchronized: false,
+            max_consecutive_failures: None,
+            interval_profiles: Vec::new(),
+            processor_error_policy: None,
+        }
+    }
+
+    pub fn name<S: ToString>(mut self, name: S) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// A zero interval calls the target back-to-back, see
+    /// [ScrapeTargetConfig::interval].
     pub fn interval(mut self, d: Duration) -> Self {
-        self.interval = Some(d);
+        self.interval = Some(Interval::fixed(d));
+        self
+    }
+
+    /// Wait a uniformly random time between `min` and `max` before each
+    /// call, see [ScrapeTargetConfig::interval].
+    pub fn random_interval(mut self, min: Duration, max: Duration) -> Self {
+        self.interval = Some(Interval::random(min, max));
         self
     }
 
@@ -95,20 +1441,123 @@ impl ScrapeTargetBuilder {
         self
     }
 
+    pub fn unscheduled_timeout(mut self, d: Duration) -> Self {
+        self.unscheduled_timeout = Some(d);
+        self
+    }
+
     pub fn action(mut self, a: Action) -> Self {
         self.action = Some(a);
         self
     }
 
+    pub fn label<K: ToString, V: ToString>(mut self, k: K, v: V) -> Self {
+        self.labels.insert(k.to_string(), v.to_string());
+        self
+    }
+
+    pub fn sink<S: ToString>(mut self, sink: S) -> Self {
+        self.sink = Some(sink.to_string());
+        self
+    }
+
+    pub fn pre(mut self, hook: Hook) -> Self {
+        self.pre = Some(hook);
+        self
+    }
+
+    pub fn post(mut self, hook: Hook) -> Self {
+        self.post = Some(hook);
+        self
+    }
+
+    pub fn derived<S: ToString>(mut self, name: S, f: DerivedField) -> Self {
+        self.derived.insert(name.to_string(), f);
+        self
+    }
+
+    /// See [ScrapeTargetConfig::promote_fields].
+    pub fn promote_field<S: ToString>(mut self, path: S) -> Self {
+        self.promote_fields.push(path.to_string());
+        self
+    }
+
+    /// See [ScrapeTargetConfig::checks].
+    pub fn check<S: ToString>(mut self, name: S, c: Check) -> Self {
+        self.checks.insert(name.to_string(), c);
+        self
+    }
+
+    pub fn circuit_breaker(mut self, failures: u32, open_for: Duration) -> Self {
+        self.circuit_breaker = Some(CircuitBreakerConfig { failures, open_for });
+        self
+    }
+
+    /// See [ScrapeTargetConfig::synchronized].
+    pub fn synchronized(mut self) -> Self {
+        self.synchronized = true;
+        self
+    }
+
+    /// See [ScrapeTargetConfig::max_consecutive_failures].
+    ///
+    /// # Panics
+    ///
+    /// If `n` is zero.
+    pub fn max_consecutive_failures(mut self, n: u32) -> Self {
+        let n = NonZeroU32::new(n).expect("the failure limit must be positive");
+        self.max_consecutive_failures = Some(n);
+        self
+    }
+
+    /// Add an interval profile, see [ScrapeTargetConfig::interval_profiles].
+    pub fn interval_profile(mut self, profile: IntervalProfile) -> Self {
+        self.interval_profiles.push(profile);
+        self
+    }
+
+    /// See [ScrapeTargetConfig::processor_error_policy].
+    pub fn processor_error_policy(mut self, policy: ProcessorErrorPolicy) -> Self {
+        self.processor_error_policy = Some(policy);
+        self
+    }
+
     pub fn build(self) -> ScrapeTargetConfig {
         ScrapeTargetConfig {
+            name: self.name,
             interval: self.interval.expect("No interval set!"),
             timeout: self.timeout,
+            unscheduled_timeout: self.unscheduled_timeout,
             action: self.action.expect("No action specified"),
+            group: self.group,
+            labels: self.labels,
+            sink: self.sink,
+            pre: self.pre,
+            post: self.post,
+            derived: self.derived,
+            promote_fields: self.promote_fields,
+            checks: self.checks,
+            circuit_breaker: self.circuit_breaker,
+            synchronized: self.synchronized,
+            max_consecutive_failures: self.max_consecutive_failures,
+            interval_profiles: self.interval_profiles,
+            processor_error_policy: self.processor_error_policy,
         }
     }
 }
 
+/// Deserialize an optional number that must not be NaN, such that the
+/// configs containing it can be [Eq].
+pub(crate) fn deserialize_opt_number<'de, D>(d: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<f64>::deserialize(d)? {
+        Some(n) if n.is_nan() => Err(serde::de::Error::custom("NaN is not a valid number")),
+        n => Ok(n),
+    }
+}
+
 fn serialize_opt_method<S>(v: &Option<Method>, s: S) -> Result<S::Ok, S::Error>
 where
     S: Serializer,
@@ -123,8 +1572,553 @@ fn deserialize_opt_method<'de, D>(d: D) -> Result<Option<Method>, D::Error>
 where
     D: Deserializer<'de>,
 {
-    Option::<&str>::deserialize(d).and_then(|s| match s {
-        Some(s) => Ok(Some(Method::from_str(s).map_err(serde::de::Error::custom)?)),
+    Option::<String>::deserialize(d).and_then(|s| match s {
+        Some(s) => Ok(Some(
+            Method::from_str(&s).map_err(serde::de::Error::custom)?,
+        )),
         None => Ok(None),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use super::*;
+
+    #[test]
+    fn targets_inherit_group_defaults() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "groups": {
+                    "network": {
+                        "interval": 30,
+                        "labels": { "component": "network", "tier": "infra" }
+                    }
+                },
+                "scrape_targets": [
+                    {
+                        "group": "network",
+                        "labels": { "tier": "edge" },
+                        "action": { "type": "Command", "command": "ss", "args": [] }
+                    },
+                    {
+                        "group": "network",
+                        "interval": 5,
+                        "action": { "type": "Http", "method": "GET", "url": "http://localhost/" }
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let [t0, t1] = &config.scrape_targets[..] else {
+            panic!("Expected two targets")
+        };
+        assert_eq!(Interval::fixed(Duration::from_secs(30)), t0.interval);
+        assert_eq!(Some("edge"), t0.labels.get("tier").map(String::as_str));
+        assert_eq!(
+            Some("network"),
+            t0.labels.get("component").map(String::as_str)
+        );
+        assert_eq!(Interval::fixed(Duration::from_secs(5)), t1.interval);
+        assert_eq!(2, t1.labels.len());
+    }
+
+    #[test]
+    fn processor_error_policies_are_parsed() {
+        let v = serde_json::json!({
+            "interval": 1,
+            "action": { "type": "SelfStatus" },
+            "processor_error_policy": { "type": "Retry", "attempts": 2, "backoff": 0.5 },
+        });
+        let t: ScrapeTargetConfig = serde_json::from_value(v).unwrap();
+        assert_eq!(
+            Some(ProcessorErrorPolicy::Retry {
+                attempts: 2,
+                backoff: Duration::from_millis(500)
+            }),
+            t.processor_error_policy
+        );
+    }
+
+    #[test]
+    fn http_methods_are_optional() {
+        let action = |v| serde_json::from_value::<Action>(v).unwrap();
+        let Action::Http { method, .. } =
+            action(serde_json::json!({ "type": "Http", "url": "http://localhost/" }))
+        else {
+            panic!("expected an HTTP action");
+        };
+        assert_eq!(None, method);
+        let probe =
+            serde_json::json!({ "type": "Http", "method": "HEAD", "url": "http://localhost/" });
+        let Action::Http { method, .. } = action(probe) else {
+            panic!("expected an HTTP action");
+        };
+        assert_eq!(Some(Method::HEAD), method);
+        // Actions without a method survive a round trip, e.g. when templates
+        // are instantiated.
+        let get = Action::http(UrlTemplate::new("http://localhost/"));
+        assert_eq!(get, action(serde_json::to_value(&get).unwrap()));
+    }
+
+    #[test]
+    fn targets_are_enabled_by_tags() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "only": ["role=database", "role=web"],
+                "exclude": ["experimental"],
+                "scrape_targets": [
+                    {
+                        "name": "pg",
+                        "interval": 5,
+                        "labels": { "role": "database" },
+                        "action": { "type": "Command", "command": "pg_stat", "args": [] }
+                    },
+                    {
+                        "name": "pg-wal",
+                        "interval": 5,
+                        "labels": { "role": "database", "experimental": "yes" },
+                        "action": { "type": "Command", "command": "pg_wal", "args": [] }
+                    },
+                    {
+                        "name": "nginx",
+                        "interval": 5,
+                        "labels": { "role": "web" },
+                        "action": { "type": "Command", "command": "nginx", "args": [] }
+                    },
+                    {
+                        "name": "gpu",
+                        "interval": 5,
+                        "action": { "type": "Command", "command": "nvidia-smi", "args": [] }
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let names = |c: &Config| {
+            c.scrape_targets
+                .iter()
+                .map(|t| t.name.clone().unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec!["pg", "nginx"], names(&config));
+
+        let mut config = config;
+        config.retain_tagged(&[], &["role=web".parse().unwrap()]);
+        assert_eq!(vec!["pg"], names(&config));
+
+        let invalid = r#"{ "only": ["=x"], "scrape_targets": [] }"#;
+        assert!(serde_json::from_str::<Config>(invalid).is_err());
+    }
+
+    #[test]
+    fn continuous_intervals_are_zero() {
+        let config: Config = serde_json::from_str(
+            r#"{
+                "groups": { "samplers": { "interval": "continuous" } },
+                "scrape_targets": [
+                    {
+                        "group": "samplers",
+                        "action": { "type": "Command", "command": "vmstat", "args": ["1", "10"] }
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        let t = &config.scrape_targets[0];
+        assert_eq!(Interval::fixed(Duration::ZERO), t.interval);
+        let v = serde_json::to_value(t).unwrap();
+        assert_eq!("continuous", v["interval"]);
+        assert!(serde_json::from_str::<IntervalRepr>(r#""often""#).is_err());
+        let zero: IntervalRepr = serde_json::from_str("0").unwrap();
+        assert!(Interval::try_from(zero).is_err());
+    }
+
+    #[test]
+    fn interval_ranges_are_parsed() {
+        let t: ScrapeTargetConfig = serde_json::from_str(
+            r#"{
+                "interval": { "min": 30, "max": 90 },
+                "action": { "type": "Command", "command": "true", "args": [] }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            Interval::random(Duration::from_secs(30), Duration::from_secs(90)),
+            t.interval
+        );
+        let v = serde_json::to_value(&t).unwrap();
+        assert_eq!(serde_json::json!({ "min": 30, "max": 90 }), v["interval"]);
+
+        let inverted = r#"{ "min": 90, "max": 30 }"#;
+        assert!(serde_json::from_str::<IntervalRepr>(inverted)
+            .map(Interval::try_from)
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn label_selectors_match_all_terms() {
+        let target = |labels: &[(&str, &str)]| {
+            let mut b = ScrapeTargetBuilder::new()
+                .interval(Duration::from_secs(1))
+                .action(Action::shell("true"));
+            for (k, v) in labels {
+                b = b.label(k, v);
+            }
+            b.build()
+        };
+        let network = target(&[("component", "network"), ("env", "prod")]);
+        let test = target(&[("component", "network"), ("env", "test")]);
+        let disk = target(&[("component", "disk")]);
+
+        let selector: LabelSelector = "component=network, env!=test".parse().unwrap();
+        assert!(selector.matches(&network));
+        assert!(!selector.matches(&test));
+        assert!(!selector.matches(&disk));
+
+        let selector: LabelSelector = "!env".parse().unwrap();
+        assert_eq!(
+            [false, false, true],
+            [&network, &test, &disk].map(|c| selector.matches(c))
+        );
+        assert!("env".parse::<LabelSelector>().unwrap().matches(&test));
+        assert!(LabelSelector::default().matches(&disk));
+        assert!("=x".parse::<LabelSelector>().is_err());
+        assert!("a!".parse::<LabelSelector>().is_err());
+    }
+
+    #[test]
+    fn unknown_group_is_rejected() {
+        let res = serde_json::from_str::<Config>(
+            r#"{ "scrape_targets": [ { "group": "nope", "action": {} } ] }"#,
+        );
+        assert!(res.unwrap_err().to_string().contains("unknown group"));
+    }
+
+    #[test]
+    fn zero_failure_limit_is_rejected() {
+        let res = serde_json::from_str::<Config>(
+            r#"{ "scrape_targets": [ {
+                "interval": 1,
+                "max_consecutive_failures": 0,
+                "action": { "type": "Http", "url": "http://localhost/" }
+            } ] }"#,
+        );
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn invalid_server_name_is_rejected() {
+        let res = serde_json::from_str::<Config>(
+            r#"{ "scrape_targets": [ {
+                "interval": 1,
+                "action": { "type": "TlsHandshake", "host": "localhost", "server_name": "not a name" }
+            } ] }"#,
+        );
+        assert!(matches!(res, Err(e) if e.to_string().contains("Invalid TLS server name")));
+    }
+
+    #[test]
+    fn stream_timeouts_must_exceed_the_flushes() {
+        let target = |timeout: u64| {
+            let stream = HttpStreamConfig {
+                window: Duration::from_secs(60),
+                flush_every: Duration::from_secs(5),
+            };
+            ScrapeTargetBuilder::new()
+                .interval(Duration::ZERO)
+                .timeout(Duration::from_secs(timeout))
+                .action(Action::http(UrlTemplate::new("http://localhost/events")).stream(stream))
+                .build()
+        };
+        assert!(matches!(
+            target(5).validate(),
+            Err(ConfigError::StreamTimeout(_))
+        ));
+        assert!(target(6).validate().is_ok());
+    }
+
+    #[test]
+    fn invalid_process_name_pattern_is_rejected() {
+        let res = serde_json::from_str::<Config>(
+            r#"{ "scrape_targets": [ {
+                "interval": 1,
+                "action": { "type": "Processes", "name": "(unclosed" }
+            } ] }"#,
+        );
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn invalid_promoted_fields_are_rejected() {
+        let config = |path: &str| {
+            let v = serde_json::json!({ "scrape_targets": [{
+                "interval": 1,
+                "promote_fields": [path],
+                "action": { "type": "SelfStatus" }
+            }] });
+            serde_json::from_value::<Config>(v).map_err(|e| e.to_string())
+        };
+        assert!(config("$.status.leader").is_ok());
+        assert!(config("$.result.x").is_ok());
+        assert!(config("$.result").unwrap_err().contains("collides"));
+        assert!(config("$.sequence").unwrap_err().contains("collides"));
+        assert!(config("status").unwrap_err().contains("Invalid field path"));
+    }
+
+    #[test]
+    fn schema_does_not_require_interval() {
+        let schema = serde_json::to_value(Config::json_schema()).unwrap();
+        let required = &schema["$defs"]["ScrapeTargetConfig"]["required"];
+        assert_eq!(&serde_json::json!(["action"]), required);
+    }
+
+    #[test]
+    fn unknown_fields_are_rejected_with_suggestion() {
+        let v = serde_json::json!({
+            "scrape_targets": [{
+                "interval": 1,
+                "timout": { "secs": 5, "nanos": 0 },
+                "labels": { "any": "key" },
+                "action": { "type": "Http", "url": "http://localhost/" }
+            }]
+        });
+        let schema = serde_json::to_value(Config::json_schema()).unwrap();
+        let err = check_unknown_fields(&v, &schema, &schema, "config").unwrap_err();
+        assert_eq!(
+            "Unknown field 'timout' in config.scrape_targets[0], did you mean 'timeout'?",
+            err.to_string()
+        );
+
+        let v = serde_json::json!({
+            "scrape_targets": [{
+                "interval": 1,
+                "action": { "type": "Command", "command": "ls", "args": [], "url": "" }
+            }]
+        });
+        let err = check_unknown_fields(&v, &schema, &schema, "config").unwrap_err();
+        assert!(matches!(err, ConfigError::UnknownField { field, .. } if field == "url"));
+    }
+
+    #[test]
+    fn command_specs_are_flattened() {
+        let v = serde_json::json!({
+            "type": "Command",
+            "command": "df",
+            "args": ["-h"],
+            "env": { "LC_ALL": "C" },
+            "limits": { "cpu_seconds": 5 },
+            "kill_grace_period": 0.5,
+            "only_new_output": true,
+            "parse": { "format": "table", "columns": ["fs", "size"] }
+        });
+        let action: Action = serde_json::from_value(v.clone()).unwrap();
+        let expected = Action::Command {
+            spec: CommandSpec::new("df")
+                .arg("-h")
+                .env("LC_ALL", "C")
+                .limits(crate::command::ResourceLimits {
+                    cpu_seconds: Some(5),
+                    ..Default::default()
+                })
+                .kill_grace_period(Duration::from_millis(500)),
+            only_new_output: true,
+            parse: Some(OutputParser::Table {
+                columns: vec!["fs".into(), "size".into()],
+            }),
+        };
+        assert_eq!(expected, action);
+        assert_eq!(v, serde_json::to_value(&action).unwrap());
+
+        let config = serde_json::json!({ "scrape_targets": [{ "interval": 1, "action": v }] });
+        let schema = serde_json::to_value(Config::json_schema()).unwrap();
+        check_unknown_fields(&config, &schema, &schema, "config").unwrap();
+    }
+
+    #[test]
+    fn interval_profiles_may_wrap_around_midnight() {
+        let v = serde_json::json!({
+            "interval": 60,
+            "interval_profiles": [
+                { "from": "08:00", "to": "18:00", "interval": 30 },
+                { "from": "20:00", "to": "06:00", "interval": 600 }
+            ],
+            "action": { "type": "SelfStatus" }
+        });
+        let c: ScrapeTargetConfig = serde_json::from_value(v.clone()).unwrap();
+        assert_eq!(
+            v["interval_profiles"],
+            serde_json::to_value(&c).unwrap()["interval_profiles"]
+        );
+        let [day, night] = &c.interval_profiles[..] else {
+            panic!("expected two profiles");
+        };
+        assert_eq!(Interval::fixed(Duration::from_secs(30)), day.interval);
+        assert!(day.contains(TimeOfDay::new(8, 0)));
+        assert!(!day.contains(TimeOfDay::new(18, 0)));
+        assert!(night.contains(TimeOfDay::new(23, 59)));
+        assert!(night.contains(TimeOfDay::new(5, 59)));
+        assert!(!night.contains(TimeOfDay::new(12, 0)));
+        let all_day =
+            IntervalProfile::new(TimeOfDay::new(0, 0), TimeOfDay::new(0, 0), day.interval);
+        assert!(all_day.contains(TimeOfDay::new(13, 37)));
+
+        assert!("24:00".parse::<TimeOfDay>().is_err());
+        assert!("8".parse::<TimeOfDay>().is_err());
+        let utc = TimeZone::new("UTC").unwrap();
+        assert_eq!(
+            TimeOfDay::new(0, 1),
+            TimeOfDay::at(UNIX_EPOCH + Duration::from_secs(86400 + 60), Some(&utc))
+        );
+    }
+
+    #[test]
+    fn interval_profiles_follow_their_time_zone() {
+        let v = serde_json::json!({
+            "from": "08:00",
+            "to": "18:00",
+            "interval": 30,
+            "timezone": "Europe/Berlin"
+        });
+        let day: IntervalProfile = serde_json::from_value(v.clone()).unwrap();
+        assert_eq!(v, serde_json::to_value(&day).unwrap());
+        let berlin = day.timezone.clone().unwrap();
+
+        // 2024-01-15T07:30:00Z and 2024-07-15T07:30:00Z.
+        let winter = UNIX_EPOCH + Duration::from_secs(1705303800);
+        let summer = UNIX_EPOCH + Duration::from_secs(1721028600);
+        assert_eq!(TimeOfDay::new(8, 30), TimeOfDay::at(winter, Some(&berlin)));
+        assert_eq!(TimeOfDay::new(9, 30), TimeOfDay::at(summer, Some(&berlin)));
+        assert!(day.contains_time(winter));
+        // 2024-01-15T17:30:00Z is 18:30 in Berlin.
+        assert!(!day.contains_time(winter + Duration::from_secs(10 * 3600)));
+
+        let v = serde_json::json!({
+            "from": "08:00",
+            "to": "18:00",
+            "interval": 30,
+            "timezone": "Mars/Olympus_Mons"
+        });
+        assert!(serde_json::from_value::<IntervalProfile>(v).is_err());
+    }
+
+    #[test]
+    fn environment_variables_are_interpolated() {
+        std::env::set_var("DEBUGBUNNY_TEST_PORT", "8080");
+        let s = interpolate_env(
+            r#"{ "url": "http://localhost:${DEBUGBUNNY_TEST_PORT}/${DEBUGBUNNY_UNSET:-metrics}", "cost": "$$5" }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            r#"{ "url": "http://localhost:8080/metrics", "cost": "$5" }"#,
+            s
+        );
+        std::env::set_var("DEBUGBUNNY_TEST_PASSWORD", r#"p"\w"#);
+        std::env::set_var("DEBUGBUNNY_TEST_INTERVAL", "10");
+        let s = interpolate_env(
+            r#"{ "password": "\"${DEBUGBUNNY_TEST_PASSWORD}\"", "interval": ${DEBUGBUNNY_TEST_INTERVAL} }"#,
+        )
+        .unwrap();
+        let v: serde_json::Value = serde_json::from_str(&s).unwrap();
+        assert_eq!(
+            serde_json::json!({ "password": r#""p"\w""#, "interval": 10 }),
+            v
+        );
+        assert!(matches!(
+            interpolate_env("${DEBUGBUNNY_UNSET}"),
+            Err(ConfigError::UnsetVariable(_))
+        ));
+    }
+
+    #[test]
+    fn included_files_are_merged() {
+        let dir = std::env::temp_dir().join(format!("debugbunny-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("targets.d")).unwrap();
+        let target = |name: &str| {
+            format!(
+                r#"{{ "name": "{name}", "interval": 1, "action": {{ "type": "Command", "command": "true", "args": [] }} }}"#
+            )
+        };
+        std::fs::write(
+            dir.join("main.json"),
+            format!(
+                r#"{{ "include": ["targets.d/*.json"], "scrape_targets": [{}] }}"#,
+                target("a")
+            ),
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("targets.d/team.json"),
+            format!(r#"{{ "scrape_targets": [{}] }}"#, target("b")),
+        )
+        .unwrap();
+
+        let config = Config::load(dir.join("main.json")).unwrap();
+        let names: Vec<_> = config
+            .scrape_targets
+            .iter()
+            .map(|t| t.name.as_deref().unwrap())
+            .collect();
+        assert_eq!(vec!["a", "b"], names);
+
+        std::fs::write(
+            dir.join("targets.d/other.json"),
+            format!(r#"{{ "scrape_targets": [{}] }}"#, target("a")),
+        )
+        .unwrap();
+        let res = Config::load(dir.join("main.json"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(res
+            .unwrap_err()
+            .to_string()
+            .contains("'a' is defined more than once"));
+    }
+
+    #[test]
+    fn yaml_files_are_read_as_yaml() {
+        let dir = std::env::temp_dir().join(format!("debugbunny-yaml-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::env::set_var("DEBUGBUNNY_TEST_YAML_INTERVAL", "5");
+        std::fs::write(
+            dir.join("main.yaml"),
+            r#"
+include: ["targets.json"]
+scrape_targets:
+  - name: a
+    interval: ${DEBUGBUNNY_TEST_YAML_INTERVAL}
+    action:
+      type: Command
+      command: "true"
+      args: []
+"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("targets.json"),
+            r#"{ "scrape_targets": [{ "name": "b", "interval": 1, "action": { "type": "SelfStatus" } }] }"#,
+        )
+        .unwrap();
+
+        let res = Config::load(dir.join("main.yaml"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        #[cfg(feature = "yaml")]
+        {
+            let config = res.unwrap();
+            let names: Vec<_> = config
+                .scrape_targets
+                .iter()
+                .map(|t| t.name.as_deref().unwrap())
+                .collect();
+            assert_eq!(vec!["a", "b"], names);
+            assert_eq!(
+                Duration::from_secs(5),
+                config.scrape_targets[0].interval.min
+            );
+        }
+        #[cfg(not(feature = "yaml"))]
+        assert!(matches!(res, Err(ConfigError::YamlNotSupported(_))));
+    }
+}
@@ -1,131 +1,1893 @@
 use std::{
-    sync::{Arc, Mutex},
-    time::Duration,
+    collections::BTreeMap,
+    num::NonZeroUsize,
+    ops::ControlFlow,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DurationSecondsWithFrac};
+use sha2::{Digest, Sha256};
+
 use tokio::{
-    sync::watch::{self, Receiver, Sender},
-    task::JoinHandle,
+    sync::{
+        broadcast,
+        watch::{self, Receiver, Sender},
+        Notify,
+    },
+    task::{JoinHandle, JoinSet},
+};
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    Stream, StreamExt,
 };
+use tracing::Instrument;
 
 use crate::{
-    command::new_from_config,
-    config::ScrapeTargetConfig,
-    http::HttpScrapeTarget,
-    result_processor::ScrapeResultProcessor,
-    scrape_target::{BoxedScrapeService, ScrapeOk, ScrapeService, ScrapeTarget, Timeout},
+    banner::Banner,
+    command::{find_executable, new_from_spec, CommandScrapeService, CommandSpec, OutputCursor},
+    config::{Action, Config, ConfigError, LabelSelector, ScrapeTargetConfig, DEFAULT_TIMEOUT},
+    disk::DiskUsageCollector,
+    health::{HealthPolicy, HealthTracker},
+    hook::Hooked,
+    http::{client_from_config, HostLimits, HttpScrapeTarget},
+    memory::{MemoryBudget, Reservation},
+    netdev::NetDevCollector,
+    observer::{Observed, ScrapeObserver},
+    preflight::{classify, PreflightCheck, PreflightReport, Problem, PREFLIGHT_TIMEOUT},
+    process::ProcessCollector,
+    result_processor::{target_key, BoxedProcessor, ScrapeResultProcessor, Unprocessed},
+    scrape_target::{
+        BoxedScrapeService, CircuitBreaker, CircuitState, FutureScrapeResult, Memoized,
+        RateLimiter, ScrapeErr, ScrapeOk, ScrapeResult, ScrapeService, ScrapeTarget, Timeout,
+    },
+    state::{StateStore, TargetState},
+    summary::{Summaries, SummaryService},
+    template::Variables,
+    tls::TlsHandshakeTarget,
 };
 
 pub struct DebugBunny {
-    configs: Vec<ScrapeTargetConfig>,
-    scheduled_tasks: Vec<JoinHandle<()>>,
-    unscheduled_targets: Vec<Arc<Mutex<BoxedScrapeService>>>,
-    cancel_signal: Sender<()>,
+    /// In the order in which they were added.
+    targets: Vec<Target>,
+    next_id: usize,
+    ctx: LaunchContext,
+    /// Writes the changes of the state file, see [crate::state].
+    state_saver: Option<JoinHandle<()>>,
 }
 
-impl DebugBunny {
+/// Identifies a target of a [DebugBunny] instance by the order in which the
+/// targets were added, starting with the configurations the instance was
+/// started with. Unlike names, ids are unique and every target has one. Ids
+/// of removed targets are not reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TargetId(pub usize);
+
+/// The circumstances of a result.
+#[derive(Debug, Clone)]
+pub struct ScrapeMeta {
+    pub config: ScrapeTargetConfig,
+    /// When the result was produced.
+    pub at: SystemTime,
+    /// Whether the result is from a call outside of the schedule.
+    pub unscheduled: bool,
+    /// The state of the circuit breaker of the target after the call, if it
+    /// has one.
+    pub circuit: Option<CircuitState>,
+}
+
+/// A result of a target, as handed to subscribers. See [DebugBunny::subscribe].
+#[derive(Clone)]
+pub struct ScrapeEvent {
+    pub target: TargetId,
+    pub meta: ScrapeMeta,
+    pub result: ScrapeResult<ScrapeOk>,
+}
+
+/// The number of results a slow subscriber may fall behind before it misses
+/// results.
+const RESULT_BROADCAST_CAPACITY: usize = 256;
+
+/// Hands the results of a target to any number of subscribers, in addition
+/// to the processor of the target.
+#[derive(Clone)]
+struct ResultBroadcast {
+    target: TargetId,
+    sender: broadcast::Sender<ScrapeEvent>,
+    circuit: Option<Receiver<CircuitState>>,
+}
+
+impl ResultBroadcast {
+    fn publish(&self, c: &ScrapeTargetConfig, r: &ScrapeResult<ScrapeOk>, unscheduled: bool) {
+        // Results are only cloned if someone is listening.
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let _ = self.sender.send(ScrapeEvent {
+            target: self.target,
+            meta: ScrapeMeta {
+                config: c.clone(),
+                at: SystemTime::now(),
+                unscheduled,
+                circuit: self.circuit.as_ref().map(|c| *c.borrow()),
+            },
+            result: r.clone(),
+        });
+    }
+}
+
+/// Why a [DebugBunny] instance could not be started.
+#[derive(Debug, thiserror::Error)]
+pub enum StartError {
+    #[error("Target refers to unknown sink '{0}'")]
+    UnknownSink(String),
+    #[error("Error policy falls back to unknown sink '{0}'")]
+    UnknownFallbackSink(String),
+    #[error("Could not build the HTTP client of target '{0}': {1}")]
+    HttpClient(String, #[source] reqwest::Error),
+    #[error(transparent)]
+    InvalidTarget(#[from] ConfigError),
+}
+
+/// The runtime state of a single scrape target.
+struct Target {
+    config: ScrapeTargetConfig,
+    unscheduled: Arc<Mutex<BoxedScrapeService>>,
+    /// Shares results between unscheduled calls, see
+    /// [DebugBunnyBuilder::coalesce_unscheduled_calls].
+    coalesced: Option<Arc<Mutex<BoxedScrapeService>>>,
+    /// The processor of scheduled results.
+    processor: BoxedProcessor,
+    paused: Sender<bool>,
+    stats: Arc<TargetStats>,
+    results: ResultBroadcast,
+    errors: ErrorHandling,
+    /// Stops the scheduled and in-flight calls of the target.
+    cancel: Sender<()>,
+    /// Runs the scheduled calls.
+    task: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct TargetStats {
+    processor_errors: AtomicU64,
+    stopped: AtomicBool,
+    /// Failed scheduled calls since the last successful one.
+    consecutive_failures: AtomicU32,
+    /// The number of results currently being processed.
+    processing: AtomicU64,
+    /// The start of the current scheduled call, if any. Unscheduled calls are
+    /// not watched, as restarting the scheduled loop does not end them.
+    call_started: std::sync::Mutex<Option<Instant>>,
+    watchdog_restarts: AtomicU64,
+    /// Whether a result has been processed, also if processing failed.
+    has_result: AtomicBool,
+    /// Notified whenever a result has been processed.
+    processed: Notify,
+    /// The size of the latest body, the estimate of the next one.
+    body_len: AtomicUsize,
+}
+
+impl TargetStats {
+    /// Resolves once the target has produced a result or has been stopped.
+    async fn first_result(&self) {
+        loop {
+            // Created before checking, such that no notification is missed.
+            let processed = self.processed.notified();
+            if self.has_result.load(Ordering::Relaxed) || self.stopped.load(Ordering::Relaxed) {
+                return;
+            }
+            processed.await;
+        }
+    }
+
+    fn status(&self, config: &ScrapeTargetConfig, paused: bool) -> TargetStatus {
+        TargetStatus {
+            name: config.name.clone(),
+            group: config.group.clone(),
+            paused,
+            stopped: self.stopped.load(Ordering::Relaxed),
+            processor_errors: self.processor_errors.load(Ordering::Relaxed),
+            processing: self.processing.load(Ordering::Relaxed),
+            watchdog_restarts: self.watchdog_restarts.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The state of a [DebugBunny] instance as reported by
+/// [crate::config::Action::SelfStatus] targets. Targets are added once they
+/// have been launched. Feeds the statistics of the summary targets as an
+/// observer of all targets.
+struct SelfState {
+    started: Instant,
+    memory_budget: MemoryBudget,
+    targets: Mutex<BTreeMap<TargetId, TargetView>>,
+    /// The statistics of each summary target, by the key of the target.
+    summaries: Mutex<BTreeMap<String, Summaries>>,
+}
+
+impl SelfState {
+    fn new(memory_budget: MemoryBudget) -> Self {
+        Self {
+            started: Instant::now(),
+            memory_budget,
+            targets: Default::default(),
+            summaries: Default::default(),
+        }
+    }
+
+    /// The statistics of summary target `c`, shared by all summary targets
+    /// with the same key.
+    fn summaries(&self, c: &ScrapeTargetConfig) -> Summaries {
+        let mut summaries = self.summaries.lock().unwrap();
+        summaries.entry(target_key(c)).or_default().clone()
+    }
+}
+
+impl ScrapeObserver for SelfState {
+    fn on_finish(
+        &self,
+        config: &ScrapeTargetConfig,
+        duration: Duration,
+        result: &ScrapeResult<ScrapeOk>,
+    ) {
+        let summaries = self.summaries.lock().unwrap();
+        for s in summaries.values() {
+            s.on_finish(config, duration, result);
+        }
+    }
+}
+
+/// The parts of a [Target] needed to report its status.
+type TargetView = (ScrapeTargetConfig, Receiver<bool>, Arc<TargetStats>);
+
+struct SelfStatusService(Arc<SelfState>);
+
+impl ScrapeService for SelfStatusService {
+    type Response = ScrapeOk;
+
+    fn call(&mut self) -> FutureScrapeResult<ScrapeOk> {
+        let state = &self.0;
+        let targets: Vec<_> = state
+            .targets
+            .lock()
+            .unwrap()
+            .values()
+            .map(|(c, paused, stats)| stats.status(c, *paused.borrow()))
+            .collect();
+        let limit = state.memory_budget.limit();
+        let status = serde_json::json!({
+            "uptime_secs": state.started.elapsed().as_secs_f64(),
+            "memory": {
+                "in_use": state.memory_budget.in_use(),
+                "limit": (limit != usize::MAX).then_some(limit),
+            },
+            "targets": targets,
+        });
+        Box::pin(async move { Ok(ScrapeOk::Structured(status)) })
+    }
+}
+
+/// What to do if a result processor fails to process a result of a target.
+/// In any case, the error is logged and counted. See
+/// [DebugBunnyBuilder::processor_error_policy],
+/// [DebugBunnyBuilder::sink_error_policy] and
+/// [ScrapeTargetConfig::processor_error_policy].
+#[serde_as]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type")]
+pub enum ProcessorErrorPolicy {
+    /// Drop the result.
+    #[default]
+    Drop,
+    /// Pass the result to the processor again, up to `attempts` more times,
+    /// waiting `backoff` in between. Failed scrapes carry no data, so they
+    /// are not retried.
+    Retry {
+        attempts: u32,
+        /// In seconds.
+        #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+        backoff: Duration,
+    },
+    /// Pass the result to the named sink instead. If that fails as well, the
+    /// result is dropped.
+    Fallback { sink: String },
+    /// Stop calling the target and notify the observers, see
+    /// [ScrapeObserver::on_target_stopped].
+    StopTarget,
+}
+
+/// A snapshot of the state of a target. See [DebugBunny::target_status].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TargetStatus {
+    pub name: Option<String>,
+    pub group: Option<String>,
+    pub paused: bool,
+    /// Whether the target has been stopped due to [ProcessorErrorPolicy::StopTarget],
+    /// because its command was not found on start or because it was disabled
+    /// after too many failures, see
+    /// [ScrapeTargetConfig::max_consecutive_failures].
+    pub stopped: bool,
+    pub processor_errors: u64,
+    /// The number of results that are currently being processed.
+    pub processing: u64,
+    /// How often the scheduled loop was restarted due to a stuck call.
+    pub watchdog_restarts: u64,
+}
+
+/// Options that apply to all scrape targets of a [DebugBunny] instance.
+#[derive(Default)]
+pub struct DebugBunnyBuilder {
+    max_in_flight_bytes: Option<usize>,
+    variables: Variables,
+    sinks: BTreeMap<String, BoxedProcessor>,
+    observers: Vec<Arc<dyn ScrapeObserver>>,
+    error_policy: ProcessorErrorPolicy,
+    sink_policies: BTreeMap<String, ProcessorErrorPolicy>,
+    watchdog: Option<u32>,
+    state_file: Option<PathBuf>,
+    preempt: bool,
+    skip_overruns: bool,
+    coalesce: Option<Duration>,
+    http_client_policy: HttpClientPolicy,
+    max_requests_per_host: Option<NonZeroUsize>,
+    max_scrapes_per_second: Option<f64>,
+    health: Option<HealthPolicy>,
+}
+
+/// The HTTP client used by targets without client settings of their own.
+/// Targets with client settings always use a dedicated client.
+#[derive(Debug, Default, Clone)]
+pub enum HttpClientPolicy {
+    /// One client shared by all targets, such that connections are reused.
+    #[default]
+    Shared,
+    /// A client per target, such that targets do not share connection pools.
+    PerTarget,
+    /// The given client, shared by all targets, e.g. with custom TLS settings
+    /// or proxies.
+    Custom(reqwest::Client),
+}
+
+impl DebugBunnyBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit the total number of bytes of scrape bodies held in memory across
+    /// all targets. New scrapes are delayed until their expected body fits,
+    /// and scrapes whose body turns out not to fit fail with
+    /// [ScrapeErr::MemoryExhausted]. See [crate::memory].
+    pub fn max_in_flight_bytes(mut self, n: usize) -> Self {
+        self.max_in_flight_bytes = Some(n);
+        self
+    }
+
+    /// Values for the placeholders in URL templates. See [Variables].
+    pub fn variables(mut self, variables: Variables) -> Self {
+        self.variables = variables;
+        self
+    }
+
+    /// Register a named output sink. Targets that name the sink in their
+    /// config are routed to it instead of the default processor.
+    pub fn sink<S: ToString, P: ScrapeResultProcessor + 'static>(mut self, name: S, p: P) -> Self {
+        self.sinks.insert(name.to_string(), BoxedProcessor::new(p));
+        self
+    }
+
+    /// Notify `o` about every scrape. See [ScrapeObserver].
+    pub fn observer<O: ScrapeObserver + 'static>(mut self, o: O) -> Self {
+        self.observers.push(Arc::new(o));
+        self
+    }
+
+    /// What to do if a result cannot be processed. Defaults to
+    /// [ProcessorErrorPolicy::Drop].
+    pub fn processor_error_policy(mut self, policy: ProcessorErrorPolicy) -> Self {
+        self.error_policy = policy;
+        self
+    }
+
+    /// What to do if the sink `name` cannot process a result. Overrides
+    /// [Self::processor_error_policy] for the targets routed to the sink.
+    /// The policy of a target itself takes precedence, see
+    /// [ScrapeTargetConfig::processor_error_policy].
+    pub fn sink_error_policy<S: ToString>(mut self, name: S, policy: ProcessorErrorPolicy) -> Self {
+        self.sink_policies.insert(name.to_string(), policy);
+        self
+    }
+
+    /// Watch for scheduled calls that take longer than `factor` times their
+    /// timeout, e.g. due to a custom [ScrapeService] that ignores the timeout
+    /// by blocking. The scheduled loop of such a target is abandoned and
+    /// restarted right away, and a [ScrapeErr::Stuck] error is passed to its
+    /// processor. A call that blocks its thread keeps blocking it until it
+    /// returns.
+    pub fn watchdog(mut self, factor: u32) -> Self {
+        self.watchdog = Some(factor);
+        self
+    }
+
+    /// Let unscheduled calls cancel in-flight scheduled calls of the same
+    /// target instead of queuing behind them, e.g. to get the freshest data
+    /// as soon as possible when an incident fires. The cancelled calls yield a
+    /// [ScrapeErr::Preempted] error.
+    pub fn preempt_scheduled_calls(mut self) -> Self {
+        self.preempt = true;
+        self
+    }
+
+    /// Skip scheduled scrapes that, based on recent durations, cannot complete
+    /// before the next one is due, and emit a [ScrapeErr::WouldOverrun] error
+    /// instead. Without this, such scrapes slide the schedule of the target.
+    pub fn skip_overrunning_scrapes(mut self) -> Self {
+        self.skip_overruns = true;
+        self
+    }
+
+    /// Let unscheduled calls of a target that are made while another one is
+    /// in flight, or less than `ttl` after it completed, share its result
+    /// instead of executing the action again. See [Memoized]. The calls of
+    /// [DebugBunny::burst] and [DebugBunny::trigger] always execute the
+    /// action.
+    pub fn coalesce_unscheduled_calls(mut self, ttl: Duration) -> Self {
+        self.coalesce = Some(ttl);
+        self
+    }
+
+    /// Defaults to [HttpClientPolicy::Shared].
+    pub fn http_client_policy(mut self, policy: HttpClientPolicy) -> Self {
+        self.http_client_policy = policy;
+        self
+    }
+
+    /// Limit the number of concurrent requests of all HTTP targets to the same
+    /// host and port, such that debugbunny does not add to the load of a
+    /// struggling service it observes. Requests beyond the limit wait, which
+    /// counts against the timeout of the target. See [HostLimits].
+    ///
+    /// # Panics
+    ///
+    /// If `n` is 0.
+    pub fn max_requests_per_host(mut self, n: usize) -> Self {
+        let n = NonZeroUsize::new(n).expect("the request limit must be positive");
+        self.max_requests_per_host = Some(n);
+        self
+    }
+
+    /// Start at most `n` scheduled calls per second across all targets, such
+    /// that many targets with short intervals cannot overload the host. Due
+    /// calls are delayed in the order in which they became due. Unscheduled
+    /// calls are not limited. See [RateLimiter].
+    ///
+    /// # Panics
+    ///
+    /// If `n` is not positive.
+    pub fn max_scrapes_per_second(mut self, n: f64) -> Self {
+        assert!(n > 0.0, "the rate limit must be positive");
+        self.max_scrapes_per_second = Some(n);
+        self
+    }
+
+    /// Track whether each target is healthy or failing according to `policy`
+    /// and pass the transitions to the processor of the target, see
+    /// [crate::health]. Only scheduled calls are tracked.
+    pub fn track_health(mut self, policy: HealthPolicy) -> Self {
+        self.health = Some(policy);
+        self
+    }
+
+    /// Persist the schedule and the cursors of incremental actions of named
+    /// targets in the given file, such that they are continued after a
+    /// restart. See [crate::state].
+    pub fn state_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.state_file = Some(path.into());
+        self
+    }
+
+    /// Start scraping the given targets. The results of targets without a
+    /// sink are passed to `p`.
+    ///
+    /// Fails if a target or a [ProcessorErrorPolicy::Fallback] refers to a
+    /// sink that has not been registered.
     pub async fn start_scraping<P: ScrapeResultProcessor + 'static>(
+        self,
         configs: Vec<ScrapeTargetConfig>,
         p: P,
-    ) -> Self {
-        use crate::config::Action::*;
-        let (cancel_signal, cancel) = watch::channel(());
-        let client = reqwest::Client::new();
-        let (scheduled_tasks, unscheduled_targets): (Vec<_>, Vec<_>) = configs
+    ) -> Result<DebugBunny, StartError> {
+        let policies = self.sink_policies.values().chain([&self.error_policy]);
+        check_targets(&configs, &self.sinks, policies)?;
+        let client = self.client();
+        let memory_budget = self
+            .max_in_flight_bytes
+            .map(MemoryBudget::new)
+            .unwrap_or_default();
+        // A broken state file must not keep targets from being scraped.
+        let state = self
+            .state_file
+            .as_ref()
+            .and_then(|path| match StateStore::open(path) {
+                Ok(s) => Some(s),
+                Err(e) => {
+                    tracing::error!(error = %e, ?path, "not persisting schedule state");
+                    None
+                }
+            });
+        let self_state = Arc::new(SelfState::new(memory_budget.clone()));
+        // Feeds the statistics of the summary targets.
+        let mut observers = self.observers;
+        observers.push(self_state.clone());
+        let ctx = LaunchContext {
+            client,
+            host_limits: self.max_requests_per_host.map(HostLimits::new),
+            variables: Arc::new(self.variables),
+            self_state,
+            state,
+            processor: BoxedProcessor::new(p),
+            memory_budget,
+            observers: observers.into(),
+            error_policy: self.error_policy,
+            sink_policies: self.sink_policies,
+            sinks: self.sinks,
+            watchdog: self.watchdog,
+            preempt: self.preempt,
+            skip_overruns: self.skip_overruns,
+            coalesce: self.coalesce,
+            rate_limit: self.max_scrapes_per_second.map(RateLimiter::new),
+            health: self.health,
+            results: broadcast::channel(RESULT_BROADCAST_CAPACITY).0,
+        };
+        // All services are built before anything is written or launched, such
+        // that an invalid target does not leave a partially started instance.
+        let services = ctx.build_services(&configs, &ctx.variables)?;
+        let banner = Banner::new(&configs);
+        let processors = [("default", &ctx.processor)]
+            .into_iter()
+            .chain(ctx.sinks.iter().map(|(name, p)| (name.as_str(), p)));
+        for (sink, p) in processors {
+            if let Err(e) = p.process_banner(&banner).await {
+                tracing::error!(error = %e, sink, "could not write banner");
+            }
+        }
+        let state_saver = ctx
+            .state
+            .clone()
+            .map(|state| tokio::task::spawn(async move { state.save_periodically().await }));
+        let mut debugbunny = DebugBunny {
+            targets: Vec::with_capacity(configs.len()),
+            next_id: 0,
+            ctx,
+            state_saver,
+        };
+        debugbunny.launch(configs, services).await;
+        Ok(debugbunny)
+    }
+
+    /// Like [Self::start_scraping], but with the targets of `config`. The
+    /// variables of `config` are added to the ones of the builder, replacing
+    /// the ones of the same names.
+    pub async fn start_config<P: ScrapeResultProcessor + 'static>(
+        mut self,
+        config: Config,
+        p: P,
+    ) -> Result<DebugBunny, StartError> {
+        self.variables.merge(config.variables);
+        self.start_scraping(config.scrape_targets, p).await
+    }
+
+    /// Call each of `configs` once and report problems that would keep them
+    /// from being scraped. The results are not processed and hooks are not
+    /// run. The calls run concurrently, with a timeout of at least
+    /// [PREFLIGHT_TIMEOUT].
+    pub async fn preflight(&self, configs: &[ScrapeTargetConfig]) -> PreflightReport {
+        let client = self.client();
+        let host_limits = self.max_requests_per_host.map(HostLimits::new);
+        let variables = Arc::new(self.variables.clone());
+        let self_state = Arc::new(SelfState::new(MemoryBudget::default()));
+        let mut calls = JoinSet::new();
+        let mut checks: Vec<_> = configs
             .iter()
-            .map(|c| match &c.action {
-                Http { url, .. } => {
-                    let s = HttpScrapeTarget::new(client.clone(), url.clone());
-                    Self::launch_scheduled_task(s, p.clone(), c, cancel.clone())
+            .map(|c| PreflightCheck {
+                config: c.clone(),
+                took: Duration::ZERO,
+                problem: missing_command(c).map(|c| Problem::MissingBinary(c.clone())),
+            })
+            .collect();
+        // The calls that have not returned. A call that panicked does not
+        // return its index, so it is still pending after all calls ended.
+        let mut pending = vec![false; configs.len()];
+        for (i, c) in configs.iter().enumerate() {
+            if checks[i].problem.is_some() {
+                continue;
+            }
+            let mut s = match Self::build_service(
+                c,
+                client.as_ref(),
+                host_limits.as_ref(),
+                &variables,
+                &self_state,
+                None,
+            ) {
+                Ok(s) => s,
+                Err(e) => {
+                    checks[i].problem = Some(Problem::Failed(e.to_string()));
+                    continue;
                 }
-                Command { command, args } => {
-                    let s = new_from_config(command.clone(), args.clone());
-                    Self::launch_scheduled_task(s, p.clone(), c, cancel.clone())
+            };
+            let timeout = c.timeout.unwrap_or_default().max(PREFLIGHT_TIMEOUT);
+            pending[i] = true;
+            calls.spawn(async move {
+                let started = Instant::now();
+                let problem = match tokio::time::timeout(timeout, s.call()).await {
+                    Ok(r) => classify(&r),
+                    Err(_) => Some(Problem::Timeout(timeout)),
+                };
+                (i, started.elapsed(), problem)
+            });
+        }
+        while let Some(joined) = calls.join_next().await {
+            let Ok((i, took, problem)) = joined else {
+                continue;
+            };
+            pending[i] = false;
+            checks[i].took = took;
+            checks[i].problem = problem;
+        }
+        for (check, _) in checks.iter_mut().zip(pending).filter(|(_, p)| *p) {
+            check.problem = Some(Problem::Failed("the call panicked".to_string()));
+        }
+        PreflightReport { checks }
+    }
+
+    fn client(&self) -> Option<reqwest::Client> {
+        match self.http_client_policy {
+            HttpClientPolicy::Shared => Some(reqwest::Client::new()),
+            HttpClientPolicy::PerTarget => None,
+            HttpClientPolicy::Custom(ref client) => Some(client.clone()),
+        }
+    }
+
+    /// Build the service that executes the action of a target.
+    fn build_service(
+        c: &ScrapeTargetConfig,
+        client: Option<&reqwest::Client>,
+        host_limits: Option<&HostLimits>,
+        variables: &Arc<Variables>,
+        self_state: &Arc<SelfState>,
+        cursor: Option<OutputCursor>,
+    ) -> Result<BoxedScrapeService, StartError> {
+        use crate::config::Action::*;
+        Ok(match &c.action {
+            Http {
+                method,
+                url,
+                fallback_urls,
+                query,
+                client: client_config,
+                expect_status,
+                stream,
+                vsock,
+                ..
+            } => {
+                let client = match client_config {
+                    Some(cc) => client_from_config(cc).map_err(|e| {
+                        StartError::HttpClient(c.name.clone().unwrap_or_default(), e)
+                    })?,
+                    None => client.cloned().unwrap_or_default(),
+                };
+                let s = HttpScrapeTarget::from_template(client, url.clone(), variables.clone())
+                    .with_method(method.clone())
+                    .with_fallback_urls(fallback_urls.clone())
+                    .with_query(query.clone())
+                    .with_expected_status(expect_status.clone())
+                    .with_accept_encoding(
+                        client_config
+                            .as_ref()
+                            .and_then(|cc| cc.accept_encoding.clone()),
+                    )
+                    .with_stall_timeout(client_config.as_ref().and_then(|cc| cc.stall_timeout))
+                    .with_host_limits(host_limits.cloned())
+                    .with_streaming(*stream)
+                    .with_vsock(*vsock)
+                    .with_static_hosts(
+                        client_config
+                            .iter()
+                            .flat_map(|cc| cc.resolve.keys().cloned()),
+                    );
+                Box::new(s)
+            }
+            Command {
+                spec,
+                only_new_output,
+                parse,
+            } => {
+                let s = new_from_spec(spec.clone());
+                let s = match parse {
+                    Some(p) => s.parse_output(p.clone()),
+                    None => s,
+                };
+                boxed_command(s, *only_new_output, cursor)
+            }
+            SelfStatus => Box::new(SelfStatusService(self_state.clone())),
+            Summary => Box::new(SummaryService(self_state.summaries(c))),
+            #[cfg(feature = "runtime-metrics")]
+            RuntimeMetrics => Box::new(crate::runtime_metrics::RuntimeMetricsCollector::new()),
+            DiskUsage { mount_points } => Box::new(DiskUsageCollector::new(mount_points.clone())),
+            NetworkInterfaces { interfaces, deltas } => {
+                let c = NetDevCollector::new(interfaces.clone());
+                if *deltas {
+                    Box::new(c.deltas())
+                } else {
+                    Box::new(c)
                 }
-            })
-            .unzip();
+            }
+            Processes { name, top } => {
+                let mut c = ProcessCollector::new();
+                if let Some(name) = name {
+                    c = c.name(name.clone());
+                }
+                if let Some(n) = top {
+                    c = c.top(*n);
+                }
+                Box::new(c)
+            }
+            TlsHandshake {
+                host,
+                port,
+                server_name,
+            } => {
+                let name = server_name.as_ref().unwrap_or(host);
+                let s =
+                    TlsHandshakeTarget::new(host.clone(), port.unwrap_or(443), server_name.clone())
+                        .map_err(|_| ConfigError::InvalidServerName(name.clone()))?;
+                Box::new(s)
+            }
+        })
+    }
+}
 
-        let unscheduled_targets = unscheduled_targets
-            .into_iter()
-            .map(|s| Arc::new(Mutex::new(s)))
-            .collect();
-        Self {
-            configs,
-            scheduled_tasks,
-            unscheduled_targets,
-            cancel_signal,
+/// Settings and signals shared by the targets of an instance, also by the ones
+/// added after the start.
+struct LaunchContext {
+    client: Option<reqwest::Client>,
+    host_limits: Option<HostLimits>,
+    /// The variables of the builder.
+    variables: Arc<Variables>,
+    self_state: Arc<SelfState>,
+    state: Option<StateStore>,
+    /// The processor of the targets without a sink.
+    processor: BoxedProcessor,
+    memory_budget: MemoryBudget,
+    observers: Arc<[Arc<dyn ScrapeObserver>]>,
+    error_policy: ProcessorErrorPolicy,
+    sink_policies: BTreeMap<String, ProcessorErrorPolicy>,
+    sinks: BTreeMap<String, BoxedProcessor>,
+    watchdog: Option<u32>,
+    preempt: bool,
+    skip_overruns: bool,
+    coalesce: Option<Duration>,
+    rate_limit: Option<RateLimiter>,
+    health: Option<HealthPolicy>,
+    results: broadcast::Sender<ScrapeEvent>,
+}
+
+/// The service of a target, along with its persisted state and the time of
+/// its last persisted call.
+type BuiltService = (BoxedScrapeService, Option<(Persisted, Option<SystemTime>)>);
+
+impl LaunchContext {
+    /// How processor errors of target `c` are handled, depending on its own
+    /// policy and its sink.
+    fn error_handling(&self, c: &ScrapeTargetConfig) -> ErrorHandling {
+        let policy = c
+            .processor_error_policy
+            .as_ref()
+            .or_else(|| c.sink.as_ref().and_then(|s| self.sink_policies.get(s)))
+            .unwrap_or(&self.error_policy)
+            .clone();
+        let fallback = match &policy {
+            ProcessorErrorPolicy::Fallback { sink } => self.sinks.get(sink).cloned(),
+            _ => None,
+        };
+        ErrorHandling {
+            policy,
+            fallback,
+            observers: self.observers.clone(),
         }
     }
 
-    fn launch_scheduled_task<S, P>(
+    /// Build the services of `configs` with the given variables and restore
+    /// the persisted state of the targets.
+    fn build_services(
+        &self,
+        configs: &[ScrapeTargetConfig],
+        variables: &Arc<Variables>,
+    ) -> Result<Vec<BuiltService>, StartError> {
+        configs
+            .iter()
+            .map(|c| {
+                let persisted = self
+                    .state
+                    .as_ref()
+                    .zip(c.name.as_ref())
+                    .map(|(store, name)| {
+                        let saved = store.get(name).unwrap_or_default();
+                        let incremental = matches!(
+                            c.action,
+                            Action::Command {
+                                only_new_output: true,
+                                ..
+                            }
+                        );
+                        let cursor = incremental
+                            .then(|| Arc::new(Mutex::new(saved.cursor.unwrap_or_default())));
+                        (
+                            Persisted {
+                                store: store.clone(),
+                                name: name.clone(),
+                                cursor,
+                            },
+                            saved.last_run,
+                        )
+                    });
+                let cursor = persisted.as_ref().and_then(|(p, _)| p.cursor.clone());
+                let s = DebugBunnyBuilder::build_service(
+                    c,
+                    self.client.as_ref(),
+                    self.host_limits.as_ref(),
+                    variables,
+                    &self.self_state,
+                    cursor,
+                )?;
+                Ok((s, persisted))
+            })
+            .collect()
+    }
+
+    /// Start the scheduled calls of target `c`. The first call is delayed by
+    /// `offset`, unless the schedule is resumed from the persisted state.
+    fn launch<S>(
+        &self,
         s: S,
-        p: P,
         c: &ScrapeTargetConfig,
-        cancel: Receiver<()>,
-    ) -> (JoinHandle<()>, BoxedScrapeService)
+        id: TargetId,
+        offset: Duration,
+        stats: Arc<TargetStats>,
+        persisted: Option<(Persisted, Option<SystemTime>)>,
+    ) -> Target
     where
         S: ScrapeService<Response = ScrapeOk> + 'static,
-        P: ScrapeResultProcessor + 'static,
     {
-        let t = Timeout::new_with_cancel(
-            s,
-            c.timeout.unwrap_or(Duration::from_secs(2)),
-            cancel.clone(),
-        );
-        let st = ScrapeTarget::new_with_cancel(t, c.interval, cancel.clone());
-        let mut s = st.scheduled;
-        let u = st.unscheduled;
+        let p = route(&self.sinks, c, &self.processor);
+        let (cancel_signal, cancel) = watch::channel(());
+        let timeout = c.timeout.unwrap_or(DEFAULT_TIMEOUT);
+        let t = Timeout::new_with_cancel(s, timeout, cancel.clone())
+            .with_unscheduled_timeout(c.unscheduled_timeout);
+        let t = Hooked::new(t, c.clone(), p.clone());
+        // Hooks are not run while the circuit is open.
+        let (t, circuit): (BoxedScrapeService, _) = match &c.circuit_breaker {
+            Some(cb) => {
+                let t = CircuitBreaker::new(t, cb.failures, cb.open_for);
+                let circuit = t.subscribe();
+                (Box::new(t), Some(circuit))
+            }
+            None => (Box::new(t), None),
+        };
+        let t = Heartbeat {
+            inner: t,
+            stats: stats.clone(),
+        };
+        let t = Observed::new(t, c.clone(), self.observers.clone());
+        let mut st = ScrapeTarget::new_with_cancel(t, c.interval.min, cancel.clone());
+        if c.interval.is_random() {
+            st = st.random_interval(c.interval.max);
+        }
+        if !c.interval_profiles.is_empty() {
+            st = st.interval_profiles(c.interval_profiles.clone());
+        }
+        let (persisted, last_run) = persisted.unzip();
+        if let Some(last_run) = last_run.flatten() {
+            st = st.resume_from(last_run);
+        } else {
+            st = st.delay_first_call(offset);
+        }
+        if self.skip_overruns {
+            st = st.skip_overruns();
+        }
+        if let Some(limiter) = &self.rate_limit {
+            st = st.rate_limited(limiter.clone());
+        }
+        let s = st.scheduled;
+        let u = if self.preempt {
+            st.unscheduled.preempting()
+        } else {
+            st.unscheduled
+        };
+        let coalesced = self.coalesce.map(|ttl| {
+            let m: BoxedScrapeService = Box::new(Memoized::new(u.clone(), ttl));
+            Arc::new(Mutex::new(m))
+        });
+        let u: BoxedScrapeService = Box::new(u);
+        let (paused_signal, paused) = watch::channel(false);
+        let results = ResultBroadcast {
+            target: id,
+            sender: self.results.clone(),
+            circuit,
+        };
+
+        let span = tracing::info_span!("target", name = c.name.as_deref().unwrap_or_default());
 
         // scheduled driver
-        let scheduled = tokio::task::spawn({
+        let run = {
             let p = p.clone();
             let c = c.clone();
+            let memory_budget = self.memory_budget.clone();
+            let stats = stats.clone();
             let cancel = cancel.clone();
-            async move {
-                // xxx(dsd): here we just treat receive errors on the signal as
-                // a change
-                while !cancel.has_changed().unwrap_or(true) {
-                    if let Err(e) = p.process(&c, s.call().await).await {
-                        eprintln!("Error: {e:?}");
+            let errors = self.error_handling(&c);
+            let results = results.clone();
+            let health = self
+                .health
+                .map(|policy| Arc::new(Mutex::new(HealthTracker::new(policy))));
+            let persisted = persisted.map(Arc::new);
+            move || {
+                let persisted = persisted.clone();
+                let mut s = s.clone();
+                let p = p.clone();
+                let c = c.clone();
+                let memory_budget = memory_budget.clone();
+                let stats = stats.clone();
+                let mut paused = paused.clone();
+                let mut cancel = cancel.clone();
+                let results = results.clone();
+                let errors = errors.clone();
+                let health = health.clone();
+                async move {
+                    // xxx(dsd): here we just treat receive errors on the signal as
+                    // a change
+                    while !cancel.has_changed().unwrap_or(true) {
+                        if stats.stopped.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        if *paused.borrow() {
+                            tracing::debug!("paused");
+                        }
+                        tokio::select! {
+                            _ = paused.wait_for(|p| !*p) => {},
+                            _ = cancel.changed() => break,
+                        }
+                        let estimate = stats.body_len.load(Ordering::Relaxed);
+                        // Pausing a target abandons its in-flight scheduled call.
+                        let (r, reservation) = tokio::select! {
+                            r = memory_budget.account(estimate, s.call()) => r,
+                            _ = paused.wait_for(|p| *p) => continue,
+                        };
+                        let _reservation = hold_body(&stats, &r, reservation);
+                        if let Some(persisted) = &persisted {
+                            persisted.record();
+                        }
+                        results.publish(&c, &r, false);
+                        let failures = record_failure(&stats, &r);
+                        let transition = health.as_ref().and_then(|h| h.lock().unwrap().record(&r));
+                        if process_result(&p, &c, r, &errors, &stats).await.is_break() {
+                            break;
+                        }
+                        if let Some(t) = transition {
+                            tracing::warn!(health = ?t.health, "target {t}");
+                            if let Err(e) = p.process_transition(&c, &t).await {
+                                tracing::warn!(error = %e, "could not process transition");
+                            }
+                        }
+                        let exceeded = failures
+                            .zip(c.max_consecutive_failures)
+                            .filter(|(failures, max)| *failures >= max.get());
+                        if let Some((failures, _)) = exceeded {
+                            tracing::error!(
+                                failures,
+                                "disabling target after consecutive failures"
+                            );
+                            stats.stopped.store(true, Ordering::Relaxed);
+                            let r = Err(ScrapeErr::Disabled(failures));
+                            results.publish(&c, &r, false);
+                            let _ = process_result(&p, &c, r, &errors, &stats).await;
+                            break;
+                        }
                     }
+                    tracing::debug!("scheduled calls stopped");
                 }
             }
-        });
-        (scheduled, Box::new(u))
+        };
+        let scheduled = match self.watchdog {
+            Some(factor) => tokio::task::spawn(
+                supervise(run, p.clone(), c.clone(), stats.clone(), timeout * factor)
+                    .instrument(span),
+            ),
+            None => tokio::task::spawn(run().instrument(span)),
+        };
+        Target {
+            config: c.clone(),
+            unscheduled: Arc::new(Mutex::new(u)),
+            coalesced,
+            processor: p,
+            paused: paused_signal,
+            stats,
+            results,
+            errors: self.error_handling(c),
+            cancel: cancel_signal,
+            task: scheduled,
+        }
     }
+}
 
-    pub async fn unscheduled_call<P: ScrapeResultProcessor + 'static>(&self, p: P) {
-        let mut jhs = vec![];
-        for (c, u) in self.configs.iter().zip(self.unscheduled_targets.iter()) {
-            let jh = tokio::task::spawn({
-                let p = p.clone();
-                let c = c.clone();
-                let u = u.clone();
-                async move {
-                    let f = u.lock().unwrap().call();
-                    if let Err(e) = p.process(&c, f.await).await {
-                        eprintln!("Error: {e:?}");
-                    }
+/// The [ProcessorErrorPolicy] of a target, resolved at launch.
+#[derive(Clone)]
+struct ErrorHandling {
+    policy: ProcessorErrorPolicy,
+    /// The sink of [ProcessorErrorPolicy::Fallback].
+    fallback: Option<BoxedProcessor>,
+    /// Notified if the target is stopped.
+    observers: Arc<[Arc<dyn ScrapeObserver>]>,
+}
+
+/// Records the start of each scheduled call in the stats of the target, such
+/// that the watchdog can detect stuck calls.
+struct Heartbeat<S> {
+    inner: S,
+    stats: Arc<TargetStats>,
+}
+
+impl<S: ScrapeService<Response = ScrapeOk>> ScrapeService for Heartbeat<S> {
+    type Response = ScrapeOk;
+
+    fn call(&mut self) -> FutureScrapeResult<ScrapeOk> {
+        // The start is recorded before calling the inner service, as a buggy
+        // service might already block here.
+        let started = Instant::now();
+        *self.stats.call_started.lock().unwrap() = Some(started);
+        let guard = ClearOnDrop(self.stats.clone(), started);
+        let call = self.inner.call();
+        Box::pin(async move {
+            let _guard = guard;
+            call.await
+        })
+    }
+
+    fn call_unscheduled(&mut self) -> FutureScrapeResult<ScrapeOk> {
+        self.inner.call_unscheduled()
+    }
+}
+
+/// Clears the start of a call, unless a later call has started since, e.g.
+/// when an aborted call is dropped after the loop has been restarted.
+struct ClearOnDrop(Arc<TargetStats>, Instant);
+
+impl Drop for ClearOnDrop {
+    fn drop(&mut self) {
+        let mut started = self.0.call_started.lock().unwrap();
+        if *started == Some(self.1) {
+            *started = None;
+        }
+    }
+}
+
+/// Run the scheduled loop created by `run`. If a call takes longer than
+/// `limit`, the loop is aborted, an error is passed to the processor and the
+/// loop is restarted.
+async fn supervise<F, Fut, P>(
+    run: F,
+    p: P,
+    c: ScrapeTargetConfig,
+    stats: Arc<TargetStats>,
+    limit: Duration,
+) where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = ()> + Send + 'static,
+    P: ScrapeResultProcessor,
+{
+    loop {
+        let mut task = tokio::task::spawn(run().in_current_span());
+        let stuck_for = loop {
+            tokio::select! {
+                _ = &mut task => return,
+                _ = tokio::time::sleep(limit / 2) => {}
+            }
+            let started = *stats.call_started.lock().unwrap();
+            if let Some(elapsed) = started.map(|s| s.elapsed()).filter(|e| *e > limit) {
+                break elapsed;
+            }
+        };
+        tracing::error!(?stuck_for, "aborting stuck scrape");
+        // A call that blocks its thread cannot be aborted until it yields,
+        // so the task is detached instead of awaited.
+        task.abort();
+        drop(task);
+        *stats.call_started.lock().unwrap() = None;
+        stats.watchdog_restarts.fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = p.process(&c, Err(ScrapeErr::Stuck(stuck_for))).await {
+            tracing::warn!(error = %e, "could not process result");
+        }
+    }
+}
+
+/// Spread the first calls of targets that share an interval evenly across the
+/// interval, such that their load is flat. The order within an interval is
+/// derived from the names of the targets, such that it is stable across
+/// restarts. See [ScrapeTargetConfig::synchronized].
+fn stagger(configs: &[ScrapeTargetConfig]) -> Vec<Duration> {
+    let mut by_interval: BTreeMap<Duration, Vec<_>> = BTreeMap::new();
+    for (i, c) in configs.iter().enumerate().filter(|(_, c)| !c.synchronized) {
+        let name = c.name.clone().unwrap_or_else(|| format!("#{i}"));
+        let key = Sha256::digest(name.as_bytes());
+        by_interval
+            .entry(c.interval.min)
+            .or_default()
+            .push((key, i));
+    }
+    let mut offsets = vec![Duration::ZERO; configs.len()];
+    for (interval, mut targets) in by_interval {
+        targets.sort();
+        let n = targets.len() as u32;
+        for (k, (_, i)) in (0..).zip(targets) {
+            offsets[i] = interval * k / n;
+        }
+    }
+    offsets
+}
+
+/// Fail if one of `configs` is invalid, or if a target or a
+/// [ProcessorErrorPolicy::Fallback] refers to a sink that is not in `sinks`.
+/// `policies` are the policies of the builder.
+fn check_targets<'a>(
+    configs: &'a [ScrapeTargetConfig],
+    sinks: &BTreeMap<String, BoxedProcessor>,
+    policies: impl IntoIterator<Item = &'a ProcessorErrorPolicy>,
+) -> Result<(), StartError> {
+    if let Some(sink) = configs
+        .iter()
+        .filter_map(|c| c.sink.as_ref())
+        .find(|s| !sinks.contains_key(*s))
+    {
+        return Err(StartError::UnknownSink(sink.clone()));
+    }
+    if let Some(sink) = policies
+        .into_iter()
+        .chain(
+            configs
+                .iter()
+                .filter_map(|c| c.processor_error_policy.as_ref()),
+        )
+        .filter_map(|p| match p {
+            ProcessorErrorPolicy::Fallback { sink } => Some(sink),
+            _ => None,
+        })
+        .find(|s| !sinks.contains_key(*s))
+    {
+        return Err(StartError::UnknownFallbackSink(sink.clone()));
+    }
+    for c in configs {
+        c.validate()?;
+    }
+    Ok(())
+}
+
+/// The command of `c`, if it is not a shell command and cannot be found.
+fn missing_command(c: &ScrapeTargetConfig) -> Option<&String> {
+    match &c.action {
+        Action::Command {
+            spec:
+                CommandSpec {
+                    command,
+                    shell: false,
+                    ..
+                },
+            ..
+        } => find_executable(command).is_none().then_some(command),
+        _ => None,
+    }
+}
+
+fn boxed_command<T>(
+    s: CommandScrapeService<T>,
+    only_new_output: bool,
+    cursor: Option<OutputCursor>,
+) -> BoxedScrapeService
+where
+    T: Fn() -> tokio::process::Command + Send + 'static,
+{
+    match (only_new_output, cursor) {
+        (true, Some(cursor)) => Box::new(s.only_new_output_after(cursor)),
+        (true, None) => Box::new(s.only_new_output()),
+        (false, _) => Box::new(s),
+    }
+}
+
+/// The handle to the persisted state of a named target.
+struct Persisted {
+    store: StateStore,
+    name: String,
+    cursor: Option<OutputCursor>,
+}
+
+impl Persisted {
+    /// Record a scheduled scrape that just happened. The state is saved
+    /// periodically, see [crate::state].
+    fn record(&self) {
+        let state = TargetState {
+            last_run: Some(SystemTime::now()),
+            cursor: self.cursor.as_ref().map(|c| c.lock().unwrap().clone()),
+        };
+        self.store.set(&self.name, state);
+    }
+}
+
+/// Pass a result to the processor and handle errors according to `errors`.
+/// Breaks if the target is to be stopped.
+async fn process_result<P: ScrapeResultProcessor>(
+    p: &P,
+    c: &ScrapeTargetConfig,
+    r: ScrapeResult<ScrapeOk>,
+    errors: &ErrorHandling,
+    stats: &TargetStats,
+) -> ControlFlow<()> {
+    let (attempts, backoff) = match errors.policy {
+        ProcessorErrorPolicy::Retry { attempts, backoff } => (attempts, backoff),
+        _ => (0, Duration::ZERO),
+    };
+    let _processed = ProcessedOnDrop(stats);
+    stats.processing.fetch_add(1, Ordering::Relaxed);
+    let _processing = DecrementOnDrop(&stats.processing);
+    let mut r = r;
+    let mut attempt = 0;
+    // The result is only handed back if it could not be processed, such that
+    // it is not copied up front for a retry or the fallback sink.
+    let (e, r) = loop {
+        let Err(Unprocessed { error: e, result }) = p.process_or_return(c, r).await else {
+            return ControlFlow::Continue(());
+        };
+        stats.processor_errors.fetch_add(1, Ordering::Relaxed);
+        tracing::warn!(target = c.name.as_deref().unwrap_or_default(), error = %e, attempt, "could not process result");
+        if attempt >= attempts || result.is_err() {
+            break (e, result);
+        }
+        r = result;
+        attempt += 1;
+        tokio::time::sleep(backoff).await;
+    };
+    if let Some(fallback) = &errors.fallback {
+        if let Err(e) = fallback.process(c, r).await {
+            tracing::warn!(target = c.name.as_deref().unwrap_or_default(), error = %e, "could not divert result to fallback sink");
+        }
+    }
+    if errors.policy == ProcessorErrorPolicy::StopTarget {
+        tracing::error!(
+            target = c.name.as_deref().unwrap_or_default(),
+            "stopping target due to processor error"
+        );
+        stats.stopped.store(true, Ordering::Relaxed);
+        for o in errors.observers.iter() {
+            o.on_target_stopped(c, &e);
+        }
+        return ControlFlow::Break(());
+    }
+    ControlFlow::Continue(())
+}
+
+/// The reservation of the body of `r`, held while the result is processed.
+/// Its size is the estimate of the next call of the target. The partial body
+/// of a failed call has been dropped already.
+fn hold_body(
+    stats: &TargetStats,
+    r: &ScrapeResult<ScrapeOk>,
+    reservation: Option<Reservation>,
+) -> Option<Reservation> {
+    let reservation = reservation.filter(|_| r.is_ok())?;
+    stats.body_len.store(reservation.used(), Ordering::Relaxed);
+    Some(reservation)
+}
+
+/// Count a failed scheduled call or reset the count after a successful one.
+/// Calls that did not reach the target are not counted. Returns the number
+/// of consecutive failures if the call failed.
+fn record_failure(stats: &TargetStats, r: &ScrapeResult<ScrapeOk>) -> Option<u32> {
+    match r {
+        Ok(_) => {
+            stats.consecutive_failures.store(0, Ordering::Relaxed);
+            None
+        }
+        Err(e) if e.is_unreachable() => None,
+        Err(_) => Some(stats.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1),
+    }
+}
+
+struct DecrementOnDrop<'a>(&'a AtomicU64);
+
+impl Drop for DecrementOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct ProcessedOnDrop<'a>(&'a TargetStats);
+
+impl Drop for ProcessedOnDrop<'_> {
+    fn drop(&mut self) {
+        self.0.has_result.store(true, Ordering::Relaxed);
+        self.0.processed.notify_waiters();
+    }
+}
+
+/// The processor for the results of target `c`.
+fn route(
+    sinks: &BTreeMap<String, BoxedProcessor>,
+    c: &ScrapeTargetConfig,
+    default: &BoxedProcessor,
+) -> BoxedProcessor {
+    c.sink
+        .as_ref()
+        .and_then(|s| sinks.get(s))
+        .unwrap_or(default)
+        .clone()
+}
+
+impl DebugBunny {
+    pub fn builder() -> DebugBunnyBuilder {
+        DebugBunnyBuilder::new()
+    }
+
+    /// See [DebugBunnyBuilder::start_scraping].
+    pub async fn start_scraping<P: ScrapeResultProcessor + 'static>(
+        configs: Vec<ScrapeTargetConfig>,
+        p: P,
+    ) -> Result<Self, StartError> {
+        Self::builder().start_scraping(configs, p).await
+    }
+
+    /// See [DebugBunnyBuilder::start_config].
+    pub async fn start_config<P: ScrapeResultProcessor + 'static>(
+        config: Config,
+        p: P,
+    ) -> Result<Self, StartError> {
+        Self::builder().start_config(config, p).await
+    }
+
+    /// Start scraping `configs` in addition to the running targets, with the
+    /// settings of the builder this instance was started with. Unlike at the
+    /// start, no banner is written. The first calls of the added targets are
+    /// staggered among each other, see [ScrapeTargetConfig::synchronized].
+    ///
+    /// Fails like [DebugBunnyBuilder::start_scraping], in which case none of
+    /// the targets is added. Returns the ids of the added targets.
+    pub async fn add_targets(
+        &mut self,
+        configs: Vec<ScrapeTargetConfig>,
+    ) -> Result<Vec<TargetId>, StartError> {
+        let variables = self.ctx.variables.clone();
+        self.add(configs, &variables).await
+    }
+
+    /// Like [Self::add_targets], but with the targets of `config`. The
+    /// variables of `config` are added to the ones of the builder for these
+    /// targets, see [DebugBunnyBuilder::start_config].
+    pub async fn add_config(&mut self, config: Config) -> Result<Vec<TargetId>, StartError> {
+        let mut variables = (*self.ctx.variables).clone();
+        variables.merge(config.variables);
+        self.add(config.scrape_targets, &Arc::new(variables)).await
+    }
+
+    async fn add(
+        &mut self,
+        configs: Vec<ScrapeTargetConfig>,
+        variables: &Arc<Variables>,
+    ) -> Result<Vec<TargetId>, StartError> {
+        let policies = self
+            .ctx
+            .sink_policies
+            .values()
+            .chain([&self.ctx.error_policy]);
+        check_targets(&configs, &self.ctx.sinks, policies)?;
+        let services = self.ctx.build_services(&configs, variables)?;
+        Ok(self.launch(configs, services).await)
+    }
+
+    /// Launch the targets of `configs` with their services and assign their
+    /// ids.
+    async fn launch(
+        &mut self,
+        configs: Vec<ScrapeTargetConfig>,
+        services: Vec<BuiltService>,
+    ) -> Vec<TargetId> {
+        let offsets = stagger(&configs);
+        let mut ids = Vec::with_capacity(configs.len());
+        for ((c, (s, persisted)), offset) in configs.iter().zip(services).zip(offsets) {
+            let stats = Arc::<TargetStats>::default();
+            // A missing binary fails every call the same way, so it is
+            // reported once and the target is not scheduled.
+            if let Some(command) = missing_command(c) {
+                tracing::error!(
+                    target = c.name.as_deref().unwrap_or_default(),
+                    command,
+                    "command not found, not scheduling target"
+                );
+                let p = route(&self.ctx.sinks, c, &self.ctx.processor);
+                let e = ScrapeErr::CommandNotFound(command.clone());
+                if let Err(e) = p.process(c, Err(e)).await {
+                    tracing::warn!(error = %e, "could not process result");
                 }
+                stats.stopped.store(true, Ordering::Relaxed);
+            }
+            let id = TargetId(self.next_id);
+            self.next_id += 1;
+            let t = self.ctx.launch(s, c, id, offset, stats, persisted);
+            let view = (t.config.clone(), t.paused.subscribe(), t.stats.clone());
+            self.ctx.self_state.targets.lock().unwrap().insert(id, view);
+            self.targets.push(t);
+            ids.push(id);
+        }
+        ids
+    }
+
+    /// Stop the target with the given id and remove it, once its scheduled
+    /// calls have stopped. Results of unscheduled calls that are still in
+    /// flight are processed. Unlike [Self::await_shutdown], this does not
+    /// shut down any processor. Returns false if there is no such target.
+    pub async fn remove_target(&mut self, id: TargetId) -> bool {
+        let Some(i) = self.targets.iter().position(|t| t.results.target == id) else {
+            return false;
+        };
+        let t = self.targets.remove(i);
+        let _ = t.cancel.send(());
+        if let Err(e) = t.task.await {
+            tracing::error!(error = %e, "scheduled task panicked");
+        }
+        self.ctx.self_state.targets.lock().unwrap().remove(&id);
+        // The statistics are kept while another summary target shares them.
+        let key = target_key(&t.config);
+        let shared = self
+            .targets
+            .iter()
+            .any(|t| matches!(t.config.action, Action::Summary) && target_key(&t.config) == key);
+        if matches!(t.config.action, Action::Summary) && !shared {
+            self.ctx.self_state.summaries.lock().unwrap().remove(&key);
+        }
+        true
+    }
+
+    /// Call all targets at once. Results of targets with a sink are routed
+    /// to the sink, all other results are passed to `p`.
+    pub async fn unscheduled_call<P: ScrapeResultProcessor + 'static>(&self, p: P) {
+        self.unscheduled_call_filtered(|_| true, p, None).await;
+    }
+
+    /// Like [Self::unscheduled_call], but calls that have not finished after
+    /// `deadline` are cancelled and a [ScrapeErr::DeadlineExceeded] error is
+    /// processed instead of their result. Returns the targets whose calls
+    /// were cancelled.
+    pub async fn unscheduled_call_with_deadline<P: ScrapeResultProcessor + 'static>(
+        &self,
+        deadline: Duration,
+        p: P,
+    ) -> Vec<TargetId> {
+        self.unscheduled_call_filtered(|_| true, p, Some(deadline))
+            .await
+    }
+
+    /// Wait until every target has produced at least one result, or until
+    /// `timeout` expires. Stopped targets are not waited for. Returns whether
+    /// all targets produced a result in time.
+    pub async fn wait_for_first_results(&self, timeout: Duration) -> bool {
+        let all = async {
+            for t in &self.targets {
+                t.stats.first_result().await;
+            }
+        };
+        tokio::time::timeout(timeout, all).await.is_ok()
+    }
+
+    /// The state of all targets, in the order of their configs.
+    pub fn target_status(&self) -> Vec<TargetStatus> {
+        self.targets
+            .iter()
+            .map(|t| t.stats.status(&t.config, *t.paused.borrow()))
+            .collect()
+    }
+
+    /// Call all targets of the given group at once.
+    pub async fn unscheduled_call_group<P: ScrapeResultProcessor + 'static>(
+        &self,
+        group: &str,
+        p: P,
+    ) {
+        self.unscheduled_call_filtered(|c| c.group.as_deref() == Some(group), p, None)
+            .await;
+    }
+
+    /// Call all targets selected by `selector` at once, e.g. the targets of
+    /// the subsystem under investigation. Results are routed like with
+    /// [Self::unscheduled_call].
+    pub async fn unscheduled_call_matching<P: ScrapeResultProcessor + 'static>(
+        &self,
+        selector: &LabelSelector,
+        p: P,
+    ) {
+        self.unscheduled_call_filtered(|c| selector.matches(c), p, None)
+            .await;
+    }
+
+    /// Pause the scheduled calls of all targets of the given group. In-flight
+    /// scheduled calls are abandoned. Unscheduled calls are still possible.
+    pub fn pause_group(&self, group: &str) {
+        self.set_group_paused(group, true);
+    }
+
+    pub fn resume_group(&self, group: &str) {
+        self.set_group_paused(group, false);
+    }
+
+    fn set_group_paused(&self, group: &str, paused: bool) {
+        self.targets
+            .iter()
+            .filter(|t| t.config.group.as_deref() == Some(group))
+            .for_each(|t| {
+                t.paused.send_replace(paused);
             });
-            jhs.push(jh);
+    }
+
+    /// Call the target with the given name `count` times, `spacing` apart,
+    /// e.g. to investigate a transient spike. The results are processed like
+    /// scheduled results. As with all unscheduled calls, the calls do not
+    /// overlap with other calls of the target, and the regular schedule
+    /// continues one interval after the last call. With a zero `spacing`, the
+    /// calls follow each other back to back.
+    ///
+    /// Returns false if there is no target with the given name.
+    pub async fn burst(&self, target: &str, count: usize, spacing: Duration) -> bool {
+        let Some(t) = self
+            .targets
+            .iter()
+            .find(|t| t.config.name.as_deref() == Some(target))
+        else {
+            return false;
+        };
+        // `interval` panics on a zero period.
+        let mut ticks = (!spacing.is_zero()).then(|| tokio::time::interval(spacing));
+        if let Some(ticks) = &mut ticks {
+            // A call that takes longer than `spacing` delays the following ones.
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        }
+        for _ in 0..count {
+            if let Some(ticks) = &mut ticks {
+                ticks.tick().await;
+            }
+            if t.stats.stopped.load(Ordering::Relaxed) {
+                break;
+            }
+            self.call_unscheduled(t, t.processor.clone(), None, false)
+                .await;
+        }
+        true
+    }
+
+    /// Call all targets matching `filter` at once. Unlike with
+    /// [DebugBunny::unscheduled_call], the results are processed like
+    /// scheduled results. Returns the number of targets called.
+    pub async fn trigger<F>(&self, filter: F) -> usize
+    where
+        F: Fn(&ScrapeTargetConfig) -> bool,
+    {
+        self.call_matching(filter, |t| t.processor.clone(), None, false)
+            .await
+            .len()
+    }
+
+    /// Subscribe to the results of all targets, scheduled and unscheduled,
+    /// in addition to their processors. A subscriber that falls behind by
+    /// more than 256 results misses the oldest ones.
+    pub fn subscribe(&self) -> broadcast::Receiver<ScrapeEvent> {
+        self.ctx.results.subscribe()
+    }
+
+    /// The results of all targets, scheduled and unscheduled, as a stream.
+    /// This is an alternative to implementing [ScrapeResultProcessor]; the
+    /// results are still passed to the processors of the targets. Results
+    /// that are missed due to the stream falling behind (see
+    /// [DebugBunny::subscribe]) are skipped with a warning.
+    pub fn results(
+        &self,
+    ) -> impl Stream<Item = (TargetId, ScrapeMeta, ScrapeResult<ScrapeOk>)> + Send + 'static {
+        BroadcastStream::new(self.subscribe()).filter_map(|e| match e {
+            Ok(e) => Some((e.target, e.meta, e.result)),
+            Err(BroadcastStreamRecvError::Lagged(n)) => {
+                tracing::warn!(missed = n, "result stream fell behind");
+                None
+            }
+        })
+    }
+
+    /// Returns the targets whose calls were cancelled due to the deadline.
+    async fn unscheduled_call_filtered<F, P>(
+        &self,
+        filter: F,
+        p: P,
+        deadline: Option<Duration>,
+    ) -> Vec<TargetId>
+    where
+        F: Fn(&ScrapeTargetConfig) -> bool,
+        P: ScrapeResultProcessor + 'static,
+    {
+        let default = BoxedProcessor::new(p);
+        let deadline = deadline.map(|d| (tokio::time::Instant::now() + d, d));
+        self.call_matching(
+            filter,
+            |t| route(&self.ctx.sinks, &t.config, &default),
+            deadline,
+            true,
+        )
+        .await
+        .into_iter()
+        .filter_map(|(id, finished)| (!finished).then_some(id))
+        .collect()
+    }
+
+    /// Returns the targets called and whether their calls finished before
+    /// the deadline.
+    async fn call_matching<F, R>(
+        &self,
+        filter: F,
+        processor: R,
+        deadline: Option<(tokio::time::Instant, Duration)>,
+        coalesce: bool,
+    ) -> Vec<(TargetId, bool)>
+    where
+        F: Fn(&ScrapeTargetConfig) -> bool,
+        R: Fn(&Target) -> BoxedProcessor,
+    {
+        let mut jhs = vec![];
+        let targets = self
+            .targets
+            .iter()
+            .filter(|t| filter(&t.config) && !t.stats.stopped.load(Ordering::Relaxed));
+        for t in targets {
+            let call = self.call_unscheduled(t, processor(t), deadline, coalesce);
+            jhs.push((t.results.target, tokio::task::spawn(call)));
         }
-        for jh in jhs {
-            if let Err(e) = jh.await {
-                eprintln!("Error: {e:?}");
+        let mut called = Vec::with_capacity(jhs.len());
+        for (id, jh) in jhs {
+            match jh.await {
+                Ok(finished) => called.push((id, finished)),
+                Err(e) => {
+                    tracing::error!(error = %e, "unscheduled call panicked");
+                    called.push((id, true));
+                }
             }
         }
+        called
+    }
+
+    /// A single unscheduled call of `t`, independent of the lifetime of `self`.
+    /// The call is cancelled at `deadline`, which is given together with its
+    /// original duration. With `coalesce`, the call may share the result of
+    /// another one. Resolves to whether the call finished in time.
+    fn call_unscheduled(
+        &self,
+        t: &Target,
+        p: BoxedProcessor,
+        deadline: Option<(tokio::time::Instant, Duration)>,
+        coalesce: bool,
+    ) -> impl std::future::Future<Output = bool> + Send + 'static {
+        let c = t.config.clone();
+        let u = match (&t.coalesced, coalesce) {
+            (Some(coalesced), true) => coalesced.clone(),
+            _ => t.unscheduled.clone(),
+        };
+        let stats = t.stats.clone();
+        let memory_budget = self.ctx.memory_budget.clone();
+        let errors = t.errors.clone();
+        let results = t.results.clone();
+        async move {
+            let call = async {
+                let f = u.lock().unwrap().call();
+                f.await
+            };
+            let estimate = stats.body_len.load(Ordering::Relaxed);
+            let call = memory_budget.account(estimate, call);
+            let ((r, reservation), finished) = match deadline {
+                Some((at, d)) => match tokio::time::timeout_at(at, call).await {
+                    Ok(r) => (r, true),
+                    Err(_) => {
+                        tracing::warn!(
+                            target = c.name.as_deref().unwrap_or_default(),
+                            "unscheduled call cancelled after deadline"
+                        );
+                        ((Err(ScrapeErr::DeadlineExceeded(d)), None), false)
+                    }
+                },
+                None => (call.await, true),
+            };
+            let _reservation = hold_body(&stats, &r, reservation);
+            // The scheduled calls observe the stopped flag themselves.
+            results.publish(&c, &r, true);
+            let _ = process_result(&p, &c, r, &errors, &stats).await;
+            finished
+        }
     }
 
+    /// Stop all targets. In-flight calls are aborted and resolve with
+    /// [ScrapeErr::Cancelled]; running commands are terminated, see
+    /// [CommandSpec::kill_grace_period](crate::command::CommandSpec::kill_grace_period).
     pub fn stop(&self) {
-        let _ = self.cancel_signal.send(());
+        for t in &self.targets {
+            let _ = t.cancel.send(());
+        }
     }
 
+    /// Wait for the targets to stop, see [Self::stop], save the state file,
+    /// then flush and shut down the processors the targets were started with,
+    /// including sinks.
     pub async fn await_shutdown(self) {
-        for jh in self.scheduled_tasks {
-            if let Err(e) = jh.await {
-                eprintln!("Error: {e:?}");
+        for t in self.targets {
+            if let Err(e) = t.task.await {
+                tracing::error!(error = %e, "scheduled task panicked");
+            }
+        }
+        if let Some(saver) = self.state_saver {
+            saver.abort();
+        }
+        let ctx = self.ctx;
+        if let Some(state) = &ctx.state {
+            if let Err(e) = state.save_if_dirty().await {
+                tracing::error!(error = %e, "could not save schedule state");
             }
         }
+        let processors = [("default", &ctx.processor)]
+            .into_iter()
+            .chain(ctx.sinks.iter().map(|(name, p)| (name.as_str(), p)));
+        for (sink, p) in processors {
+            if let Err(e) = p.flush().await {
+                tracing::error!(error = %e, sink, "could not flush processor");
+            }
+            if let Err(e) = p.shutdown().await {
+                tracing::error!(error = %e, sink, "could not shut down processor");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{Action, ScrapeTargetBuilder},
+        result_processor::ErrorCollector,
+    };
+
+    #[test]
+    fn first_calls_are_staggered() {
+        let target = |name: &str, secs| {
+            ScrapeTargetBuilder::new()
+                .name(name)
+                .interval(Duration::from_secs(secs))
+                .action(Action::SelfStatus)
+        };
+        let configs = vec![
+            target("a", 60).build(),
+            target("b", 60).build(),
+            target("c", 60).build(),
+            target("d", 60).synchronized().build(),
+            target("e", 10).build(),
+        ];
+        let offsets = stagger(&configs);
+        let mut shared: Vec<_> = offsets[..3].iter().map(Duration::as_secs).collect();
+        shared.sort();
+        assert_eq!(vec![0, 20, 40], shared);
+        assert_eq!(Duration::ZERO, offsets[3]);
+        assert_eq!(Duration::ZERO, offsets[4]);
+        assert_eq!(offsets, stagger(&configs));
+    }
+
+    #[tokio::test]
+    async fn stuck_loops_are_restarted() {
+        let config = ScrapeTargetBuilder::new()
+            .interval(Duration::from_secs(1))
+            .action(Action::command("true".to_string()))
+            .build();
+        let stats = Arc::<TargetStats>::default();
+        let runs = Arc::new(AtomicU64::new(0));
+        let run = {
+            let stats = stats.clone();
+            let runs = runs.clone();
+            move || {
+                runs.fetch_add(1, Ordering::Relaxed);
+                *stats.call_started.lock().unwrap() = Some(Instant::now());
+                std::future::pending()
+            }
+        };
+        let errors = ErrorCollector::default();
+        let supervisor = tokio::spawn(supervise(
+            run,
+            errors.clone(),
+            config,
+            stats.clone(),
+            Duration::from_millis(20),
+        ));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        supervisor.abort();
+
+        assert!(runs.load(Ordering::Relaxed) >= 2);
+        assert!(stats.watchdog_restarts.load(Ordering::Relaxed) >= 1);
+        assert!(errors.0.lock().unwrap()[0].starts_with("Scrape got stuck"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn loops_blocking_their_thread_are_restarted_right_away() {
+        let config = ScrapeTargetBuilder::new()
+            .interval(Duration::from_secs(1))
+            .action(Action::command("true".to_string()))
+            .build();
+        let stats = Arc::<TargetStats>::default();
+        let runs = Arc::new(AtomicU64::new(0));
+        let run = {
+            let stats = stats.clone();
+            let runs = runs.clone();
+            move || {
+                let first = runs.fetch_add(1, Ordering::Relaxed) == 0;
+                let stats = stats.clone();
+                async move {
+                    *stats.call_started.lock().unwrap() = Some(Instant::now());
+                    if first {
+                        // Never yields, so aborting it has no effect.
+                        std::thread::sleep(Duration::from_secs(1));
+                    }
+                    std::future::pending::<()>().await;
+                }
+            }
+        };
+        let errors = ErrorCollector::default();
+        let supervisor = tokio::spawn(supervise(
+            run,
+            errors.clone(),
+            config,
+            stats.clone(),
+            Duration::from_millis(20),
+        ));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        supervisor.abort();
+
+        // Well before the blocked call returns.
+        assert!(runs.load(Ordering::Relaxed) >= 2);
+        assert!(errors.0.lock().unwrap()[0].starts_with("Scrape got stuck"));
+    }
+
+    #[tokio::test]
+    async fn only_scheduled_calls_are_watched() {
+        struct Pending;
+
+        impl ScrapeService for Pending {
+            type Response = ScrapeOk;
+
+            fn call(&mut self) -> FutureScrapeResult<ScrapeOk> {
+                Box::pin(std::future::pending())
+            }
+        }
+
+        let stats = Arc::<TargetStats>::default();
+        let mut t = Heartbeat {
+            inner: Pending,
+            stats: stats.clone(),
+        };
+        let unscheduled = t.call_unscheduled();
+        assert!(stats.call_started.lock().unwrap().is_none());
+        drop(unscheduled);
+
+        let scheduled = t.call();
+        assert!(stats.call_started.lock().unwrap().is_some());
+        drop(scheduled);
+        assert!(stats.call_started.lock().unwrap().is_none());
     }
 }
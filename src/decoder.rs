@@ -0,0 +1,879 @@
+//! Reassembly of the records written by a
+//! [LogOutputWriter](crate::result_processor::LogOutputWriter).
+//!
+//! A [Decoder] consumes the lines of a log, relates the chunk records to the
+//! record of their scrape call and yields a [DecodedRecord] with the
+//! decompressed (and decrypted) body as soon as all chunks of a body have
+//! been read, in any order. Chunks that precede the record of their call, e.g.
+//! because a log pipeline reordered lines, are kept until it is read, up to
+//! [MAX_ORPHAN_BYTES]. Derived fields that were moved to overflow records are merged
+//! back into the record of their call. Lines that are not debugbunny records
+//! are skipped, such that the decoder can be fed a log shared with other
+//! output.
+//!
+//! As debugbunny usually runs as a systemd service, its records typically end
+//! up in the journal. A [JournalReader] reads the output of
+//! `journalctl -o json --all` directly:
+//!
+//! - `MESSAGE` fields that journald stores as an array of bytes (e.g. if they
+//!   contain non-printable characters) are mapped back to the raw message,
+//! - lines that journald split due to `LineMax=` (marked with
+//!   `_LINE_BREAK=line-max`) are joined again.
+//!
+//! Without `--all`, journalctl replaces large fields with `null`, which
+//! yields [DecodeError::Truncated].
+//!
+//! ```no_run
+//! # use std::io::stdin;
+//! # use debugbunny::decoder::{Decoder, JournalReader};
+//! for record in JournalReader::new(stdin().lock(), Decoder::new()) {
+//!     let record = record.unwrap();
+//!     println!("{:?}: {} bytes", record.call.target_config.name, record.body.map_or(0, |b| b.len()));
+//! }
+//! ```
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, BufRead, Read},
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::{
+    chunks::{Chunk, ChunkEncoding, Chunks, ChunksError},
+    delta::{self, DeltaRepr},
+    dictionary::Dictionary,
+    encryption::{EncryptionError, PayloadKey},
+    result_processor::{
+        target_key, ChunkRepr, DictionaryRepr, OverflowRepr, ScrapeCallRepr, ScrapeResultRepr,
+    },
+    signing::{RecordVerifier, SigningError},
+};
+
+/// The maximum size of the chunks kept while the record they belong to has
+/// not been read. Beyond it, the chunks of the call seen first are dropped.
+pub const MAX_ORPHAN_BYTES: usize = 64 * 1024 * 1024;
+
+/// The number of bodies kept per target that uses delta encoding.
+const MAX_BASES: usize = 4;
+
+/// A scrape call and its body, if the call was successful.
+#[derive(Debug, Clone)]
+pub struct DecodedRecord {
+    pub call: ScrapeCallRepr,
+    /// The decompressed body, unless the call failed or the body was spooled
+    /// (see [ScrapeCallRepr::spooled]). For commands, this is the JSON
+    /// document of the interleaved lines of stdout and stderr.
+    pub body: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    #[error("Could not read input")]
+    Io(#[from] io::Error),
+    #[error("Invalid journal entry")]
+    Journal(#[source] serde_json::Error),
+    #[error("The message of a journal entry is missing; use `journalctl --all`")]
+    Truncated,
+    #[error("Invalid chunks")]
+    Chunks(#[from] ChunksError),
+    #[error("The body of invocation {0} does not match its id")]
+    DigestMismatch(Uuid),
+    #[error("The raw data of a chunk of invocation {0} does not match its length")]
+    RawLength(Uuid),
+    #[error("No key with id {0}")]
+    UnknownKey(String),
+    #[error("No dictionary with id {0}; the log must include the record that introduced it")]
+    UnknownDictionary(String),
+    #[error("The body of call {0}, which a delta refers to, was not read")]
+    MissingBase(Uuid),
+    #[error("Could not decrypt body")]
+    Decryption(#[from] EncryptionError),
+    #[error("Could not decompress body")]
+    Decompression(#[source] io::Error),
+    #[error("Invalid record signature")]
+    Signature(#[from] SigningError),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Record {
+    Chunk(ChunkRepr),
+    Call(Box<ScrapeCallRepr>),
+    Overflow(OverflowRepr),
+    Dictionary(DictionaryRepr),
+}
+
+/// Reassembles the records of a log. See the [module docs](self).
+#[derive(Default)]
+pub struct Decoder {
+    keys: HashMap<String, PayloadKey>,
+    verifier: Option<RecordVerifier>,
+    /// Calls whose body is not complete yet.
+    pending: HashMap<Uuid, Pending>,
+    /// Dictionaries whose chunks are still to be read, by invocation id.
+    pending_dictionaries: HashMap<Uuid, (String, Vec<Chunk>)>,
+    /// See [crate::dictionary].
+    dictionaries: HashMap<String, Dictionary>,
+    /// The latest bodies of each target that uses delta encoding, newest
+    /// last, along with the invocation ids of their calls. Calls that overlap
+    /// may refer to the same base, so more than the latest one is kept. See
+    /// [crate::delta].
+    bases: HashMap<String, VecDeque<(Uuid, Vec<u8>)>>,
+    /// A raw chunk whose data is being read.
+    raw: Option<RawData>,
+    /// Chunks whose call or dictionary record was not read yet, by
+    /// invocation id.
+    orphans: HashMap<Uuid, Vec<ChunkRepr>>,
+    /// The keys of `orphans`, oldest first.
+    orphan_order: VecDeque<Uuid>,
+    orphan_bytes: usize,
+}
+
+/// A call whose overflow records or body are still to be read.
+struct Pending {
+    call: ScrapeCallRepr,
+    chunks: Vec<Chunk>,
+    overflow_left: usize,
+}
+
+/// The data of a [ChunkEncoding::Raw] chunk, read from the lines following its
+/// record. As the data may contain line breaks, it may span several lines.
+struct RawData {
+    chunk: ChunkRepr,
+    length: usize,
+    data: Vec<u8>,
+    lines: usize,
+}
+
+#[derive(Deserialize)]
+struct RawLength {
+    length: usize,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decrypt bodies that were encrypted with `key`. Several keys may be
+    /// given, e.g. if keys were rotated.
+    pub fn with_key(mut self, key: PayloadKey) -> Self {
+        self.keys.insert(key.id().to_string(), key);
+        self
+    }
+
+    /// Only accept records with a valid signature.
+    pub fn with_verifier(mut self, verifier: RecordVerifier) -> Self {
+        self.verifier = Some(verifier);
+        self
+    }
+
+    /// Feed a single line of the log. Returns the record the line completes,
+    /// if any.
+    pub fn push_line(&mut self, line: &[u8]) -> Result<Option<DecodedRecord>, DecodeError> {
+        if let Some(raw) = &mut self.raw {
+            if raw.lines > 0 {
+                raw.data.push(b'\n');
+            }
+            raw.lines += 1;
+            raw.data.extend_from_slice(line);
+            if raw.data.len() < raw.length {
+                return Ok(None);
+            }
+            let mut raw = self.raw.take().expect("is some");
+            if raw.data.len() > raw.length {
+                return Err(DecodeError::RawLength(raw.chunk.invocation_id));
+            }
+            raw.chunk.data = raw.data.into();
+            return self.push_chunk(raw.chunk);
+        }
+        let Ok(record) = serde_json::from_slice::<Record>(line) else {
+            return Ok(None);
+        };
+        if let Some(verifier) = &self.verifier {
+            verifier.verify(line)?;
+        }
+        match record {
+            Record::Call(call) => {
+                let body_follows =
+                    matches!(call.result, ScrapeResultRepr::Success(_)) && call.spooled.is_none();
+                if body_follows || call.overflow.is_some() {
+                    let pending = Pending {
+                        overflow_left: call.overflow.unwrap_or_default(),
+                        call: *call,
+                        chunks: vec![],
+                    };
+                    let id = pending.call.invocation_id;
+                    self.pending.insert(id, pending);
+                    return self.adopt_orphans(id);
+                }
+                self.take_orphans(call.invocation_id);
+                Ok(Some(DecodedRecord {
+                    call: *call,
+                    body: None,
+                }))
+            }
+            Record::Chunk(chunk) if chunk.encoding == ChunkEncoding::Raw => {
+                let RawLength { length } = serde_json::from_slice(line)
+                    .map_err(|_| DecodeError::RawLength(chunk.invocation_id))?;
+                self.raw = Some(RawData {
+                    chunk,
+                    length,
+                    data: Vec::with_capacity(length),
+                    lines: 0,
+                });
+                Ok(None)
+            }
+            Record::Chunk(chunk) => self.push_chunk(chunk),
+            // Overflow records precede the chunks of their call.
+            Record::Overflow(overflow) => {
+                let id = overflow.invocation_id;
+                let Some(pending) = self.pending.get_mut(&id) else {
+                    return Ok(None);
+                };
+                pending.call.derived.extend(overflow.derived);
+                pending.overflow_left = pending.overflow_left.saturating_sub(1);
+                // A spooled body is not part of the log, so the call is
+                // complete with its last overflow record.
+                if pending.overflow_left > 0 || pending.call.spooled.is_none() {
+                    return Ok(None);
+                }
+                let call = self.pending.remove(&id).expect("is pending").call;
+                Ok(Some(DecodedRecord { call, body: None }))
+            }
+            Record::Dictionary(d) => {
+                let pending = (d.dictionary_id, vec![]);
+                self.pending_dictionaries.insert(d.invocation_id, pending);
+                self.adopt_orphans(d.invocation_id)
+            }
+        }
+    }
+
+    fn push_chunk(&mut self, chunk: ChunkRepr) -> Result<Option<DecodedRecord>, DecodeError> {
+        let invocation_id = chunk.invocation_id;
+        let data = Chunk {
+            remaining: chunk.remaining,
+            data: chunk.data.clone(),
+        };
+        // Records of earlier versions do not name the total size, so their
+        // chunks must be in order.
+        let complete = |chunks: &[Chunk]| match chunk.total_size {
+            Some(total) => Chunks::is_complete(chunks, total),
+            None => chunk.remaining == chunk.data.len(),
+        };
+        if let Some((_, chunks)) = self.pending_dictionaries.get_mut(&invocation_id) {
+            chunks.push(data);
+            if complete(chunks) {
+                let (id, chunks) = self.pending_dictionaries.remove(&invocation_id).unwrap();
+                let payload = self.payload(chunks, &chunk)?;
+                let data =
+                    zstd::decode_all(payload.as_slice()).map_err(DecodeError::Decompression)?;
+                self.dictionaries.insert(id, Dictionary::new(data));
+            }
+            return Ok(None);
+        }
+        let Some(pending) = self.pending.get_mut(&invocation_id) else {
+            self.keep_orphan(chunk);
+            return Ok(None);
+        };
+        if pending.chunks.is_empty() {
+            pending.chunks.reserve(chunk.chunk_count.unwrap_or(0));
+        }
+        pending.chunks.push(data);
+        if !complete(&pending.chunks) {
+            return Ok(None);
+        }
+        let Pending { call, chunks, .. } = self.pending.remove(&invocation_id).expect("is pending");
+        let payload = self.payload(chunks, &chunk)?;
+        let target = call.delta.map(|_| target_key(&call.target_config));
+        let body = match (call.delta, &call.dictionary) {
+            (Some(DeltaRepr::Patch { base }), _) => {
+                let (_, body) = self
+                    .bases
+                    .get(target.as_ref().expect("is delta"))
+                    .and_then(|bases| bases.iter().find(|(id, _)| *id == base))
+                    .ok_or(DecodeError::MissingBase(base))?;
+                delta::patch(body, &payload)
+            }
+            (_, Some(id)) => self
+                .dictionaries
+                .get(id)
+                .ok_or_else(|| DecodeError::UnknownDictionary(id.clone()))?
+                .decompress(&payload),
+            (_, None) => zstd::decode_all(payload.as_slice()),
+        }
+        .map_err(DecodeError::Decompression)?;
+        if let Some(target) = target {
+            let bases = self.bases.entry(target).or_default();
+            if bases.len() == MAX_BASES {
+                bases.pop_front();
+            }
+            bases.push_back((invocation_id, body.clone()));
+        }
+        Ok(Some(DecodedRecord {
+            call,
+            body: Some(body),
+        }))
+    }
+
+    /// Keep a chunk until the record it belongs to is read. Chunks of calls
+    /// that are never read, e.g. because the log was rotated in between, are
+    /// dropped eventually.
+    fn keep_orphan(&mut self, chunk: ChunkRepr) {
+        let id = chunk.invocation_id;
+        self.orphan_bytes += chunk.data.len();
+        match self.orphans.get_mut(&id) {
+            Some(chunks) => chunks.push(chunk),
+            None => {
+                self.orphans.insert(id, vec![chunk]);
+                self.orphan_order.push_back(id);
+            }
+        }
+        while self.orphan_bytes > MAX_ORPHAN_BYTES {
+            let Some(oldest) = self.orphan_order.front().copied() else {
+                break;
+            };
+            self.take_orphans(oldest);
+        }
+    }
+
+    fn take_orphans(&mut self, id: Uuid) -> Vec<ChunkRepr> {
+        let Some(chunks) = self.orphans.remove(&id) else {
+            return vec![];
+        };
+        self.orphan_order.retain(|o| *o != id);
+        self.orphan_bytes -= chunks.iter().map(|c| c.data.len()).sum::<usize>();
+        chunks
+    }
+
+    /// Hand the chunks that preceded the record of `id` to it.
+    fn adopt_orphans(&mut self, id: Uuid) -> Result<Option<DecodedRecord>, DecodeError> {
+        let mut decoded = None;
+        for chunk in self.take_orphans(id) {
+            if let Some(r) = self.push_chunk(chunk)? {
+                decoded = Some(r);
+            }
+        }
+        Ok(decoded)
+    }
+
+    /// Reassemble the chunks of a payload, given in any order, and decrypt
+    /// it. The payload is still compressed.
+    fn payload(&self, chunks: Vec<Chunk>, chunk: &ChunkRepr) -> Result<Vec<u8>, DecodeError> {
+        let chunks = Chunks::from_unordered_chunks_with_digest(chunks, chunk.id.algorithm())?;
+        if chunks.id() != chunk.id {
+            return Err(DecodeError::DigestMismatch(chunk.invocation_id));
+        }
+        let mut payload = Vec::with_capacity(chunks.total_size());
+        chunks.reader().read_to_end(&mut payload)?;
+        if let Some(key_id) = &chunk.key_id {
+            let key = self
+                .keys
+                .get(key_id)
+                .ok_or_else(|| DecodeError::UnknownKey(key_id.clone()))?;
+            payload = key.decrypt(&payload)?;
+        }
+        Ok(payload)
+    }
+}
+
+/// Joins the messages of journal entries that journald split.
+#[derive(Default)]
+pub struct JournalReassembler {
+    /// The partial messages, keyed by the stream they were logged to.
+    partial: HashMap<String, Vec<u8>>,
+}
+
+#[derive(Deserialize)]
+struct JournalEntry {
+    #[serde(rename = "MESSAGE")]
+    message: Option<JournalField>,
+    #[serde(rename = "_LINE_BREAK")]
+    line_break: Option<String>,
+    #[serde(rename = "_STREAM_ID")]
+    stream_id: Option<String>,
+    #[serde(rename = "_PID")]
+    pid: Option<String>,
+}
+
+/// journald exports fields that are not valid UTF-8 or contain non-printable
+/// characters as an array of bytes.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JournalField {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl JournalReassembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feed a single line of `journalctl -o json` output. Returns the
+    /// message of the entry, unless it is continued by a following entry.
+    pub fn push_entry(&mut self, entry: &[u8]) -> Result<Option<Vec<u8>>, DecodeError> {
+        let entry: JournalEntry = serde_json::from_slice(entry).map_err(DecodeError::Journal)?;
+        let stream = entry.stream_id.or(entry.pid).unwrap_or_default();
+        let message = match entry.message {
+            Some(JournalField::Text(s)) => s.into_bytes(),
+            Some(JournalField::Bytes(b)) => b,
+            None => {
+                self.partial.remove(&stream);
+                return Err(DecodeError::Truncated);
+            }
+        };
+        let mut message = match self.partial.remove(&stream) {
+            Some(mut partial) => {
+                partial.extend_from_slice(&message);
+                partial
+            }
+            None => message,
+        };
+        if entry.line_break.as_deref() == Some("line-max") {
+            self.partial.insert(stream, std::mem::take(&mut message));
+            return Ok(None);
+        }
+        Ok(Some(message))
+    }
+}
+
+/// Reads the records from the output of `journalctl -o json --all`.
+pub struct JournalReader<R> {
+    lines: io::Split<R>,
+    journal: JournalReassembler,
+    decoder: Decoder,
+}
+
+impl<R: BufRead> JournalReader<R> {
+    pub fn new(reader: R, decoder: Decoder) -> Self {
+        Self {
+            lines: reader.split(b'\n'),
+            journal: JournalReassembler::new(),
+            decoder,
+        }
+    }
+
+    fn next_record(
+        &mut self,
+        line: io::Result<Vec<u8>>,
+    ) -> Result<Option<DecodedRecord>, DecodeError> {
+        let Some(message) = self.journal.push_entry(&line?)? else {
+            return Ok(None);
+        };
+        self.decoder.push_line(&message)
+    }
+}
+
+impl<R: BufRead> Iterator for JournalReader<R> {
+    type Item = Result<DecodedRecord, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = self.lines.next()?;
+            if let Some(r) = self.next_record(line).transpose() {
+                return Some(r);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex,
+        },
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use serde_json::json;
+    use tokio::io::AsyncWrite;
+
+    use super::*;
+    use crate::{
+        config::{Action, ScrapeTargetBuilder},
+        delta::DeltaEncoding,
+        dictionary::Dictionaries,
+        result_processor::{LogOutputWriter, ScrapeResultProcessor},
+        scrape_target::{ScrapeErr, ScrapeOk},
+    };
+
+    #[tokio::test]
+    async fn records_are_reassembled_from_the_journal() {
+        let config = ScrapeTargetBuilder::new()
+            .name("t")
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let key = PayloadKey::new("k1", [7; 32]);
+        let log = SharedBuf::default();
+        let w = LogOutputWriter::new(log.clone()).with_encryption(key.clone());
+        // Random data does not compress, so the body spans several chunks.
+        let data: Vec<u32> = (0..4000u32).map(|i| i.wrapping_mul(2654435761)).collect();
+        let body = serde_json::to_vec(&json!({ "data": data })).unwrap();
+        let ok = ScrapeOk::Structured(serde_json::from_slice(&body).unwrap());
+        w.process(&config, Ok(ok)).await.unwrap();
+        w.process(&config, Err(ScrapeErr::Cancelled)).await.unwrap();
+        let log = log.0.lock().unwrap().clone();
+
+        // Export the log the way journald would, splitting long lines and
+        // storing one of them as bytes.
+        let mut journal = Vec::new();
+        let mut entry = |message: serde_json::Value, line_break: Option<&str>| {
+            let mut e = json!({ "MESSAGE": message, "_STREAM_ID": "s", "_PID": "1" });
+            if let Some(lb) = line_break {
+                e["_LINE_BREAK"] = json!(lb);
+            }
+            serde_json::to_writer(&mut journal, &e).unwrap();
+            journal.push(b'\n');
+        };
+        entry(json!("some unrelated line"), None);
+        for (i, line) in log
+            .split(|b| *b == b'\n')
+            .filter(|l| !l.is_empty())
+            .enumerate()
+        {
+            let line = std::str::from_utf8(line).unwrap();
+            let (head, tail) = line.split_at(line.len() / 2);
+            if i == 1 {
+                entry(json!(head.as_bytes()), Some("line-max"));
+            } else {
+                entry(json!(head), Some("line-max"));
+            }
+            entry(json!(tail), None);
+        }
+
+        let records: Vec<_> = JournalReader::new(journal.as_slice(), Decoder::new().with_key(key))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(2, records.len());
+        assert_eq!(Some(body), records[0].body);
+        assert_eq!(Some("t"), records[0].call.target_config.name.as_deref());
+        assert_eq!(1, records[1].call.sequence);
+        assert!(records[1].body.is_none());
+    }
+
+    #[tokio::test]
+    async fn chunk_encodings_are_decoded() {
+        let config = ScrapeTargetBuilder::new()
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let data: Vec<u32> = (0..4000u32).map(|i| i.wrapping_mul(2654435761)).collect();
+        let body = serde_json::to_vec(&json!({ "data": data })).unwrap();
+        for encoding in [
+            ChunkEncoding::Base85,
+            ChunkEncoding::Hex,
+            ChunkEncoding::Raw,
+        ] {
+            let log = SharedBuf::default();
+            let w = LogOutputWriter::new(log.clone()).with_chunk_encoding(encoding);
+            let ok = ScrapeOk::Structured(serde_json::from_slice(&body).unwrap());
+            w.process(&config, Ok(ok)).await.unwrap();
+            let log = log.0.lock().unwrap().clone();
+
+            let mut decoder = Decoder::new();
+            let records: Vec<_> = log
+                .split(|b| *b == b'\n')
+                .filter_map(|l| decoder.push_line(l).unwrap())
+                .collect();
+            assert_eq!(1, records.len(), "{encoding:?}");
+            assert_eq!(Some(&body), records[0].body.as_ref(), "{encoding:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn chunks_may_arrive_out_of_order() {
+        let config = ScrapeTargetBuilder::new()
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let data: Vec<u32> = (0..4000u32).map(|i| i.wrapping_mul(2654435761)).collect();
+        let body = serde_json::to_vec(&json!({ "data": data })).unwrap();
+        let log = SharedBuf::default();
+        let w = LogOutputWriter::new(log.clone());
+        let ok = ScrapeOk::Structured(serde_json::from_slice(&body).unwrap());
+        w.process(&config, Ok(ok)).await.unwrap();
+        let log = log.0.lock().unwrap().clone();
+
+        let mut lines: Vec<_> = log
+            .split(|b| *b == b'\n')
+            .filter(|l| !l.is_empty())
+            .collect();
+        assert!(lines.len() > 3);
+        // The call record comes first, then its chunks, last chunk first.
+        lines[1..].reverse();
+        lines.push(lines[2]);
+        let mut decoder = Decoder::new();
+        let records: Vec<_> = lines
+            .into_iter()
+            .filter_map(|l| decoder.push_line(l).unwrap())
+            .collect();
+        assert_eq!(1, records.len());
+        assert_eq!(Some(&body), records[0].body.as_ref());
+    }
+
+    #[tokio::test]
+    async fn chunks_may_precede_their_call() {
+        let config = ScrapeTargetBuilder::new()
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let log = SharedBuf::default();
+        let w = LogOutputWriter::new(log.clone());
+        let mut calls = vec![];
+        for i in 0..2 {
+            let ok = ScrapeOk::Structured(json!({ "call": i }));
+            w.process(&config, Ok(ok)).await.unwrap();
+            let mut log = std::mem::take(&mut *log.0.lock().unwrap());
+            // The chunks of the call first, then its call record.
+            log.pop();
+            let mut lines: Vec<_> = log.split(|b| *b == b'\n').map(<[u8]>::to_vec).collect();
+            assert!(lines.len() > 1);
+            lines.reverse();
+            calls.push(lines);
+        }
+        let lines: Vec<_> = calls.concat();
+        let mut decoder = Decoder::new();
+        let bodies: Vec<_> = lines
+            .into_iter()
+            .filter_map(|l| decoder.push_line(&l).unwrap())
+            .map(|r| r.body.unwrap())
+            .collect();
+        assert_eq!(
+            vec![b"{\"call\":0}".to_vec(), b"{\"call\":1}".to_vec()],
+            bodies
+        );
+    }
+
+    #[tokio::test]
+    async fn bodies_compressed_with_dictionaries_are_decoded() {
+        let config = ScrapeTargetBuilder::new()
+            .name("metrics")
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let key = PayloadKey::new("k1", [7; 32]);
+        let log = SharedBuf::default();
+        let dictionaries = Dictionaries::new().samples(8).retrain_after(4);
+        let w = LogOutputWriter::new(log.clone())
+            .with_encryption(key.clone())
+            .with_dictionaries(dictionaries);
+        let bodies: Vec<_> = (0..14)
+            .map(|i| {
+                let metrics: serde_json::Map<_, _> = (0..50)
+                    .map(|m| {
+                        (
+                            format!("requests_{m}{{code=\"200\"}}"),
+                            json!(i * 31 + m * 7),
+                        )
+                    })
+                    .collect();
+                serde_json::to_string(&metrics).unwrap()
+            })
+            .collect();
+        for body in &bodies {
+            let ok = ScrapeOk::Structured(serde_json::from_str(body).unwrap());
+            w.process(&config, Ok(ok)).await.unwrap();
+        }
+        let log = log.0.lock().unwrap().clone();
+
+        let mut decoder = Decoder::new().with_key(key);
+        let records: Vec<_> = log
+            .split(|b| *b == b'\n')
+            .filter_map(|l| decoder.push_line(l).unwrap())
+            .collect();
+        let decoded: Vec<_> = records
+            .iter()
+            .map(|r| String::from_utf8(r.body.clone().unwrap()).unwrap())
+            .collect();
+        assert_eq!(bodies, decoded);
+        // Trained after 8 bodies and again after another 4.
+        let ids: Vec<_> = records.iter().map(|r| r.call.dictionary.clone()).collect();
+        assert!(ids[..8].iter().all(Option::is_none), "{ids:?}");
+        assert!(ids[8..].iter().all(Option::is_some), "{ids:?}");
+        assert_ne!(ids[8], ids[13]);
+        let announced = log
+            .split(|b| *b == b'\n')
+            .filter(|l| serde_json::from_slice::<DictionaryRepr>(l).is_ok())
+            .count();
+        assert_eq!(2, announced);
+    }
+
+    #[tokio::test]
+    async fn dictionaries_are_announced_again() {
+        let config = ScrapeTargetBuilder::new()
+            .name("metrics")
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let log = SharedBuf::default();
+        let dictionaries = Dictionaries::new().samples(8).reannounce_after(3);
+        let w = LogOutputWriter::new(log.clone()).with_dictionaries(dictionaries);
+        let bodies: Vec<_> = (0..14)
+            .map(|i| {
+                let metrics: serde_json::Map<_, _> = (0..50)
+                    .map(|m| (format!("requests_{m}"), json!(i * 31 + m * 7)))
+                    .collect();
+                serde_json::to_string(&metrics).unwrap()
+            })
+            .collect();
+        for body in &bodies {
+            let ok = ScrapeOk::Structured(serde_json::from_str(body).unwrap());
+            w.process(&config, Ok(ok)).await.unwrap();
+        }
+        let log = log.0.lock().unwrap().clone();
+
+        // Trained after 8 bodies, written along with the 9th and 12th.
+        let lines: Vec<_> = log.split(|b| *b == b'\n').collect();
+        let announcements: Vec<_> = (0..lines.len())
+            .filter(|i| serde_json::from_slice::<DictionaryRepr>(lines[*i]).is_ok())
+            .collect();
+        assert_eq!(2, announcements.len());
+        // A rotated log starts with the second announcement.
+        let mut decoder = Decoder::new();
+        let decoded: Vec<_> = lines[announcements[1]..]
+            .iter()
+            .filter_map(|l| decoder.push_line(l).unwrap())
+            .map(|r| String::from_utf8(r.body.unwrap()).unwrap())
+            .collect();
+        assert_eq!(bodies[11..], decoded);
+    }
+
+    #[tokio::test]
+    async fn deltas_are_decoded() {
+        let config = ScrapeTargetBuilder::new()
+            .name("stats")
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let log = SharedBuf::default();
+        let w = LogOutputWriter::new(log.clone())
+            .with_delta_encoding(DeltaEncoding::new().snapshot_every(3));
+        let data: Vec<u32> = (0..4000u32).map(|i| i.wrapping_mul(2654435761)).collect();
+        let mut bodies = vec![];
+        for i in 0..5 {
+            let mut data = data.clone();
+            data[i * 10] = 0;
+            let body = json!({ "data": data });
+            bodies.push(serde_json::to_vec(&body).unwrap());
+            w.process(&config, Ok(ScrapeOk::Structured(body)))
+                .await
+                .unwrap();
+        }
+        let log = log.0.lock().unwrap().clone();
+
+        let lines: Vec<_> = log.split(|b| *b == b'\n').collect();
+        let mut decoder = Decoder::new();
+        let records: Vec<_> = lines
+            .iter()
+            .filter_map(|l| decoder.push_line(l).unwrap())
+            .collect();
+        assert_eq!(bodies.len(), records.len());
+        for (r, body) in records.iter().zip(&bodies) {
+            assert_eq!(Some(body), r.body.as_ref());
+        }
+        let kinds: Vec<_> = records.iter().map(|r| r.call.delta).collect();
+        assert_eq!(Some(DeltaRepr::Snapshot), kinds[0]);
+        assert_eq!(
+            Some(DeltaRepr::Patch {
+                base: records[0].call.invocation_id
+            }),
+            kinds[1]
+        );
+        assert_eq!(Some(DeltaRepr::Snapshot), kinds[3]);
+        // Random data does not compress, but deltas of it do.
+        let lines_of = |r: &DecodedRecord| {
+            let prefix = format!(r#"{{"invocation_id":"{}""#, r.call.invocation_id);
+            lines
+                .iter()
+                .filter(|l| l.starts_with(prefix.as_bytes()))
+                .count()
+        };
+        assert!(lines_of(&records[0]) > 2);
+        assert_eq!(2, lines_of(&records[1]));
+
+        // Without the snapshot, the deltas up to the next snapshot can't be
+        // decoded.
+        let mut decoder = Decoder::new();
+        let first = records[1].call.invocation_id.to_string();
+        let results: Vec<_> = lines
+            .iter()
+            .skip_while(|l| !std::str::from_utf8(l).unwrap().contains(&first))
+            .filter_map(|l| decoder.push_line(l).transpose())
+            .collect();
+        assert_eq!(4, results.len());
+        assert!(matches!(results[0], Err(DecodeError::MissingBase(_))));
+        assert!(matches!(results[1], Err(DecodeError::MissingBase(_))));
+        assert!(results[2..].iter().all(Result::is_ok));
+    }
+
+    #[tokio::test]
+    async fn deltas_only_refer_to_written_bodies() {
+        let config = ScrapeTargetBuilder::new()
+            .name("stats")
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let log = SharedBuf::default();
+        let w = LogOutputWriter::new(log.clone()).with_delta_encoding(DeltaEncoding::new());
+        let body = |i: u32| ScrapeOk::Structured(json!({ "data": vec![i; 1000] }));
+        w.process(&config, Ok(body(0))).await.unwrap();
+        log.1.store(true, Ordering::Relaxed);
+        assert!(w.process(&config, Ok(body(1))).await.is_err());
+        log.1.store(false, Ordering::Relaxed);
+        // Overlapping calls may refer to the same base.
+        let (a, b) = tokio::join!(
+            w.process(&config, Ok(body(2))),
+            w.process(&config, Ok(body(3)))
+        );
+        a.unwrap();
+        b.unwrap();
+        w.process(&config, Ok(body(4))).await.unwrap();
+        let log = log.0.lock().unwrap().clone();
+
+        let mut decoder = Decoder::new();
+        let records = log
+            .split(|b| *b == b'\n')
+            .filter_map(|l| decoder.push_line(l).transpose())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(4, records.len());
+        assert!(records[1..]
+            .iter()
+            .all(|r| matches!(r.call.delta, Some(DeltaRepr::Patch { .. }))));
+    }
+
+    /// A log that fails to write while its flag is set.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>, Arc<AtomicBool>);
+
+    impl AsyncWrite for SharedBuf {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            if self.1.load(Ordering::Relaxed) {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Encoding bodies as deltas against the previous body of their target.
+//!
+//! Large outputs that change slowly, e.g. a process list or the stats of a
+//! database, make up most of the volume of a log although most of each body
+//! repeats the previous one. With [DeltaEncoding], a
+//! [LogOutputWriter](crate::result_processor::LogOutputWriter) compresses a
+//! body with the previous body of the same target as reference (zstd's
+//! "patch-from"), such that only the changes take up space. Every so often, a
+//! body is written in full as a snapshot instead.
+//!
+//! Decoding a delta requires the bodies since the last snapshot, see
+//! [ScrapeCallRepr::delta](crate::result_processor::ScrapeCallRepr::delta).
+//! The [Decoder](crate::decoder::Decoder) keeps the latest bodies of each
+//! target for that purpose. A body only becomes the base of later ones once
+//! its record was written.
+
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// The largest window zstd decodes by default.
+const MAX_WINDOW_LOG: u32 = 27;
+
+/// Tracks the previous body of each target. Clones share their state. See the
+/// [module docs](self).
+#[derive(Debug, Clone)]
+pub struct DeltaEncoding {
+    snapshot_every: usize,
+    targets: Arc<Mutex<HashMap<String, Target>>>,
+}
+
+#[derive(Debug, Default)]
+struct Target {
+    previous: Option<Base>,
+    /// Deltas written since the last snapshot.
+    deltas: usize,
+}
+
+/// The body a delta refers to.
+#[derive(Debug, Clone)]
+pub(crate) struct Base {
+    /// The call the body belongs to.
+    pub(crate) invocation_id: Uuid,
+    pub(crate) body: Arc<Vec<u8>>,
+}
+
+/// How the body of a call is encoded, if delta encoding is used.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeltaRepr {
+    /// The body is written in full.
+    Snapshot,
+    /// The body is a delta against the body of the call `base`, which is the
+    /// previous call of the target.
+    Patch { base: Uuid },
+}
+
+impl DeltaEncoding {
+    pub fn new() -> Self {
+        Self {
+            snapshot_every: 10,
+            targets: Default::default(),
+        }
+    }
+
+    /// Write every `n`th body of a target in full. Defaults to 10.
+    pub fn snapshot_every(mut self, n: usize) -> Self {
+        self.snapshot_every = n.max(1);
+        self
+    }
+
+    /// The body the next body of `target` is encoded against, or `None` if it
+    /// is due to be a snapshot.
+    pub(crate) fn base(&self, target: &str) -> Option<Base> {
+        let targets = self.targets.lock().unwrap();
+        let t = targets.get(target)?;
+        if t.deltas + 1 >= self.snapshot_every {
+            return None;
+        }
+        t.previous.clone()
+    }
+
+    /// Record the body of a call, which was a delta if `patched`.
+    pub(crate) fn update(&self, target: &str, invocation_id: Uuid, body: &[u8], patched: bool) {
+        let mut targets = self.targets.lock().unwrap();
+        let t = targets.entry(target.to_string()).or_default();
+        t.previous = Some(Base {
+            invocation_id,
+            body: Arc::new(body.to_vec()),
+        });
+        t.deltas = if patched { t.deltas + 1 } else { 0 };
+    }
+}
+
+impl Default for DeltaEncoding {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Compress `data` with `base` as reference.
+pub(crate) fn diff(base: &[u8], data: &[u8], level: i32) -> io::Result<Vec<u8>> {
+    let mut encoder = zstd::Encoder::with_ref_prefix(Vec::new(), level, base)?;
+    // References into the base must not exceed the window.
+    let len = (base.len() + data.len()).max(2) as u64;
+    let window_log = (u64::BITS - (len - 1).leading_zeros()).clamp(10, MAX_WINDOW_LOG);
+    encoder.window_log(window_log)?;
+    encoder.long_distance_matching(true)?;
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+/// Reverse [diff].
+pub(crate) fn patch(base: &[u8], delta: &[u8]) -> io::Result<Vec<u8>> {
+    let mut decoder = zstd::Decoder::with_ref_prefix(delta, base)?;
+    decoder.window_log_max(MAX_WINDOW_LOG)?;
+    let mut data = Vec::new();
+    decoder.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deltas_are_small_and_reversible() {
+        let base: Vec<u8> = (0..100_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        let mut data = base.clone();
+        data[1000..1010].fill(0xff);
+        let delta = diff(&base, &data, 10).unwrap();
+        assert!(delta.len() < 100, "{}", delta.len());
+        assert_eq!(data, patch(&base, &delta).unwrap());
+    }
+
+    #[test]
+    fn snapshots_are_taken_periodically() {
+        let deltas = DeltaEncoding::new().snapshot_every(3);
+        let mut kinds = vec![];
+        for _ in 0..7 {
+            let base = deltas.base("t");
+            kinds.push(base.is_some());
+            deltas.update("t", Uuid::new_v4(), b"body", base.is_some());
+        }
+        assert_eq!(vec![false, true, true, false, true, true, false], kinds);
+    }
+}
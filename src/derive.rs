@@ -0,0 +1,231 @@
+//! Fields derived from scrape output.
+//!
+//! A target may define named [DerivedField]s, each a regular expression whose
+//! first capture group is extracted from the body of a successful scrape,
+//! e.g. `MemAvailable:\s+(\d+) kB` on `/proc/meminfo`. Values that parse as
+//! numbers are scaled and emitted as numbers, all other values are emitted
+//! as strings. If the pattern does not match, the field is omitted.
+//!
+//! The body of a command is its stdout, the body of structured output is its
+//! JSON encoding.
+//!
+//! For targets that return JSON, values can be promoted to the record of the
+//! call instead, see [ScrapeTargetConfig::promote_fields]. A path is a subset
+//! of JSONPath: `$` followed by member names and array indices, e.g.
+//! `$.status.leader` or `$.queues[0].depth`. The value is emitted as a
+//! top-level key named after the path without the leading `$.`, e.g.
+//! `status.leader`. Only strings, numbers, booleans and `null` are promoted.
+//! Paths whose key is a key of the record itself, e.g. `$.result`, are
+//! rejected.
+
+use std::{borrow::Cow, collections::BTreeMap};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    config::{ConfigError, Pattern, ScrapeTargetConfig},
+    result_processor::RESERVED_KEYS,
+    scrape_target::ScrapeOk,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, JsonSchema)]
+pub struct DerivedField {
+    /// The value is the first capture group, or the whole match if there is
+    /// no capture group.
+    #[schemars(with = "String")]
+    pub pattern: Pattern,
+    /// Numeric values are multiplied by this factor, e.g. 1024 to convert
+    /// kB to bytes.
+    #[serde(
+        default,
+        deserialize_with = "crate::config::deserialize_opt_number",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub scale: Option<f64>,
+}
+
+// Equality is total, as a NaN scale is rejected.
+impl Eq for DerivedField {}
+
+impl DerivedField {
+    pub fn new(pattern: Pattern) -> Self {
+        Self {
+            pattern,
+            scale: None,
+        }
+    }
+
+    /// # Panics
+    ///
+    /// If `factor` is NaN.
+    pub fn scale(mut self, factor: f64) -> Self {
+        assert!(!factor.is_nan(), "the scale must be a number");
+        self.scale = Some(factor);
+        self
+    }
+
+    pub fn evaluate(&self, body: &[u8]) -> Option<Value> {
+        let captures = self.pattern.regex().captures(body)?;
+        let m = captures.get(1).or_else(|| captures.get(0))?;
+        let s = String::from_utf8_lossy(m.as_bytes());
+        let s = s.trim();
+        match s.parse::<f64>() {
+            Ok(n) => serde_json::Number::from_f64(n * self.scale.unwrap_or(1.0)).map(Value::Number),
+            Err(_) => Some(Value::String(s.to_string())),
+        }
+    }
+}
+
+/// Evaluate the derived fields of `config` against `ok`.
+pub fn derive_fields(config: &ScrapeTargetConfig, ok: &ScrapeOk) -> BTreeMap<String, Value> {
+    if config.derived.is_empty() {
+        return BTreeMap::new();
+    }
+    let body = body(ok);
+    config
+        .derived
+        .iter()
+        .filter_map(|(name, f)| Some((name.clone(), f.evaluate(&body)?)))
+        .collect()
+}
+
+/// Select the values of the [ScrapeTargetConfig::promote_fields] of `config`
+/// from `ok`, if its body is JSON.
+pub fn promote_fields(config: &ScrapeTargetConfig, ok: &ScrapeOk) -> BTreeMap<String, Value> {
+    if config.promote_fields.is_empty() {
+        return BTreeMap::new();
+    }
+    let parsed;
+    let root = match ok {
+        ScrapeOk::Structured(v) => v,
+        _ => match serde_json::from_slice(&body(ok)) {
+            Ok(v) => {
+                parsed = v;
+                &parsed
+            }
+            Err(_) => return BTreeMap::new(),
+        },
+    };
+    config
+        .promote_fields
+        .iter()
+        .filter_map(|path| {
+            // Invalid paths are rejected when the config is validated.
+            let segments = parse_path(path)?;
+            let v = segments.iter().try_fold(root, |v, s| match s {
+                Segment::Member(name) => v.get(name),
+                Segment::Index(i) => v.get(i),
+            })?;
+            (!v.is_object() && !v.is_array()).then(|| (promoted_key(path).to_string(), v.clone()))
+        })
+        .collect()
+}
+
+/// Check that `path` is valid and that its key does not collide with the keys
+/// of the record of a call.
+pub(crate) fn validate_path(path: &str) -> Result<(), ConfigError> {
+    if parse_path(path).is_none() {
+        return Err(ConfigError::InvalidFieldPath(path.to_string()));
+    }
+    if RESERVED_KEYS.contains(&promoted_key(path)) {
+        return Err(ConfigError::ReservedField(path.to_string()));
+    }
+    Ok(())
+}
+
+/// The key a value is promoted to, e.g. `status.leader` for `$.status.leader`.
+fn promoted_key(path: &str) -> &str {
+    path.strip_prefix("$.").unwrap_or(&path[1..])
+}
+
+enum Segment<'a> {
+    Member(&'a str),
+    Index(usize),
+}
+
+/// Parse a path like `$.a.b[0]`.
+fn parse_path(path: &str) -> Option<Vec<Segment<'_>>> {
+    let mut rest = path.strip_prefix('$')?;
+    let mut segments = Vec::new();
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix('.') {
+            let end = r.find(['.', '[']).unwrap_or(r.len());
+            if end == 0 {
+                return None;
+            }
+            segments.push(Segment::Member(&r[..end]));
+            rest = &r[end..];
+        } else if let Some(r) = rest.strip_prefix('[') {
+            let (index, r) = r.split_once(']')?;
+            segments.push(Segment::Index(index.parse().ok()?));
+            rest = r;
+        } else {
+            return None;
+        }
+    }
+    (!segments.is_empty()).then_some(segments)
+}
+
+/// The body of a successful scrape that derived fields are evaluated against.
+pub(crate) fn body(ok: &ScrapeOk) -> Cow<'_, [u8]> {
+    match ok {
+        ScrapeOk::HttpResponse(r) => Cow::Borrowed(r.body()),
+        ScrapeOk::CommandResponse(o) => Cow::Borrowed(&o.stdout),
+        ScrapeOk::Structured(v) => Cow::Owned(serde_json::to_vec(v).expect("can't fail")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_are_extracted() {
+        let meminfo = b"MemTotal:       16318412 kB\nMemAvailable:    8159206 kB\n";
+        let field = |pattern: &str| DerivedField::new(Pattern::new(pattern).unwrap());
+        let available = field(r"MemAvailable:\s+(\d+) kB").scale(1024.0);
+        assert_eq!(
+            Some(serde_json::json!(8159206.0 * 1024.0)),
+            available.evaluate(meminfo)
+        );
+        assert_eq!(
+            Some(serde_json::json!("MemTotal")),
+            field("^Mem[A-Za-z]+").evaluate(meminfo)
+        );
+        assert_eq!(None, field("Swap").evaluate(meminfo));
+    }
+
+    #[test]
+    fn invalid_fields_are_rejected() {
+        let field = |v| serde_json::from_value::<DerivedField>(v);
+        assert!(field(serde_json::json!({ "pattern": "(unclosed" })).is_err());
+        assert!(field(serde_json::json!({ "pattern": "x", "scale": 2.0 })).is_ok());
+    }
+
+    #[test]
+    fn json_fields_are_promoted() {
+        let config = crate::config::ScrapeTargetBuilder::new()
+            .interval(std::time::Duration::from_secs(1))
+            .action(crate::config::Action::SelfStatus)
+            .promote_field("$.status.leader")
+            .promote_field("$.queues[1].depth")
+            .promote_field("$.status")
+            .promote_field("$.missing")
+            .promote_field("status")
+            .build();
+        let ok = ScrapeOk::Structured(serde_json::json!({
+            "status": { "leader": "node-2" },
+            "queues": [{ "depth": 1 }, { "depth": 7 }]
+        }));
+        let promoted = promote_fields(&config, &ok);
+        assert_eq!(
+            BTreeMap::from([
+                ("queues[1].depth".to_string(), serde_json::json!(7)),
+                ("status.leader".to_string(), serde_json::json!("node-2")),
+            ]),
+            promoted
+        );
+    }
+}
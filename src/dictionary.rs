@@ -0,0 +1,207 @@
+//! Compressing bodies with dictionaries trained per target.
+//!
+//! The bodies of a target are alike across calls, e.g. a `/metrics` page
+//! mostly repeats the names and labels of its metrics. zstd compresses such
+//! bodies much better with a dictionary trained on earlier ones. With
+//! [Dictionaries], a [LogOutputWriter](crate::result_processor::LogOutputWriter)
+//! samples the bodies of each target, trains a dictionary once enough samples
+//! were collected and retrains it periodically, such that it follows changes
+//! of the bodies.
+//!
+//! A dictionary is written to the log as a
+//! [DictionaryRepr](crate::result_processor::DictionaryRepr) record followed by
+//! its chunks, before the first body compressed with it. It is written again
+//! every [Dictionaries::reannounce_after] bodies, such that the bodies
+//! following it can be decoded from a rotated or truncated log. The record of a call
+//! refers to the dictionary by
+//! [ScrapeCallRepr::dictionary](crate::result_processor::ScrapeCallRepr::dictionary).
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+};
+
+use sha2::{Digest, Sha256};
+
+/// Only the start of large bodies is sampled, which is where their
+/// boilerplate usually is.
+const MAX_SAMPLE_LEN: usize = 64 << 10;
+
+/// Trains and keeps the dictionaries of targets. Clones share their
+/// dictionaries. See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct Dictionaries {
+    samples: usize,
+    max_size: usize,
+    retrain_after: usize,
+    pub(crate) reannounce_after: usize,
+    targets: Arc<Mutex<HashMap<String, Target>>>,
+}
+
+#[derive(Debug, Default)]
+struct Target {
+    samples: VecDeque<Vec<u8>>,
+    /// Bodies seen since the last training.
+    seen: usize,
+    trained: bool,
+    current: Option<Arc<Dictionary>>,
+}
+
+/// A trained dictionary.
+#[derive(Debug)]
+pub(crate) struct Dictionary {
+    /// A digest of the dictionary.
+    pub(crate) id: String,
+    pub(crate) data: Vec<u8>,
+}
+
+impl Dictionaries {
+    pub fn new() -> Self {
+        Self {
+            samples: 32,
+            max_size: 16 << 10,
+            retrain_after: 1000,
+            reannounce_after: 100,
+            targets: Default::default(),
+        }
+    }
+
+    /// The number of bodies a dictionary is trained on, the latest ones are
+    /// kept. Defaults to 32.
+    pub fn samples(mut self, n: usize) -> Self {
+        self.samples = n.max(1);
+        self
+    }
+
+    /// The maximum size of a dictionary in bytes. Defaults to 16 KiB.
+    pub fn max_size(mut self, n: usize) -> Self {
+        self.max_size = n;
+        self
+    }
+
+    /// Train a new dictionary after `n` bodies compressed with the current
+    /// one. Defaults to 1000.
+    pub fn retrain_after(mut self, n: usize) -> Self {
+        self.retrain_after = n;
+        self
+    }
+
+    /// Write a dictionary to the log again after `n` bodies compressed with
+    /// it. Defaults to 100.
+    pub fn reannounce_after(mut self, n: usize) -> Self {
+        self.reannounce_after = n.max(1);
+        self
+    }
+
+    /// The dictionary to compress the next body of `target` with, if one was
+    /// trained.
+    pub(crate) fn current(&self, target: &str) -> Option<Arc<Dictionary>> {
+        let targets = self.targets.lock().unwrap();
+        targets.get(target)?.current.clone()
+    }
+
+    /// Record a body of `target` and train a new dictionary if one is due.
+    /// Blocks while training.
+    pub(crate) fn sample(&self, target: &str, body: &[u8]) {
+        let samples = {
+            let mut targets = self.targets.lock().unwrap();
+            let t = targets.entry(target.to_string()).or_default();
+            t.samples
+                .push_back(body[..body.len().min(MAX_SAMPLE_LEN)].to_vec());
+            if t.samples.len() > self.samples {
+                t.samples.pop_front();
+            }
+            t.seen += 1;
+            let due = !t.trained || t.seen >= self.retrain_after;
+            if t.samples.len() < self.samples || !due {
+                return;
+            }
+            t.trained = true;
+            t.seen = 0;
+            t.samples.iter().cloned().collect::<Vec<_>>()
+        };
+        // Training fails e.g. if the bodies are too small. It is retried once
+        // `retrain_after` more bodies were seen.
+        match zstd::dict::from_samples(&samples, self.max_size) {
+            Ok(data) => {
+                let dictionary = Dictionary::new(data);
+                tracing::debug!(target, id = dictionary.id, "Trained dictionary");
+                let mut targets = self.targets.lock().unwrap();
+                if let Some(t) = targets.get_mut(target) {
+                    t.current = Some(Arc::new(dictionary));
+                }
+            }
+            Err(e) => tracing::debug!(target, error = %e, "Could not train dictionary"),
+        }
+    }
+}
+
+impl Default for Dictionaries {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Dictionary {
+    pub(crate) fn new(data: Vec<u8>) -> Self {
+        let id = hex::encode(&Sha256::digest(&data)[..8]);
+        Self { id, data }
+    }
+
+    pub(crate) fn compress(&self, data: &[u8], level: i32) -> io::Result<Vec<u8>> {
+        let mut encoder = zstd::Encoder::with_dictionary(Vec::new(), level, &self.data)?;
+        encoder.write_all(data)?;
+        encoder.finish()
+    }
+
+    pub(crate) fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decoder = zstd::Decoder::with_dictionary(data, &self.data)?;
+        let mut body = Vec::new();
+        decoder.read_to_end(&mut body)?;
+        Ok(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A metrics page whose values change with `i`.
+    fn metrics(i: usize) -> Vec<u8> {
+        (0..50)
+            .map(|m| {
+                format!(
+                    "# HELP requests_{m} The number of requests.\n\
+                     # TYPE requests_{m} counter\n\
+                     requests_{m}{{method=\"GET\",code=\"200\"}} {}\n",
+                    i * 31 + m * 7
+                )
+            })
+            .collect::<String>()
+            .into_bytes()
+    }
+
+    #[test]
+    fn dictionaries_are_trained_and_improve_compression() {
+        let dictionaries = Dictionaries::new().samples(8).retrain_after(4);
+        for i in 0..7 {
+            dictionaries.sample("t", &metrics(i));
+        }
+        assert!(dictionaries.current("t").is_none());
+        dictionaries.sample("t", &metrics(7));
+        let first = dictionaries.current("t").unwrap();
+        assert!(dictionaries.current("other").is_none());
+
+        let body = metrics(100);
+        let plain = zstd::encode_all(body.as_slice(), 10).unwrap();
+        let compressed = first.compress(&body, 10).unwrap();
+        assert!(compressed.len() * 3 < plain.len() * 2);
+        assert_eq!(body, first.decompress(&compressed).unwrap());
+
+        for i in 8..12 {
+            dictionaries.sample("t", &metrics(i));
+        }
+        assert_ne!(first.id, dictionaries.current("t").unwrap().id);
+    }
+}
@@ -0,0 +1,529 @@
+//! Discovering targets from files, HTTP endpoints, Kubernetes and DNS.
+//!
+//! Like the `file_sd` and `http_sd` mechanisms of Prometheus, a [Discovery]
+//! periodically reads the targets from [DiscoverySource]s, e.g. a file that is
+//! maintained by a configuration management system, or an endpoint of an
+//! inventory service. Sources have the format of a config file, see
+//! [Config::load]; only their targets and variables are used. The variables
+//! of a source apply to its targets, in addition to the ones of the builder,
+//! see [DebugBunnyBuilder::start_config]. With the `yaml` feature, files
+//! ending in `.yaml` or `.yml` are read as YAML. With the `kubernetes`
+//! feature, HTTP targets can also be generated from pods and services, see
+//! [crate::kubernetes]. With the `dns-sd` feature, a template target can be
+//! instantiated for each SRV record of a name, see [crate::srv].
+//!
+//! Whenever the discovered targets change, the targets that were added are
+//! added to the running instance and the ones that were removed are removed
+//! from it, see [DebugBunny::add_config] and [DebugBunny::remove_target], such
+//! that the running targets always match the sources. Targets that did not
+//! change keep running, along with their schedule, counters and circuit
+//! breakers. If a source cannot be read, the targets it yielded last are kept.
+//! Static targets run regardless of the sources.
+//!
+//! The static and the discovered targets run in a single [DebugBunny]
+//! instance, so the limits of the builder, e.g.
+//! [DebugBunnyBuilder::max_scrapes_per_second] and
+//! [DebugBunnyBuilder::max_in_flight_bytes], and its watchdog apply to all of
+//! them together. The banner is written once, with the static targets, see
+//! [crate::banner]. The first calls of discovered targets that are added
+//! together are staggered, see [ScrapeTargetConfig::synchronized].
+
+#[cfg(feature = "kubernetes")]
+use std::io;
+use std::{path::PathBuf, time::Duration};
+
+use tokio::{sync::watch, task::JoinHandle};
+use url::Url;
+
+use crate::{
+    config::{Config, ConfigError, ScrapeTargetConfig},
+    debugbunny::{DebugBunny, DebugBunnyBuilder, StartError, TargetId},
+    result_processor::ScrapeResultProcessor,
+    template::Variables,
+};
+
+/// A discovered target along with the variables of its source.
+type Discovered = (ScrapeTargetConfig, Variables);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiscoverySource {
+    /// A config file, which is read again on every refresh.
+    File(PathBuf),
+    /// An endpoint that responds with a config as JSON to a `GET` request.
+    Http(Url),
+    /// Pods or services listed via the Kubernetes API.
+    #[cfg(feature = "kubernetes")]
+    Kubernetes(crate::kubernetes::KubernetesSd),
+    /// A template instantiated for each SRV record of a name.
+    #[cfg(feature = "dns-sd")]
+    Srv(crate::srv::SrvSd),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DiscoveryError {
+    #[error("Could not load the targets")]
+    Config(#[from] ConfigError),
+    #[error("Could not fetch {0}")]
+    Http(Url, #[source] reqwest::Error),
+    #[error("Could not parse the targets of {0}")]
+    Parse(Url, #[source] serde_json::Error),
+    #[cfg(feature = "kubernetes")]
+    #[error("Could not read {0}")]
+    Io(PathBuf, #[source] io::Error),
+    #[cfg(feature = "kubernetes")]
+    #[error("Not running in a Kubernetes cluster")]
+    NotInCluster,
+    #[cfg(feature = "dns-sd")]
+    #[error("Could not look up the SRV records of {0}")]
+    Srv(String, #[source] hickory_resolver::error::ResolveError),
+}
+
+/// Reads targets from the sources. See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct Discovery {
+    sources: Vec<DiscoverySource>,
+    static_targets: Vec<ScrapeTargetConfig>,
+    refresh: Duration,
+    client: reqwest::Client,
+}
+
+/// Runs the discovered targets, see [Discovery::start].
+pub struct DiscoveryHandle {
+    stop: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl Discovery {
+    /// Read the sources every `refresh`.
+    pub fn new(refresh: Duration) -> Self {
+        Self {
+            sources: vec![],
+            static_targets: vec![],
+            refresh,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.sources.push(DiscoverySource::File(path.into()));
+        self
+    }
+
+    pub fn http(mut self, url: Url) -> Self {
+        self.sources.push(DiscoverySource::Http(url));
+        self
+    }
+
+    #[cfg(feature = "kubernetes")]
+    pub fn kubernetes(mut self, sd: crate::kubernetes::KubernetesSd) -> Self {
+        self.sources.push(DiscoverySource::Kubernetes(sd));
+        self
+    }
+
+    #[cfg(feature = "dns-sd")]
+    pub fn srv(mut self, sd: crate::srv::SrvSd) -> Self {
+        self.sources.push(DiscoverySource::Srv(sd));
+        self
+    }
+
+    /// Targets that are run in addition to the discovered ones.
+    pub fn static_targets(mut self, targets: Vec<ScrapeTargetConfig>) -> Self {
+        self.static_targets = targets;
+        self
+    }
+
+    /// The client used to fetch HTTP sources.
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// Read all sources once. Returns the static targets followed by the
+    /// targets of the sources in order.
+    pub async fn discover(&self) -> Result<Vec<ScrapeTargetConfig>, DiscoveryError> {
+        let mut targets = self.static_targets.clone();
+        for source in &self.sources {
+            targets.extend(self.discover_source(source).await?.scrape_targets);
+        }
+        Ok(targets)
+    }
+
+    async fn discover_source(&self, source: &DiscoverySource) -> Result<Config, DiscoveryError> {
+        let config = match source {
+            DiscoverySource::File(path) => {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || Config::load(path))
+                    .await
+                    .expect("loading panicked")?
+            }
+            DiscoverySource::Http(url) => {
+                let fetch = async {
+                    self.client
+                        .get(url.clone())
+                        .send()
+                        .await?
+                        .error_for_status()?
+                        .bytes()
+                        .await
+                };
+                let body = fetch
+                    .await
+                    .map_err(|e| DiscoveryError::Http(url.clone(), e))?;
+                serde_json::from_slice(&body).map_err(|e| DiscoveryError::Parse(url.clone(), e))?
+            }
+            #[cfg(feature = "kubernetes")]
+            DiscoverySource::Kubernetes(sd) => Config {
+                scrape_targets: sd.discover().await?,
+                ..Config::default()
+            },
+            #[cfg(feature = "dns-sd")]
+            DiscoverySource::Srv(sd) => Config {
+                scrape_targets: sd.discover().await?,
+                ..Config::default()
+            },
+        };
+        Ok(config)
+    }
+
+    /// Start the static targets with `builder` and run them along with the
+    /// discovered targets until [DiscoveryHandle::stop] is called. All targets
+    /// run in the same [DebugBunny] instance, see the [module docs](self).
+    ///
+    /// Fails like [DebugBunnyBuilder::start_scraping] if the static targets
+    /// cannot be started.
+    pub async fn start<P>(
+        self,
+        builder: DebugBunnyBuilder,
+        p: P,
+    ) -> Result<DiscoveryHandle, StartError>
+    where
+        P: ScrapeResultProcessor + 'static,
+    {
+        let mut debugbunny = builder
+            .start_scraping(self.static_targets.clone(), p)
+            .await?;
+        let (stop, mut stopped) = watch::channel(false);
+        let task = tokio::spawn(async move {
+            // The config of each source as of its last successful read.
+            let mut discovered = vec![Config::default(); self.sources.len()];
+            let mut running: Vec<(Discovered, TargetId)> = Vec::new();
+            loop {
+                for (source, config) in self.sources.iter().zip(&mut discovered) {
+                    match self.discover_source(source).await {
+                        Ok(c) => *config = c,
+                        Err(e) => tracing::warn!(error = %e, ?source, "could not discover targets"),
+                    }
+                }
+                let targets = discovered
+                    .iter()
+                    .flat_map(|c| {
+                        let targets = c.scrape_targets.iter().cloned();
+                        targets.map(|t| (t, c.variables.clone()))
+                    })
+                    .collect();
+                running = reconcile(&mut debugbunny, running, targets).await;
+                tokio::select! {
+                    _ = tokio::time::sleep(self.refresh) => {}
+                    _ = stopped.wait_for(|s| *s) => break,
+                }
+            }
+            debugbunny.stop();
+            debugbunny.await_shutdown().await;
+        });
+        Ok(DiscoveryHandle { stop, task })
+    }
+}
+
+/// Remove the running targets that are not in `targets` and add the ones that
+/// are not running yet. A target whose variables changed is restarted.
+/// Returns the running targets.
+async fn reconcile(
+    debugbunny: &mut DebugBunny,
+    mut stale: Vec<(Discovered, TargetId)>,
+    targets: Vec<Discovered>,
+) -> Vec<(Discovered, TargetId)> {
+    let mut running = Vec::with_capacity(targets.len());
+    let mut added: Vec<Discovered> = Vec::new();
+    for t in targets {
+        match stale.iter().position(|(c, _)| *c == t) {
+            Some(i) => running.push(stale.swap_remove(i)),
+            None => added.push(t),
+        }
+    }
+    if !added.is_empty() || !stale.is_empty() {
+        tracing::info!(
+            added = added.len(),
+            removed = stale.len(),
+            "discovered targets changed"
+        );
+    }
+    for (_, id) in stale {
+        debugbunny.remove_target(id).await;
+    }
+    // Targets with the same variables are added together, such that their
+    // first calls are staggered.
+    while let Some((_, variables)) = added.first() {
+        let variables = variables.clone();
+        let (batch, rest): (Vec<_>, Vec<_>) = added.into_iter().partition(|(_, v)| *v == variables);
+        added = rest;
+        let config = Config {
+            scrape_targets: batch.iter().map(|(c, _)| c.clone()).collect(),
+            variables: variables.clone(),
+            ..Config::default()
+        };
+        match debugbunny.add_config(config).await {
+            Ok(ids) => running.extend(batch.into_iter().zip(ids)),
+            // An invalid target must not keep the others from running.
+            Err(_) => {
+                for t in batch {
+                    let config = Config {
+                        scrape_targets: vec![t.0.clone()],
+                        variables: t.1.clone(),
+                        ..Config::default()
+                    };
+                    match debugbunny.add_config(config).await {
+                        Ok(ids) => running.extend(ids.into_iter().map(|id| (t.clone(), id))),
+                        Err(e) => tracing::error!(error = %e, "could not start target"),
+                    }
+                }
+            }
+        }
+    }
+    running
+}
+
+impl DiscoveryHandle {
+    /// Stop the running targets and discovering new ones.
+    pub fn stop(&self) {
+        let _ = self.stop.send(true);
+    }
+
+    /// Wait for the targets to stop, see [Self::stop], then shut down the
+    /// processor and the sinks of the builder, see
+    /// [DebugBunny::await_shutdown].
+    pub async fn await_shutdown(self) {
+        if let Err(e) = self.task.await {
+            tracing::error!(error = %e, "discovery task panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use httptest::{matchers::*, responders::*, Expectation, Server};
+
+    use super::*;
+    use crate::scrape_target::{ScrapeOk, ScrapeResult};
+
+    /// Records the names of the targets of the successful results it sees.
+    #[derive(Clone, Default)]
+    struct Names(Arc<Mutex<Vec<String>>>);
+
+    impl ScrapeResultProcessor for Names {
+        async fn process(
+            &self,
+            config: &ScrapeTargetConfig,
+            result: ScrapeResult<ScrapeOk>,
+        ) -> io::Result<()> {
+            if result.is_ok() {
+                let name = config.name.clone().unwrap_or_default();
+                self.0.lock().unwrap().push(name);
+            }
+            Ok(())
+        }
+    }
+
+    fn targets(names: &[&str]) -> serde_json::Value {
+        let targets: Vec<_> = names
+            .iter()
+            .map(|n| serde_json::json!({ "name": n, "interval": 3600, "action": { "type": "SelfStatus" } }))
+            .collect();
+        serde_json::json!({ "scrape_targets": targets })
+    }
+
+    #[tokio::test]
+    async fn targets_follow_the_file() {
+        let path =
+            std::env::temp_dir().join(format!("debugbunny-discovery-{}.json", std::process::id()));
+        std::fs::write(&path, targets(&["a"]).to_string()).unwrap();
+        let names = Names::default();
+        let handle = Discovery::new(Duration::from_millis(100))
+            .file(&path)
+            .start(DebugBunny::builder(), names.clone())
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        std::fs::write(&path, targets(&["b"]).to_string()).unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        handle.stop();
+        handle.await_shutdown().await;
+        std::fs::remove_file(&path).unwrap();
+
+        let names = names.0.lock().unwrap();
+        assert_eq!(Some("a"), names.first().map(String::as_str));
+        assert_eq!(Some("b"), names.last().map(String::as_str));
+    }
+
+    #[tokio::test]
+    async fn unchanged_targets_keep_running() {
+        let path = std::env::temp_dir().join(format!(
+            "debugbunny-discovery-unchanged-{}.json",
+            std::process::id()
+        ));
+        std::fs::write(&path, targets(&["a"]).to_string()).unwrap();
+        let names = Names::default();
+        let handle = Discovery::new(Duration::from_millis(100))
+            .file(&path)
+            .file("/nonexistent/targets.json")
+            .static_targets(
+                serde_json::from_value::<Config>(targets(&["s"]))
+                    .unwrap()
+                    .scrape_targets,
+            )
+            .start(DebugBunny::builder(), names.clone())
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        std::fs::write(&path, targets(&["a", "b"]).to_string()).unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        handle.stop();
+        handle.await_shutdown().await;
+        std::fs::remove_file(&path).unwrap();
+
+        // Each target was called once, at its start, although one of the
+        // sources cannot be read.
+        let mut names = names.0.lock().unwrap().clone();
+        names.sort();
+        assert_eq!(vec!["a", "b", "s"], names);
+    }
+
+    /// Records the statuses reported by SelfStatus targets.
+    #[derive(Clone, Default)]
+    struct Statuses(Arc<Mutex<Vec<serde_json::Value>>>);
+
+    impl ScrapeResultProcessor for Statuses {
+        async fn process(
+            &self,
+            _config: &ScrapeTargetConfig,
+            result: ScrapeResult<ScrapeOk>,
+        ) -> io::Result<()> {
+            if let Ok(ScrapeOk::Structured(v)) = result {
+                self.0.lock().unwrap().push(v);
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn discovered_targets_share_the_instance_of_the_static_ones() {
+        let path = std::env::temp_dir().join(format!(
+            "debugbunny-discovery-shared-{}.json",
+            std::process::id()
+        ));
+        let config = serde_json::json!({
+            "scrape_targets": [{
+                "name": "status",
+                "interval": 3600,
+                "synchronized": true,
+                "action": { "type": "SelfStatus" },
+            }],
+        });
+        std::fs::write(&path, config.to_string()).unwrap();
+        let statuses = Statuses::default();
+        let handle = Discovery::new(Duration::from_secs(3600))
+            .file(&path)
+            .static_targets(
+                serde_json::from_value::<Config>(targets(&["s"]))
+                    .unwrap()
+                    .scrape_targets,
+            )
+            .start(DebugBunny::builder(), statuses.clone())
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        handle.stop();
+        handle.await_shutdown().await;
+        std::fs::remove_file(&path).unwrap();
+
+        // The discovered target reports the static one, so both run in the
+        // same instance.
+        let statuses = statuses.0.lock().unwrap();
+        let last = statuses.last().unwrap();
+        let names: Vec<_> = last["targets"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| t["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(vec!["s", "status"], names);
+    }
+
+    #[tokio::test]
+    async fn variables_of_sources_apply_to_their_targets() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/status"))
+                .respond_with(status_code(200)),
+        );
+        let path = std::env::temp_dir().join(format!(
+            "debugbunny-discovery-variables-{}.json",
+            std::process::id()
+        ));
+        let config = serde_json::json!({
+            "scrape_targets": [{
+                "name": "templated",
+                "interval": 3600,
+                "action": { "type": "Http", "url": format!("http://{}/{{page}}", server.addr()) },
+            }],
+            "variables": { "page": "status" },
+        });
+        std::fs::write(&path, config.to_string()).unwrap();
+        let names = Names::default();
+        let handle = Discovery::new(Duration::from_secs(3600))
+            .file(&path)
+            .start(DebugBunny::builder(), names.clone())
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        handle.stop();
+        handle.await_shutdown().await;
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(vec!["templated"], *names.0.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn targets_are_fetched_from_endpoints() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/targets"))
+                .respond_with(json_encoded(targets(&["x", "y"]))),
+        );
+        let url = Url::parse(&server.url("/targets").to_string()).unwrap();
+        let discovery = Discovery::new(Duration::from_secs(1))
+            .http(url)
+            .static_targets(
+                serde_json::from_value::<Config>(targets(&["s"]))
+                    .unwrap()
+                    .scrape_targets,
+            );
+
+        let names: Vec<_> = discovery
+            .discover()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|t| t.name.unwrap())
+            .collect();
+        assert_eq!(vec!["s", "x", "y"], names);
+    }
+}
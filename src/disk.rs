@@ -0,0 +1,106 @@
+//! Disk usage of file systems.
+//!
+//! A [DiskUsageCollector] reports the size, usage and inode numbers of the
+//! file systems of the configured mount points as structured output, such that
+//! the output of `df` does not have to be parsed.
+//!
+//! The numbers are queried via `statvfs`, which is only available on Unix.
+
+use std::{io, path::PathBuf};
+
+use serde::Serialize;
+
+use crate::scrape_target::{FutureScrapeResult, ScrapeOk, ScrapeService};
+
+pub struct DiskUsageCollector {
+    mount_points: Vec<PathBuf>,
+}
+
+/// The usage of a single file system. If it could not be queried, only
+/// `error` is set.
+#[derive(Debug, Default, Serialize)]
+pub struct FilesystemUsage {
+    pub mount_point: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub total_bytes: u64,
+    pub used_bytes: u64,
+    /// The bytes available to unprivileged users.
+    pub available_bytes: u64,
+    pub inodes_total: u64,
+    pub inodes_free: u64,
+}
+
+impl DiskUsageCollector {
+    pub fn new(mount_points: Vec<PathBuf>) -> Self {
+        Self { mount_points }
+    }
+}
+
+impl ScrapeService for DiskUsageCollector {
+    type Response = ScrapeOk;
+
+    fn call(&mut self) -> FutureScrapeResult<ScrapeOk> {
+        let mount_points = self.mount_points.clone();
+        Box::pin(async move {
+            // `statvfs` blocks on unresponsive network file systems.
+            let filesystems = tokio::task::spawn_blocking(move || {
+                mount_points
+                    .into_iter()
+                    .map(|m| match usage(&m) {
+                        Ok(u) => u,
+                        Err(e) => FilesystemUsage {
+                            mount_point: m,
+                            error: Some(e.to_string()),
+                            ..Default::default()
+                        },
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .await
+            .map_err(io::Error::other)?;
+            Ok(ScrapeOk::Structured(serde_json::json!({
+                "filesystems": filesystems
+            })))
+        })
+    }
+}
+
+#[cfg(unix)]
+fn usage(mount_point: &std::path::Path) -> io::Result<FilesystemUsage> {
+    let s = rustix::fs::statvfs(mount_point)?;
+    Ok(FilesystemUsage {
+        mount_point: mount_point.to_path_buf(),
+        error: None,
+        total_bytes: s.f_blocks * s.f_frsize,
+        used_bytes: (s.f_blocks - s.f_bfree) * s.f_frsize,
+        available_bytes: s.f_bavail * s.f_frsize,
+        inodes_total: s.f_files,
+        inodes_free: s.f_ffree,
+    })
+}
+
+#[cfg(not(unix))]
+fn usage(_mount_point: &std::path::Path) -> io::Result<FilesystemUsage> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "disk usage is only supported on Unix",
+    ))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn usage_of_root_and_missing_mount_point() {
+        let mut c = DiskUsageCollector::new(vec!["/".into(), "/does/not/exist".into()]);
+        let Ok(ScrapeOk::Structured(v)) = c.call().await else {
+            panic!("expected structured output");
+        };
+        let fs = &v["filesystems"];
+        assert!(fs[0]["total_bytes"].as_u64().unwrap() > 0);
+        assert!(fs[0].get("error").is_none());
+        assert!(fs[1]["error"].is_string());
+    }
+}
@@ -0,0 +1,152 @@
+//! Name resolution for HTTP targets.
+//!
+//! The [CachingResolver] keeps resolved addresses for a configurable TTL. If a
+//! lookup fails after the TTL has expired, the stale entry is served instead.
+//! That way, scrapes keep working during resolver outages, which is exactly
+//! when debugging data is most valuable.
+//!
+//! Further, the resolver can be restricted to a single address family, such
+//! that a target is deliberately scraped via IPv4 or IPv6.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+
+use crate::config::IpFamily;
+
+/// A resolver with optional caching. Without a TTL, nothing is cached.
+#[derive(Clone, Default)]
+pub struct CachingResolver {
+    ttl: Option<Duration>,
+    family: IpFamily,
+    cache: Arc<Mutex<HashMap<String, CacheEntry>>>,
+}
+
+struct CacheEntry {
+    resolved_at: Instant,
+    addrs: Vec<SocketAddr>,
+}
+
+impl CachingResolver {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl: Some(ttl),
+            ..Default::default()
+        }
+    }
+
+    /// Only return addresses of the given family.
+    pub fn with_family(mut self, family: IpFamily) -> Self {
+        self.family = family;
+        self
+    }
+
+    /// Returns the cached addresses for `name` (if any) and whether they are
+    /// still fresh.
+    fn cached(&self, name: &str) -> Option<(Vec<SocketAddr>, bool)> {
+        let ttl = self.ttl?;
+        let cache = self.cache.lock().unwrap();
+        cache
+            .get(name)
+            .map(|e| (e.addrs.clone(), e.resolved_at.elapsed() < ttl))
+    }
+
+    fn addrs(&self, name: &str, addrs: Vec<SocketAddr>) -> Result<Addrs, io::Error> {
+        let addrs: Vec<_> = addrs
+            .into_iter()
+            .filter(|a| match self.family {
+                IpFamily::V4 => a.is_ipv4(),
+                IpFamily::V6 => a.is_ipv6(),
+                IpFamily::Any => true,
+            })
+            .collect();
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No {:?} address found for {name}", self.family),
+            ));
+        }
+        Ok(Box::new(addrs.into_iter()))
+    }
+}
+
+impl Resolve for CachingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let this = self.clone();
+        Box::pin(async move {
+            let name = name.as_str().to_string();
+            let cached = this.cached(&name);
+            if let Some((addrs, true)) = cached {
+                return Ok(this.addrs(&name, addrs)?);
+            }
+
+            // The port is ignored by reqwest, it uses the port of the URL.
+            let lookup = tokio::net::lookup_host(format!("{name}:0")).await;
+            match lookup {
+                Ok(addrs) => {
+                    let addrs: Vec<_> = addrs.collect();
+                    if this.ttl.is_some() {
+                        this.cache.lock().unwrap().insert(
+                            name.clone(),
+                            CacheEntry {
+                                resolved_at: Instant::now(),
+                                addrs: addrs.clone(),
+                            },
+                        );
+                    }
+                    Ok(this.addrs(&name, addrs)?)
+                }
+                Err(e) => match cached {
+                    Some((addrs, _)) => Ok(this.addrs(&name, addrs)?),
+                    None => Err(e.into()),
+                },
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn stale_entries_are_served_if_lookup_fails() {
+        let resolver = CachingResolver::new(Duration::ZERO);
+        // `.invalid` is guaranteed to never resolve (RFC 2606).
+        let name = "debugbunny.invalid";
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        resolver.cache.lock().unwrap().insert(
+            name.to_string(),
+            CacheEntry {
+                resolved_at: Instant::now(),
+                addrs: vec![addr],
+            },
+        );
+
+        let addrs: Vec<_> = resolver
+            .resolve(Name::from_str(name).unwrap())
+            .await
+            .unwrap()
+            .collect();
+        assert_eq!(vec![addr], addrs);
+    }
+
+    #[tokio::test]
+    async fn addresses_are_filtered_by_family() {
+        let resolver = CachingResolver::default().with_family(IpFamily::V6);
+        let v4 = SocketAddr::from(([127, 0, 0, 1], 0));
+        let v6 = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 1], 0));
+
+        let addrs: Vec<_> = resolver.addrs("localhost", vec![v4, v6]).unwrap().collect();
+        assert_eq!(vec![v6], addrs);
+        assert!(resolver.addrs("localhost", vec![v4]).is_err());
+    }
+}
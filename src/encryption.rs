@@ -0,0 +1,97 @@
+//! Encryption of chunk payloads.
+//!
+//! If a [PayloadKey] is configured, the compressed payload of a scrape result
+//! is encrypted with AES-256-GCM before it is chunked. The encrypted payload
+//! consists of the 12-byte nonce followed by the ciphertext. Every chunk
+//! record carries the id of the key, such that decoders can pick the right key
+//! even if keys are rotated.
+
+use std::{fmt::Debug, io, path::Path};
+
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Key, Nonce,
+};
+use thiserror::Error;
+
+const NONCE_LEN: usize = 12;
+
+#[derive(Clone)]
+pub struct PayloadKey {
+    id: String,
+    cipher: Aes256Gcm,
+}
+
+impl Debug for PayloadKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PayloadKey").field("id", &self.id).finish()
+    }
+}
+
+impl PayloadKey {
+    pub fn new<S: ToString>(id: S, key: [u8; 32]) -> Self {
+        Self {
+            id: id.to_string(),
+            cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)),
+        }
+    }
+
+    /// Read a key from a file that contains the 32-byte key hex-encoded.
+    /// Surrounding whitespace is ignored.
+    pub fn from_file<S: ToString, P: AsRef<Path>>(id: S, path: P) -> Result<Self, EncryptionError> {
+        let s = std::fs::read_to_string(path)?;
+        let mut key = [0u8; 32];
+        hex::decode_to_slice(s.trim(), &mut key).map_err(|_| EncryptionError::InvalidKey)?;
+        Ok(Self::new(id, key))
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .expect("encryption of in-memory data can't fail");
+        let mut res = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        res.extend_from_slice(&nonce);
+        res.extend_from_slice(&ciphertext);
+        res
+    }
+
+    pub fn decrypt(&self, payload: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        if payload.len() < NONCE_LEN {
+            return Err(EncryptionError::Decryption);
+        }
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| EncryptionError::Decryption)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum EncryptionError {
+    #[error("Could not read key file")]
+    Io(#[from] io::Error),
+    #[error("A key must consist of 32 hex-encoded bytes")]
+    InvalidKey,
+    #[error("Payload could not be decrypted")]
+    Decryption,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads_can_be_decrypted_with_the_same_key_only() {
+        let key = PayloadKey::new("k1", [7; 32]);
+        let payload = key.encrypt(b"diagnostics");
+        assert_eq!(b"diagnostics", key.decrypt(&payload).unwrap().as_slice());
+        let other = PayloadKey::new("k2", [8; 32]);
+        assert!(other.decrypt(&payload).is_err());
+    }
+}
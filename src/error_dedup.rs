@@ -0,0 +1,265 @@
+//! Deduplicating repeated errors of a target.
+//!
+//! A target that is down fails with the same error on every call, and an
+//! identical record every interval buries the interesting ones. An
+//! [ErrorDedupLayer] passes the first occurrence of an error on, and then
+//! suppresses its repetitions. Once per period, and when the target fails
+//! differently or succeeds again, the suppressed repetitions are summarized
+//! by a single [ScrapeErr::Repeated]. Summaries that are pending on shutdown
+//! are emitted by [ScrapeResultProcessor::flush].
+//!
+//! Errors are compared by their debug representation, which is also what is
+//! recorded by a [LogOutputWriter](crate::result_processor::LogOutputWriter).
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{
+    banner::Banner,
+    config::ScrapeTargetConfig,
+    health::Transition,
+    layer::ProcessorLayer,
+    result_processor::{target_key, ScrapeResultProcessor},
+    scrape_target::{ScrapeErr, ScrapeOk, ScrapeResult},
+};
+
+/// Suppresses repeated errors. See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct ErrorDedupLayer {
+    period: Duration,
+}
+
+impl ErrorDedupLayer {
+    /// Summarize the repetitions of an error at most once per `period`.
+    pub fn new(period: Duration) -> Self {
+        Self { period }
+    }
+}
+
+impl<P: ScrapeResultProcessor> ProcessorLayer<P> for ErrorDedupLayer {
+    type Processor = ErrorDedup<P>;
+
+    fn layer(&self, inner: P) -> Self::Processor {
+        ErrorDedup {
+            inner,
+            period: self.period,
+            repeats: Default::default(),
+        }
+    }
+}
+
+pub struct ErrorDedup<P> {
+    inner: P,
+    period: Duration,
+    repeats: Arc<Mutex<HashMap<String, Repeat>>>,
+}
+
+impl<P: Clone> Clone for ErrorDedup<P> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            period: self.period,
+            repeats: self.repeats.clone(),
+        }
+    }
+}
+
+/// The error a target failed with last.
+struct Repeat {
+    config: ScrapeTargetConfig,
+    message: String,
+    /// The start of the current period.
+    since: Instant,
+    suppressed: u64,
+}
+
+impl Repeat {
+    /// The summary of the suppressed repetitions, if any.
+    fn summary(&self) -> Option<(ScrapeTargetConfig, ScrapeResult<ScrapeOk>)> {
+        (self.suppressed > 0).then(|| {
+            let e = ScrapeErr::Repeated {
+                message: self.message.clone(),
+                count: self.suppressed,
+                over: self.since.elapsed(),
+            };
+            (self.config.clone(), Err(e))
+        })
+    }
+}
+
+impl<P: ScrapeResultProcessor> ErrorDedup<P> {
+    /// The results to pass on in place of `result`.
+    fn dedup(
+        &self,
+        config: &ScrapeTargetConfig,
+        result: ScrapeResult<ScrapeOk>,
+    ) -> Vec<(ScrapeTargetConfig, ScrapeResult<ScrapeOk>)> {
+        let mut repeats = self.repeats.lock().unwrap();
+        let key = target_key(config);
+        let message = match &result {
+            Ok(_) => None,
+            Err(e) => Some(format!("{e:?}")),
+        };
+        let mut forward = Vec::new();
+        match (repeats.get_mut(&key), message) {
+            (Some(r), Some(message)) if r.message == message => {
+                r.suppressed += 1;
+                if r.since.elapsed() >= self.period {
+                    forward.extend(r.summary());
+                    r.since = Instant::now();
+                    r.suppressed = 0;
+                }
+                return forward;
+            }
+            (previous, message) => {
+                forward.extend(previous.and_then(|r| r.summary()));
+                match message {
+                    Some(message) => {
+                        let r = Repeat {
+                            config: config.clone(),
+                            message,
+                            since: Instant::now(),
+                            suppressed: 0,
+                        };
+                        repeats.insert(key, r);
+                    }
+                    None => {
+                        repeats.remove(&key);
+                    }
+                }
+            }
+        }
+        forward.push((config.clone(), result));
+        forward
+    }
+}
+
+impl<P: ScrapeResultProcessor> ScrapeResultProcessor for ErrorDedup<P> {
+    fn process(
+        &self,
+        config: &ScrapeTargetConfig,
+        result: ScrapeResult<ScrapeOk>,
+    ) -> impl Future<Output = io::Result<()>> + Send {
+        let forward = self.dedup(config, result);
+        let inner = self.inner.clone();
+        async move {
+            for (config, result) in forward {
+                inner.process(&config, result).await?;
+            }
+            Ok(())
+        }
+    }
+
+    fn flush(&self) -> impl Future<Output = io::Result<()>> + Send {
+        let pending: Vec<_> = self
+            .repeats
+            .lock()
+            .unwrap()
+            .values_mut()
+            .filter_map(|r| {
+                let summary = r.summary();
+                r.since = Instant::now();
+                r.suppressed = 0;
+                summary
+            })
+            .collect();
+        let inner = self.inner.clone();
+        async move {
+            for (config, result) in pending {
+                inner.process(&config, result).await?;
+            }
+            inner.flush().await
+        }
+    }
+
+    fn shutdown(&self) -> impl Future<Output = io::Result<()>> + Send {
+        self.inner.shutdown()
+    }
+
+    fn process_transition(
+        &self,
+        config: &ScrapeTargetConfig,
+        transition: &Transition,
+    ) -> impl Future<Output = io::Result<()>> + Send {
+        self.inner.process_transition(config, transition)
+    }
+
+    fn process_banner(&self, banner: &Banner) -> impl Future<Output = io::Result<()>> + Send {
+        self.inner.process_banner(banner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{Action, ScrapeTargetBuilder},
+        layer::ProcessorBuilder,
+    };
+
+    /// Records the messages of the results it sees, `ok` for successes.
+    #[derive(Clone, Default)]
+    struct Messages(Arc<Mutex<Vec<String>>>);
+
+    impl ScrapeResultProcessor for Messages {
+        async fn process(
+            &self,
+            _config: &ScrapeTargetConfig,
+            result: ScrapeResult<ScrapeOk>,
+        ) -> io::Result<()> {
+            let m = match result {
+                Ok(_) => "ok".to_string(),
+                Err(ScrapeErr::Repeated { count, .. }) => format!("repeated {count}"),
+                Err(e) => e.to_string(),
+            };
+            self.0.lock().unwrap().push(m);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn repeated_errors_are_summarized() {
+        let config = ScrapeTargetBuilder::new()
+            .name("t")
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let messages = Messages::default();
+        let p = ProcessorBuilder::new()
+            .layer(ErrorDedupLayer::new(Duration::from_millis(100)))
+            .processor(messages.clone());
+        let ok = || Ok(ScrapeOk::Structured(serde_json::json!({})));
+
+        for _ in 0..3 {
+            p.process(&config, Err(ScrapeErr::Cancelled)).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        p.process(&config, Err(ScrapeErr::Cancelled)).await.unwrap();
+        p.process(&config, Err(ScrapeErr::Cancelled)).await.unwrap();
+        p.process(&config, Err(ScrapeErr::Preempted)).await.unwrap();
+        p.process(&config, ok()).await.unwrap();
+        p.process(&config, Err(ScrapeErr::Preempted)).await.unwrap();
+        p.process(&config, Err(ScrapeErr::Preempted)).await.unwrap();
+        p.flush().await.unwrap();
+
+        assert_eq!(
+            vec![
+                "Cancelled",
+                "repeated 3",
+                "repeated 1",
+                "Preempted by an unscheduled call",
+                "ok",
+                "Preempted by an unscheduled call",
+                "repeated 1",
+            ],
+            *messages.0.lock().unwrap()
+        );
+    }
+}
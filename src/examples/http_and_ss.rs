@@ -10,6 +10,12 @@ use url::Url;
 
 #[tokio::main]
 async fn main() {
+    // E.g., `RUST_LOG=debugbunny=debug` traces every scrape.
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .with_writer(std::io::stderr)
+        .init();
+
     let mut config = Config::new();
     let half_min = Duration::from_secs(30);
     let quarter_min = half_min / 2;
@@ -33,7 +39,9 @@ async fn main() {
 
     let stderr = stderr();
     let p = LogOutputWriter::new(stderr);
-    let debugbunny = DebugBunny::start_scraping(config.scrape_targets, p).await;
+    let debugbunny = DebugBunny::start_config(config, p)
+        .await
+        .expect("Could not start scraping");
 
     // Wait for the SIGTERM signal
     match signal::unix::signal(signal::unix::SignalKind::terminate()) {
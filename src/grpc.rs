@@ -0,0 +1,218 @@
+//! A gRPC service to drive debugbunny remotely. Requires the `grpc` feature.
+//!
+//! The service is defined in `proto/debugbunny.proto`. It allows central
+//! tooling to drive a fleet of debugbunny agents:
+//!
+//! - `ListTargets` returns the targets of the agent and their state,
+//! - `Trigger` calls the selected targets once, outside of their schedule,
+//! - `StreamResults` streams the results of the selected targets as they are
+//!   produced.
+//!
+//! Triggered results are processed like scheduled results, i.e. they are
+//! passed to the processor of the target and show up in the stream. Like for
+//! [derived fields](crate::derive), the body of a command is its stdout and
+//! the body of structured output is JSON.
+//!
+//! A client that falls behind the stream misses results rather than holding
+//! up the targets (see [DebugBunny::results]).
+
+use std::{pin::Pin, sync::Arc, time::UNIX_EPOCH};
+
+use tokio::net::TcpListener;
+use tokio_stream::{wrappers::TcpListenerStream, Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+use crate::{
+    config::ScrapeTargetConfig,
+    debugbunny::{DebugBunny, ScrapeMeta},
+    derive,
+    scrape_target::{ScrapeOk, ScrapeResult},
+};
+
+use self::proto::{
+    agent_server::{Agent, AgentServer},
+    scrape_result::Outcome,
+    ListTargetsRequest, ListTargetsResponse, Selector, StreamResultsRequest, TriggerRequest,
+    TriggerResponse,
+};
+
+/// The types generated from `proto/debugbunny.proto`.
+pub mod proto {
+    tonic::include_proto!("debugbunny.v1");
+}
+
+/// Serve the gRPC service for `debugbunny` on `listener` until an error
+/// occurs.
+pub async fn serve(
+    listener: TcpListener,
+    debugbunny: Arc<DebugBunny>,
+) -> Result<(), tonic::transport::Error> {
+    tonic::transport::Server::builder()
+        .add_service(AgentServer::new(AgentService { debugbunny }))
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await
+}
+
+struct AgentService {
+    debugbunny: Arc<DebugBunny>,
+}
+
+type ResultStream = Pin<Box<dyn Stream<Item = Result<proto::ScrapeResult, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Agent for AgentService {
+    async fn list_targets(
+        &self,
+        _request: Request<ListTargetsRequest>,
+    ) -> Result<Response<ListTargetsResponse>, Status> {
+        let targets = self
+            .debugbunny
+            .target_status()
+            .into_iter()
+            .map(|s| proto::Target {
+                name: s.name,
+                group: s.group,
+                paused: s.paused,
+                stopped: s.stopped,
+                processor_errors: s.processor_errors,
+            })
+            .collect();
+        Ok(Response::new(ListTargetsResponse { targets }))
+    }
+
+    async fn trigger(
+        &self,
+        request: Request<TriggerRequest>,
+    ) -> Result<Response<TriggerResponse>, Status> {
+        let selector = request.into_inner().selector.unwrap_or_default();
+        let triggered = self.debugbunny.trigger(|c| selects(&selector, c)).await;
+        Ok(Response::new(TriggerResponse {
+            triggered: triggered as u32,
+        }))
+    }
+
+    type StreamResultsStream = ResultStream;
+
+    // The error type is given by tonic.
+    #[allow(clippy::result_large_err)]
+    async fn stream_results(
+        &self,
+        request: Request<StreamResultsRequest>,
+    ) -> Result<Response<ResultStream>, Status> {
+        let selector = request.into_inner().selector.unwrap_or_default();
+        let results = self
+            .debugbunny
+            .results()
+            .filter(move |(_, meta, _)| selects(&selector, &meta.config))
+            .map(|(_, meta, result)| Ok(to_proto(meta, result)));
+        Ok(Response::new(Box::pin(results)))
+    }
+}
+
+fn selects(selector: &Selector, c: &ScrapeTargetConfig) -> bool {
+    let name = selector
+        .name
+        .as_ref()
+        .map_or(true, |n| c.name.as_ref() == Some(n));
+    let group = selector
+        .group
+        .as_ref()
+        .map_or(true, |g| c.group.as_ref() == Some(g));
+    name && group
+}
+
+fn to_proto(meta: ScrapeMeta, result: ScrapeResult<ScrapeOk>) -> proto::ScrapeResult {
+    let outcome = match &result {
+        Ok(ok) => Outcome::Body(derive::body(ok).into_owned()),
+        Err(err) => Outcome::Error(err.to_string()),
+    };
+    proto::ScrapeResult {
+        config_json: serde_json::to_string(&meta.config).expect("can't fail"),
+        timestamp: meta
+            .at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64(),
+        unscheduled: meta.unscheduled,
+        name: meta.config.name,
+        group: meta.config.group,
+        outcome: Some(outcome),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{proto::agent_client::AgentClient, *};
+    use crate::{
+        config::{Action, ScrapeTargetBuilder},
+        result_processor::LogOutputWriter,
+    };
+
+    #[tokio::test]
+    async fn triggered_results_are_streamed() {
+        let config = ScrapeTargetBuilder::new()
+            .name("self")
+            .interval(Duration::from_secs(3600))
+            .action(Action::SelfStatus)
+            .build();
+        let bunny =
+            DebugBunny::start_scraping(vec![config], LogOutputWriter::new(tokio::io::sink()))
+                .await
+                .unwrap();
+        let bunny = Arc::new(bunny);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, bunny.clone()));
+
+        let mut client = AgentClient::connect(format!("http://{addr}"))
+            .await
+            .unwrap();
+        let targets = client
+            .list_targets(ListTargetsRequest {})
+            .await
+            .unwrap()
+            .into_inner()
+            .targets;
+        assert_eq!(1, targets.len());
+        assert_eq!(Some("self"), targets[0].name.as_deref());
+
+        let selector = Selector {
+            name: Some("self".to_string()),
+            group: None,
+        };
+        let mut results = client
+            .stream_results(StreamResultsRequest {
+                selector: Some(selector.clone()),
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let triggered = client
+            .trigger(TriggerRequest {
+                selector: Some(selector),
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .triggered;
+        assert_eq!(1, triggered);
+
+        // The first scheduled result may show up as well.
+        let triggered = async {
+            loop {
+                let r = results.next().await.unwrap().unwrap();
+                if r.unscheduled {
+                    break r;
+                }
+            }
+        };
+        let r = tokio::time::timeout(Duration::from_secs(5), triggered)
+            .await
+            .unwrap();
+        assert_eq!(Some("self"), r.name.as_deref());
+        assert!(matches!(r.outcome, Some(Outcome::Body(_))));
+        bunny.stop();
+    }
+}
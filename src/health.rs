@@ -0,0 +1,182 @@
+//! Tracking whether targets are healthy or failing.
+//!
+//! A single failed scrape is rarely worth an alert, and a target that fails
+//! every other scrape should not raise an alert every other scrape either. A
+//! [HealthTracker] follows the results of a target and reports a
+//! [Transition] only once a [HealthPolicy] number of consecutive results
+//! disagree with the current state, which damps flapping. Targets start out
+//! healthy.
+//!
+//! With [DebugBunnyBuilder::track_health](crate::debugbunny::DebugBunnyBuilder::track_health),
+//! the transitions of all targets are passed to their processors, see
+//! [ScrapeResultProcessor::process_transition](crate::result_processor::ScrapeResultProcessor::process_transition).
+//! A [LogOutputWriter](crate::result_processor::LogOutputWriter) writes them
+//! as [TransitionRepr](crate::result_processor::TransitionRepr) records.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::scrape_target::{ScrapeOk, ScrapeResult};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Health {
+    Healthy,
+    Failing,
+}
+
+/// How many consecutive results it takes to change the state of a target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HealthPolicy {
+    down_after: u32,
+    up_after: u32,
+}
+
+impl HealthPolicy {
+    /// Every result that disagrees with the state changes it.
+    pub fn new() -> Self {
+        Self {
+            down_after: 1,
+            up_after: 1,
+        }
+    }
+
+    /// A healthy target is failing after `n` consecutive failures.
+    pub fn down_after(mut self, n: u32) -> Self {
+        self.down_after = n.max(1);
+        self
+    }
+
+    /// A failing target is healthy again after `n` consecutive successes.
+    pub fn up_after(mut self, n: u32) -> Self {
+        self.up_after = n.max(1);
+        self
+    }
+}
+
+impl Default for HealthPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A change of the state of a target.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Transition {
+    /// The new state.
+    pub health: Health,
+    /// The number of consecutive results that changed the state.
+    pub after: u32,
+    /// The error of the latest failure, if the target is failing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.health {
+            Health::Failing => write!(f, "down after {} failures", self.after)?,
+            Health::Healthy => write!(f, "recovered after {} successes", self.after)?,
+        }
+        if let Some(e) = &self.error {
+            write!(f, ": {e}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The state of a single target. See the [module docs](self).
+#[derive(Debug, Clone)]
+pub struct HealthTracker {
+    policy: HealthPolicy,
+    health: Health,
+    /// Consecutive results that disagree with `health`.
+    streak: u32,
+}
+
+impl HealthTracker {
+    pub fn new(policy: HealthPolicy) -> Self {
+        Self {
+            policy,
+            health: Health::Healthy,
+            streak: 0,
+        }
+    }
+
+    pub fn health(&self) -> Health {
+        self.health
+    }
+
+    /// Record the result of a call. Calls that did not reach the target, e.g.
+    /// cancelled ones, are ignored. Returns the transition the result caused,
+    /// if any.
+    pub fn record(&mut self, result: &ScrapeResult<ScrapeOk>) -> Option<Transition> {
+        let health = match result {
+            Ok(_) => Health::Healthy,
+            Err(e) if e.is_unreachable() => return None,
+            Err(_) => Health::Failing,
+        };
+        if health == self.health {
+            self.streak = 0;
+            return None;
+        }
+        self.streak += 1;
+        let threshold = match health {
+            Health::Failing => self.policy.down_after,
+            Health::Healthy => self.policy.up_after,
+        };
+        if self.streak < threshold {
+            return None;
+        }
+        let transition = Transition {
+            health,
+            after: self.streak,
+            error: result.as_ref().err().map(ToString::to_string),
+        };
+        self.health = health;
+        self.streak = 0;
+        Some(transition)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scrape_target::ScrapeErr;
+
+    #[test]
+    fn flapping_is_damped() {
+        let ok = || Ok(ScrapeOk::Structured(serde_json::Value::Null));
+        let err = || Err(ScrapeErr::CommandNotFound("x".to_string()));
+        let mut tracker = HealthTracker::new(HealthPolicy::new().down_after(3).up_after(2));
+        let results = [
+            err(),
+            ok(),
+            err(),
+            err(),
+            Err(ScrapeErr::Cancelled),
+            err(),
+            ok(),
+            err(),
+            ok(),
+            ok(),
+        ];
+        let transitions: Vec<_> = results
+            .iter()
+            .enumerate()
+            .filter_map(|(i, r)| Some((i, tracker.record(r)?)))
+            .collect();
+
+        let [(5, down), (9, up)] = &transitions[..] else {
+            panic!("unexpected transitions: {transitions:?}");
+        };
+        assert_eq!(Health::Failing, down.health);
+        assert_eq!(
+            "down after 3 failures: Command not found: x",
+            down.to_string()
+        );
+        assert_eq!("recovered after 2 successes", up.to_string());
+        assert_eq!(Health::Healthy, tracker.health());
+    }
+}
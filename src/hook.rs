@@ -0,0 +1,140 @@
+//! Commands run before and after scrapes.
+//!
+//! A [Hooked] service runs the `pre` and `post` hooks of a target around every
+//! call of the inner service. As hooks are run within the call, they are
+//! subject to the same no-overlap guarantee as the action itself, i.e. the
+//! hooks of two calls never interleave.
+//!
+//! A failing hook is reported to the processor of the target as a separate
+//! [ScrapeErr::Hook] result, such that the result of the scrape itself is not
+//! lost.
+
+use std::{sync::Arc, time::Duration};
+
+use crate::{
+    command::new_shell,
+    config::{Hook, ScrapeTargetConfig},
+    result_processor::ScrapeResultProcessor,
+    scrape_target::{FutureScrapeResult, ScrapeErr, ScrapeOk, ScrapeService},
+};
+
+const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Hooked<S, P> {
+    inner: S,
+    config: Arc<ScrapeTargetConfig>,
+    processor: P,
+}
+
+impl<S, P> Hooked<S, P> {
+    pub fn new(inner: S, config: ScrapeTargetConfig, processor: P) -> Self {
+        Self {
+            inner,
+            config: Arc::new(config),
+            processor,
+        }
+    }
+}
+
+impl<S, P> ScrapeService for Hooked<S, P>
+where
+    S: ScrapeService<Response = ScrapeOk>,
+    P: ScrapeResultProcessor + 'static,
+{
+    type Response = ScrapeOk;
+
+    fn call(&mut self) -> FutureScrapeResult<ScrapeOk> {
+        let call = self.inner.call();
+        self.hooked(call)
+    }
+
+    fn call_unscheduled(&mut self) -> FutureScrapeResult<ScrapeOk> {
+        let call = self.inner.call_unscheduled();
+        self.hooked(call)
+    }
+}
+
+impl<S, P: ScrapeResultProcessor + 'static> Hooked<S, P> {
+    /// The returned future of the inner service is lazy, so the scrape only
+    /// starts after the pre hook has finished.
+    fn hooked(&self, call: FutureScrapeResult<ScrapeOk>) -> FutureScrapeResult<ScrapeOk> {
+        let config = self.config.clone();
+        let p = self.processor.clone();
+        Box::pin(async move {
+            if let Some(pre) = &config.pre {
+                run_reported("pre", pre, &config, &p).await;
+            }
+            let r = call.await;
+            if let Some(post) = &config.post {
+                run_reported("post", post, &config, &p).await;
+            }
+            r
+        })
+    }
+}
+
+/// Run `hook` and pass its failure to `p`, if any.
+async fn run_reported<P: ScrapeResultProcessor>(
+    name: &'static str,
+    hook: &Hook,
+    config: &ScrapeTargetConfig,
+    p: &P,
+) {
+    let Err(reason) = run(hook).await else {
+        return;
+    };
+    tracing::warn!(hook = name, %reason, "hook failed");
+    let e = ScrapeErr::Hook { hook: name, reason };
+    if let Err(e) = p.process(config, Err(e)).await {
+        tracing::warn!(error = %e, "could not process result");
+    }
+}
+
+async fn run(hook: &Hook) -> Result<(), String> {
+    let timeout = hook.timeout.unwrap_or(DEFAULT_HOOK_TIMEOUT);
+    let call = new_shell(hook.command.clone()).call();
+    match tokio::time::timeout(timeout, call).await {
+        Err(_) => Err(format!("timed out after {timeout:?}")),
+        Ok(Err(e)) => Err(e.to_string()),
+        Ok(Ok(ScrapeOk::CommandResponse(out))) if !out.status.success() => {
+            Err(format!("exited with {}", out.status))
+        }
+        Ok(Ok(_)) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{Action, ScrapeTargetBuilder},
+        result_processor::ErrorCollector,
+    };
+
+    struct Constant;
+
+    impl ScrapeService for Constant {
+        type Response = ScrapeOk;
+
+        fn call(&mut self) -> FutureScrapeResult<ScrapeOk> {
+            Box::pin(async { Ok(ScrapeOk::Structured(serde_json::Value::Null)) })
+        }
+    }
+
+    #[tokio::test]
+    async fn failing_hooks_are_reported() {
+        let config = ScrapeTargetBuilder::new()
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .pre(Hook::new("exit 3"))
+            .post(Hook::new("echo done"))
+            .build();
+        let errors = ErrorCollector::default();
+        let mut s = Hooked::new(Constant, config, errors.clone());
+
+        assert!(s.call().await.is_ok());
+        let errors = errors.0.lock().unwrap();
+        assert_eq!(1, errors.len());
+        assert!(errors[0].starts_with("The pre hook failed: exited with"));
+    }
+}
@@ -1,38 +1,891 @@
 //! A scrape service that sends HTTP-requests and collects the responses.
+//!
+//! Endpoints that stream their response, e.g. with chunked transfer encoding
+//! or as server-sent events, never finish a body within the timeout of a call.
+//! For those, [HttpScrapeTarget::with_streaming] keeps the connection open
+//! across calls and emits the data received so far with every call.
+//!
+//! `HEAD` and `OPTIONS` requests are probes: the body is never read, and only
+//! the status and the headers are recorded, see [HeadersOnly].
 
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    net::SocketAddr,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::{Bytes, BytesMut};
+use http::{HeaderMap, Method, StatusCode, Version};
 use http_body_util::BodyExt;
 use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::Instant,
+};
+use tracing::debug;
 
-use crate::scrape_target::{FutureScrapeResult, ScrapeOk, ScrapeService};
+use crate::{
+    config::{HttpClientConfig, HttpStreamConfig, VsockAddr},
+    dns::CachingResolver,
+    scrape_target::{FutureScrapeResult, ScrapeErr, ScrapeOk, ScrapeResult, ScrapeService},
+    template::{TemplateError, UrlTemplate, Variables},
+};
 
+#[derive(Clone)]
 pub struct HttpScrapeTarget {
     client: reqwest::Client,
+    method: Method,
+    /// The primary URL followed by the fallback URLs. Never empty.
+    urls: Vec<UrlTemplate>,
+    /// Appended to the query of each URL. Values may contain placeholders.
+    query: BTreeMap<String, String>,
+    variables: Arc<Variables>,
+    expect_status: Vec<StatusCode>,
+    accept_encoding: Option<String>,
+    stall_timeout: Option<Duration>,
+    host_limits: Option<HostLimits>,
+    streaming: Option<Streaming>,
+    vsock: Option<VsockAddr>,
+    /// Hosts whose addresses the client does not resolve via DNS.
+    static_hosts: BTreeSet<String>,
+}
+
+#[derive(Clone)]
+struct Streaming {
+    config: HttpStreamConfig,
+    /// The response currently being streamed, shared by the clones of a call.
+    open: Arc<tokio::sync::Mutex<Option<OpenStream>>>,
+}
+
+struct OpenStream {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    url: Url,
+    remote_addr: Option<RemoteAddr>,
+    body: reqwest::Body,
+    /// The end of the window, after which the connection is closed.
+    until: Instant,
+    next_index: u64,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// A response whose body has not been read yet.
+struct Answer {
     url: Url,
+    response: http::Response<reqwest::Body>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Limits the number of concurrent requests per host and port, such that
+/// many targets pointing at the same struggling service do not overwhelm it.
+/// Clones share the limits.
+#[derive(Debug, Clone)]
+pub struct HostLimits {
+    max_per_host: NonZeroUsize,
+    hosts: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+impl HostLimits {
+    pub fn new(max_per_host: NonZeroUsize) -> Self {
+        Self {
+            max_per_host,
+            hosts: Default::default(),
+        }
+    }
+
+    async fn acquire(&self, url: &Url) -> OwnedSemaphorePermit {
+        let host = format!(
+            "{}:{}",
+            url.host_str().unwrap_or_default(),
+            url.port_or_known_default().unwrap_or_default()
+        );
+        let semaphore = self
+            .hosts
+            .lock()
+            .unwrap()
+            .entry(host)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max_per_host.get())))
+            .clone();
+        semaphore.acquire_owned().await.expect("never closed")
+    }
+}
+
+/// Response extension recording which endpoint answered a scrape.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Endpoint(pub Url);
+
+/// Response extension recording the address a scrape was connected to, and
+/// where the address came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemoteAddr(pub SocketAddr, pub AddrSource);
+
+/// How the host of a URL was mapped to the address connected to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum AddrSource {
+    /// The host is an IP address.
+    Literal,
+    /// The host is overridden in the client, see [HttpClientConfig::resolve].
+    Static,
+    /// The host was resolved, possibly from a cache.
+    Dns,
+}
+
+/// Response extension marking the response to a probe, i.e. a `HEAD` or
+/// `OPTIONS` request. Its body is empty and is not recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeadersOnly;
+
+/// Response extension holding the trailers of a response, if it had any.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trailers(pub HeaderMap);
+
+/// Response extension marking a part of a streamed response, see
+/// [HttpScrapeTarget::with_streaming].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamPart {
+    /// Counts the parts of a response, starting at 0.
+    pub index: u64,
+    /// The connection was closed after this part, because the stream ended or
+    /// the window elapsed.
+    pub last: bool,
 }
 
 impl HttpScrapeTarget {
     pub fn new(client: reqwest::Client, url: Url) -> Self {
-        Self { client, url }
+        Self::from_template(client, url.into(), Default::default())
+    }
+
+    /// The placeholders of the template are expanded on each call using the
+    /// given variables.
+    pub fn from_template(
+        client: reqwest::Client,
+        url: UrlTemplate,
+        variables: Arc<Variables>,
+    ) -> Self {
+        Self {
+            client,
+            method: Method::GET,
+            urls: vec![url],
+            query: BTreeMap::new(),
+            variables,
+            expect_status: vec![],
+            accept_encoding: None,
+            stall_timeout: None,
+            host_limits: None,
+            streaming: None,
+            vsock: None,
+            static_hosts: BTreeSet::new(),
+        }
+    }
+
+    /// Send requests with the given method instead of `GET`. `HEAD` and
+    /// `OPTIONS` requests are probes, whose responses are marked with
+    /// [HeadersOnly] and whose bodies are never read. Probes are not streamed.
+    pub fn with_method(mut self, method: Option<Method>) -> Self {
+        self.method = method.unwrap_or(Method::GET);
+        self
+    }
+
+    fn is_probe(&self) -> bool {
+        self.method == Method::HEAD || self.method == Method::OPTIONS
+    }
+
+    /// If a request to the primary URL fails, the fallback URLs are tried in
+    /// order until one of them succeeds. The URL that answered is recorded as
+    /// [Endpoint] in the extensions of the response.
+    pub fn with_fallback_urls<U: Into<UrlTemplate>>(mut self, urls: Vec<U>) -> Self {
+        self.urls.truncate(1);
+        self.urls.extend(urls.into_iter().map(Into::into));
+        self
+    }
+
+    /// Append the given parameters to the query of the URLs. Keys and values
+    /// are encoded, and placeholders in the values are expanded on each call.
+    pub fn with_query(mut self, query: BTreeMap<String, String>) -> Self {
+        self.query = query;
+        self
+    }
+
+    /// Responses with a status code that is not contained in `status` resolve
+    /// to [ScrapeErr::UnexpectedStatus]. An empty list accepts any status.
+    pub fn with_expected_status(mut self, status: Vec<StatusCode>) -> Self {
+        self.expect_status = status;
+        self
+    }
+
+    /// Send the given `Accept-Encoding` header instead of the one of the
+    /// client.
+    pub fn with_accept_encoding(mut self, accept_encoding: Option<String>) -> Self {
+        self.accept_encoding = accept_encoding;
+        self
+    }
+
+    /// Fail with [ScrapeErr::Stalled] if no data of the body arrives for the
+    /// given duration, even though the timeout of the call has not elapsed.
+    pub fn with_stall_timeout(mut self, stall_timeout: Option<Duration>) -> Self {
+        self.stall_timeout = stall_timeout;
+        self
+    }
+
+    /// Wait for a free slot of the host before sending a request. The wait
+    /// counts against the timeout of the call.
+    pub fn with_host_limits(mut self, host_limits: Option<HostLimits>) -> Self {
+        self.host_limits = host_limits;
+        self
+    }
+
+    /// Keep reading the response of a request for the window of `config`
+    /// instead of waiting for the body to end. Each call returns the data
+    /// that arrived within `flush_every`, or since the previous call, as a
+    /// response marked with a [StreamPart]. Once the stream ends or the window
+    /// elapses, the connection is closed and the next call sends a new
+    /// request.
+    ///
+    /// The timeout of a call must exceed `flush_every`. To not miss data
+    /// between the calls, use a continuous interval, see
+    /// [Interval](crate::config::Interval). The stall timeout does not apply
+    /// to streamed responses.
+    pub fn with_streaming(mut self, config: Option<HttpStreamConfig>) -> Self {
+        self.streaming = config.map(|config| Streaming {
+            config,
+            open: Default::default(),
+        });
+        self
+    }
+
+    /// Connect to the given vsock address instead of the hosts of the URLs.
+    /// The client is not used then, and bodies are not decompressed. See
+    /// [crate::vsock].
+    pub fn with_vsock(mut self, vsock: Option<VsockAddr>) -> Self {
+        self.vsock = vsock;
+        self
+    }
+
+    /// The hosts that are mapped to static addresses by the client, see
+    /// [HttpClientConfig::resolve]. Used to tell the [AddrSource] of the
+    /// [RemoteAddr] of a response; other hosts are assumed to be resolved via
+    /// DNS.
+    pub fn with_static_hosts<I: IntoIterator<Item = String>>(mut self, hosts: I) -> Self {
+        self.static_hosts = hosts.into_iter().collect();
+        self
+    }
+
+    fn addr_source(&self, url: &Url) -> AddrSource {
+        match url.host() {
+            Some(url::Host::Domain(d)) if self.static_hosts.contains(d) => AddrSource::Static,
+            Some(url::Host::Domain(_)) | None => AddrSource::Dns,
+            Some(_) => AddrSource::Literal,
+        }
+    }
+
+    /// Send the request to the URLs in order until one of them answers.
+    async fn send(&self) -> ScrapeResult<Answer> {
+        let mut last_err = None;
+        for url in &self.urls {
+            let url = match self.expand(url) {
+                Ok(url) => url,
+                Err(e) => {
+                    last_err = Some(e.into());
+                    continue;
+                }
+            };
+            let permit = match &self.host_limits {
+                Some(limits) => Some(limits.acquire(&url).await),
+                None => None,
+            };
+            debug!(url = %url, "fetching");
+            let sent = match self.vsock {
+                Some(addr) => {
+                    send_vsock(
+                        addr,
+                        self.method.clone(),
+                        &url,
+                        self.accept_encoding.as_deref(),
+                    )
+                    .await
+                }
+                None => {
+                    let source = self.addr_source(&url);
+                    send(
+                        &self.client,
+                        self.method.clone(),
+                        url.clone(),
+                        self.accept_encoding.as_deref(),
+                        source,
+                    )
+                    .await
+                }
+            };
+            match sent.and_then(|r| self.check_status(r)) {
+                Ok(response) => {
+                    return Ok(Answer {
+                        url,
+                        response,
+                        permit,
+                    })
+                }
+                Err(e) => {
+                    debug!(url = %url, error = %e, "request failed");
+                    last_err = Some(e)
+                }
+            }
+        }
+        Err(last_err.expect("at least one URL is configured"))
+    }
+
+    fn check_status(
+        &self,
+        resp: http::Response<reqwest::Body>,
+    ) -> ScrapeResult<http::Response<reqwest::Body>> {
+        debug!(status = %resp.status(), version = ?resp.version(), "received response");
+        if !self.expect_status.is_empty() && !self.expect_status.contains(&resp.status()) {
+            return Err(ScrapeErr::UnexpectedStatus(resp.status()));
+        }
+        Ok(resp)
+    }
+
+    fn expand(&self, url: &UrlTemplate) -> Result<Url, TemplateError> {
+        let mut url = url.expand(&self.variables)?;
+        if !self.query.is_empty() {
+            let mut pairs = url.query_pairs_mut();
+            for (k, v) in &self.query {
+                pairs.append_pair(k, &self.variables.expand(v)?);
+            }
+        }
+        Ok(url)
+    }
+
+    async fn fetch(&self) -> ScrapeResult<http::Response<Bytes>> {
+        // We want to fully materialize the response inside this method.
+        // E.g., the outer timeout should also apply to reading the body,
+        // and any open underlying response reader, etc. should be closed
+        // before we return.
+        crate::memory::admit().await;
+        let Answer {
+            url,
+            response,
+            permit: _permit,
+        } = self.send().await?;
+        let (mut parts, body) = response.into_parts();
+        parts.extensions.insert(Endpoint(url));
+        if self.is_probe() {
+            // A response to `HEAD` has no body, and the one of `OPTIONS` is
+            // not of interest. Dropping it closes the connection if needed.
+            drop(body);
+            parts.extensions.insert(HeadersOnly);
+            return Ok(http::Response::from_parts(parts, Bytes::new()));
+        }
+        let (body, trailers) = collect(body, self.stall_timeout).await?;
+        if let Some(trailers) = trailers {
+            parts.extensions.insert(Trailers(trailers));
+        }
+        Ok(http::Response::from_parts(parts, body))
+    }
+
+    async fn next_part(&self, s: &Streaming) -> ScrapeResult<http::Response<Bytes>> {
+        let mut open = s.open.lock().await;
+        crate::memory::admit().await;
+        if open.is_none() {
+            let answer = self.send().await?;
+            *open = Some(OpenStream::new(answer, s.config.window));
+        }
+        let stream = open.as_mut().expect("opened above");
+        let flush_at = (Instant::now() + s.config.flush_every).min(stream.until);
+        let mut collected = BytesMut::new();
+        let mut trailers = None;
+        let ended = loop {
+            match tokio::time::timeout_at(flush_at, stream.body.frame()).await {
+                Err(_) => break false,
+                Ok(None) => break true,
+                Ok(Some(Err(e))) => {
+                    *open = None;
+                    return Err(e.into());
+                }
+                Ok(Some(Ok(frame))) => match frame.into_data() {
+                    Ok(data) => {
+                        if let Err(e) = crate::memory::charge(data.len()) {
+                            *open = None;
+                            return Err(e);
+                        }
+                        collected.extend_from_slice(&data);
+                    }
+                    Err(frame) => trailers = frame.into_trailers().ok(),
+                },
+            }
+        };
+        let last = ended || Instant::now() >= stream.until;
+        let mut part = stream.part(collected.freeze(), last);
+        if let Some(trailers) = trailers {
+            part.extensions_mut().insert(Trailers(trailers));
+        }
+        if last {
+            debug!(ended, "closing stream");
+            *open = None;
+        }
+        Ok(part)
     }
 }
 
+impl OpenStream {
+    fn new(answer: Answer, window: Duration) -> Self {
+        let (parts, body) = answer.response.into_parts();
+        Self {
+            status: parts.status,
+            version: parts.version,
+            headers: parts.headers,
+            url: answer.url,
+            remote_addr: parts.extensions.get().copied(),
+            body,
+            until: Instant::now() + window,
+            next_index: 0,
+            _permit: answer.permit,
+        }
+    }
+
+    fn part(&mut self, body: Bytes, last: bool) -> http::Response<Bytes> {
+        let mut resp = http::Response::new(body);
+        *resp.status_mut() = self.status;
+        *resp.version_mut() = self.version;
+        *resp.headers_mut() = self.headers.clone();
+        resp.extensions_mut().insert(Endpoint(self.url.clone()));
+        if let Some(addr) = self.remote_addr {
+            resp.extensions_mut().insert(addr);
+        }
+        resp.extensions_mut().insert(StreamPart {
+            index: self.next_index,
+            last,
+        });
+        self.next_index += 1;
+        resp
+    }
+}
+
+/// Build a dedicated client for a target according to its configuration.
+pub fn client_from_config(c: &HttpClientConfig) -> reqwest::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(n) = c.pool_max_idle_per_host {
+        builder = builder.pool_max_idle_per_host(n);
+    }
+    match c.http2 {
+        #[cfg(feature = "http2")]
+        Some(true) => builder = builder.http2_prior_knowledge(),
+        #[cfg(not(feature = "http2"))]
+        Some(true) => tracing::warn!("HTTP/2 is not supported by this build, using HTTP/1"),
+        Some(false) => builder = builder.http1_only(),
+        None => {}
+    }
+    if let Some(d) = c.tcp_keepalive {
+        builder = builder.tcp_keepalive(d);
+    }
+    if let Some(ua) = &c.user_agent {
+        builder = builder.user_agent(ua);
+    }
+    for (host, ip) in &c.resolve {
+        builder = builder.resolve(host, SocketAddr::new(*ip, 0));
+    }
+    if c.dns_cache_ttl.is_some() || !c.ip_family.is_any() {
+        let resolver = match c.dns_cache_ttl {
+            Some(ttl) => CachingResolver::new(ttl),
+            None => CachingResolver::default(),
+        };
+        builder = builder.dns_resolver(Arc::new(resolver.with_family(c.ip_family)));
+    }
+    if c.decompress == Some(false) {
+        // Without decompression, reqwest would not advertise any encoding.
+        // Still ask for the encodings a decompressing client would accept,
+        // such that the captured body is what a regular client receives.
+        #[cfg(feature = "compression")]
+        {
+            builder = builder.gzip(false).brotli(false);
+        }
+        builder = builder.default_headers(http::HeaderMap::from_iter([(
+            http::header::ACCEPT_ENCODING,
+            http::HeaderValue::from_static("gzip, br"),
+        )]));
+    }
+    builder.build()
+}
+
 impl ScrapeService for HttpScrapeTarget {
     type Response = ScrapeOk;
     fn call(&mut self) -> FutureScrapeResult<ScrapeOk> {
-        let client = self.client.clone();
-        let url = self.url.clone();
+        let this = self.clone();
         // todo(dsd): Consider using hyper directly instead of reqwest.
         Box::pin(async move {
-            // We want to fully materialize the response inside this method.
-            // E.g., the outer timeout should also apply to reading the body,
-            // and any open underlying response reader, etc. should be closed
-            // before we return.
-            let resp = client.get(url).send().await?;
-            let (parts, body) = http::Response::from(resp).into_parts();
-            let body = BodyExt::collect(body).await.map(|b| b.to_bytes())?.to_vec();
-            Ok(ScrapeOk::HttpResponse(http::Response::from_parts(
-                parts, body,
-            )))
+            match &this.streaming {
+                Some(s) if !this.is_probe() => this.next_part(s).await,
+                _ => this.fetch().await,
+            }
+            .map(ScrapeOk::HttpResponse)
+        })
+    }
+}
+
+async fn send(
+    client: &reqwest::Client,
+    method: Method,
+    url: Url,
+    accept_encoding: Option<&str>,
+    source: AddrSource,
+) -> ScrapeResult<http::Response<reqwest::Body>> {
+    let mut req = client.request(method, url);
+    if let Some(ae) = accept_encoding {
+        req = req.header(http::header::ACCEPT_ENCODING, ae);
+    }
+    let resp = req.send().await?;
+    let remote_addr = resp.remote_addr();
+    let mut resp = http::Response::from(resp);
+    if let Some(addr) = remote_addr {
+        resp.extensions_mut().insert(RemoteAddr(addr, source));
+    }
+    Ok(resp)
+}
+
+#[cfg(target_os = "linux")]
+async fn send_vsock(
+    addr: VsockAddr,
+    method: Method,
+    url: &Url,
+    accept_encoding: Option<&str>,
+) -> ScrapeResult<http::Response<reqwest::Body>> {
+    Ok(crate::vsock::request(addr, method, url, accept_encoding).await?)
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn send_vsock(
+    _addr: VsockAddr,
+    _method: Method,
+    _url: &Url,
+    _accept_encoding: Option<&str>,
+) -> ScrapeResult<http::Response<reqwest::Body>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "vsock is only supported on Linux",
+    )
+    .into())
+}
+
+/// Collect the body and the trailers, if any, charging the body to the memory
+/// budget as it arrives. With a `stall_timeout`, fail if no data arrives for
+/// that long.
+async fn collect(
+    mut body: reqwest::Body,
+    stall_timeout: Option<Duration>,
+) -> ScrapeResult<(Bytes, Option<HeaderMap>)> {
+    let mut collected = BytesMut::new();
+    let mut trailers = None;
+    loop {
+        let frame = match stall_timeout {
+            Some(after) => match tokio::time::timeout(after, body.frame()).await {
+                Ok(frame) => frame,
+                Err(_) => {
+                    let received = collected.len();
+                    debug!(received, "body stalled");
+                    return Err(ScrapeErr::Stalled { after, received });
+                }
+            },
+            None => body.frame().await,
+        };
+        let Some(frame) = frame else {
+            return Ok((collected.freeze(), trailers));
+        };
+        match frame?.into_data() {
+            Ok(data) => {
+                crate::memory::charge(data.len())?;
+                collected.extend_from_slice(&data);
+            }
+            Err(frame) => trailers = frame.into_trailers().ok(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use httptest::{matchers::*, responders::*, Expectation, Server};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn unexpected_status_is_an_error() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/"))
+                .times(2)
+                .respond_with(status_code(500)),
+        );
+        let url = Url::parse(&server.url("/").to_string()).unwrap();
+
+        let mut s = HttpScrapeTarget::new(reqwest::Client::new(), url);
+        assert!(matches!(s.call().await, Ok(ScrapeOk::HttpResponse(_))));
+
+        let mut s = s.with_expected_status(vec![StatusCode::OK]);
+        assert!(matches!(
+            s.call().await,
+            Err(ScrapeErr::UnexpectedStatus(
+                StatusCode::INTERNAL_SERVER_ERROR
+            ))
+        ));
+    }
+
+    #[tokio::test]
+    async fn probes_do_not_read_the_body() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("OPTIONS", "/")).respond_with(
+                status_code(204)
+                    .insert_header("allow", "GET, HEAD")
+                    .body("ignored"),
+            ),
+        );
+        let url = Url::parse(&server.url("/").to_string()).unwrap();
+
+        let mut s =
+            HttpScrapeTarget::new(reqwest::Client::new(), url).with_method(Some(Method::OPTIONS));
+        let Ok(ScrapeOk::HttpResponse(r)) = s.call().await else {
+            panic!("expected a response");
+        };
+        assert_eq!(StatusCode::NO_CONTENT, r.status());
+        assert_eq!("GET, HEAD", r.headers()["allow"]);
+        assert_eq!(Some(&HeadersOnly), r.extensions().get());
+        assert!(r.body().is_empty());
+    }
+
+    #[tokio::test]
+    async fn requests_to_the_same_host_are_limited() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method("GET"))
+                .times(2)
+                .respond_with(delay_and_then(Duration::from_millis(100), status_code(200))),
+        );
+        let limits = HostLimits::new(NonZeroUsize::MIN);
+        let target = |path| {
+            let url = Url::parse(&server.url(path).to_string()).unwrap();
+            HttpScrapeTarget::new(reqwest::Client::new(), url)
+                .with_host_limits(Some(limits.clone()))
+        };
+        let (mut a, mut b) = (target("/a"), target("/b"));
+
+        let start = tokio::time::Instant::now();
+        let (a, b) = tokio::join!(a.call(), b.call());
+        assert!(a.is_ok() && b.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn stalled_bodies_are_aborted() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            let (mut s, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = s.read(&mut buf).await;
+            s.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 10\r\n\r\nabc")
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let mut s = HttpScrapeTarget::new(reqwest::Client::new(), url)
+            .with_stall_timeout(Some(Duration::from_millis(100)));
+        assert!(matches!(
+            s.call().await,
+            Err(ScrapeErr::Stalled { received: 3, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn streamed_responses_are_emitted_in_parts() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move {
+            let (mut s, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = s.read(&mut buf).await;
+            s.write_all(b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\n\r\n3\r\none\r\n")
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(300)).await;
+            s.write_all(b"3\r\ntwo\r\n0\r\n\r\n").await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let mut s = HttpScrapeTarget::new(reqwest::Client::new(), url).with_streaming(Some(
+            HttpStreamConfig {
+                window: Duration::from_secs(10),
+                flush_every: Duration::from_millis(100),
+            },
+        ));
+        let mut parts = vec![];
+        loop {
+            let Ok(ScrapeOk::HttpResponse(resp)) = s.call().await else {
+                panic!("Invalid response")
+            };
+            let part = *resp.extensions().get::<StreamPart>().unwrap();
+            assert_eq!(parts.len() as u64, part.index);
+            parts.push(resp.into_body());
+            if part.last {
+                break;
+            }
+        }
+        assert!(parts.len() > 2, "{parts:?}");
+        assert_eq!(b"one", parts[0].as_ref());
+        assert_eq!(b"onetwo", parts.concat().as_slice());
+    }
+
+    #[tokio::test]
+    async fn query_parameters_are_encoded() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/search"),
+                request::query(url_decoded(contains(("page", "1")))),
+                request::query(url_decoded(contains(("q", "a b&c=10.0.0.1")))),
+            ])
+            .respond_with(status_code(200)),
+        );
+        let url = UrlTemplate::new(server.url("/search?page=1"));
+        let mut variables = Variables::new();
+        variables.insert("pod_ip", "10.0.0.1");
+
+        let mut s =
+            HttpScrapeTarget::from_template(reqwest::Client::new(), url, Arc::new(variables))
+                .with_query(BTreeMap::from([(
+                    "q".to_string(),
+                    "a b&c={pod_ip}".to_string(),
+                )]))
+                .with_expected_status(vec![StatusCode::OK]);
+        assert!(s.call().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn statically_resolved_addresses_are_marked() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/")).respond_with(status_code(200)),
+        );
+        let addr = server.addr();
+        let client = client_from_config(&HttpClientConfig {
+            resolve: BTreeMap::from([("debugbunny.test".to_string(), addr.ip())]),
+            ..Default::default()
         })
+        .unwrap();
+        let url = Url::parse(&format!("http://debugbunny.test:{}/", addr.port())).unwrap();
+        let mut s =
+            HttpScrapeTarget::new(client, url).with_static_hosts(["debugbunny.test".to_string()]);
+        let Ok(ScrapeOk::HttpResponse(resp)) = s.call().await else {
+            panic!("Invalid response")
+        };
+        assert_eq!(
+            Some(&RemoteAddr(addr, AddrSource::Static)),
+            resp.extensions().get()
+        );
+    }
+
+    #[tokio::test]
+    async fn trailers_and_remote_address_are_recorded() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let url = Url::parse(&format!("http://{addr}/")).unwrap();
+        tokio::spawn(async move {
+            let (mut s, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 1024];
+            let _ = s.read(&mut buf).await;
+            s.write_all(
+                b"HTTP/1.1 200 OK\r\ntransfer-encoding: chunked\r\ntrailer: x-checksum\r\n\r\n\
+                  3\r\nabc\r\n0\r\nx-checksum: 42\r\n\r\n",
+            )
+            .await
+            .unwrap();
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        });
+
+        let mut s = HttpScrapeTarget::new(reqwest::Client::new(), url);
+        let Ok(ScrapeOk::HttpResponse(resp)) = s.call().await else {
+            panic!("Invalid response")
+        };
+        assert_eq!(b"abc", resp.body().as_ref());
+        assert_eq!(Version::HTTP_11, resp.version());
+        assert_eq!(
+            Some(&RemoteAddr(addr, AddrSource::Literal)),
+            resp.extensions().get()
+        );
+        let Some(Trailers(trailers)) = resp.extensions().get() else {
+            panic!("No trailers")
+        };
+        assert_eq!("42", trailers["x-checksum"]);
+    }
+
+    #[tokio::test]
+    async fn fallback_url_answers_if_primary_fails() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/primary"))
+                .respond_with(status_code(503)),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/secondary"))
+                .respond_with(status_code(200)),
+        );
+        let primary = Url::parse(&server.url("/primary").to_string()).unwrap();
+        let secondary = Url::parse(&server.url("/secondary").to_string()).unwrap();
+
+        let mut s = HttpScrapeTarget::new(reqwest::Client::new(), primary)
+            .with_fallback_urls(vec![secondary.clone()])
+            .with_expected_status(vec![StatusCode::OK]);
+        let Ok(ScrapeOk::HttpResponse(resp)) = s.call().await else {
+            panic!("Invalid response")
+        };
+        assert_eq!(Some(&Endpoint(secondary)), resp.extensions().get());
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn compressed_body_is_captured_verbatim() {
+        let server = Server::run();
+        let gzipped = {
+            use std::io::Write;
+            let mut e = flate2::write::GzEncoder::new(vec![], flate2::Compression::fast());
+            e.write_all(b"hello").unwrap();
+            e.finish().unwrap()
+        };
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/"),
+                request::headers(contains(("accept-encoding", "gzip, br"))),
+            ])
+            .times(2)
+            .respond_with(
+                status_code(200)
+                    .insert_header("content-encoding", "gzip")
+                    .body(gzipped.clone()),
+            ),
+        );
+        let url = Url::parse(&server.url("/").to_string()).unwrap();
+
+        let client = client_from_config(&HttpClientConfig {
+            decompress: Some(false),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut s = HttpScrapeTarget::new(client, url.clone());
+        let Ok(ScrapeOk::HttpResponse(resp)) = s.call().await else {
+            panic!("Invalid response")
+        };
+        assert_eq!(gzipped, resp.body().as_ref());
+        assert_eq!("gzip", resp.headers()[http::header::CONTENT_ENCODING]);
+
+        let mut s = HttpScrapeTarget::new(reqwest::Client::new(), url)
+            .with_accept_encoding(Some("gzip, br".to_string()));
+        let Ok(ScrapeOk::HttpResponse(resp)) = s.call().await else {
+            panic!("Invalid response")
+        };
+        assert_eq!(b"hello", resp.body().as_ref());
     }
 }
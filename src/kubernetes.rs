@@ -0,0 +1,336 @@
+//! Discovering HTTP targets from Kubernetes pods and services.
+//!
+//! A [KubernetesSd] lists the pods or services that match a label selector
+//! via the Kubernetes API and turns each of them into an HTTP target, see
+//! [DiscoverySource::Kubernetes](crate::discovery::DiscoverySource::Kubernetes).
+//! Run as a daemonset with [KubernetesSd::node], debugbunny follows the
+//! workloads scheduled onto its node. Requires the `kubernetes` feature.
+//!
+//! The URL of a target is taken from annotations of the pod or service:
+//!
+//! * `debugbunny.io/port`: the port to scrape. Defaults to the first declared
+//!   port. Objects without a port are skipped.
+//! * `debugbunny.io/path`: the path to scrape. Defaults to `/`.
+//! * `debugbunny.io/scrape`: set to `false` to skip the object.
+//!
+//! Only running pods are scraped, via their IP. Services are scraped via their
+//! DNS name. Targets are named `<namespace>/<name>` and carry the labels of
+//! the object as well as `namespace` and `pod` or `service` labels.
+
+use std::{path::PathBuf, time::Duration};
+
+use serde_json::Value;
+use url::Url;
+
+use crate::{
+    config::{Action, ScrapeTargetBuilder, ScrapeTargetConfig},
+    discovery::DiscoveryError,
+};
+
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Pod,
+    Service,
+}
+
+/// Lists pods or services via the Kubernetes API. See the
+/// [module docs](self).
+#[derive(Debug, Clone)]
+pub struct KubernetesSd {
+    role: Role,
+    label_selector: String,
+    namespace: Option<String>,
+    node: Option<String>,
+    interval: Duration,
+    timeout: Option<Duration>,
+    api_server: Option<Url>,
+    token_file: Option<PathBuf>,
+    client: Option<reqwest::Client>,
+}
+
+impl PartialEq for KubernetesSd {
+    fn eq(&self, other: &Self) -> bool {
+        (
+            self.role,
+            &self.label_selector,
+            &self.namespace,
+            &self.node,
+            self.interval,
+            self.timeout,
+            &self.api_server,
+            &self.token_file,
+        ) == (
+            other.role,
+            &other.label_selector,
+            &other.namespace,
+            &other.node,
+            other.interval,
+            other.timeout,
+            &other.api_server,
+            &other.token_file,
+        )
+    }
+}
+
+impl Eq for KubernetesSd {}
+
+impl KubernetesSd {
+    /// Scrape the objects matching `label_selector`, e.g. `app=nginx`, every
+    /// `interval`. By default, the API server and the credentials of the
+    /// service account of the pod debugbunny runs in are used.
+    pub fn new<S: ToString>(role: Role, label_selector: S, interval: Duration) -> Self {
+        Self {
+            role,
+            label_selector: label_selector.to_string(),
+            namespace: None,
+            node: None,
+            interval,
+            timeout: None,
+            api_server: None,
+            token_file: Some(PathBuf::from(SERVICE_ACCOUNT).join("token")),
+            client: None,
+        }
+    }
+
+    /// Only list objects in the given namespace instead of all namespaces.
+    pub fn namespace<S: ToString>(mut self, namespace: S) -> Self {
+        self.namespace = Some(namespace.to_string());
+        self
+    }
+
+    /// Only list pods scheduled onto the given node, e.g. the one from the
+    /// `spec.nodeName` field passed via the downward API. Ignored for
+    /// services.
+    pub fn node<S: ToString>(mut self, node: S) -> Self {
+        self.node = Some(node.to_string());
+        self
+    }
+
+    pub fn timeout(mut self, d: Duration) -> Self {
+        self.timeout = Some(d);
+        self
+    }
+
+    /// Use the given API server instead of the one of the cluster.
+    pub fn api_server(mut self, url: Url) -> Self {
+        self.api_server = Some(url);
+        self
+    }
+
+    /// Send the bearer token read from the given file with every request, or
+    /// no token. The file is read on every request, as tokens are rotated.
+    pub fn token_file(mut self, path: Option<PathBuf>) -> Self {
+        self.token_file = path;
+        self
+    }
+
+    /// Use the given client instead of one trusting the CA of the cluster.
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// List the objects and build a target for each of them.
+    pub async fn discover(&self) -> Result<Vec<ScrapeTargetConfig>, DiscoveryError> {
+        let url = self.list_url()?;
+        let client = match &self.client {
+            Some(client) => client.clone(),
+            None => in_cluster_client(&url).await?,
+        };
+        let mut req = client.get(url.clone());
+        if let Some(path) = &self.token_file {
+            let token = tokio::fs::read_to_string(path)
+                .await
+                .map_err(|e| DiscoveryError::Io(path.clone(), e))?;
+            req = req.bearer_auth(token.trim());
+        }
+        let fetch = async { req.send().await?.error_for_status()?.bytes().await };
+        let body = fetch
+            .await
+            .map_err(|e| DiscoveryError::Http(url.clone(), e))?;
+        let list: Value =
+            serde_json::from_slice(&body).map_err(|e| DiscoveryError::Parse(url, e))?;
+        let items = list["items"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        Ok(items.iter().filter_map(|o| self.target(o)).collect())
+    }
+
+    fn list_url(&self) -> Result<Url, DiscoveryError> {
+        let base = match &self.api_server {
+            Some(url) => url.clone(),
+            None => {
+                let host = std::env::var("KUBERNETES_SERVICE_HOST")
+                    .map_err(|_| DiscoveryError::NotInCluster)?;
+                let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or("443".into());
+                let host = match host.contains(':') {
+                    true => format!("[{host}]"),
+                    false => host,
+                };
+                Url::parse(&format!("https://{host}:{port}/"))
+                    .map_err(|_| DiscoveryError::NotInCluster)?
+            }
+        };
+        let resource = match self.role {
+            Role::Pod => "pods",
+            Role::Service => "services",
+        };
+        let path = match &self.namespace {
+            Some(ns) => format!("api/v1/namespaces/{ns}/{resource}"),
+            None => format!("api/v1/{resource}"),
+        };
+        let mut url = base.join(&path).expect("valid path");
+        url.query_pairs_mut()
+            .append_pair("labelSelector", &self.label_selector);
+        if let (Role::Pod, Some(node)) = (self.role, &self.node) {
+            url.query_pairs_mut()
+                .append_pair("fieldSelector", &format!("spec.nodeName={node}"));
+        }
+        Ok(url)
+    }
+
+    /// The target of a pod or service, if it is to be scraped.
+    fn target(&self, object: &Value) -> Option<ScrapeTargetConfig> {
+        let meta = &object["metadata"];
+        let (name, namespace) = (meta["name"].as_str()?, meta["namespace"].as_str()?);
+        let annotation = |key: &str| meta["annotations"][key].as_str();
+        if annotation("debugbunny.io/scrape") == Some("false") {
+            return None;
+        }
+        let (host, kind, ports) = match self.role {
+            Role::Pod => {
+                if object["status"]["phase"] != "Running" {
+                    return None;
+                }
+                let ports = object["spec"]["containers"]
+                    .as_array()?
+                    .iter()
+                    .filter_map(|c| c["ports"][0]["containerPort"].as_u64())
+                    .next();
+                (
+                    object["status"]["podIP"].as_str()?.to_string(),
+                    "pod",
+                    ports,
+                )
+            }
+            Role::Service => (
+                format!("{name}.{namespace}.svc"),
+                "service",
+                object["spec"]["ports"][0]["port"].as_u64(),
+            ),
+        };
+        let port = match annotation("debugbunny.io/port") {
+            Some(p) => p.parse().ok()?,
+            None => ports?,
+        };
+        let path = annotation("debugbunny.io/path").unwrap_or("/");
+        let host = match host.contains(':') {
+            true => format!("[{host}]"),
+            false => host,
+        };
+        let url = Url::parse(&format!("http://{host}:{port}"))
+            .ok()?
+            .join(path)
+            .ok()?;
+        let mut builder = ScrapeTargetBuilder::new()
+            .name(format!("{namespace}/{name}"))
+            .interval(self.interval)
+            .action(Action::http(url));
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
+        }
+        let labels = meta["labels"].as_object().into_iter().flatten();
+        for (k, v) in labels.filter_map(|(k, v)| Some((k, v.as_str()?))) {
+            builder = builder.label(k, v);
+        }
+        Some(
+            builder
+                .label("namespace", namespace)
+                .label(kind, name)
+                .build(),
+        )
+    }
+}
+
+/// A client trusting the CA of the cluster.
+async fn in_cluster_client(url: &Url) -> Result<reqwest::Client, DiscoveryError> {
+    let path = PathBuf::from(SERVICE_ACCOUNT).join("ca.crt");
+    let pem = tokio::fs::read(&path)
+        .await
+        .map_err(|e| DiscoveryError::Io(path, e))?;
+    reqwest::Certificate::from_pem(&pem)
+        .and_then(|ca| reqwest::Client::builder().add_root_certificate(ca).build())
+        .map_err(|e| DiscoveryError::Http(url.clone(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use httptest::{matchers::*, responders::*, Expectation, Server};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn running_pods_become_targets() {
+        let pod = |name: &str, phase: &str, annotations: serde_json::Value| {
+            serde_json::json!({
+                "metadata": {
+                    "name": name,
+                    "namespace": "web",
+                    "labels": { "app": "nginx" },
+                    "annotations": annotations,
+                },
+                "spec": { "containers": [{ "ports": [{ "containerPort": 8080 }] }] },
+                "status": { "phase": phase, "podIP": "10.0.0.7" },
+            })
+        };
+        let pods = serde_json::json!({ "items": [
+            pod("a", "Running", serde_json::json!({ "debugbunny.io/path": "/status" })),
+            pod("b", "Pending", serde_json::json!({})),
+            pod("c", "Running", serde_json::json!({ "debugbunny.io/scrape": "false" })),
+            pod("d", "Running", serde_json::json!({ "debugbunny.io/port": "9090" })),
+        ]});
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", "/api/v1/namespaces/web/pods"),
+                request::query(url_decoded(contains(("labelSelector", "app=nginx")))),
+                request::query(url_decoded(contains(("fieldSelector", "spec.nodeName=n1")))),
+            ])
+            .respond_with(json_encoded(pods)),
+        );
+        let api = Url::parse(&server.url("/").to_string()).unwrap();
+        let sd = KubernetesSd::new(Role::Pod, "app=nginx", Duration::from_secs(10))
+            .namespace("web")
+            .node("n1")
+            .api_server(api)
+            .token_file(None)
+            .client(reqwest::Client::new());
+
+        let targets = sd.discover().await.unwrap();
+        let urls: Vec<_> = targets
+            .iter()
+            .map(|t| match &t.action {
+                Action::Http { url, .. } => (t.name.clone().unwrap(), url.to_string()),
+                _ => panic!("expected an HTTP target"),
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                (
+                    "web/a".to_string(),
+                    "http://10.0.0.7:8080/status".to_string()
+                ),
+                ("web/d".to_string(), "http://10.0.0.7:9090/".to_string()),
+            ],
+            urls
+        );
+        assert_eq!(
+            Some("nginx"),
+            targets[0].labels.get("app").map(String::as_str)
+        );
+        assert_eq!(Some("a"), targets[0].labels.get("pod").map(String::as_str));
+    }
+}
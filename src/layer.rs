@@ -0,0 +1,263 @@
+//! Composable middleware for result processors.
+//!
+//! Cross-cutting concerns such as redaction, filtering, sampling or
+//! deduplication are implemented as a [ProcessorLayer] that wraps an inner
+//! [ScrapeResultProcessor]. Layers are stacked with a [ProcessorBuilder] around
+//! a base sink, similar to `tower::ServiceBuilder`:
+//!
+//! ```
+//! # use debugbunny::{layer::{FilterLayer, ProcessorBuilder}, result_processor::LogOutputWriter};
+//! let p = ProcessorBuilder::new()
+//!     .layer(FilterLayer::new(|_config, result| result.is_ok()))
+//!     .processor(LogOutputWriter::new(tokio::io::stderr()));
+//! ```
+//!
+//! The first layer added is the outermost one, i.e. it sees results first.
+
+use std::{future::Future, io, sync::Arc};
+
+use crate::{
+    banner::Banner,
+    config::ScrapeTargetConfig,
+    health::Transition,
+    result_processor::{ScrapeResultProcessor, Unprocessed},
+    scrape_target::{ScrapeOk, ScrapeResult},
+};
+
+/// Wraps a processor to add behavior to it.
+pub trait ProcessorLayer<P> {
+    type Processor: ScrapeResultProcessor;
+
+    fn layer(&self, inner: P) -> Self::Processor;
+}
+
+/// The layer that does nothing.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Identity;
+
+impl<P: ScrapeResultProcessor> ProcessorLayer<P> for Identity {
+    type Processor = P;
+
+    fn layer(&self, inner: P) -> P {
+        inner
+    }
+}
+
+/// Two layers applied in sequence, `outer` around `inner`.
+#[derive(Debug, Clone)]
+pub struct Stack<Inner, Outer> {
+    inner: Inner,
+    outer: Outer,
+}
+
+impl<P, Inner, Outer> ProcessorLayer<P> for Stack<Inner, Outer>
+where
+    Inner: ProcessorLayer<P>,
+    Outer: ProcessorLayer<Inner::Processor>,
+{
+    type Processor = Outer::Processor;
+
+    fn layer(&self, p: P) -> Self::Processor {
+        self.outer.layer(self.inner.layer(p))
+    }
+}
+
+/// Stacks layers around a base processor.
+#[derive(Debug, Clone)]
+pub struct ProcessorBuilder<L> {
+    layer: L,
+}
+
+impl ProcessorBuilder<Identity> {
+    pub fn new() -> Self {
+        Self { layer: Identity }
+    }
+}
+
+impl Default for ProcessorBuilder<Identity> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<L> ProcessorBuilder<L> {
+    /// Add a layer. It is applied inside of all previously added layers.
+    pub fn layer<T>(self, layer: T) -> ProcessorBuilder<Stack<T, L>> {
+        ProcessorBuilder {
+            layer: Stack {
+                inner: layer,
+                outer: self.layer,
+            },
+        }
+    }
+
+    /// Wrap `p` in all layers.
+    pub fn processor<P>(&self, p: P) -> L::Processor
+    where
+        L: ProcessorLayer<P>,
+    {
+        self.layer.layer(p)
+    }
+}
+
+/// Only pass results to the inner processor for which the predicate holds.
+pub struct FilterLayer<F> {
+    predicate: Arc<F>,
+}
+
+impl<F> FilterLayer<F>
+where
+    F: Fn(&ScrapeTargetConfig, &ScrapeResult<ScrapeOk>) -> bool + Send + Sync + 'static,
+{
+    pub fn new(predicate: F) -> Self {
+        Self {
+            predicate: Arc::new(predicate),
+        }
+    }
+}
+
+impl<F, P> ProcessorLayer<P> for FilterLayer<F>
+where
+    F: Fn(&ScrapeTargetConfig, &ScrapeResult<ScrapeOk>) -> bool + Send + Sync + 'static,
+    P: ScrapeResultProcessor,
+{
+    type Processor = Filter<F, P>;
+
+    fn layer(&self, inner: P) -> Self::Processor {
+        Filter {
+            predicate: self.predicate.clone(),
+            inner,
+        }
+    }
+}
+
+pub struct Filter<F, P> {
+    predicate: Arc<F>,
+    inner: P,
+}
+
+impl<F, P: Clone> Clone for Filter<F, P> {
+    fn clone(&self) -> Self {
+        Self {
+            predicate: self.predicate.clone(),
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<F, P> ScrapeResultProcessor for Filter<F, P>
+where
+    F: Fn(&ScrapeTargetConfig, &ScrapeResult<ScrapeOk>) -> bool + Send + Sync + 'static,
+    P: ScrapeResultProcessor,
+{
+    fn process(
+        &self,
+        config: &ScrapeTargetConfig,
+        result: ScrapeResult<ScrapeOk>,
+    ) -> impl Future<Output = io::Result<()>> + Send {
+        let forward = (self.predicate)(config, &result);
+        let inner = self.inner.clone();
+        let config = config.clone();
+        async move {
+            if forward {
+                inner.process(&config, result).await
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn process_or_return(
+        &self,
+        config: &ScrapeTargetConfig,
+        result: ScrapeResult<ScrapeOk>,
+    ) -> impl Future<Output = Result<(), Unprocessed>> + Send {
+        let forward = (self.predicate)(config, &result);
+        let inner = self.inner.clone();
+        let config = config.clone();
+        async move {
+            if forward {
+                inner.process_or_return(&config, result).await
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&self) -> impl Future<Output = io::Result<()>> + Send {
+        self.inner.flush()
+    }
+
+    fn shutdown(&self) -> impl Future<Output = io::Result<()>> + Send {
+        self.inner.shutdown()
+    }
+
+    fn process_transition(
+        &self,
+        config: &ScrapeTargetConfig,
+        transition: &Transition,
+    ) -> impl Future<Output = io::Result<()>> + Send {
+        self.inner.process_transition(config, transition)
+    }
+
+    fn process_banner(&self, banner: &Banner) -> impl Future<Output = io::Result<()>> + Send {
+        self.inner.process_banner(banner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::{
+        config::{Action, ScrapeTargetBuilder},
+        scrape_target::ScrapeErr,
+    };
+
+    #[derive(Clone, Default)]
+    struct Counter(Arc<AtomicUsize>);
+
+    impl ScrapeResultProcessor for Counter {
+        async fn process(
+            &self,
+            _config: &ScrapeTargetConfig,
+            _result: ScrapeResult<ScrapeOk>,
+        ) -> io::Result<()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn layers_are_applied_in_order() {
+        let config = ScrapeTargetBuilder::new()
+            .name("t")
+            .interval(Duration::from_secs(1))
+            .action(Action::command("true".to_string()))
+            .build();
+        let counter = Counter::default();
+        let p = ProcessorBuilder::new()
+            .layer(FilterLayer::new(|c, _| c.name.as_deref() == Some("t")))
+            .layer(FilterLayer::new(|_, r| r.is_ok()))
+            .processor(counter.clone());
+
+        p.process(&config, Err(ScrapeErr::Cancelled)).await.unwrap();
+        assert_eq!(0, counter.0.load(Ordering::Relaxed));
+        let other = ScrapeTargetBuilder::new()
+            .interval(Duration::from_secs(1))
+            .action(Action::command("true".to_string()))
+            .build();
+        p.process(&other, Ok(ok())).await.unwrap();
+        assert_eq!(0, counter.0.load(Ordering::Relaxed));
+        p.process(&config, Ok(ok())).await.unwrap();
+        assert_eq!(1, counter.0.load(Ordering::Relaxed));
+    }
+
+    fn ok() -> ScrapeOk {
+        ScrapeOk::HttpResponse(http::Response::new(Default::default()))
+    }
+}
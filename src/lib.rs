@@ -32,10 +32,52 @@
 //! +--------------------------------------------+
 //! ```
 
+pub mod admin;
+pub mod anomaly;
+pub mod banner;
+pub mod blocking_writer;
+pub mod body_spool;
+pub mod check;
 pub mod chunks;
 pub mod command;
 pub mod config;
 pub mod debugbunny;
+pub mod decoder;
+pub mod delta;
+pub mod derive;
+pub mod dictionary;
+pub mod discovery;
+pub mod disk;
+pub mod dns;
+pub mod encryption;
+pub mod error_dedup;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod health;
+pub mod hook;
 pub mod http;
+#[cfg(feature = "kubernetes")]
+pub mod kubernetes;
+pub mod layer;
+pub mod memory;
+pub mod netdev;
+pub mod notifier;
+pub mod observer;
+pub mod output_dir;
+pub mod parse;
+pub mod preflight;
+pub mod process;
 pub mod result_processor;
+pub mod retry_queue;
+#[cfg(feature = "runtime-metrics")]
+pub mod runtime_metrics;
 pub mod scrape_target;
+pub mod signing;
+#[cfg(feature = "dns-sd")]
+pub mod srv;
+pub mod state;
+pub mod summary;
+pub mod template;
+pub mod tls;
+#[cfg(target_os = "linux")]
+pub mod vsock;
@@ -1,4 +1,72 @@
+use std::process::ExitCode;
+
+use debugbunny::config::{Config, LabelSelector};
+
+const USAGE: &str = "Usage: debugbunny <command>
+
+Commands:
+  schema              Print the JSON schema of the config format
+  preflight [--only <tag>]... [--exclude <tag>]... <config>
+                      Call each target once and report problems
+
+Options:
+  --only <tag>        Only enable targets with the tag, e.g. role=database
+  --exclude <tag>     Disable targets with the tag";
+
 #[tokio::main]
-async fn main() {
-    // ...
+async fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("schema") => {
+            let schema = Config::json_schema();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&schema).expect("can't fail")
+            );
+            ExitCode::SUCCESS
+        }
+        Some("preflight") => {
+            let Some((path, only, exclude)) = parse_tags(&args[1..]) else {
+                eprintln!("{USAGE}");
+                return ExitCode::FAILURE;
+            };
+            let mut config = match Config::load(path) {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("{e}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            config.retain_tagged(&only, &exclude);
+            let report = config.preflight().await;
+            println!("{report}");
+            if report.is_ok() {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        _ => {
+            eprintln!("{USAGE}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+type Tags = Vec<LabelSelector>;
+
+/// Split `args` into the config path and the tags of `--only` and
+/// `--exclude`. Returns `None` if the arguments are invalid.
+fn parse_tags(args: &[String]) -> Option<(&str, Tags, Tags)> {
+    let (mut path, mut only, mut exclude) = (None, vec![], vec![]);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--only" => only.push(args.next()?.parse().ok()?),
+            "--exclude" => exclude.push(args.next()?.parse().ok()?),
+            _ if path.is_none() => path = Some(arg.as_str()),
+            _ => return None,
+        }
+    }
+    Some((path?, only, exclude))
 }
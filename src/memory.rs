@@ -0,0 +1,290 @@
+//! Accounting of scrape bodies held in memory.
+//!
+//! A [MemoryBudget] is shared between all scrape targets, and every call of a
+//! target runs within [MemoryBudget::account]. Before a service of the call
+//! starts to collect a body, it waits in [admit] until the budget has room
+//! for an estimate of the body, the size of the previous body of the target.
+//! While the body is collected, every piece of it is [charge]d as it arrives.
+//! If a piece does not fit into the budget anymore, the call fails with
+//! [ScrapeErr::MemoryExhausted] and its partial body is dropped. Bodies are
+//! not spooled to disk instead. Once the call has finished, the bytes of its
+//! body stay reserved until its result has been processed.
+//!
+//! Thus, the limit is a hard limit for the bodies of all calls in flight and
+//! all results being processed, however many calls are admitted at once. It
+//! covers the bodies of HTTP responses and the output of commands. Copies
+//! made while processing a result, e.g. compressed chunks, and structured
+//! results are not accounted for.
+
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::Notify;
+
+use crate::scrape_target::ScrapeErr;
+
+tokio::task_local! {
+    /// The account of the call that is currently being polled.
+    static CALL: CallAccount;
+}
+
+#[derive(Clone)]
+pub struct MemoryBudget {
+    inner: Arc<BudgetInner>,
+}
+
+struct BudgetInner {
+    limit: usize,
+    in_use: AtomicUsize,
+    released: Notify,
+}
+
+#[derive(Clone)]
+struct CallAccount {
+    budget: MemoryBudget,
+    estimate: usize,
+    /// Set once the call has been admitted.
+    reservation: Arc<Mutex<Option<Reservation>>>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            inner: Arc::new(BudgetInner {
+                limit,
+                in_use: AtomicUsize::new(0),
+                released: Notify::new(),
+            }),
+        }
+    }
+
+    pub fn unlimited() -> Self {
+        Self::new(usize::MAX)
+    }
+
+    pub fn limit(&self) -> usize {
+        self.inner.limit
+    }
+
+    /// The number of bytes that are currently reserved.
+    pub fn in_use(&self) -> usize {
+        self.inner.in_use.load(Ordering::Acquire)
+    }
+
+    /// Run `call` such that the bodies collected by the services it calls are
+    /// charged against this budget, see the [module docs](self). `estimate`
+    /// is the expected size of the body.
+    ///
+    /// Returns the output of `call` together with the reservation of its
+    /// body, if a service admitted the call. Hold the reservation until the
+    /// body has been processed.
+    pub async fn account<F: Future>(
+        &self,
+        estimate: usize,
+        call: F,
+    ) -> (F::Output, Option<Reservation>) {
+        let account = CallAccount {
+            budget: self.clone(),
+            // Otherwise, the call would never be admitted.
+            estimate: estimate.min(self.inner.limit),
+            reservation: Default::default(),
+        };
+        let output = CALL.scope(account.clone(), call).await;
+        let reservation = account.reservation.lock().unwrap().take();
+        (output, reservation.map(Reservation::settle))
+    }
+
+    /// Reserve `bytes` as soon as they fit into the budget.
+    async fn reserve(&self, bytes: usize) -> Reservation {
+        loop {
+            // The future must be created before checking the condition, such
+            // that we do not miss a release in between.
+            let released = self.inner.released.notified();
+            if self.try_add(bytes) {
+                return Reservation {
+                    budget: self.clone(),
+                    bytes,
+                    used: 0,
+                };
+            }
+            released.await;
+        }
+    }
+
+    /// Add `bytes` to the bytes in use, unless that would exceed the limit.
+    fn try_add(&self, bytes: usize) -> bool {
+        self.inner
+            .in_use
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                n.checked_add(bytes).filter(|n| *n <= self.inner.limit)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, bytes: usize) {
+        if bytes > 0 {
+            self.inner.in_use.fetch_sub(bytes, Ordering::AcqRel);
+            self.inner.released.notify_waiters();
+        }
+    }
+}
+
+impl Default for MemoryBudget {
+    fn default() -> Self {
+        Self::unlimited()
+    }
+}
+
+/// Wait until the budget of the current call has room for the estimated size
+/// of its body. Services call this before they start to collect a body, such
+/// that the wait counts against the timeout of the call. Does nothing outside
+/// of [MemoryBudget::account] and if the call has been admitted already.
+pub async fn admit() {
+    let Ok(account) = CALL.try_with(CallAccount::clone) else {
+        return;
+    };
+    if account.reservation.lock().unwrap().is_some() {
+        return;
+    }
+    let reservation = account.budget.reserve(account.estimate).await;
+    *account.reservation.lock().unwrap() = Some(reservation);
+}
+
+/// Charge `bytes` of a body that was just received to the current call. Fails
+/// with [ScrapeErr::MemoryExhausted] if they do not fit into the budget.
+/// Does nothing outside of [MemoryBudget::account].
+pub fn charge(bytes: usize) -> Result<(), ScrapeErr> {
+    let Ok(account) = CALL.try_with(CallAccount::clone) else {
+        return Ok(());
+    };
+    let mut reservation = account.reservation.lock().unwrap();
+    let reservation = reservation.get_or_insert_with(|| Reservation {
+        budget: account.budget.clone(),
+        bytes: 0,
+        used: 0,
+    });
+    let used = reservation.used.saturating_add(bytes);
+    if used > reservation.bytes {
+        if !reservation.budget.try_add(used - reservation.bytes) {
+            return Err(ScrapeErr::MemoryExhausted {
+                received: reservation.used,
+            });
+        }
+        reservation.bytes = used;
+    }
+    reservation.used = used;
+    Ok(())
+}
+
+/// Bytes reserved until the reservation is dropped.
+pub struct Reservation {
+    budget: MemoryBudget,
+    bytes: usize,
+    /// The bytes charged so far, at most `bytes`.
+    used: usize,
+}
+
+impl Reservation {
+    /// The number of bytes charged to the call.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    /// Release the part of the estimate that was not used.
+    fn settle(mut self) -> Self {
+        self.budget.release(self.bytes - self.used);
+        self.bytes = self.used;
+        self
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn exhausted_budget_delays_admission_until_release() {
+        let budget = MemoryBudget::new(100);
+        let (r, reservation) = budget
+            .account(0, async {
+                admit().await;
+                charge(80)
+            })
+            .await;
+        r.unwrap();
+        let reservation = reservation.unwrap();
+        assert_eq!(80, reservation.used());
+        assert_eq!(80, budget.in_use());
+
+        // The estimate does not fit next to the body being processed.
+        let waiter = tokio::spawn({
+            let budget = budget.clone();
+            async move { budget.account(50, admit()).await.1.unwrap() }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+
+        drop(reservation);
+        let reservation = tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        // Nothing was charged, so the estimate has been released.
+        assert_eq!(0, reservation.used());
+        assert_eq!(0, budget.in_use());
+    }
+
+    #[tokio::test]
+    async fn concurrent_bodies_do_not_exceed_the_budget() {
+        let budget = MemoryBudget::new(1000);
+        let peak = Arc::new(AtomicUsize::new(0));
+        let calls: Vec<_> = (0..8)
+            .map(|_| {
+                let budget = budget.clone();
+                let peak = peak.clone();
+                tokio::spawn(async move {
+                    let call = async {
+                        admit().await;
+                        for _ in 0..5 {
+                            charge(100)?;
+                            peak.fetch_max(budget.in_use(), Ordering::Relaxed);
+                            tokio::task::yield_now().await;
+                        }
+                        Ok::<_, ScrapeErr>(())
+                    };
+                    let (r, reservation) = budget.account(300, call).await;
+                    if r.is_ok() {
+                        // Hold the body while it is being processed.
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                    drop(reservation);
+                    r
+                })
+            })
+            .collect();
+        let mut results = vec![];
+        for call in calls {
+            results.push(call.await.unwrap());
+        }
+
+        assert!(peak.load(Ordering::Relaxed) <= 1000);
+        assert!(results.iter().any(Result::is_ok));
+        assert!(results
+            .iter()
+            .any(|r| matches!(r, Err(ScrapeErr::MemoryExhausted { .. }))));
+        assert_eq!(0, budget.in_use());
+    }
+}
@@ -0,0 +1,215 @@
+//! Statistics of network interfaces.
+//!
+//! A [NetDevCollector] snapshots the counters of network interfaces from
+//! `/sys/class/net` as structured output. Optionally, it emits the deltas since
+//! the previous scrape instead of the absolute values. Counters of an
+//! interface that was not seen before, or whose counters were reset, are
+//! emitted as they are. Entries of `/sys/class/net` without counters, like
+//! `bonding_masters`, and interfaces that are gone are skipped.
+//!
+//! The counters are only available on Linux.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use serde::Serialize;
+
+use crate::scrape_target::{FutureScrapeResult, ScrapeOk, ScrapeService};
+
+const COUNTERS: [&str; 6] = [
+    "rx_bytes",
+    "tx_bytes",
+    "rx_errors",
+    "tx_errors",
+    "rx_dropped",
+    "tx_dropped",
+];
+
+/// The counters of an interface, keyed by their name in sysfs.
+pub type Counters = BTreeMap<&'static str, u64>;
+
+pub struct NetDevCollector {
+    root: PathBuf,
+    interfaces: Vec<String>,
+    /// The counters of the previous scrape, if deltas are to be emitted.
+    previous: Option<Arc<Mutex<BTreeMap<String, Counters>>>>,
+}
+
+#[derive(Debug, Serialize)]
+struct Interface {
+    name: String,
+    #[serde(flatten)]
+    counters: Counters,
+}
+
+impl NetDevCollector {
+    /// Snapshot the given interfaces, or all interfaces if none are given.
+    pub fn new(interfaces: Vec<String>) -> Self {
+        Self {
+            root: PathBuf::from("/sys/class/net"),
+            interfaces,
+            previous: None,
+        }
+    }
+
+    /// Emit the deltas since the previous scrape.
+    pub fn deltas(mut self) -> Self {
+        self.previous = Some(Default::default());
+        self
+    }
+
+    #[cfg(test)]
+    fn with_root(mut self, root: PathBuf) -> Self {
+        self.root = root;
+        self
+    }
+}
+
+impl ScrapeService for NetDevCollector {
+    type Response = ScrapeOk;
+
+    fn call(&mut self) -> FutureScrapeResult<ScrapeOk> {
+        let root = self.root.clone();
+        let interfaces = self.interfaces.clone();
+        let previous = self.previous.clone();
+        Box::pin(async move {
+            let mut snapshot = read_counters(root, interfaces).await?;
+            if let Some(previous) = previous {
+                let mut previous = previous.lock().unwrap();
+                let current = snapshot.clone();
+                for (name, counters) in &mut snapshot {
+                    if let Some(p) = previous.get(name) {
+                        subtract(counters, p);
+                    }
+                }
+                *previous = current;
+            }
+            let interfaces: Vec<_> = snapshot
+                .into_iter()
+                .map(|(name, counters)| Interface { name, counters })
+                .collect();
+            Ok(ScrapeOk::Structured(serde_json::json!({
+                "interfaces": interfaces
+            })))
+        })
+    }
+}
+
+async fn read_counters(
+    root: PathBuf,
+    mut interfaces: Vec<String>,
+) -> io::Result<BTreeMap<String, Counters>> {
+    if interfaces.is_empty() {
+        let mut dir = tokio::fs::read_dir(&root).await?;
+        while let Some(e) = dir.next_entry().await? {
+            if tokio::fs::metadata(e.path().join("statistics"))
+                .await
+                .is_ok()
+            {
+                interfaces.push(e.file_name().to_string_lossy().into_owned());
+            }
+        }
+    }
+    let mut snapshot = BTreeMap::new();
+    for name in interfaces {
+        let statistics = root.join(&name).join("statistics");
+        match read_interface(&statistics).await {
+            Ok(counters) => {
+                snapshot.insert(name, counters);
+            }
+            // The interface was removed in the meantime.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(snapshot)
+}
+
+async fn read_interface(statistics: &Path) -> io::Result<Counters> {
+    let mut counters = Counters::new();
+    for counter in COUNTERS {
+        let value = tokio::fs::read_to_string(statistics.join(counter)).await?;
+        let value = value
+            .trim()
+            .parse()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        counters.insert(counter, value);
+    }
+    Ok(counters)
+}
+
+/// Subtract the previous counters, unless a counter was reset.
+fn subtract(counters: &mut Counters, previous: &Counters) {
+    if counters
+        .iter()
+        .any(|(k, v)| previous.get(k).is_some_and(|p| p > v))
+    {
+        return;
+    }
+    for (k, v) in counters.iter_mut() {
+        *v -= previous.get(k).copied().unwrap_or_default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_counters(root: &std::path::Path, rx_bytes: u64) {
+        let statistics = root.join("eth0").join("statistics");
+        std::fs::create_dir_all(&statistics).unwrap();
+        for counter in COUNTERS {
+            std::fs::write(statistics.join(counter), "1\n").unwrap();
+        }
+        std::fs::write(statistics.join("rx_bytes"), format!("{rx_bytes}\n")).unwrap();
+    }
+
+    #[tokio::test]
+    async fn deltas_are_emitted() {
+        let root = std::env::temp_dir().join(format!("debugbunny-netdev-{}", std::process::id()));
+        write_counters(&root, 100);
+        let mut c = NetDevCollector::new(vec![])
+            .with_root(root.clone())
+            .deltas();
+        let rx_bytes = |r| match r {
+            Ok(ScrapeOk::Structured(v)) => v["interfaces"][0]["rx_bytes"].as_u64().unwrap(),
+            _ => panic!("expected structured output"),
+        };
+
+        assert_eq!(100, rx_bytes(c.call().await));
+        write_counters(&root, 150);
+        assert_eq!(50, rx_bytes(c.call().await));
+        // The counters were reset.
+        write_counters(&root, 20);
+        assert_eq!(20, rx_bytes(c.call().await));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn entries_without_counters_are_skipped() {
+        let root =
+            std::env::temp_dir().join(format!("debugbunny-netdev-entries-{}", std::process::id()));
+        write_counters(&root, 100);
+        std::fs::write(root.join("bonding_masters"), "\n").unwrap();
+        let names = |r| match r {
+            Ok(ScrapeOk::Structured(v)) => v["interfaces"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|i| i["name"].as_str().unwrap().to_string())
+                .collect::<Vec<_>>(),
+            _ => panic!("expected structured output"),
+        };
+
+        let mut all = NetDevCollector::new(vec![]).with_root(root.clone());
+        assert_eq!(vec!["eth0"], names(all.call().await));
+        let mut gone =
+            NetDevCollector::new(vec!["eth0".into(), "eth1".into()]).with_root(root.clone());
+        assert_eq!(vec!["eth0"], names(gone.call().await));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
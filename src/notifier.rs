@@ -0,0 +1,232 @@
+//! Notifications about targets that start or stop failing.
+//!
+//! A [WebhookNotifier] is a [ScrapeObserver] that tracks the health of each
+//! target, see [crate::health]. When a target turns from healthy to failing
+//! or back, it POSTs a [Notification] as JSON to a URL, such that existing
+//! webhook receivers can page someone. By default, every failure after a
+//! success is a transition, see [WebhookNotifier::policy].
+//!
+//! ```no_run
+//! # use debugbunny::{debugbunny::DebugBunny, notifier::WebhookNotifier, result_processor::LogOutputWriter};
+//! # async fn run() {
+//! let url = "https://alerts.example.com/hooks/debugbunny".parse().unwrap();
+//! let debugbunny = DebugBunny::builder()
+//!     .observer(WebhookNotifier::new(url))
+//!     .start_scraping(vec![], LogOutputWriter::new(tokio::io::stderr()))
+//!     .await
+//!     .unwrap();
+//! # }
+//! ```
+//!
+//! Notifications are sent one at a time, in the order of the transitions. A
+//! notification that cannot be delivered is logged and dropped.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use url::Url;
+
+use crate::{
+    config::ScrapeTargetConfig,
+    health::{Health, HealthPolicy, HealthTracker, Transition},
+    observer::ScrapeObserver,
+    result_processor::target_key,
+    scrape_target::{ScrapeOk, ScrapeResult},
+};
+
+/// POSTs a [Notification] whenever a target turns healthy or failing. Clones
+/// share their state. See the [module docs](self).
+#[derive(Clone)]
+pub struct WebhookNotifier {
+    url: Url,
+    client: reqwest::Client,
+    timeout: Duration,
+    policy: HealthPolicy,
+    trackers: Arc<Mutex<HashMap<String, HealthTracker>>>,
+    sender: Arc<OnceLock<mpsc::UnboundedSender<Notification>>>,
+}
+
+/// The payload of a webhook.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Notification {
+    /// The name of the target, or its config as JSON if it has none.
+    pub target: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// The state the target turned into.
+    pub health: Health,
+    /// The error of the failed scrape, if the target is failing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+impl WebhookNotifier {
+    pub fn new(url: Url) -> Self {
+        Self {
+            url,
+            client: reqwest::Client::new(),
+            timeout: Duration::from_secs(10),
+            policy: HealthPolicy::new(),
+            trackers: Default::default(),
+            sender: Default::default(),
+        }
+    }
+
+    /// The client to send notifications with, e.g. with custom TLS settings.
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = client;
+        self
+    }
+
+    /// How long to wait for the receiver of a notification. Defaults to 10
+    /// seconds.
+    pub fn timeout(mut self, d: Duration) -> Self {
+        self.timeout = d;
+        self
+    }
+
+    /// When targets change their state, e.g. to only notify about targets
+    /// that failed several times in a row. Defaults to [HealthPolicy::new].
+    pub fn policy(mut self, policy: HealthPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// The transition of `config` caused by `result`, if any.
+    fn transition(
+        &self,
+        config: &ScrapeTargetConfig,
+        result: &ScrapeResult<ScrapeOk>,
+    ) -> Option<Transition> {
+        let mut trackers = self.trackers.lock().unwrap();
+        trackers
+            .entry(target_key(config))
+            .or_insert_with(|| HealthTracker::new(self.policy))
+            .record(result)
+    }
+
+    fn send(&self, n: Notification) {
+        let sender = self.sender.get_or_init(|| {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            tokio::spawn(deliver(
+                receiver,
+                self.client.clone(),
+                self.url.clone(),
+                self.timeout,
+            ));
+            sender
+        });
+        let _ = sender.send(n);
+    }
+}
+
+impl ScrapeObserver for WebhookNotifier {
+    fn on_finish(
+        &self,
+        config: &ScrapeTargetConfig,
+        _duration: Duration,
+        result: &ScrapeResult<ScrapeOk>,
+    ) {
+        let Some(transition) = self.transition(config, result) else {
+            return;
+        };
+        tracing::info!(
+            target = target_key(config),
+            %transition,
+            "target health changed"
+        );
+        self.send(Notification {
+            target: target_key(config),
+            group: config.group.clone(),
+            health: transition.health,
+            error: transition.error,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        });
+    }
+}
+
+async fn deliver(
+    mut receiver: mpsc::UnboundedReceiver<Notification>,
+    client: reqwest::Client,
+    url: Url,
+    timeout: Duration,
+) {
+    while let Some(n) = receiver.recv().await {
+        let sent = client
+            .post(url.clone())
+            .timeout(timeout)
+            .json(&n)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        if let Err(e) = sent {
+            tracing::warn!(target = n.target, error = %e, "could not deliver notification");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use httptest::{matchers::*, responders::*, Expectation, Server};
+
+    use super::*;
+    use crate::{
+        config::{Action, ScrapeTargetBuilder},
+        scrape_target::ScrapeErr,
+    };
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Received {
+        target: String,
+        health: Health,
+    }
+
+    #[tokio::test]
+    async fn transitions_are_posted() {
+        let mut server = Server::run();
+        for health in [Health::Failing, Health::Healthy] {
+            let received = Received {
+                target: "t".to_string(),
+                health,
+            };
+            server.expect(
+                Expectation::matching(all_of![
+                    request::method_path("POST", "/hook"),
+                    request::body(json_decoded(eq(received))),
+                ])
+                .times(1)
+                .respond_with(status_code(200)),
+            );
+        }
+
+        let notifier = WebhookNotifier::new(server.url("/hook").to_string().parse().unwrap());
+        let c = ScrapeTargetBuilder::new()
+            .name("t")
+            .interval(Duration::from_secs(1))
+            .action(Action::shell("true"))
+            .build();
+        let results = [
+            Ok(ScrapeOk::Structured(serde_json::Value::Null)),
+            Err(ScrapeErr::CommandNotFound("x".to_string())),
+            Err(ScrapeErr::Cancelled),
+            Err(ScrapeErr::CommandNotFound("x".to_string())),
+            Ok(ScrapeOk::Structured(serde_json::Value::Null)),
+            Ok(ScrapeOk::Structured(serde_json::Value::Null)),
+        ];
+        for r in &results {
+            notifier.on_finish(&c, Duration::ZERO, r);
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        server.verify_and_clear();
+    }
+}
@@ -0,0 +1,240 @@
+//! Callbacks around every scrape.
+//!
+//! A [ScrapeObserver] is notified when a scrape starts and when it finishes,
+//! together with its duration and outcome. This allows embedders to feed their
+//! own metrics systems. The [DurationHistogram] is a simple observer that
+//! records the scrape durations per target.
+//!
+//! The duration covers the execution of the action including the timeout, but
+//! not the time spent waiting for the schedule.
+//!
+//! Further, every observed scrape runs in a `scrape` tracing span with the
+//! name of the target and the number of the attempt.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+use tracing::{debug, field, Instrument};
+
+use crate::{
+    config::ScrapeTargetConfig,
+    scrape_target::{FutureScrapeResult, ScrapeOk, ScrapeResult, ScrapeService},
+};
+
+/// Callbacks for scrapes of scheduled and unscheduled calls. The methods are
+/// called on the task executing the scrape, so they should return quickly.
+pub trait ScrapeObserver: Send + Sync {
+    fn on_start(&self, _config: &ScrapeTargetConfig) {}
+
+    fn on_finish(
+        &self,
+        _config: &ScrapeTargetConfig,
+        _duration: Duration,
+        _result: &ScrapeResult<ScrapeOk>,
+    ) {
+    }
+    /// The target was stopped because its processor failed, see
+    /// [ProcessorErrorPolicy::StopTarget](crate::debugbunny::ProcessorErrorPolicy::StopTarget).
+    fn on_target_stopped(&self, _config: &ScrapeTargetConfig, _error: &std::io::Error) {}
+}
+
+/// A scrape service that notifies observers about the calls to the inner
+/// service.
+pub struct Observed<S> {
+    inner: S,
+    config: Arc<ScrapeTargetConfig>,
+    observers: Arc<[Arc<dyn ScrapeObserver>]>,
+    attempt: u64,
+}
+
+impl<S> Observed<S> {
+    pub fn new(
+        inner: S,
+        config: ScrapeTargetConfig,
+        observers: Arc<[Arc<dyn ScrapeObserver>]>,
+    ) -> Self {
+        Self {
+            inner,
+            config: Arc::new(config),
+            observers,
+            attempt: 0,
+        }
+    }
+}
+
+impl<S> ScrapeService for Observed<S>
+where
+    S: ScrapeService<Response = ScrapeOk>,
+{
+    type Response = ScrapeOk;
+
+    fn call(&mut self) -> FutureScrapeResult<ScrapeOk> {
+        self.observe(S::call)
+    }
+
+    fn call_unscheduled(&mut self) -> FutureScrapeResult<ScrapeOk> {
+        self.observe(S::call_unscheduled)
+    }
+}
+
+impl<S> Observed<S>
+where
+    S: ScrapeService<Response = ScrapeOk>,
+{
+    fn observe(
+        &mut self,
+        call: fn(&mut S) -> FutureScrapeResult<ScrapeOk>,
+    ) -> FutureScrapeResult<ScrapeOk> {
+        self.attempt += 1;
+        let span = tracing::debug_span!(
+            "scrape",
+            target = self.config.name.as_deref().unwrap_or_default(),
+            attempt = self.attempt,
+            duration_ms = field::Empty,
+        );
+        let call = span.in_scope(|| call(&mut self.inner));
+        let config = self.config.clone();
+        let observers = self.observers.clone();
+        Box::pin(
+            async move {
+                observers.iter().for_each(|o| o.on_start(&config));
+                let start = Instant::now();
+                let r = call.await;
+                let duration = start.elapsed();
+                tracing::Span::current().record("duration_ms", duration.as_millis() as u64);
+                match &r {
+                    Ok(_) => debug!("scrape succeeded"),
+                    Err(e) => debug!(error = %e, "scrape failed"),
+                }
+                observers
+                    .iter()
+                    .for_each(|o| o.on_finish(&config, duration, &r));
+                r
+            }
+            .instrument(span),
+        )
+    }
+}
+
+/// Records scrape durations per target in buckets with the given upper
+/// bounds. Unnamed targets are recorded under the empty name.
+#[derive(Clone)]
+pub struct DurationHistogram {
+    bounds: Arc<[Duration]>,
+    histograms: Arc<Mutex<BTreeMap<String, Histogram>>>,
+}
+
+/// A snapshot of the durations of a target. `counts[i]` is the number of
+/// scrapes that took at most `bounds[i]` (cumulative, like Prometheus
+/// histograms). Scrapes that took longer than all bounds are only counted in
+/// `count`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    pub bounds: Arc<[Duration]>,
+    pub counts: Vec<u64>,
+    pub count: u64,
+    pub errors: u64,
+    pub sum: Duration,
+}
+
+impl DurationHistogram {
+    pub fn new(mut bounds: Vec<Duration>) -> Self {
+        bounds.sort();
+        Self {
+            bounds: bounds.into(),
+            histograms: Default::default(),
+        }
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, Histogram> {
+        self.histograms.lock().unwrap().clone()
+    }
+}
+
+impl Default for DurationHistogram {
+    /// Buckets from 10ms to 10s.
+    fn default() -> Self {
+        Self::new(
+            [10, 50, 100, 250, 500, 1000, 2500, 5000, 10000]
+                .into_iter()
+                .map(Duration::from_millis)
+                .collect(),
+        )
+    }
+}
+
+impl ScrapeObserver for DurationHistogram {
+    fn on_finish(
+        &self,
+        config: &ScrapeTargetConfig,
+        duration: Duration,
+        result: &ScrapeResult<ScrapeOk>,
+    ) {
+        let mut histograms = self.histograms.lock().unwrap();
+        let h = histograms
+            .entry(config.name.clone().unwrap_or_default())
+            .or_insert_with(|| Histogram {
+                bounds: self.bounds.clone(),
+                counts: vec![0; self.bounds.len()],
+                count: 0,
+                errors: 0,
+                sum: Duration::ZERO,
+            });
+        for (bound, count) in h.bounds.iter().zip(h.counts.iter_mut()) {
+            if duration <= *bound {
+                *count += 1;
+            }
+        }
+        h.count += 1;
+        h.errors += u64::from(result.is_err());
+        h.sum += duration;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{Action, ScrapeTargetBuilder},
+        scrape_target::ScrapeErr,
+    };
+
+    struct Sleep(Duration);
+
+    impl ScrapeService for Sleep {
+        type Response = ScrapeOk;
+
+        fn call(&mut self) -> FutureScrapeResult<ScrapeOk> {
+            let d = self.0;
+            Box::pin(async move {
+                tokio::time::sleep(d).await;
+                Err(ScrapeErr::Cancelled)
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn durations_are_recorded() {
+        let config = ScrapeTargetBuilder::new()
+            .name("t")
+            .interval(Duration::from_secs(1))
+            .action(Action::command("true".to_string()))
+            .build();
+        let h = DurationHistogram::new(vec![Duration::from_millis(10), Duration::from_secs(10)]);
+        let mut s = Observed::new(
+            Sleep(Duration::from_millis(50)),
+            config,
+            Arc::from([Arc::new(h.clone()) as Arc<dyn ScrapeObserver>]),
+        );
+        let _ = s.call().await;
+
+        let h = &h.snapshot()["t"];
+        assert_eq!(vec![0, 1], h.counts);
+        assert_eq!((1, 1), (h.count, h.errors));
+        assert!(h.sum >= Duration::from_millis(50));
+    }
+}
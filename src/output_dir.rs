@@ -0,0 +1,227 @@
+//! Writing the records of each target to a file of its own.
+//!
+//! An [OutputDir] writes the records of a target to `<dir>/<target>.ndjson` in
+//! the format of [LogOutputWriter]. Unlike one interleaved stream, such a
+//! directory can be handed over as is, e.g. to archive the diagnostics
+//! collected during an incident.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    sync::Mutex,
+};
+
+use crate::{
+    banner::Banner,
+    config::ScrapeTargetConfig,
+    encryption::PayloadKey,
+    health::Transition,
+    result_processor::{LogOutputWriter, ScrapeResultProcessor, Unprocessed},
+    scrape_target::{ScrapeOk, ScrapeResult},
+    signing::RecordSigner,
+};
+
+/// A processor that writes the records of each target to a file of its own in
+/// a directory. Files are opened for appending, such that records of earlier
+/// runs are kept.
+#[derive(Clone)]
+pub struct OutputDir {
+    dir: PathBuf,
+    key: Option<PayloadKey>,
+    signer: Option<RecordSigner>,
+    writers: Arc<Mutex<HashMap<PathBuf, LogOutputWriter<File>>>>,
+}
+
+impl OutputDir {
+    /// The directory is created with the first record, if needed.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self {
+            dir: dir.into(),
+            key: None,
+            signer: None,
+            writers: Default::default(),
+        }
+    }
+
+    /// See [LogOutputWriter::with_encryption].
+    pub fn with_encryption(mut self, key: PayloadKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// See [LogOutputWriter::with_signer].
+    pub fn with_signer(mut self, signer: RecordSigner) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// The file the records of the target are written to. Bytes of the name
+    /// other than ASCII alphanumerics, `-`, `_` and `.` are percent-encoded,
+    /// e.g. `b/c` as `b%2Fc`, such that different names map to different
+    /// files. A leading `_` is encoded as well, as it marks the files of
+    /// debugbunny itself. Unnamed targets are identified by a digest of their
+    /// config.
+    pub fn path(&self, config: &ScrapeTargetConfig) -> PathBuf {
+        let name = match &config.name {
+            Some(name) => name
+                .bytes()
+                .enumerate()
+                .map(|(i, b)| match b {
+                    b'_' if i == 0 => "%5F".to_string(),
+                    b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => {
+                        char::from(b).to_string()
+                    }
+                    _ => format!("%{b:02X}"),
+                })
+                .collect(),
+            None => {
+                let config = serde_json::to_vec(config).expect("can't fail");
+                format!("unnamed-{}", &hex::encode(Sha256::digest(config))[..16])
+            }
+        };
+        self.dir.join(format!("{name}.ndjson"))
+    }
+
+    async fn writer(&self, path: &Path) -> io::Result<LogOutputWriter<File>> {
+        let mut writers = self.writers.lock().await;
+        if let Some(w) = writers.get(path) {
+            return Ok(w.clone());
+        }
+        fs::create_dir_all(&self.dir).await?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let mut w = LogOutputWriter::new(file);
+        if let Some(key) = &self.key {
+            w = w.with_encryption(key.clone());
+        }
+        if let Some(signer) = &self.signer {
+            w = w.with_signer(signer.clone());
+        }
+        writers.insert(path.to_owned(), w.clone());
+        Ok(w)
+    }
+}
+
+impl ScrapeResultProcessor for OutputDir {
+    fn process(
+        &self,
+        config: &ScrapeTargetConfig,
+        result: ScrapeResult<ScrapeOk>,
+    ) -> impl Future<Output = io::Result<()>> + Send {
+        let this = self.clone();
+        let config = config.clone();
+        async move {
+            let writer = this.writer(&this.path(&config)).await?;
+            writer.process(&config, result).await
+        }
+    }
+
+    fn process_or_return(
+        &self,
+        config: &ScrapeTargetConfig,
+        result: ScrapeResult<ScrapeOk>,
+    ) -> impl Future<Output = Result<(), Unprocessed>> + Send {
+        let this = self.clone();
+        let config = config.clone();
+        async move {
+            match this.writer(&this.path(&config)).await {
+                Ok(writer) => writer.process_or_return(&config, result).await,
+                Err(error) => Err(Unprocessed { error, result }),
+            }
+        }
+    }
+
+    async fn flush(&self) -> io::Result<()> {
+        for w in self.writers.lock().await.values() {
+            w.flush().await?;
+        }
+        Ok(())
+    }
+
+    async fn shutdown(&self) -> io::Result<()> {
+        for w in self.writers.lock().await.values() {
+            w.shutdown().await?;
+        }
+        Ok(())
+    }
+
+    fn process_transition(
+        &self,
+        config: &ScrapeTargetConfig,
+        transition: &Transition,
+    ) -> impl Future<Output = io::Result<()>> + Send {
+        let this = self.clone();
+        let config = config.clone();
+        let transition = transition.clone();
+        async move {
+            let writer = this.writer(&this.path(&config)).await?;
+            writer.process_transition(&config, &transition).await
+        }
+    }
+
+    /// Banners are written to `<dir>/_debugbunny.ndjson`.
+    fn process_banner(&self, banner: &Banner) -> impl Future<Output = io::Result<()>> + Send {
+        let this = self.clone();
+        let banner = banner.clone();
+        async move {
+            let writer = this.writer(&this.dir.join("_debugbunny.ndjson")).await?;
+            writer.process_banner(&banner).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::config::{Action, ScrapeTargetBuilder};
+
+    #[tokio::test]
+    async fn records_are_written_per_target() {
+        let dir =
+            std::env::temp_dir().join(format!("debugbunny-output-dir-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let target = |name: &str| {
+            ScrapeTargetBuilder::new()
+                .name(name)
+                .interval(Duration::from_secs(1))
+                .action(Action::SelfStatus)
+                .build()
+        };
+        let (a, b, c) = (target("a"), target("b/c"), target("b_c"));
+        let p = OutputDir::new(&dir);
+        for c in [&a, &b, &a, &c] {
+            let r = Ok(ScrapeOk::Structured(serde_json::json!({})));
+            p.process(c, r).await.unwrap();
+        }
+
+        // Counts the meta records, which are followed by the payload chunks.
+        let records = |name| {
+            std::fs::read_to_string(dir.join(name))
+                .unwrap()
+                .lines()
+                .filter(|l| l.contains("\"target_config\""))
+                .count()
+        };
+        assert_eq!(2, records("a.ndjson"));
+        assert_eq!(1, records("b%2Fc.ndjson"));
+        assert_eq!(1, records("b_c.ndjson"));
+        assert_eq!(
+            dir.join("%5Fdebugbunny.ndjson"),
+            p.path(&target("_debugbunny"))
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+//! Parsers for the stdout of commands.
+//!
+//! Many tools print well-structured text, e.g. `key=value` lines, CSV or a
+//! table with a header line like `df -P`. With an [OutputParser], the stdout
+//! of such a command is emitted as JSON instead of opaque text, see
+//! [CommandOutput::parsed](crate::command::CommandOutput::parsed):
+//!
+//! * `key_value`: an object of the keys and values of all lines containing the
+//!   separator. Later lines override earlier ones.
+//! * `csv`: an array with an object per row, keyed by the header line.
+//!   Fields may be quoted with `"`.
+//! * `json_lines`: an array of the JSON documents on each line.
+//! * `table`: like `csv`, but columns are separated by whitespace. The last
+//!   column takes the rest of the line, such that it may contain spaces.
+//!
+//! Except for `json_lines`, values that parse as numbers are emitted as
+//! numbers. Empty lines are ignored. If the output does not parse, the command
+//! is recorded as if no parser was configured.
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(tag = "format", rename_all = "snake_case")]
+pub enum OutputParser {
+    KeyValue {
+        /// Defaults to `=`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        separator: Option<String>,
+    },
+    Csv {
+        /// Defaults to `,`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        delimiter: Option<char>,
+    },
+    JsonLines,
+    Table {
+        /// Names of the columns to use instead of the ones of the header
+        /// line, e.g. if a name contains a space. The header line is skipped
+        /// regardless.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        columns: Vec<String>,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error("output is not valid UTF-8")]
+    Utf8(#[from] std::str::Utf8Error),
+    #[error("line {line}: {source}")]
+    Json {
+        line: usize,
+        source: serde_json::Error,
+    },
+    #[error("line {line}: unterminated quote")]
+    UnterminatedQuote { line: usize },
+}
+
+impl OutputParser {
+    pub fn key_value() -> Self {
+        Self::KeyValue { separator: None }
+    }
+
+    pub fn csv() -> Self {
+        Self::Csv { delimiter: None }
+    }
+
+    pub fn table() -> Self {
+        Self::Table { columns: vec![] }
+    }
+
+    pub fn parse(&self, output: &[u8]) -> Result<Value, ParseError> {
+        let output = std::str::from_utf8(output)?;
+        let mut lines = output
+            .lines()
+            .enumerate()
+            .map(|(i, l)| (i + 1, l))
+            .filter(|(_, l)| !l.trim().is_empty());
+        match self {
+            Self::KeyValue { separator } => {
+                let separator = separator.as_deref().unwrap_or("=");
+                let fields = lines
+                    .filter_map(|(_, l)| l.split_once(separator))
+                    .map(|(k, v)| (k.trim().to_string(), scalar(v.trim())))
+                    .collect::<Map<_, _>>();
+                Ok(Value::Object(fields))
+            }
+            Self::Csv { delimiter } => {
+                let delimiter = delimiter.unwrap_or(',');
+                let Some((n, header)) = lines.next() else {
+                    return Ok(Value::Array(vec![]));
+                };
+                let header = split_csv(header, delimiter, n)?;
+                lines
+                    .map(|(n, l)| Ok(row(&header, split_csv(l, delimiter, n)?)))
+                    .collect::<Result<_, _>>()
+                    .map(Value::Array)
+            }
+            Self::JsonLines => lines
+                .map(|(line, l)| {
+                    serde_json::from_str(l).map_err(|source| ParseError::Json { line, source })
+                })
+                .collect::<Result<_, _>>()
+                .map(Value::Array),
+            Self::Table { columns } => {
+                let Some((_, header)) = lines.next() else {
+                    return Ok(Value::Array(vec![]));
+                };
+                let header = match columns.is_empty() {
+                    true => header.split_whitespace().map(str::to_string).collect(),
+                    false => columns.clone(),
+                };
+                let rows = lines
+                    .map(|(_, l)| row(&header, split_table(l, header.len())))
+                    .collect();
+                Ok(Value::Array(rows))
+            }
+        }
+    }
+}
+
+/// An object of the fields of a row keyed by the header. Surplus fields are
+/// dropped, missing fields are omitted.
+fn row<S: AsRef<str>>(header: &[String], fields: Vec<S>) -> Value {
+    let fields = header
+        .iter()
+        .zip(fields)
+        .map(|(k, v)| (k.clone(), scalar(v.as_ref())))
+        .collect::<Map<_, _>>();
+    Value::Object(fields)
+}
+
+fn scalar(s: &str) -> Value {
+    if let Ok(n) = s.parse::<i64>() {
+        return n.into();
+    }
+    match s.parse().ok().and_then(serde_json::Number::from_f64) {
+        Some(n) => Value::Number(n),
+        None => s.into(),
+    }
+}
+
+/// Split `line` into at most `n` fields separated by whitespace.
+fn split_table(line: &str, n: usize) -> Vec<&str> {
+    let mut fields = Vec::with_capacity(n);
+    let mut rest = line.trim();
+    while !rest.is_empty() {
+        if fields.len() + 1 == n {
+            fields.push(rest);
+            break;
+        }
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        fields.push(&rest[..end]);
+        rest = rest[end..].trim_start();
+    }
+    fields
+}
+
+fn split_csv(line: &str, delimiter: char, n: usize) -> Result<Vec<String>, ParseError> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            '"' => quoted = !quoted,
+            c if c == delimiter && !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(ParseError::UnterminatedQuote { line: n });
+    }
+    fields.push(field);
+    Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn outputs_are_parsed() {
+        let kv = OutputParser::KeyValue {
+            separator: Some(":".into()),
+        };
+        assert_eq!(
+            json!({ "Total": 180, "TCP": "12 (estab 4)" }),
+            kv.parse(b"Total: 180\nTCP:   12 (estab 4)\n\nTransport Total\n")
+                .unwrap()
+        );
+
+        let csv = b"name,size\r\n\"a, b\",1.5\n\"say \"\"hi\"\"\",2\n";
+        assert_eq!(
+            json!([{ "name": "a, b", "size": 1.5 }, { "name": "say \"hi\"", "size": 2 }]),
+            OutputParser::csv().parse(csv).unwrap()
+        );
+
+        let jsonl = OutputParser::JsonLines;
+        assert_eq!(
+            json!([{ "a": 1 }, [2]]),
+            jsonl.parse(b"{\"a\":1}\n[2]\n").unwrap()
+        );
+        assert!(matches!(
+            jsonl.parse(b"{}\n{"),
+            Err(ParseError::Json { line: 2, .. })
+        ));
+
+        let df = b"Filesystem 1024-blocks Used Available Capacity Mounted on\n\
+            /dev/sda1     1000   250       750      25% /mnt/my disk\n";
+        let columns = ["fs", "blocks", "used", "available", "capacity", "mount"];
+        let table = OutputParser::Table {
+            columns: columns.map(String::from).to_vec(),
+        };
+        assert_eq!(
+            json!([{
+                "fs": "/dev/sda1",
+                "blocks": 1000,
+                "used": 250,
+                "available": 750,
+                "capacity": "25%",
+                "mount": "/mnt/my disk"
+            }]),
+            table.parse(df).unwrap()
+        );
+    }
+}
@@ -0,0 +1,131 @@
+//! Checking targets before scraping them.
+//!
+//! A preflight calls each target once, with a relaxed timeout, and reports the
+//! problems that would otherwise only show up as a steady stream of failed
+//! scrapes: hosts that cannot be resolved, rejected credentials, missing
+//! binaries and insufficient permissions. See
+//! [DebugBunnyBuilder::preflight](crate::debugbunny::DebugBunnyBuilder::preflight)
+//! and [Config::preflight](crate::config::Config::preflight).
+
+use std::{error::Error, fmt, io, time::Duration};
+
+use reqwest::StatusCode;
+
+use crate::{
+    config::ScrapeTargetConfig,
+    scrape_target::{ScrapeErr, ScrapeOk, ScrapeResult},
+};
+
+/// The minimum timeout of a preflight call. Targets with a longer timeout keep
+/// theirs.
+pub const PREFLIGHT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The outcome of a preflight, one check per target in the order of the
+/// configs.
+#[derive(Debug, Clone)]
+pub struct PreflightReport {
+    pub checks: Vec<PreflightCheck>,
+}
+
+impl PreflightReport {
+    /// Whether no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.checks.iter().all(|c| c.problem.is_none())
+    }
+
+    pub fn problems(&self) -> impl Iterator<Item = &PreflightCheck> {
+        self.checks.iter().filter(|c| c.problem.is_some())
+    }
+}
+
+impl fmt::Display for PreflightReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, check) in self.checks.iter().enumerate() {
+            let name = check.config.name.clone().unwrap_or_else(|| format!("#{i}"));
+            match &check.problem {
+                None => writeln!(f, "ok   {name} ({:?})", check.took)?,
+                Some(p) => writeln!(f, "FAIL {name}: {p}")?,
+            }
+        }
+        let failed = self.problems().count();
+        write!(f, "{failed} of {} targets failed", self.checks.len())
+    }
+}
+
+/// The preflight of a single target.
+#[derive(Debug, Clone)]
+pub struct PreflightCheck {
+    pub config: ScrapeTargetConfig,
+    /// How long the call took.
+    pub took: Duration,
+    pub problem: Option<Problem>,
+}
+
+/// A problem found by a preflight.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Problem {
+    #[error("command not found: {0}")]
+    MissingBinary(String),
+    #[error("could not resolve {0}")]
+    Dns(String),
+    #[error("access denied with HTTP status {0}")]
+    Unauthorized(StatusCode),
+    #[error("permission denied: {0}")]
+    PermissionDenied(String),
+    #[error("command exited with {status}: {stderr}")]
+    CommandFailed { status: String, stderr: String },
+    #[error("no result within {0:?}")]
+    Timeout(Duration),
+    #[error("{0}")]
+    Failed(String),
+}
+
+/// The problem a result indicates, if any. Unlike for scraping, responses that
+/// deny access and failed commands count as problems.
+pub(crate) fn classify(result: &ScrapeResult<ScrapeOk>) -> Option<Problem> {
+    match result {
+        Ok(ScrapeOk::HttpResponse(r)) => {
+            denied(r.status()).then(|| Problem::Unauthorized(r.status()))
+        }
+        Ok(ScrapeOk::CommandResponse(o)) if !o.status.success() => {
+            let stderr = String::from_utf8_lossy(&o.stderr);
+            let stderr = stderr.lines().next().unwrap_or_default().trim().to_string();
+            if stderr.to_lowercase().contains("permission denied") {
+                Some(Problem::PermissionDenied(stderr))
+            } else {
+                Some(Problem::CommandFailed {
+                    status: o.status.to_string(),
+                    stderr,
+                })
+            }
+        }
+        Ok(_) => None,
+        Err(ScrapeErr::CommandNotFound(command)) => Some(Problem::MissingBinary(command.clone())),
+        Err(ScrapeErr::UnexpectedStatus(s)) if denied(*s) => Some(Problem::Unauthorized(*s)),
+        Err(ScrapeErr::IoErr(e)) if e.kind() == io::ErrorKind::PermissionDenied => {
+            Some(Problem::PermissionDenied(e.to_string()))
+        }
+        // reqwest does not tell resolver errors apart, but hyper names them.
+        Err(ScrapeErr::HttpErr(e)) if chain(e.as_ref()).contains("dns error") => {
+            let host = e.url().and_then(|u| u.host_str()).unwrap_or_default();
+            Some(Problem::Dns(host.to_string()))
+        }
+        Err(e) => Some(Problem::Failed(chain(e))),
+    }
+}
+
+fn denied(status: StatusCode) -> bool {
+    status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN
+}
+
+/// `e` and its sources, separated by colons.
+fn chain(e: &dyn Error) -> String {
+    let mut s = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        s.push_str(": ");
+        s.push_str(&e.to_string());
+        source = e.source();
+    }
+    s
+}
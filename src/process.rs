@@ -0,0 +1,193 @@
+//! Snapshots of the process table.
+//!
+//! A [ProcessCollector] lists processes natively via procfs instead of
+//! parsing the output of `ps`. Processes can be filtered by a regular
+//! expression over their name and limited to the ones with the largest
+//! resident set size.
+//!
+//! Like in the output of `ps`, the CPU usage is the average over the lifetime
+//! of the process. Processes are only available on Linux.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+use crate::{
+    config::Pattern,
+    scrape_target::{FutureScrapeResult, ScrapeOk, ScrapeService},
+};
+
+pub struct ProcessCollector {
+    root: PathBuf,
+    name: Option<Pattern>,
+    top: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Process {
+    pub pid: u32,
+    pub ppid: u32,
+    pub name: String,
+    pub state: String,
+    pub rss_bytes: u64,
+    pub cpu_percent: f64,
+    /// Kernel threads have no command line.
+    pub cmdline: String,
+}
+
+impl ProcessCollector {
+    pub fn new() -> Self {
+        Self {
+            root: PathBuf::from("/proc"),
+            name: None,
+            top: None,
+        }
+    }
+
+    /// Only list processes whose name matches `name`.
+    pub fn name(mut self, name: Pattern) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    /// Only list the `n` processes with the largest resident set size.
+    pub fn top(mut self, n: usize) -> Self {
+        self.top = Some(n);
+        self
+    }
+}
+
+impl Default for ProcessCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScrapeService for ProcessCollector {
+    type Response = ScrapeOk;
+
+    fn call(&mut self) -> FutureScrapeResult<ScrapeOk> {
+        let root = self.root.clone();
+        let name = self.name.clone();
+        let top = self.top;
+        Box::pin(async move {
+            let mut processes = tokio::task::spawn_blocking(move || list(&root))
+                .await
+                .map_err(io::Error::other)??;
+            if let Some(name) = name {
+                processes.retain(|p| name.regex().is_match(p.name.as_bytes()));
+            }
+            if let Some(n) = top {
+                processes.sort_by_key(|p| std::cmp::Reverse(p.rss_bytes));
+                processes.truncate(n);
+            }
+            Ok(ScrapeOk::Structured(serde_json::json!({
+                "processes": processes
+            })))
+        })
+    }
+}
+
+#[cfg(not(unix))]
+fn list(_root: &Path) -> io::Result<Vec<Process>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "processes are only supported on Linux",
+    ))
+}
+
+#[cfg(unix)]
+fn list(root: &Path) -> io::Result<Vec<Process>> {
+    let uptime = std::fs::read_to_string(root.join("uptime"))?;
+    let uptime: f64 = uptime
+        .split_whitespace()
+        .next()
+        .and_then(|u| u.parse().ok())
+        .ok_or_else(|| invalid("uptime"))?;
+    let mut processes = vec![];
+    for e in std::fs::read_dir(root)? {
+        let Ok(pid) = e?.file_name().to_string_lossy().parse() else {
+            continue;
+        };
+        // Processes may exit while we are reading the table.
+        match read_process(root, pid, uptime) {
+            Ok(p) => processes.push(p),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(processes)
+}
+
+#[cfg(unix)]
+fn read_process(root: &Path, pid: u32, uptime: f64) -> io::Result<Process> {
+    let dir = root.join(pid.to_string());
+    let stat = std::fs::read_to_string(dir.join("stat"))?;
+    // The name may contain spaces and parentheses, so it ends at the last
+    // closing parenthesis.
+    let (start, end) = stat
+        .find('(')
+        .zip(stat.rfind(')'))
+        .ok_or_else(|| invalid("stat"))?;
+    let name = stat[start + 1..end].to_string();
+    let fields: Vec<_> = stat[end + 1..].split_whitespace().collect();
+    let field = |i: usize| -> io::Result<u64> {
+        fields
+            .get(i)
+            .and_then(|f| f.parse().ok())
+            .ok_or_else(|| invalid("stat"))
+    };
+    // Indices are relative to the state, the third field of `stat`.
+    let ticks = rustix::param::clock_ticks_per_second() as f64;
+    let cpu_secs = (field(11)? + field(12)?) as f64 / ticks;
+    let running_secs = uptime - field(19)? as f64 / ticks;
+    let cpu_percent = if running_secs > 0.0 {
+        100.0 * cpu_secs / running_secs
+    } else {
+        0.0
+    };
+    let cmdline = std::fs::read(dir.join("cmdline"))?;
+    let cmdline = cmdline
+        .split(|b| *b == 0)
+        .filter(|a| !a.is_empty())
+        .map(String::from_utf8_lossy)
+        .collect::<Vec<_>>()
+        .join(" ");
+    Ok(Process {
+        pid,
+        ppid: field(1)? as u32,
+        name,
+        state: fields.first().ok_or_else(|| invalid("stat"))?.to_string(),
+        rss_bytes: field(21)? * rustix::param::page_size() as u64,
+        cpu_percent,
+        cmdline,
+    })
+}
+
+#[cfg(unix)]
+fn invalid(file: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("invalid {file} file"))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn processes_are_filtered() {
+        let processes = |r| match r {
+            Ok(ScrapeOk::Structured(v)) => v["processes"].as_array().unwrap().clone(),
+            _ => panic!("expected structured output"),
+        };
+        let mut c = ProcessCollector::new().top(1);
+        let top = processes(c.call().await);
+        assert_eq!(1, top.len());
+        assert!(top[0]["rss_bytes"].as_u64().unwrap() > 0);
+
+        let mut c = ProcessCollector::new().name(Pattern::new("^no such process$").unwrap());
+        assert!(processes(c.call().await).is_empty());
+    }
+}
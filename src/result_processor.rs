@@ -3,29 +3,48 @@
 //! The [LogOutputWriter] serializes results as JSON-objects, such that they can
 //! be logged. The idea is that the output writer is given a `AsyncWrite` that
 //! represents some logging channel (e.g. just `stderr` in case of a
-//! systemd-service).
+//! systemd-service). Blocking writers are supported through
+//! [LogOutputWriter::blocking].
 
 use std::{
-    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap},
     future::Future,
-    io::{self, Cursor},
-    process::Output,
-    sync::Arc,
+    io::{self, Cursor, Read},
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex as StdMutex},
+    time::SystemTime,
 };
 
+use bytes::Bytes;
 use http::StatusCode;
 use serde::{Deserialize, Serialize};
-use serde_with::{
-    base64::{Base64, Standard},
-    formats::Padded,
-    serde_as, DisplayFromStr,
+use serde_with::{serde_as, DisplayFromStr, TimestampSecondsWithFrac};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::Mutex,
 };
-use tokio::{io::AsyncWrite, sync::Mutex};
+use url::Url;
+use uuid::Uuid;
 
 use crate::{
-    chunks::{Chunks, Id, DEFAULT_CHUNK_SIZE},
+    anomaly::SizeAnomalies,
+    banner::Banner,
+    blocking_writer::BlockingWriter,
+    body_spool::{BodySpool, SpooledBody},
+    check::{evaluate_checks, CheckOutcome},
+    chunks::{ChunkEncoding, Chunks, DigestAlgorithm, Id},
+    command::{CommandOutput, ResourceUsage, Stream},
     config::ScrapeTargetConfig,
+    delta::{self, DeltaEncoding, DeltaRepr},
+    derive::{derive_fields, promote_fields},
+    dictionary::{Dictionaries, Dictionary},
+    encryption::PayloadKey,
+    health::Transition,
+    http::{AddrSource, Endpoint, HeadersOnly, RemoteAddr, StreamPart, Trailers},
     scrape_target::{ScrapeOk, ScrapeResult},
+    signing::RecordSigner,
 };
 
 pub trait ScrapeResultProcessor: Sync + Send + Clone {
@@ -34,6 +53,208 @@ pub trait ScrapeResultProcessor: Sync + Send + Clone {
         config: &ScrapeTargetConfig,
         result: ScrapeResult<ScrapeOk>,
     ) -> impl Future<Output = io::Result<()>> + Send;
+
+    /// Like [Self::process], but hands the result back if it could not be
+    /// processed, such that it can be retried or passed to another processor.
+    /// By default, a copy of the result is kept until it has been processed.
+    /// Processors that can hand the result back without copying it up front
+    /// override this.
+    fn process_or_return(
+        &self,
+        config: &ScrapeTargetConfig,
+        result: ScrapeResult<ScrapeOk>,
+    ) -> impl Future<Output = Result<(), Unprocessed>> + Send {
+        let copy = result.clone();
+        let processed = self.process(config, result);
+        async move {
+            processed.await.map_err(|error| Unprocessed {
+                error,
+                result: copy,
+            })
+        }
+    }
+
+    /// Write out buffered results. Called by
+    /// [DebugBunny::await_shutdown](crate::debugbunny::DebugBunny::await_shutdown)
+    /// once all targets have stopped, before [Self::shutdown].
+    fn flush(&self) -> impl Future<Output = io::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Release the resources of the processor. No results are passed to it
+    /// afterwards.
+    fn shutdown(&self) -> impl Future<Output = io::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Record that a target turned healthy or failing, see [crate::health].
+    /// Ignored by default.
+    fn process_transition(
+        &self,
+        _config: &ScrapeTargetConfig,
+        _transition: &Transition,
+    ) -> impl Future<Output = io::Result<()>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Record that scraping started, see [crate::banner]. Ignored by default.
+    fn process_banner(&self, _banner: &Banner) -> impl Future<Output = io::Result<()>> + Send {
+        async { Ok(()) }
+    }
+}
+
+/// A result that could not be processed, handed back along with the error.
+/// See [ScrapeResultProcessor::process_or_return].
+pub struct Unprocessed {
+    pub error: io::Error,
+    pub result: ScrapeResult<ScrapeOk>,
+}
+
+/// A type-erased [ScrapeResultProcessor]. This allows to combine processors of
+/// different types, e.g. as sinks of a [crate::debugbunny::DebugBunny].
+#[derive(Clone)]
+pub struct BoxedProcessor(Arc<dyn DynProcessor>);
+
+impl BoxedProcessor {
+    pub fn new<P: ScrapeResultProcessor + 'static>(p: P) -> Self {
+        Self(Arc::new(p))
+    }
+}
+
+type FutureProcessResult<'a> = Pin<Box<dyn Future<Output = io::Result<()>> + Send + 'a>>;
+type FutureProcessOrReturn<'a> = Pin<Box<dyn Future<Output = Result<(), Unprocessed>> + Send + 'a>>;
+
+/// Object-safe counterpart of [ScrapeResultProcessor].
+trait DynProcessor: Send + Sync {
+    fn process_boxed<'a>(
+        &'a self,
+        config: &'a ScrapeTargetConfig,
+        result: ScrapeResult<ScrapeOk>,
+    ) -> FutureProcessResult<'a>;
+
+    fn process_or_return_boxed<'a>(
+        &'a self,
+        config: &'a ScrapeTargetConfig,
+        result: ScrapeResult<ScrapeOk>,
+    ) -> FutureProcessOrReturn<'a>;
+
+    fn flush_boxed(&self) -> FutureProcessResult<'_>;
+
+    fn shutdown_boxed(&self) -> FutureProcessResult<'_>;
+
+    fn process_transition_boxed<'a>(
+        &'a self,
+        config: &'a ScrapeTargetConfig,
+        transition: &'a Transition,
+    ) -> FutureProcessResult<'a>;
+
+    fn process_banner_boxed<'a>(&'a self, banner: &'a Banner) -> FutureProcessResult<'a>;
+}
+
+impl<P: ScrapeResultProcessor> DynProcessor for P {
+    fn process_boxed<'a>(
+        &'a self,
+        config: &'a ScrapeTargetConfig,
+        result: ScrapeResult<ScrapeOk>,
+    ) -> FutureProcessResult<'a> {
+        Box::pin(self.process(config, result))
+    }
+
+    fn process_or_return_boxed<'a>(
+        &'a self,
+        config: &'a ScrapeTargetConfig,
+        result: ScrapeResult<ScrapeOk>,
+    ) -> FutureProcessOrReturn<'a> {
+        Box::pin(self.process_or_return(config, result))
+    }
+
+    fn flush_boxed(&self) -> FutureProcessResult<'_> {
+        Box::pin(self.flush())
+    }
+
+    fn shutdown_boxed(&self) -> FutureProcessResult<'_> {
+        Box::pin(self.shutdown())
+    }
+
+    fn process_transition_boxed<'a>(
+        &'a self,
+        config: &'a ScrapeTargetConfig,
+        transition: &'a Transition,
+    ) -> FutureProcessResult<'a> {
+        Box::pin(self.process_transition(config, transition))
+    }
+
+    fn process_banner_boxed<'a>(&'a self, banner: &'a Banner) -> FutureProcessResult<'a> {
+        Box::pin(self.process_banner(banner))
+    }
+}
+
+impl ScrapeResultProcessor for BoxedProcessor {
+    fn process(
+        &self,
+        config: &ScrapeTargetConfig,
+        result: ScrapeResult<ScrapeOk>,
+    ) -> impl Future<Output = io::Result<()>> + Send {
+        let p = self.0.clone();
+        let config = config.clone();
+        async move { p.process_boxed(&config, result).await }
+    }
+
+    fn process_or_return(
+        &self,
+        config: &ScrapeTargetConfig,
+        result: ScrapeResult<ScrapeOk>,
+    ) -> impl Future<Output = Result<(), Unprocessed>> + Send {
+        let p = self.0.clone();
+        let config = config.clone();
+        async move { p.process_or_return_boxed(&config, result).await }
+    }
+
+    fn flush(&self) -> impl Future<Output = io::Result<()>> + Send {
+        let p = self.0.clone();
+        async move { p.flush_boxed().await }
+    }
+
+    fn shutdown(&self) -> impl Future<Output = io::Result<()>> + Send {
+        let p = self.0.clone();
+        async move { p.shutdown_boxed().await }
+    }
+
+    fn process_transition(
+        &self,
+        config: &ScrapeTargetConfig,
+        transition: &Transition,
+    ) -> impl Future<Output = io::Result<()>> + Send {
+        let p = self.0.clone();
+        let config = config.clone();
+        let transition = transition.clone();
+        async move { p.process_transition_boxed(&config, &transition).await }
+    }
+
+    fn process_banner(&self, banner: &Banner) -> impl Future<Output = io::Result<()>> + Send {
+        let p = self.0.clone();
+        let banner = banner.clone();
+        async move { p.process_banner_boxed(&banner).await }
+    }
+}
+
+/// Records the errors of the results it processes, for tests.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct ErrorCollector(pub(crate) Arc<StdMutex<Vec<String>>>);
+
+#[cfg(test)]
+impl ScrapeResultProcessor for ErrorCollector {
+    async fn process(
+        &self,
+        _config: &ScrapeTargetConfig,
+        result: ScrapeResult<ScrapeOk>,
+    ) -> io::Result<()> {
+        if let Err(e) = result {
+            self.0.lock().unwrap().push(e.to_string());
+        }
+        Ok(())
+    }
 }
 
 /// Serialize the result of a scrape call as JSON-object and write it to the
@@ -44,12 +265,37 @@ pub trait ScrapeResultProcessor: Sync + Send + Clone {
 /// fully serialized.
 pub struct LogOutputWriter<T> {
     writer: Arc<Mutex<T>>,
+    key: Option<PayloadKey>,
+    signer: Option<RecordSigner>,
+    digest: DigestAlgorithm,
+    encoding: ChunkEncoding,
+    spool: Option<BodySpool>,
+    dictionaries: Option<Dictionaries>,
+    deltas: Option<DeltaEncoding>,
+    size_anomalies: Option<SizeAnomalies>,
+    single_line: bool,
+    /// The ids of the dictionaries written so far, with the number of bodies
+    /// compressed with them since they were last written.
+    announced: Arc<StdMutex<HashMap<String, usize>>>,
+    /// The next sequence number per target.
+    sequences: Arc<StdMutex<HashMap<String, u64>>>,
 }
 
 impl<T> Clone for LogOutputWriter<T> {
     fn clone(&self) -> Self {
         Self {
             writer: self.writer.clone(),
+            key: self.key.clone(),
+            signer: self.signer.clone(),
+            digest: self.digest,
+            encoding: self.encoding,
+            spool: self.spool.clone(),
+            dictionaries: self.dictionaries.clone(),
+            deltas: self.deltas.clone(),
+            size_anomalies: self.size_anomalies.clone(),
+            single_line: self.single_line,
+            announced: self.announced.clone(),
+            sequences: self.sequences.clone(),
         }
     }
 }
@@ -61,8 +307,95 @@ where
     pub fn new(writer: T) -> Self {
         Self {
             writer: Arc::new(Mutex::new(writer)),
+            key: None,
+            signer: None,
+            digest: DigestAlgorithm::default(),
+            encoding: ChunkEncoding::default(),
+            spool: None,
+            dictionaries: None,
+            deltas: None,
+            size_anomalies: None,
+            single_line: false,
+            announced: Default::default(),
+            sequences: Default::default(),
         }
     }
+
+    /// Encrypt the payloads of results with the given key after compressing
+    /// them. See [crate::encryption].
+    pub fn with_encryption(mut self, key: PayloadKey) -> Self {
+        self.key = Some(key);
+        self
+    }
+
+    /// The algorithm used to compute the ids of chunked payloads. Defaults to
+    /// SHA-256.
+    pub fn with_digest(mut self, digest: DigestAlgorithm) -> Self {
+        self.digest = digest;
+        self
+    }
+
+    /// How the data of chunks is written. Defaults to base64. The chunk size
+    /// is chosen such that encoded chunks have about the same length
+    /// regardless of the encoding.
+    pub fn with_chunk_encoding(mut self, encoding: ChunkEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Store bodies in `spool` instead of chunking them into the log. See
+    /// [crate::body_spool].
+    pub fn with_body_spool(mut self, spool: BodySpool) -> Self {
+        self.spool = Some(spool);
+        self
+    }
+
+    /// Compress bodies with dictionaries trained per target. See
+    /// [crate::dictionary].
+    pub fn with_dictionaries(mut self, dictionaries: Dictionaries) -> Self {
+        self.dictionaries = Some(dictionaries);
+        self
+    }
+
+    /// Encode bodies as deltas against the previous body of their target. See
+    /// [crate::delta]. Bodies stored in a [BodySpool] are not delta encoded.
+    pub fn with_delta_encoding(mut self, deltas: DeltaEncoding) -> Self {
+        self.deltas = Some(deltas);
+        self
+    }
+
+    /// Flag calls whose body size deviates from the recent bodies of their
+    /// target. See [crate::anomaly].
+    pub fn with_size_anomalies(mut self, anomalies: SizeAnomalies) -> Self {
+        self.size_anomalies = Some(anomalies);
+        self
+    }
+
+    /// Sign every record written. See [crate::signing].
+    pub fn with_signer(mut self, signer: RecordSigner) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Guarantee that every record is a single line, also for log pipelines
+    /// that break lines at Unicode line separators: control characters and
+    /// line separators in strings are escaped, and chunks are written as
+    /// base64 instead of [ChunkEncoding::Raw].
+    pub fn with_single_line_records(mut self) -> Self {
+        self.single_line = true;
+        self
+    }
+}
+
+impl<W> LogOutputWriter<BlockingWriter<W>>
+where
+    W: std::io::Write + Send + 'static,
+{
+    /// Write to a blocking writer, e.g. the appender of a synchronous logging
+    /// framework. See [crate::blocking_writer].
+    pub fn blocking(writer: W) -> Self {
+        Self::new(BlockingWriter::new(writer))
+    }
 }
 
 impl<T> ScrapeResultProcessor for LogOutputWriter<T>
@@ -74,80 +407,613 @@ where
         config: &ScrapeTargetConfig,
         result: ScrapeResult<ScrapeOk>,
     ) -> impl Future<Output = io::Result<()>> + Send {
+        let processed = self.process_or_return(config, result);
+        async move { processed.await.map_err(|u| u.error) }
+    }
+
+    /// The result is encoded without being consumed, such that it can be
+    /// handed back if it cannot be written.
+    fn process_or_return(
+        &self,
+        config: &ScrapeTargetConfig,
+        result: ScrapeResult<ScrapeOk>,
+    ) -> impl Future<Output = Result<(), Unprocessed>> + Send {
         let writer = self.writer.clone();
+        let key = self.key.clone();
+        let key_id = key.as_ref().map(|k| k.id().to_string());
+        let signer = self.signer.clone();
+        let digest = self.digest;
+        let single_line = self.single_line;
+        let encoding = match self.encoding {
+            // The data would follow the record on a line of its own.
+            ChunkEncoding::Raw if single_line => ChunkEncoding::Base64,
+            e => e,
+        };
+        let spool = self.spool.clone();
+        let dictionaries = self.dictionaries.clone();
+        let reannounce_after = dictionaries
+            .as_ref()
+            .map_or(usize::MAX, |d| d.reannounce_after);
+        // Spooled bodies are meant to be read on their own.
+        let deltas = self.deltas.clone().filter(|_| self.spool.is_none());
+        let committed_deltas = deltas.clone();
+        let size_anomalies = self.size_anomalies.clone();
+        let announced = self.announced.clone();
+        let written = announced.clone();
         let config = config.clone();
+        let invocation_id = Uuid::new_v4();
+        let target = target_key(&config);
+        let delta_target = target.clone();
+        let sequence = self.next_sequence(&target);
         async move {
             // As we are performing compression here, we dispatch the
             // computation to a background thread in order not to block the
             // io-thread.
-            let (mut meta, chunks) = tokio::task::spawn_blocking(move || {
-                let (r, c) = ScrapeResultRepr::from_scrape_result(result);
-                let meta = ScrapeCallRepr {
-                    target_config: config,
-                    result: r,
-                };
-                let meta = Cursor::new(serde_json::to_vec(&meta).expect("can't fail"));
-                (meta, c)
+            let (result, encoded) = tokio::task::spawn_blocking(move || {
+                let encoded = (|| {
+                    let (derived, promoted) = match &result {
+                        Ok(ok) => (derive_fields(&config, ok), promote_fields(&config, ok)),
+                        Err(_) => Default::default(),
+                    };
+                    let checks =
+                        evaluate_checks(&config, result.as_ref().ok(), &derived, &promoted);
+                    let base = deltas.as_ref().and_then(|d| d.base(&target));
+                    // A delta needs no dictionary.
+                    let dictionary = dictionaries
+                        .as_ref()
+                        .and_then(|d| d.current(&target))
+                        .filter(|_| base.is_none());
+                    let anomalous_size = Cell::new(false);
+                    let delta_body = RefCell::new(None);
+                    let observe = |body: &[u8]| {
+                        if let Some(a) = &size_anomalies {
+                            anomalous_size.set(a.observe(&target, body.len()));
+                        }
+                        if let Some(d) = &dictionaries {
+                            d.sample(&target, body);
+                        }
+                        if deltas.is_some() {
+                            *delta_body.borrow_mut() = Some(body.to_vec());
+                        }
+                    };
+                    let payload = PayloadEncoding {
+                        key: key.as_ref(),
+                        digest,
+                        chunk_size: encoding.chunk_size(),
+                        dictionary: dictionary.as_deref(),
+                        base: base.as_ref().map(|b| b.body.as_slice()),
+                        observe: Some(&observe),
+                    };
+                    let (r, c) = ScrapeResultRepr::from_scrape_result(&result, &payload);
+                    let has_body = c.is_some();
+                    // The dictionary is written along with the first body
+                    // compressed with it, and again periodically.
+                    let dictionary = dictionary.as_ref().filter(|_| has_body).map(|d| {
+                        let plain = PayloadEncoding {
+                            dictionary: None,
+                            base: None,
+                            observe: None,
+                            ..payload
+                        };
+                        let due = written
+                            .lock()
+                            .unwrap()
+                            .get(&d.id)
+                            .map_or(true, |n| *n >= reannounce_after);
+                        let chunks = due.then(|| encode_payload(&d.data, &plain));
+                        (d.id.clone(), chunks)
+                    });
+                    let (spooled, c) = match (&spool, c) {
+                        (Some(spool), Some(c)) => {
+                            let mut data = Vec::new();
+                            c.reader().read_to_end(&mut data)?;
+                            let name = invocation_id.to_string();
+                            match spool.store(&name, &data, key_id.clone()) {
+                                Ok(spooled) => (Some(spooled), None),
+                                // Rather chunk the body into the log than
+                                // lose it.
+                                Err(e) => {
+                                    tracing::warn!(error = %e, "could not spool body");
+                                    (None, Some(c))
+                                }
+                            }
+                        }
+                        (_, c) => (None, c),
+                    };
+                    let meta = ScrapeCallRepr {
+                        invocation_id,
+                        sequence,
+                        derived,
+                        promoted,
+                        checks,
+                        target_config: config,
+                        result: r,
+                        overflow: None,
+                        anomalous_size: anomalous_size.get(),
+                        spooled,
+                        dictionary: dictionary.as_ref().map(|(id, _)| id.clone()),
+                        delta: deltas.as_ref().filter(|_| has_body).map(|_| match &base {
+                            Some(b) => DeltaRepr::Patch {
+                                base: b.invocation_id,
+                            },
+                            None => DeltaRepr::Snapshot,
+                        }),
+                    };
+                    let format = RecordFormat {
+                        signer: signer.as_ref(),
+                        single_line,
+                    };
+                    let meta = encode_call(meta, format);
+                    let delta_update = delta_body.into_inner().map(|b| (b, base.is_some()));
+                    io::Result::Ok((meta, c, dictionary, signer, key_id, delta_update))
+                })();
+                (result, encoded)
             })
             .await
             .expect("Could not join blocking code!");
-
-            // All heavy computation is done here, so grab the mutex and write
-            // the log lines.
-            meta.get_mut().push(b'\n');
-            let mut guard = writer.lock().await;
-            tokio::io::copy(&mut meta, &mut *guard).await?;
-
-            if let Some(chunks) = chunks {
-                let id = chunks.id();
-                for c in chunks.iter() {
-                    let c = ChunkRepr {
-                        id,
-                        remaining: c.remaining,
-                        data: c.data,
+            let unprocessed = |error| Unprocessed { error, result };
+            let (mut meta, chunks, dictionary, signer, key_id, delta_update) = match encoded {
+                Ok(encoded) => encoded,
+                Err(e) => return Err(unprocessed(e)),
+            };
+            let write = async {
+                // All heavy computation is done here, so grab the mutex and write
+                // the log lines.
+                let format = RecordFormat {
+                    signer: signer.as_ref(),
+                    single_line,
+                };
+                let mut guard = writer.lock().await;
+                if let Some((id, chunks)) = dictionary {
+                    // Another call may have written it in the meantime.
+                    let chunks = {
+                        let mut announced = announced.lock().unwrap();
+                        let since = announced.entry(id.clone()).or_insert(usize::MAX);
+                        let chunks = chunks.filter(|_| *since >= reannounce_after);
+                        *since = match chunks {
+                            Some(_) => 1,
+                            None => since.saturating_add(1),
+                        };
+                        chunks
                     };
+                    if let Some(chunks) = chunks {
+                        let invocation_id = Uuid::new_v4();
+                        let record = DictionaryRepr {
+                            invocation_id,
+                            dictionary_id: id,
+                        };
+                        let mut record = encode_record(&record, format);
+                        tokio::io::copy(&mut record, &mut *guard).await?;
+                        let chunks = ChunksToWrite {
+                            invocation_id,
+                            chunks,
+                            key_id: key_id.clone(),
+                            encoding,
+                        };
+                        chunks.write(&mut *guard, format).await?;
+                    }
+                }
+                tokio::io::copy(&mut meta, &mut *guard).await?;
 
-                    let mut chunk_json = Cursor::new(serde_json::to_vec(&c).expect("can't fail"));
-                    chunk_json.get_mut().push(b'\n');
-                    tokio::io::copy(&mut chunk_json, &mut *guard).await?;
+                if let Some(chunks) = chunks {
+                    let chunks = ChunksToWrite {
+                        invocation_id,
+                        chunks,
+                        key_id,
+                        encoding,
+                    };
+                    chunks.write(&mut *guard, format).await?;
                 }
-            }
+                // Later bodies may only refer to a body that was written.
+                if let (Some(d), Some((body, patched))) = (&committed_deltas, delta_update) {
+                    d.update(&delta_target, invocation_id, &body, patched);
+                }
+                io::Result::Ok(())
+            };
+            write.await.map_err(unprocessed)
+        }
+    }
+
+    fn flush(&self) -> impl Future<Output = io::Result<()>> + Send {
+        let writer = self.writer.clone();
+        async move { writer.lock().await.flush().await }
+    }
+
+    fn shutdown(&self) -> impl Future<Output = io::Result<()>> + Send {
+        let writer = self.writer.clone();
+        async move { writer.lock().await.shutdown().await }
+    }
+
+    fn process_transition(
+        &self,
+        config: &ScrapeTargetConfig,
+        transition: &Transition,
+    ) -> impl Future<Output = io::Result<()>> + Send {
+        self.write_record(&TransitionRepr {
+            message: format!("target {} {transition}", target_key(config)),
+            target_config: config.clone(),
+            transition: transition.clone(),
+        })
+    }
+
+    fn process_banner(&self, banner: &Banner) -> impl Future<Output = io::Result<()>> + Send {
+        self.write_record(&BannerRepr {
+            message: format!(
+                "debugbunny {} started with {} targets",
+                banner.version, banner.targets
+            ),
+            banner: banner.clone(),
+        })
+    }
+}
+
+impl<T> LogOutputWriter<T>
+where
+    T: AsyncWrite + Unpin + Send + 'static,
+{
+    /// Write a record that is not part of a call.
+    fn write_record<R: Serialize>(
+        &self,
+        record: &R,
+    ) -> impl Future<Output = io::Result<()>> + Send {
+        let writer = self.writer.clone();
+        let format = RecordFormat {
+            signer: self.signer.as_ref(),
+            single_line: self.single_line,
+        };
+        let mut record = encode_record(record, format);
+        async move {
+            let mut guard = writer.lock().await;
+            tokio::io::copy(&mut record, &mut *guard).await?;
             Ok(())
         }
     }
 }
 
+/// The chunks of a payload, to be written after the record they belong to.
+struct ChunksToWrite {
+    invocation_id: Uuid,
+    chunks: Chunks,
+    key_id: Option<String>,
+    encoding: ChunkEncoding,
+}
+
+impl ChunksToWrite {
+    async fn write<W: AsyncWrite + Unpin>(
+        self,
+        w: &mut W,
+        format: RecordFormat<'_>,
+    ) -> io::Result<()> {
+        let id = self.chunks.id();
+        let (total_size, chunk_count) = (self.chunks.total_size(), self.chunks.chunk_count());
+        for c in self.chunks.iter() {
+            let c = ChunkRepr {
+                invocation_id: self.invocation_id,
+                id,
+                remaining: c.remaining,
+                total_size: Some(total_size),
+                chunk_count: Some(chunk_count),
+                key_id: self.key_id.clone(),
+                encoding: self.encoding,
+                data: c.data,
+            };
+
+            let mut chunk_json = encode_record(&c, format);
+            tokio::io::copy(&mut chunk_json, w).await?;
+            // The data is covered by the signed id of the payload.
+            if self.encoding == ChunkEncoding::Raw {
+                w.write_all(&c.data).await?;
+                w.write_all(b"\n").await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Targets are identified by name. Unnamed targets are identified by their
+/// whole config.
+pub(crate) fn target_key(config: &ScrapeTargetConfig) -> String {
+    match &config.name {
+        Some(name) => name.clone(),
+        None => serde_json::to_string(config).expect("can't fail"),
+    }
+}
+
+impl<T> LogOutputWriter<T> {
+    fn next_sequence(&self, target: &str) -> u64 {
+        let mut sequences = self.sequences.lock().unwrap();
+        let next = sequences.entry(target.to_string()).or_default();
+        *next += 1;
+        *next - 1
+    }
+}
+
+/// The maximum length of the records of a call, see
+/// [crate::chunks::DEFAULT_CHUNK_SIZE].
+pub const MAX_RECORD_LEN: usize = 4096;
+
+/// Encode the record of a call. If it would exceed [MAX_RECORD_LEN], the
+/// derived fields are moved to [OverflowRepr] records following it. Fields are
+/// distributed in the order of their names, such that the split is
+/// deterministic. The target config itself is assumed to fit.
+fn encode_call(mut call: ScrapeCallRepr, format: RecordFormat) -> Cursor<Vec<u8>> {
+    let record = encode_record(&call, format);
+    if record.get_ref().len() <= MAX_RECORD_LEN || call.derived.is_empty() {
+        return record;
+    }
+    let invocation_id = call.invocation_id;
+    let mut overflow = vec![OverflowRepr {
+        invocation_id,
+        derived: BTreeMap::new(),
+    }];
+    for (name, value) in std::mem::take(&mut call.derived) {
+        let last = overflow.last_mut().expect("not empty");
+        last.derived.insert(name.clone(), value);
+        if last.derived.len() > 1 && encode_record(last, format).get_ref().len() > MAX_RECORD_LEN {
+            let value = last.derived.remove(&name).expect("just inserted");
+            overflow.push(OverflowRepr {
+                invocation_id,
+                derived: BTreeMap::from([(name, value)]),
+            });
+        }
+    }
+    call.overflow = Some(overflow.len());
+    let mut lines = encode_record(&call, format).into_inner();
+    for o in &overflow {
+        lines.extend(encode_record(o, format).into_inner());
+    }
+    Cursor::new(lines)
+}
+
+/// How records are encoded.
+#[derive(Clone, Copy)]
+struct RecordFormat<'a> {
+    signer: Option<&'a RecordSigner>,
+    /// See [LogOutputWriter::with_single_line_records].
+    single_line: bool,
+}
+
+/// Serialize a record as a line of JSON, signed if a signer is given.
+fn encode_record<R: Serialize>(record: &R, format: RecordFormat) -> Cursor<Vec<u8>> {
+    let mut json = serde_json::to_vec(record).expect("can't fail");
+    if format.single_line {
+        json = escape_line_breaks(json);
+    }
+    if let Some(signer) = format.signer {
+        signer.sign_record(&mut json);
+    }
+    json.push(b'\n');
+    Cursor::new(json)
+}
+
+/// serde_json escapes the ASCII control characters in strings, but writes
+/// DEL, the C1 control characters (e.g. NEL) and the Unicode line and
+/// paragraph separators as is.
+fn escape_line_breaks(json: Vec<u8>) -> Vec<u8> {
+    let json = String::from_utf8(json).expect("serde_json writes UTF-8");
+    let needs_escape = |c: char| c.is_control() || c == '\u{2028}' || c == '\u{2029}';
+    if !json.contains(needs_escape) {
+        return json.into_bytes();
+    }
+    let mut escaped = String::with_capacity(json.len() + 16);
+    for c in json.chars() {
+        if needs_escape(c) {
+            escaped.push_str(&format!("\\u{:04x}", c as u32));
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped.into_bytes()
+}
+
 // # Boilerplate for serialization of scrape results.
 
 /// The 'wire'-representation of a chunk of data.
-#[serde_as]
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ChunkRepr<'a> {
+#[serde(into = "WireChunk", try_from = "WireChunk")]
+pub struct ChunkRepr {
+    /// The invocation the chunk belongs to, see [ScrapeCallRepr].
+    pub invocation_id: Uuid,
+    pub id: Id,
+    pub remaining: usize,
+    /// The length of the payload and the number of its chunks, such that
+    /// readers can tell when all of them arrived, in any order, and how many
+    /// are missing. Missing in records of earlier versions.
+    pub total_size: Option<usize>,
+    pub chunk_count: Option<usize>,
+    /// The id of the key the payload is encrypted with, if any.
+    pub key_id: Option<String>,
+    pub encoding: ChunkEncoding,
+    /// Raw data is not part of the serialized record, but follows it.
+    pub data: Bytes,
+}
+
+/// The serialized form of a [ChunkRepr].
+#[derive(Serialize, Deserialize)]
+struct WireChunk {
+    invocation_id: Uuid,
     id: Id,
     remaining: usize,
-    #[serde_as(as = "Base64<Standard, Padded>")]
-    data: Cow<'a, [u8]>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chunk_count: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    key_id: Option<String>,
+    /// Omitted for base64, such that records of earlier versions are read
+    /// correctly.
+    #[serde(default, skip_serializing_if = "is_base64")]
+    encoding: ChunkEncoding,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    data: Option<String>,
+    /// The length of raw data.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    length: Option<usize>,
 }
 
-#[derive(Serialize, Deserialize)]
+fn is_base64(e: &ChunkEncoding) -> bool {
+    *e == ChunkEncoding::Base64
+}
+
+impl From<ChunkRepr> for WireChunk {
+    fn from(c: ChunkRepr) -> Self {
+        let (data, length) = match c.encoding {
+            ChunkEncoding::Raw => (None, Some(c.data.len())),
+            e => (Some(e.encode(&c.data)), None),
+        };
+        Self {
+            invocation_id: c.invocation_id,
+            id: c.id,
+            remaining: c.remaining,
+            total_size: c.total_size,
+            chunk_count: c.chunk_count,
+            key_id: c.key_id,
+            encoding: c.encoding,
+            data,
+            length,
+        }
+    }
+}
+
+impl TryFrom<WireChunk> for ChunkRepr {
+    type Error = &'static str;
+
+    fn try_from(c: WireChunk) -> Result<Self, Self::Error> {
+        let data = match (c.encoding, c.data) {
+            (ChunkEncoding::Raw, _) => Bytes::new(),
+            (e, Some(data)) => e.decode(&data).ok_or("invalid chunk data")?.into(),
+            (_, None) => return Err("missing chunk data"),
+        };
+        Ok(Self {
+            invocation_id: c.invocation_id,
+            id: c.id,
+            remaining: c.remaining,
+            total_size: c.total_size,
+            chunk_count: c.chunk_count,
+            key_id: c.key_id,
+            encoding: c.encoding,
+            data,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ScrapeCallRepr {
-    target_config: ScrapeTargetConfig,
-    result: ScrapeResultRepr,
+    /// Unique per record. Unlike the id of the body, which is the same for
+    /// identical bodies, this unambiguously relates chunks to their record.
+    pub invocation_id: Uuid,
+    /// Counts the records of a target, starting at zero when the writer is
+    /// created. Gaps indicate dropped records.
+    pub sequence: u64,
+    /// See [crate::derive].
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub derived: BTreeMap<String, serde_json::Value>,
+    /// Values promoted from the body, as top-level keys named after their
+    /// paths, see [ScrapeTargetConfig::promote_fields]. When reading a
+    /// record, all keys unknown to this struct end up here.
+    #[serde(flatten)]
+    pub promoted: BTreeMap<String, serde_json::Value>,
+    /// The outcomes of [ScrapeTargetConfig::checks], ordered by name.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub checks: Vec<CheckOutcome>,
+    pub target_config: ScrapeTargetConfig,
+    pub result: ScrapeResultRepr,
+    /// The number of [OverflowRepr] records following this one, if the
+    /// derived fields did not fit into it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub overflow: Option<usize>,
+    /// Whether the size of the body deviates from the recent bodies of the
+    /// target, see [crate::anomaly].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub anomalous_size: bool,
+    /// Where the body was stored instead of being chunked into the log.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub spooled: Option<SpooledBody>,
+    /// The id of the dictionary the body is compressed with, see
+    /// [crate::dictionary]. Also applies to a spooled body.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dictionary: Option<String>,
+    /// Whether the body is a delta against the body of the previous call of
+    /// the target, see [crate::delta].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub delta: Option<DeltaRepr>,
+}
+
+/// The keys of the records of calls and chunks. Promoted fields must not use
+/// them, as the record would not be readable anymore.
+pub(crate) const RESERVED_KEYS: &[&str] = &[
+    "invocation_id",
+    "sequence",
+    "derived",
+    "checks",
+    "target_config",
+    "result",
+    "overflow",
+    "anomalous_size",
+    "spooled",
+    "dictionary",
+    "delta",
+    "id",
+    "remaining",
+    "total_size",
+    "chunk_count",
+    "key_id",
+    "encoding",
+    "data",
+    "length",
+];
+
+/// Announces a dictionary, whose chunks follow, see [crate::dictionary].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DictionaryRepr {
+    /// Relates the chunks to this record, like for a [ScrapeCallRepr].
+    pub invocation_id: Uuid,
+    pub dictionary_id: String,
+}
+
+/// A target turned healthy or failing, see [crate::health].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TransitionRepr {
+    /// E.g. `target nginx down after 3 failures`.
+    pub message: String,
+    pub target_config: ScrapeTargetConfig,
+    #[serde(flatten)]
+    pub transition: Transition,
+}
+
+/// The first record of a run, see [crate::banner].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BannerRepr {
+    /// E.g. `debugbunny 0.1.0 started with 12 targets`.
+    pub message: String,
+    #[serde(flatten)]
+    pub banner: Banner,
+}
+
+/// Derived fields of a call that did not fit into its record, see
+/// [MAX_RECORD_LEN].
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OverflowRepr {
+    pub invocation_id: Uuid,
+    pub derived: BTreeMap<String, serde_json::Value>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "outcome")]
 pub enum ScrapeResultRepr {
-    Success(ScrapeOkRepr),
+    Success(Box<ScrapeOkRepr>),
     Error { message: String },
 }
 
 impl ScrapeResultRepr {
-    fn from_scrape_result(v: ScrapeResult<ScrapeOk>) -> (Self, Option<Chunks<'static>>) {
+    fn from_scrape_result(
+        v: &ScrapeResult<ScrapeOk>,
+        payload: &PayloadEncoding,
+    ) -> (Self, Option<Chunks>) {
         match v {
             Ok(success) => {
-                let (r, c) = Self::scrape_ok_to_meta(success);
-                (Self::Success(r), Some(c))
+                let (r, c) = Self::scrape_ok_to_meta(success, payload);
+                (Self::Success(Box::new(r)), c)
             }
             Err(e) => (
                 Self::Error {
@@ -158,45 +1024,112 @@ impl ScrapeResultRepr {
         }
     }
 
-    /// Transform successful scrape call to serializable objects.
-    fn scrape_ok_to_meta(ok: ScrapeOk) -> (ScrapeOkRepr, Chunks<'static>) {
+    /// Transform successful scrape call to serializable objects. Probes have
+    /// no body, see [HeadersOnly].
+    fn scrape_ok_to_meta(
+        ok: &ScrapeOk,
+        payload: &PayloadEncoding,
+    ) -> (ScrapeOkRepr, Option<Chunks>) {
         match ok {
+            ScrapeOk::HttpResponse(r) if r.extensions().get::<HeadersOnly>().is_some() => (
+                ScrapeOkRepr::Probe {
+                    status: r.status(),
+                    url: r.extensions().get::<Endpoint>().map(|e| e.0.clone()),
+                    version: Some(format!("{:?}", r.version())),
+                    remote_addr: r.extensions().get::<RemoteAddr>().map(|a| a.0),
+                    remote_addr_source: r.extensions().get::<RemoteAddr>().map(|a| a.1),
+                    headers: header_strings(r.headers()),
+                },
+                None,
+            ),
             ScrapeOk::HttpResponse(r) => {
-                let (parts, body) = r.into_parts();
-                // As we perform only in-memory computations here, we simply unwrap
-                // the error and fail hard.
-                let compressed =
-                    zstd::encode_all(Cursor::new(body), 10).expect("zstd compression failed");
-                let chunks = Chunks::new(compressed, DEFAULT_CHUNK_SIZE);
+                let chunks = encode_payload(r.body(), payload);
                 (
                     ScrapeOkRepr::Http {
-                        status: parts.status,
+                        status: r.status(),
+                        url: r.extensions().get::<Endpoint>().map(|e| e.0.clone()),
+                        content_encoding: r
+                            .headers()
+                            .get(http::header::CONTENT_ENCODING)
+                            .and_then(|v| v.to_str().ok())
+                            .map(str::to_string),
+                        version: Some(format!("{:?}", r.version())),
+                        remote_addr: r.extensions().get::<RemoteAddr>().map(|a| a.0),
+                        remote_addr_source: r.extensions().get::<RemoteAddr>().map(|a| a.1),
+                        trailers: r
+                            .extensions()
+                            .get::<Trailers>()
+                            .map(|t| header_strings(&t.0))
+                            .unwrap_or_default(),
+                        part: r.extensions().get::<StreamPart>().copied(),
                         body_sha256: chunks.id(),
                     },
-                    chunks,
+                    Some(chunks),
                 )
             }
             ScrapeOk::CommandResponse(c) => {
                 let exit_code = c.status.code().unwrap_or(1);
+                let usage = c.usage;
                 let cbody: CommandBody = c.into();
                 let cbody = serde_json::to_vec(&cbody).expect("json encoding failed.");
-                // As we perform only in-memory computations here, we simply unwrap
-                // the error and fail hard.
-                let compressed =
-                    zstd::encode_all(Cursor::new(cbody), 10).expect("zstd compression failed");
-                let chunks = Chunks::new(compressed, DEFAULT_CHUNK_SIZE);
+                let chunks = encode_payload(&cbody, payload);
                 (
                     ScrapeOkRepr::Command {
                         exit_code,
+                        usage: Some(usage),
+                        body_sha256: chunks.id(),
+                    },
+                    Some(chunks),
+                )
+            }
+            ScrapeOk::Structured(v) => {
+                let body = serde_json::to_vec(v).expect("json encoding failed.");
+                let chunks = encode_payload(&body, payload);
+                (
+                    ScrapeOkRepr::Structured {
                         body_sha256: chunks.id(),
                     },
-                    chunks,
+                    Some(chunks),
                 )
             }
         }
     }
 }
 
+/// How payloads are compressed, encrypted and chunked.
+#[derive(Clone, Copy)]
+struct PayloadEncoding<'a> {
+    key: Option<&'a PayloadKey>,
+    digest: DigestAlgorithm,
+    chunk_size: usize,
+    dictionary: Option<&'a Dictionary>,
+    /// The previous body of the target, if the payload is to be a delta.
+    base: Option<&'a [u8]>,
+    /// Called with the payload before it is compressed.
+    observe: Option<Observer<'a>>,
+}
+
+type Observer<'a> = &'a dyn Fn(&[u8]);
+
+/// Compress the payload and encrypt it, if a key is given.
+fn encode_payload(data: &[u8], e: &PayloadEncoding) -> Chunks {
+    // As we perform only in-memory computations here, we simply unwrap
+    // the error and fail hard.
+    let compressed = match (e.base, e.dictionary) {
+        (Some(base), _) => delta::diff(base, data, 10),
+        (None, Some(d)) => d.compress(data, 10),
+        (None, None) => zstd::encode_all(data, 10),
+    }
+    .expect("zstd compression failed");
+    if let Some(observe) = e.observe {
+        observe(data);
+    }
+    match e.key {
+        Some(key) => Chunks::with_digest(key.encrypt(&compressed), e.chunk_size, e.digest),
+        None => Chunks::with_digest(compressed, e.chunk_size, e.digest),
+    }
+}
+
 #[serde_as]
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 #[serde(tag = "type")]
@@ -204,24 +1137,395 @@ pub enum ScrapeOkRepr {
     Http {
         #[serde_as(as = "DisplayFromStr")]
         status: StatusCode,
+        /// The URL that answered; differs from the configured URL if a
+        /// fallback URL was used.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        url: Option<Url>,
+        /// Only present if the body was captured without decompression.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        content_encoding: Option<String>,
+        /// The negotiated version, e.g. `HTTP/2.0`. Absent in records of
+        /// older versions.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<String>,
+        /// The address the request was sent to, as resolved and connected.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        remote_addr: Option<SocketAddr>,
+        /// Whether `remote_addr` was resolved via DNS or configured.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        remote_addr_source: Option<AddrSource>,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        trailers: BTreeMap<String, String>,
+        /// Only present if the response was streamed in parts.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        part: Option<StreamPart>,
+        /// The id of the chunked body. The name is kept for compatibility;
+        /// the digest algorithm is encoded in the id, see [Id].
         body_sha256: Id,
     },
     Command {
         exit_code: i32,
+        /// Absent in records of older versions.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        usage: Option<ResourceUsage>,
         body_sha256: Id,
     },
+    /// The response to a `HEAD` or `OPTIONS` request. Only the status and the
+    /// headers are recorded, and no chunks are written.
+    Probe {
+        #[serde_as(as = "DisplayFromStr")]
+        status: StatusCode,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        url: Option<Url>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        version: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        remote_addr: Option<SocketAddr>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        remote_addr_source: Option<AddrSource>,
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        headers: BTreeMap<String, String>,
+    },
+    /// The body is a JSON document.
+    Structured { body_sha256: Id },
+}
+
+/// Header values that are not valid UTF-8 are converted lossily, and the
+/// values of repeated headers are joined with commas.
+fn header_strings(headers: &http::HeaderMap) -> BTreeMap<String, String> {
+    let mut strings = BTreeMap::<String, String>::new();
+    for (k, v) in headers {
+        let v = String::from_utf8_lossy(v.as_bytes());
+        strings
+            .entry(k.to_string())
+            .and_modify(|s| {
+                s.push_str(", ");
+                s.push_str(&v);
+            })
+            .or_insert_with(|| v.into());
+    }
+    strings
 }
 
+/// The interleaved lines of stdout and stderr of a command. If stdout was
+/// parsed, it is emitted as `parsed`, and only the lines of stderr are kept.
 #[derive(Serialize)]
-struct CommandBody {
-    stdout: String,
-    stderr: String,
+struct CommandBody<'a> {
+    lines: Vec<CommandLine>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parsed: Option<&'a serde_json::Value>,
+}
+
+#[serde_as]
+#[derive(Serialize)]
+struct CommandLine {
+    #[serde_as(as = "TimestampSecondsWithFrac<f64>")]
+    timestamp: SystemTime,
+    stream: Stream,
+    line: String,
+}
+
+impl<'a> From<&'a CommandOutput> for CommandBody<'a> {
+    fn from(value: &'a CommandOutput) -> Self {
+        let lines = value
+            .lines()
+            .filter(|(l, _)| value.parsed.is_none() || l.stream == Stream::Stderr)
+            .map(|(l, s)| CommandLine {
+                timestamp: l.timestamp,
+                stream: l.stream,
+                line: String::from_utf8_lossy(s.strip_suffix(b"\n").unwrap_or(s)).to_string(),
+            })
+            .collect();
+        Self {
+            lines,
+            parsed: value.parsed.as_ref(),
+        }
+    }
 }
 
-impl From<Output> for CommandBody {
-    fn from(value: Output) -> Self {
-        let stdout = String::from_utf8_lossy(&value.stdout).to_string();
-        let stderr = String::from_utf8_lossy(&value.stderr).to_string();
-        Self { stdout, stderr }
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        check::Check,
+        config::{Action, Pattern, ScrapeTargetBuilder},
+    };
+
+    #[tokio::test]
+    async fn records_are_correlated_and_numbered() {
+        let config = ScrapeTargetBuilder::new()
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let w = LogOutputWriter::new(Vec::<u8>::new());
+        let ok = ScrapeOk::Structured(serde_json::json!({ "a": 1 }));
+        w.process(&config, Ok(ok.clone())).await.unwrap();
+        w.process(&config, Ok(ok)).await.unwrap();
+
+        let out = w.writer.lock().await;
+        let records: Vec<serde_json::Value> = out
+            .split(|b| *b == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(|l| serde_json::from_slice(l).unwrap())
+            .collect();
+        // A meta record and a single chunk per invocation.
+        assert_eq!(4, records.len());
+        assert_eq!(records[0]["invocation_id"], records[1]["invocation_id"]);
+        assert_ne!(records[0]["invocation_id"], records[2]["invocation_id"]);
+        assert_eq!(records[1]["id"], records[3]["id"]);
+        assert_eq!(1, records[1]["chunk_count"]);
+        assert_eq!(records[1]["remaining"], records[1]["total_size"]);
+        assert_eq!(
+            (0, 1),
+            (
+                records[0]["sequence"].as_u64().unwrap(),
+                records[2]["sequence"].as_u64().unwrap()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn sudden_size_changes_are_flagged() {
+        let config = ScrapeTargetBuilder::new()
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let w = LogOutputWriter::new(Vec::<u8>::new())
+            .with_size_anomalies(SizeAnomalies::new(4.0).min_samples(2));
+        for n in [10, 12, 11, 200] {
+            let ok = ScrapeOk::Structured(serde_json::json!({ "a": "x".repeat(n) }));
+            w.process(&config, Ok(ok)).await.unwrap();
+        }
+
+        let out = w.writer.lock().await;
+        let flags: Vec<_> = out
+            .split(|b| *b == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(|l| serde_json::from_slice::<serde_json::Value>(l).unwrap())
+            .filter(|r| r.get("sequence").is_some())
+            .map(|r| r.get("anomalous_size").is_some_and(|a| a == true))
+            .collect();
+        assert_eq!(vec![false, false, false, true], flags);
+    }
+
+    #[tokio::test]
+    async fn check_outcomes_are_recorded() {
+        let config = ScrapeTargetBuilder::new()
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .check(
+                "has_a",
+                Check::body_matches(Pattern::new(r#""a":1"#).unwrap()),
+            )
+            .check(
+                "has_b",
+                Check::body_matches(Pattern::new(r#""b":"#).unwrap()),
+            )
+            .build();
+        let w = LogOutputWriter::new(Vec::<u8>::new());
+        let ok = ScrapeOk::Structured(serde_json::json!({ "a": 1 }));
+        w.process(&config, Ok(ok)).await.unwrap();
+
+        let out = w.writer.lock().await;
+        let line = out.split(|b| *b == b'\n').next().unwrap();
+        let record: ScrapeCallRepr = serde_json::from_slice(line).unwrap();
+        let passed: Vec<_> = record
+            .checks
+            .iter()
+            .map(|c| (c.name.as_str(), c.passed))
+            .collect();
+        assert_eq!(vec![("has_a", true), ("has_b", false)], passed);
+    }
+
+    #[tokio::test]
+    async fn probes_are_written_without_chunks() {
+        let config = ScrapeTargetBuilder::new()
+            .interval(Duration::from_secs(1))
+            .action(Action::http_with_method(
+                Url::parse("http://localhost/").unwrap(),
+                http::Method::HEAD,
+            ))
+            .build();
+        let w = LogOutputWriter::new(Vec::<u8>::new());
+        let mut r = http::Response::builder()
+            .status(StatusCode::OK)
+            .header("etag", "a")
+            .header("vary", "accept")
+            .header("vary", "cookie")
+            .body(Bytes::new())
+            .unwrap();
+        r.extensions_mut().insert(HeadersOnly);
+        w.process(&config, Ok(ScrapeOk::HttpResponse(r)))
+            .await
+            .unwrap();
+
+        let out = w.writer.lock().await;
+        let out = std::str::from_utf8(&out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(1, lines.len(), "{out}");
+        let record: serde_json::Value = serde_json::from_str(lines[0]).unwrap();
+        let probe = &record["result"];
+        assert_eq!("Probe", probe["type"]);
+        assert_eq!("200 OK", probe["status"]);
+        let headers = &probe["headers"];
+        assert_eq!("a", headers["etag"]);
+        assert_eq!("accept, cookie", headers["vary"]);
+    }
+
+    #[tokio::test]
+    async fn records_are_single_lines() {
+        let config = ScrapeTargetBuilder::new()
+            .name("a\u{2028}b\u{85}c\nd")
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let w = LogOutputWriter::new(Vec::<u8>::new())
+            .with_chunk_encoding(ChunkEncoding::Raw)
+            .with_single_line_records();
+        let ok = ScrapeOk::Structured(serde_json::json!({ "a": "\n" }));
+        w.process(&config, Ok(ok)).await.unwrap();
+
+        let out = w.writer.lock().await;
+        let out = std::str::from_utf8(&out).unwrap();
+        let lines: Vec<_> = out.lines().collect();
+        assert_eq!(2, lines.len(), "{out}");
+        assert!(!out.contains(['\u{2028}', '\u{85}']));
+        let record: ScrapeCallRepr = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(config, record.target_config);
+        let chunk: ChunkRepr = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(ChunkEncoding::Base64, chunk.encoding);
+    }
+
+    #[tokio::test]
+    async fn transitions_are_written_as_records() {
+        let config = ScrapeTargetBuilder::new()
+            .name("nginx")
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let w = LogOutputWriter::new(Vec::<u8>::new());
+        let transition = Transition {
+            health: crate::health::Health::Failing,
+            after: 3,
+            error: None,
+        };
+        w.process_transition(&config, &transition).await.unwrap();
+
+        let out = w.writer.lock().await;
+        let record: TransitionRepr = serde_json::from_slice(&out).unwrap();
+        assert_eq!("target nginx down after 3 failures", record.message);
+        assert_eq!(transition, record.transition);
+    }
+
+    #[tokio::test]
+    async fn banners_are_written_as_records() {
+        let w = LogOutputWriter::new(Vec::<u8>::new());
+        let banner = Banner::new(&[]);
+        w.process_banner(&banner).await.unwrap();
+
+        let out = w.writer.lock().await;
+        let record: BannerRepr = serde_json::from_slice(&out).unwrap();
+        // The start time does not survive the float round trip exactly.
+        assert_eq!(banner.config_sha256, record.banner.config_sha256);
+        assert!(record.message.ends_with("started with 0 targets"));
+    }
+
+    #[tokio::test]
+    async fn bodies_are_spooled() {
+        let dir = std::env::temp_dir().join(format!("debugbunny-rp-spool-{}", std::process::id()));
+        let config = ScrapeTargetBuilder::new()
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let w = LogOutputWriter::new(Vec::<u8>::new()).with_body_spool(BodySpool::new(&dir));
+        let ok = ScrapeOk::Structured(serde_json::json!({ "a": 1 }));
+        w.process(&config, Ok(ok)).await.unwrap();
+
+        let out = w.writer.lock().await;
+        let lines: Vec<_> = out
+            .split(|b| *b == b'\n')
+            .filter(|l| !l.is_empty())
+            .collect();
+        assert_eq!(1, lines.len());
+        let call: ScrapeCallRepr = serde_json::from_slice(lines[0]).unwrap();
+        let spooled = call.spooled.unwrap();
+        let body = zstd::decode_all(std::fs::File::open(&spooled.path).unwrap()).unwrap();
+        assert_eq!(br#"{"a":1}"#.as_slice(), body);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn bodies_that_cannot_be_spooled_are_chunked() {
+        // A file where the spool directory should be.
+        let dir =
+            std::env::temp_dir().join(format!("debugbunny-rp-nospool-{}", std::process::id()));
+        std::fs::write(&dir, b"").unwrap();
+        let config = ScrapeTargetBuilder::new()
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let w = LogOutputWriter::new(Vec::<u8>::new()).with_body_spool(BodySpool::new(&dir));
+        let ok = ScrapeOk::Structured(serde_json::json!({ "a": 1 }));
+        w.process(&config, Ok(ok)).await.unwrap();
+        std::fs::remove_file(&dir).unwrap();
+
+        let out = w.writer.lock().await;
+        let mut decoder = crate::decoder::Decoder::new();
+        let records: Vec<_> = out
+            .split(|b| *b == b'\n')
+            .filter_map(|l| decoder.push_line(l).unwrap())
+            .collect();
+        assert_eq!(1, records.len());
+        assert_eq!(None, records[0].call.spooled);
+        assert_eq!(Some(br#"{"a":1}"#.to_vec()), records[0].body);
+    }
+
+    #[test]
+    fn oversized_records_are_split() {
+        let derived: BTreeMap<_, _> = (0..10)
+            .map(|i| (format!("field{i}"), serde_json::json!("x".repeat(1000))))
+            .collect();
+        let call = ScrapeCallRepr {
+            invocation_id: Uuid::new_v4(),
+            sequence: 0,
+            derived: derived.clone(),
+            promoted: BTreeMap::new(),
+            checks: vec![],
+            target_config: ScrapeTargetBuilder::new()
+                .interval(Duration::from_secs(1))
+                .action(Action::SelfStatus)
+                .build(),
+            result: ScrapeResultRepr::Error {
+                message: "failed".to_string(),
+            },
+            overflow: None,
+            anomalous_size: false,
+            spooled: None,
+            dictionary: None,
+            delta: None,
+        };
+        let format = RecordFormat {
+            signer: None,
+            single_line: false,
+        };
+        let lines = encode_call(call, format).into_inner();
+        let lines: Vec<_> = lines
+            .split(|b| *b == b'\n')
+            .filter(|l| !l.is_empty())
+            .collect();
+        assert!(lines.iter().all(|l| l.len() <= MAX_RECORD_LEN));
+
+        let call: ScrapeCallRepr = serde_json::from_slice(lines[0]).unwrap();
+        assert!(call.derived.is_empty());
+        assert_eq!(Some(lines.len() - 1), call.overflow);
+        let mut merged = BTreeMap::new();
+        for l in &lines[1..] {
+            let o: OverflowRepr = serde_json::from_slice(l).unwrap();
+            assert_eq!(call.invocation_id, o.invocation_id);
+            merged.extend(o.derived);
+        }
+        assert_eq!(derived, merged);
     }
 }
@@ -0,0 +1,643 @@
+//! Buffering results on disk while a sink is unavailable.
+//!
+//! Diagnostics are needed most when the network is broken, which is exactly
+//! when a sink that ships them elsewhere fails. A [RetryQueueLayer] wraps such
+//! a sink: results it rejects are written to a queue directory and delivered
+//! in order, with exponential backoff, once it accepts them again. Results
+//! that arrive while the queue is not empty are queued behind it, such that
+//! the sink sees them in order. To that end, the sink is passed one result at
+//! a time. The queue survives restarts.
+//!
+//! Results that are still queued on shutdown stay on disk. They are delivered
+//! after the next start, beginning with the first result processed then.
+//!
+//! The queue is capped in size. When it is full, the oldest results are
+//! dropped. Queued errors only keep their message (see [ScrapeErr::Restored]).
+
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use serde_with::{base64::Base64, serde_as};
+use tokio::{fs, sync::Mutex};
+
+use crate::{
+    banner::Banner,
+    command::{CommandOutput, OutputLine, ResourceUsage, Stream},
+    config::ScrapeTargetConfig,
+    health::Transition,
+    layer::ProcessorLayer,
+    result_processor::ScrapeResultProcessor,
+    scrape_target::{ScrapeErr, ScrapeOk, ScrapeResult},
+};
+
+const EXTENSION: &str = "json";
+
+/// Queues results the inner processor fails to process. See the
+/// [module docs](self).
+#[derive(Debug, Clone)]
+pub struct RetryQueueLayer {
+    dir: PathBuf,
+    max_bytes: u64,
+    min_backoff: Duration,
+    max_backoff: Duration,
+}
+
+impl RetryQueueLayer {
+    /// The directory is created with the first queued result, if needed. It
+    /// should not be shared with other queues.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self {
+            dir: dir.into(),
+            max_bytes: 64 << 20,
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(300),
+        }
+    }
+
+    /// Drop the oldest results once the queue takes up more than `n` bytes.
+    /// The latest result is always kept. Defaults to 64 MiB.
+    pub fn max_bytes(mut self, n: u64) -> Self {
+        self.max_bytes = n;
+        self
+    }
+
+    /// The delay before retrying after the first failure, doubled with each
+    /// further failure up to `max`. Defaults to 1s and 5min.
+    pub fn backoff(mut self, min: Duration, max: Duration) -> Self {
+        self.min_backoff = min;
+        self.max_backoff = max.max(min);
+        self
+    }
+}
+
+impl<P> ProcessorLayer<P> for RetryQueueLayer
+where
+    P: ScrapeResultProcessor + 'static,
+{
+    type Processor = RetryQueue<P>;
+
+    fn layer(&self, inner: P) -> Self::Processor {
+        RetryQueue {
+            inner,
+            shared: Arc::new(Shared {
+                config: self.clone(),
+                state: Default::default(),
+            }),
+        }
+    }
+}
+
+pub struct RetryQueue<P> {
+    inner: P,
+    shared: Arc<Shared>,
+}
+
+impl<P: Clone> Clone for RetryQueue<P> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+struct Shared {
+    config: RetryQueueLayer,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Whether the entries left over from earlier runs have been read.
+    loaded: bool,
+    /// Sequence number and size of the queued results, oldest first.
+    entries: VecDeque<(u64, u64)>,
+    bytes: u64,
+    next: u64,
+    draining: bool,
+    shut_down: bool,
+}
+
+impl<P> RetryQueue<P> {
+    /// The number of results waiting to be delivered.
+    pub async fn len(&self) -> usize {
+        self.shared.state.lock().await.entries.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+}
+
+impl<P> ScrapeResultProcessor for RetryQueue<P>
+where
+    P: ScrapeResultProcessor + 'static,
+{
+    fn process(
+        &self,
+        config: &ScrapeTargetConfig,
+        result: ScrapeResult<ScrapeOk>,
+    ) -> impl Future<Output = io::Result<()>> + Send {
+        let this = self.clone();
+        let config = config.clone();
+        async move {
+            // The result is delivered under the lock, such that results that
+            // arrive in the meantime are not delivered before it, nor while
+            // it is being queued.
+            let mut state = this.shared.state.lock().await;
+            if !state.loaded {
+                this.shared.load(&mut state).await?;
+                this.start_draining(&mut state);
+            }
+            if state.entries.is_empty() && !state.draining {
+                match this.inner.process(&config, result.clone()).await {
+                    Ok(()) => return Ok(()),
+                    Err(e) => tracing::warn!(error = %e, "Sink failed, queueing the result"),
+                }
+            }
+            this.enqueue(&mut state, &config, &result).await
+        }
+    }
+
+    fn flush(&self) -> impl Future<Output = io::Result<()>> + Send {
+        self.inner.flush()
+    }
+
+    async fn shutdown(&self) -> io::Result<()> {
+        self.shared.state.lock().await.shut_down = true;
+        self.inner.shutdown().await
+    }
+
+    /// Transitions are not queued.
+    fn process_transition(
+        &self,
+        config: &ScrapeTargetConfig,
+        transition: &Transition,
+    ) -> impl Future<Output = io::Result<()>> + Send {
+        self.inner.process_transition(config, transition)
+    }
+
+    fn process_banner(&self, banner: &Banner) -> impl Future<Output = io::Result<()>> + Send {
+        self.inner.process_banner(banner)
+    }
+}
+
+impl<P> RetryQueue<P>
+where
+    P: ScrapeResultProcessor + 'static,
+{
+    async fn enqueue(
+        &self,
+        state: &mut State,
+        config: &ScrapeTargetConfig,
+        result: &ScrapeResult<ScrapeOk>,
+    ) -> io::Result<()> {
+        let entry = serde_json::to_vec(&Entry {
+            config: config.clone(),
+            queued_at: SystemTime::now(),
+            result: StoredResult::from(result),
+        })?;
+        let shared = &self.shared;
+        fs::create_dir_all(&shared.config.dir).await?;
+        let seq = state.next;
+        let tmp = shared.path(seq).with_extension("tmp");
+        fs::write(&tmp, &entry).await?;
+        fs::rename(&tmp, shared.path(seq)).await?;
+        state.next += 1;
+        state.entries.push_back((seq, entry.len() as u64));
+        state.bytes += entry.len() as u64;
+        while state.bytes > shared.config.max_bytes && state.entries.len() > 1 {
+            let (seq, len) = state.entries.pop_front().expect("not empty");
+            state.bytes -= len;
+            tracing::warn!(seq, "Retry queue is full, dropping the oldest result");
+            let _ = fs::remove_file(shared.path(seq)).await;
+        }
+        self.start_draining(state);
+        Ok(())
+    }
+
+    /// Deliver the queued results in the background, unless that is happening
+    /// already or the queue is empty.
+    fn start_draining(&self, state: &mut State) {
+        if !state.draining && !state.entries.is_empty() {
+            state.draining = true;
+            tokio::spawn(drain(self.shared.clone(), self.inner.clone()));
+        }
+    }
+}
+
+/// Deliver the queued results in order until the queue is empty.
+async fn drain<P: ScrapeResultProcessor>(shared: Arc<Shared>, inner: P) {
+    let mut backoff = shared.config.min_backoff;
+    loop {
+        let Some((seq, _)) = ({
+            let mut state = shared.state.lock().await;
+            let front = state.entries.front().copied().filter(|_| !state.shut_down);
+            state.draining = front.is_some();
+            front
+        }) else {
+            return;
+        };
+        let entry = read(&shared.path(seq)).await;
+        // The queue may have been full in the meantime.
+        if shared.state.lock().await.entries.front().map(|e| e.0) != Some(seq) {
+            continue;
+        }
+        let delivered = match entry {
+            Ok(entry) => {
+                let result = entry.result.into_result();
+                match inner.process(&entry.config, result).await {
+                    Ok(()) => true,
+                    Err(e) => {
+                        tracing::debug!(error = %e, ?backoff, "Sink still failing");
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(shared.config.max_backoff);
+                        false
+                    }
+                }
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, seq, "Dropping unreadable queued result");
+                true
+            }
+        };
+        if delivered {
+            backoff = shared.config.min_backoff;
+            let mut state = shared.state.lock().await;
+            // The entry may have been dropped in the meantime.
+            if let Some(&(front, len)) = state.entries.front() {
+                if front == seq {
+                    state.entries.pop_front();
+                    state.bytes -= len;
+                }
+            }
+            let _ = fs::remove_file(shared.path(seq)).await;
+        }
+    }
+}
+
+async fn read(path: &Path) -> io::Result<Entry> {
+    Ok(serde_json::from_slice(&fs::read(path).await?)?)
+}
+
+impl Shared {
+    fn path(&self, seq: u64) -> PathBuf {
+        self.config.dir.join(format!("{seq:020}.{EXTENSION}"))
+    }
+
+    /// Pick up the results queued by earlier runs.
+    async fn load(&self, state: &mut State) -> io::Result<()> {
+        let mut entries = Vec::new();
+        match fs::read_dir(&self.config.dir).await {
+            Ok(mut dir) => {
+                while let Some(entry) = dir.next_entry().await? {
+                    let path = entry.path();
+                    if path.extension().map_or(true, |e| e != EXTENSION) {
+                        continue;
+                    }
+                    let seq = path.file_stem().and_then(|s| s.to_str()?.parse().ok());
+                    if let Some(seq) = seq {
+                        entries.push((seq, entry.metadata().await?.len()));
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        entries.sort_unstable();
+        state.next = entries.last().map_or(0, |(seq, _)| seq + 1);
+        state.bytes = entries.iter().map(|(_, len)| len).sum();
+        state.entries = entries.into();
+        state.loaded = true;
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    config: ScrapeTargetConfig,
+    queued_at: SystemTime,
+    result: StoredResult,
+}
+
+#[serde_as]
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum StoredResult {
+    Http {
+        status: u16,
+        #[serde_as(as = "Vec<(_, Base64)>")]
+        headers: Vec<(String, Vec<u8>)>,
+        #[serde_as(as = "Base64")]
+        body: Vec<u8>,
+    },
+    Command {
+        /// The raw status as reported by the platform.
+        status: i64,
+        #[serde_as(as = "Base64")]
+        stdout: Vec<u8>,
+        #[serde_as(as = "Base64")]
+        stderr: Vec<u8>,
+        lines: Vec<(SystemTime, Stream, Range<usize>)>,
+        usage: ResourceUsage,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        parsed: Option<serde_json::Value>,
+    },
+    Structured(serde_json::Value),
+    Err(String),
+}
+
+impl From<&ScrapeResult<ScrapeOk>> for StoredResult {
+    fn from(result: &ScrapeResult<ScrapeOk>) -> Self {
+        match result {
+            Ok(ScrapeOk::HttpResponse(r)) => Self::Http {
+                status: r.status().as_u16(),
+                headers: r
+                    .headers()
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), v.as_bytes().to_vec()))
+                    .collect(),
+                body: r.body().to_vec(),
+            },
+            Ok(ScrapeOk::CommandResponse(o)) => Self::Command {
+                status: raw_status(o.status),
+                stdout: o.stdout.clone(),
+                stderr: o.stderr.clone(),
+                lines: o
+                    .lines
+                    .iter()
+                    .map(|l| (l.timestamp, l.stream, l.range.clone()))
+                    .collect(),
+                usage: o.usage,
+                parsed: o.parsed.clone(),
+            },
+            Ok(ScrapeOk::Structured(v)) => Self::Structured(v.clone()),
+            Err(e) => Self::Err(e.to_string()),
+        }
+    }
+}
+
+impl StoredResult {
+    fn into_result(self) -> ScrapeResult<ScrapeOk> {
+        match self {
+            Self::Http {
+                status,
+                headers,
+                body,
+            } => {
+                let mut r = http::Response::new(Bytes::from(body));
+                *r.status_mut() = http::StatusCode::from_u16(status)
+                    .map_err(|e| ScrapeErr::Restored(e.to_string()))?;
+                for (k, v) in headers {
+                    if let (Ok(k), Ok(v)) = (
+                        http::HeaderName::try_from(k),
+                        http::HeaderValue::from_bytes(&v),
+                    ) {
+                        r.headers_mut().append(k, v);
+                    }
+                }
+                Ok(ScrapeOk::HttpResponse(r))
+            }
+            Self::Command {
+                status,
+                stdout,
+                stderr,
+                lines,
+                usage,
+                parsed,
+            } => Ok(ScrapeOk::CommandResponse(CommandOutput {
+                status: from_raw_status(status),
+                stdout,
+                stderr,
+                lines: lines
+                    .into_iter()
+                    .map(|(timestamp, stream, range)| OutputLine {
+                        timestamp,
+                        stream,
+                        range,
+                    })
+                    .collect(),
+                usage,
+                parsed,
+            })),
+            Self::Structured(v) => Ok(ScrapeOk::Structured(v)),
+            Self::Err(e) => Err(ScrapeErr::Restored(e)),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn raw_status(status: ExitStatus) -> i64 {
+    use std::os::unix::process::ExitStatusExt;
+    status.into_raw().into()
+}
+
+#[cfg(unix)]
+fn from_raw_status(raw: i64) -> ExitStatus {
+    use std::os::unix::process::ExitStatusExt;
+    ExitStatus::from_raw(raw as i32)
+}
+
+#[cfg(windows)]
+fn raw_status(status: ExitStatus) -> i64 {
+    status.code().unwrap_or_default() as u32 as i64
+}
+
+#[cfg(windows)]
+fn from_raw_status(raw: i64) -> ExitStatus {
+    use std::os::windows::process::ExitStatusExt;
+    ExitStatus::from_raw(raw as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex as StdMutex,
+    };
+
+    use super::*;
+    use crate::{
+        config::{Action, ScrapeTargetBuilder},
+        layer::ProcessorBuilder,
+    };
+
+    #[derive(Clone, Default)]
+    struct Flaky {
+        down: Arc<AtomicBool>,
+        delivered: Arc<StdMutex<Vec<String>>>,
+    }
+
+    impl ScrapeResultProcessor for Flaky {
+        async fn process(
+            &self,
+            config: &ScrapeTargetConfig,
+            result: ScrapeResult<ScrapeOk>,
+        ) -> io::Result<()> {
+            if self.down.load(Ordering::Relaxed) {
+                return Err(io::Error::other("unavailable"));
+            }
+            let s = match result {
+                Ok(ScrapeOk::CommandResponse(o)) => String::from_utf8(o.stdout).unwrap(),
+                Ok(ScrapeOk::HttpResponse(r)) => format!("{} {:?}", r.status(), r.body()),
+                Ok(ScrapeOk::Structured(v)) => v.to_string(),
+                Err(e) => e.to_string(),
+            };
+            let name = config.name.clone().unwrap_or_default();
+            self.delivered.lock().unwrap().push(format!("{name}: {s}"));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn results_are_delivered_once_the_sink_recovers() {
+        let dir = std::env::temp_dir().join(format!("debugbunny-retry-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = ScrapeTargetBuilder::new()
+            .name("t")
+            .interval(Duration::from_secs(1))
+            .action(Action::command("echo".to_string()))
+            .build();
+        let sink = Flaky::default();
+        let layer = RetryQueueLayer::new(&dir)
+            .backoff(Duration::from_millis(10), Duration::from_millis(20))
+            .max_bytes(1200);
+        let p = ProcessorBuilder::new()
+            .layer(layer.clone())
+            .processor(sink.clone());
+
+        sink.down.store(true, Ordering::Relaxed);
+        let output = |s: &str| CommandOutput {
+            status: from_raw_status(0),
+            stdout: s.as_bytes().to_vec(),
+            stderr: vec![],
+            lines: vec![],
+            usage: Default::default(),
+            parsed: None,
+        };
+        for s in ["a", "b", "c"] {
+            let r = Ok(ScrapeOk::CommandResponse(output(s)));
+            p.process(&config, r).await.unwrap();
+        }
+        p.process(&config, Err(ScrapeErr::Cancelled)).await.unwrap();
+        let r = Ok(ScrapeOk::HttpResponse(http::Response::new("x".into())));
+        p.process(&config, r).await.unwrap();
+        // The queue is capped, the first result is dropped.
+        assert_eq!(4, p.len().await);
+        assert!(sink.delivered.lock().unwrap().is_empty());
+
+        sink.down.store(false, Ordering::Relaxed);
+        wait_until_empty(&p).await;
+        assert_eq!(
+            vec!["t: b", "t: c", "t: Cancelled", "t: 200 OK b\"x\""],
+            *sink.delivered.lock().unwrap()
+        );
+        assert_eq!(0, std::fs::read_dir(&dir).unwrap().count());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Fails the first result after a while and accepts all others.
+    #[derive(Clone, Default)]
+    struct SlowFailure {
+        failed: Arc<AtomicBool>,
+        delivered: Arc<StdMutex<Vec<String>>>,
+    }
+
+    impl ScrapeResultProcessor for SlowFailure {
+        async fn process(
+            &self,
+            _config: &ScrapeTargetConfig,
+            result: ScrapeResult<ScrapeOk>,
+        ) -> io::Result<()> {
+            if !self.failed.swap(true, Ordering::Relaxed) {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                return Err(io::Error::other("unavailable"));
+            }
+            let Ok(ScrapeOk::Structured(v)) = result else {
+                unreachable!()
+            };
+            self.delivered.lock().unwrap().push(v.to_string());
+            Ok(())
+        }
+    }
+
+    async fn wait_until_empty<P: ScrapeResultProcessor + 'static>(q: &RetryQueue<P>) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !q.is_empty().await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn results_arriving_during_a_failed_delivery_stay_in_order() {
+        let dir =
+            std::env::temp_dir().join(format!("debugbunny-retry-order-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = ScrapeTargetBuilder::new()
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let sink = SlowFailure::default();
+        let layer = RetryQueueLayer::new(&dir)
+            .backoff(Duration::from_millis(10), Duration::from_millis(10));
+        let p = ProcessorBuilder::new().layer(layer).processor(sink.clone());
+
+        let first = tokio::spawn({
+            let p = p.clone();
+            let config = config.clone();
+            async move { p.process(&config, Ok(ScrapeOk::Structured(1.into()))).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        p.process(&config, Ok(ScrapeOk::Structured(2.into())))
+            .await
+            .unwrap();
+        first.await.unwrap().unwrap();
+        wait_until_empty(&p).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(vec!["1", "2"], *sink.delivered.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn results_of_earlier_runs_are_delivered_first() {
+        let dir =
+            std::env::temp_dir().join(format!("debugbunny-retry-restart-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = ScrapeTargetBuilder::new()
+            .name("t")
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let layer = RetryQueueLayer::new(&dir)
+            .backoff(Duration::from_millis(10), Duration::from_millis(10));
+        let down = Flaky::default();
+        down.down.store(true, Ordering::Relaxed);
+        let p = ProcessorBuilder::new().layer(layer.clone()).processor(down);
+        p.process(&config, Ok(ScrapeOk::Structured(1.into())))
+            .await
+            .unwrap();
+        p.shutdown().await.unwrap();
+
+        // A new queue over the same directory, as after a restart.
+        let sink = Flaky::default();
+        let p = ProcessorBuilder::new().layer(layer).processor(sink.clone());
+        p.process(&config, Ok(ScrapeOk::Structured(2.into())))
+            .await
+            .unwrap();
+        wait_until_empty(&p).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(vec!["t: 1", "t: 2"], *sink.delivered.lock().unwrap());
+    }
+}
@@ -0,0 +1,129 @@
+//! Metrics of the tokio runtime debugbunny runs on.
+//!
+//! When debugbunny is embedded in the service being debugged, the runtime is
+//! shared with the service, and a [RuntimeMetricsCollector] reports how busy
+//! it is: the number of tasks, the depth of the queues and how long the
+//! workers were busy. Requires the `runtime-metrics` feature.
+//!
+//! Some metrics, e.g. the blocking pool and poll times, are only available if
+//! the embedding application is built with `RUSTFLAGS="--cfg tokio_unstable"`.
+//! Otherwise, they are omitted from the output.
+
+use serde::Serialize;
+
+use crate::scrape_target::{FutureScrapeResult, ScrapeOk, ScrapeService};
+
+#[derive(Default)]
+pub struct RuntimeMetricsCollector;
+
+#[derive(Debug, Default, Serialize)]
+pub struct RuntimeMetrics {
+    pub workers: usize,
+    pub alive_tasks: usize,
+    /// Tasks scheduled from outside of the runtime and not yet picked up.
+    pub global_queue_depth: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub spawned_tasks: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blocking_threads: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_blocking_threads: Option<usize>,
+    /// Blocking tasks waiting for a thread of the blocking pool.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blocking_queue_depth: Option<usize>,
+    pub worker_metrics: Vec<WorkerMetrics>,
+}
+
+/// The metrics of a single worker thread. Counters are totals since the
+/// start of the runtime.
+#[derive(Debug, Default, Serialize)]
+pub struct WorkerMetrics {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub busy_secs: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub park_count: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub poll_count: Option<u64>,
+    /// An exponentially weighted moving average of the poll times.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mean_poll_time_secs: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_queue_depth: Option<usize>,
+}
+
+impl RuntimeMetricsCollector {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl RuntimeMetrics {
+    /// Sample the metrics of the current runtime.
+    ///
+    /// # Panics
+    ///
+    /// If called outside of a tokio runtime.
+    pub fn sample() -> Self {
+        let m = tokio::runtime::Handle::current().metrics();
+        #[allow(unused_mut)]
+        let mut s = Self {
+            workers: m.num_workers(),
+            alive_tasks: m.num_alive_tasks(),
+            global_queue_depth: m.global_queue_depth(),
+            worker_metrics: (0..m.num_workers()).map(|w| worker(&m, w)).collect(),
+            ..Default::default()
+        };
+        #[cfg(tokio_unstable)]
+        {
+            s.spawned_tasks = Some(m.spawned_tasks_count());
+            s.blocking_threads = Some(m.num_blocking_threads());
+            s.idle_blocking_threads = Some(m.num_idle_blocking_threads());
+            s.blocking_queue_depth = Some(m.blocking_queue_depth());
+        }
+        s
+    }
+}
+
+#[allow(unused_variables, unused_mut)]
+fn worker(m: &tokio::runtime::RuntimeMetrics, w: usize) -> WorkerMetrics {
+    let mut s = WorkerMetrics::default();
+    #[cfg(target_has_atomic = "64")]
+    {
+        s.busy_secs = Some(m.worker_total_busy_duration(w).as_secs_f64());
+        s.park_count = Some(m.worker_park_count(w));
+    }
+    #[cfg(tokio_unstable)]
+    {
+        s.poll_count = Some(m.worker_poll_count(w));
+        s.mean_poll_time_secs = Some(m.worker_mean_poll_time(w).as_secs_f64());
+        s.local_queue_depth = Some(m.worker_local_queue_depth(w));
+    }
+    s
+}
+
+impl ScrapeService for RuntimeMetricsCollector {
+    type Response = ScrapeOk;
+
+    fn call(&mut self) -> FutureScrapeResult<ScrapeOk> {
+        let metrics = RuntimeMetrics::sample();
+        Box::pin(async move {
+            let v = serde_json::to_value(metrics).expect("can't fail");
+            Ok(ScrapeOk::Structured(v))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn metrics_of_the_current_runtime() {
+        let Ok(ScrapeOk::Structured(v)) = RuntimeMetricsCollector::new().call().await else {
+            panic!("expected structured output");
+        };
+        assert_eq!(2, v["workers"]);
+        assert_eq!(2, v["worker_metrics"].as_array().unwrap().len());
+        assert!(v["alive_tasks"].is_u64());
+    }
+}
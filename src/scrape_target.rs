@@ -1,13 +1,23 @@
-use std::{future::Future, io, pin::Pin, sync::Arc, time::Duration};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex as StdMutex},
+    time::{Duration, SystemTime},
+};
 
+use bytes::Bytes;
 use tokio::{
     sync::{
-        watch::{Receiver, Sender},
-        Mutex,
+        watch::{self, Receiver, Sender},
+        Mutex, Notify,
     },
     time::{error::Elapsed, Instant},
 };
 
+use crate::{command::CommandOutput, config::IntervalProfile, template::TemplateError};
+
 pub type FutureScrapeResult<T> = Pin<Box<dyn Future<Output = ScrapeResult<T>> + Send>>;
 pub type BoxedScrapeService = Box<dyn ScrapeService<Response = ScrapeOk>>;
 
@@ -28,6 +38,13 @@ pub type BoxedScrapeService = Box<dyn ScrapeService<Response = ScrapeOk>>;
 pub trait ScrapeService: Send {
     type Response: Send + 'static;
     fn call(&mut self) -> FutureScrapeResult<Self::Response>;
+
+    /// A call on behalf of an [UnscheduledScrapeTarget]. Wrappers forward this
+    /// to the inner service, such that e.g. [Timeout] can apply a different
+    /// budget to on-demand calls.
+    fn call_unscheduled(&mut self) -> FutureScrapeResult<Self::Response> {
+        self.call()
+    }
 }
 
 // This is the equivalent of
@@ -38,31 +55,142 @@ impl<T: ScrapeService + ?Sized> ScrapeService for Box<T> {
     fn call(&mut self) -> FutureScrapeResult<Self::Response> {
         (**self).call()
     }
+
+    fn call_unscheduled(&mut self) -> FutureScrapeResult<Self::Response> {
+        (**self).call_unscheduled()
+    }
 }
 
 pub type ScrapeResult<T> = Result<T, ScrapeErr>;
 
 pub enum ScrapeOk {
-    HttpResponse(http::Response<Vec<u8>>),
-    CommandResponse(std::process::Output),
+    HttpResponse(http::Response<Bytes>),
+    CommandResponse(CommandOutput),
+    /// Data produced by debugbunny itself rather than by an external source.
+    Structured(serde_json::Value),
 }
 
-#[derive(Debug, thiserror::Error)]
+/// Cloning is cheap, as bodies are reference-counted.
+impl Clone for ScrapeOk {
+    fn clone(&self) -> Self {
+        match self {
+            Self::HttpResponse(r) => {
+                let mut c = http::Response::new(r.body().clone());
+                *c.status_mut() = r.status();
+                *c.version_mut() = r.version();
+                *c.headers_mut() = r.headers().clone();
+                *c.extensions_mut() = r.extensions().clone();
+                Self::HttpResponse(c)
+            }
+            Self::CommandResponse(o) => Self::CommandResponse(o.clone()),
+            Self::Structured(v) => Self::Structured(v.clone()),
+        }
+    }
+}
+
+impl ScrapeOk {
+    /// The number of bytes of collected output held by this result.
+    pub fn body_len(&self) -> usize {
+        match self {
+            Self::HttpResponse(r) => r.body().len(),
+            Self::CommandResponse(o) => o.stdout.len() + o.stderr.len(),
+            // Structured data is small and not accounted for.
+            Self::Structured(_) => 0,
+        }
+    }
+}
+
+/// Errors are cheap to clone, such that a result can be handed to several
+/// consumers (see [crate::debugbunny::DebugBunny]). Sources that are not
+/// clonable are shared.
+#[derive(Debug, Clone, thiserror::Error)]
 pub enum ScrapeErr {
     #[error("Http error")]
-    HttpErr(#[from] reqwest::Error),
+    HttpErr(#[source] Arc<reqwest::Error>),
+    #[error("Unexpected HTTP status: {0}")]
+    UnexpectedStatus(http::StatusCode),
+    #[error("Invalid URL template")]
+    Template(#[source] Arc<TemplateError>),
     // xxx(dsd): this is not entirely clean, as an io-error might occur in other places too.
     #[error("Command execution error")]
-    IoErr(#[from] io::Error),
+    IoErr(#[source] Arc<io::Error>),
     #[error("Scrape timed out")]
-    Timeout(#[from] Elapsed),
+    Timeout(#[source] Arc<Elapsed>),
     #[error("Cancelled")]
     Cancelled,
+    #[error("Unscheduled call cancelled after the deadline of {0:?}")]
+    DeadlineExceeded(Duration),
+    #[error("Preempted by an unscheduled call")]
+    Preempted,
+    #[error("Skipped: would overrun (expected to take {0:?})")]
+    WouldOverrun(Duration),
+    #[error("Scrape got stuck for {0:?} and was aborted")]
+    Stuck(Duration),
+    #[error("The {hook} hook failed: {reason}")]
+    Hook { hook: &'static str, reason: String },
+    #[error("Circuit open, not calling the target for another {0:?}")]
+    CircuitOpen(Duration),
+    #[error("No data received for {after:?} after receiving {received} bytes of the body")]
+    Stalled { after: Duration, received: usize },
+    /// See [crate::memory].
+    #[error("Memory budget exhausted after receiving {received} bytes of the body")]
+    MemoryExhausted { received: usize },
+    #[error("Command not found: {0}")]
+    CommandNotFound(String),
+    #[error("Target disabled after {0} consecutive failures")]
+    Disabled(u32),
+    /// An error restored from its message, e.g. by a
+    /// [RetryQueue](crate::retry_queue::RetryQueue).
+    #[error("{0}")]
+    Restored(String),
+    /// Summarizes repetitions of an error, see [crate::error_dedup].
+    #[error("Error repeated {count} times in the last {over:?}: {message}")]
+    Repeated {
+        message: String,
+        count: u64,
+        over: Duration,
+    },
+}
+
+impl ScrapeErr {
+    /// Whether the call did not reach the target, e.g. because it was
+    /// cancelled or skipped. Such errors say nothing about the target.
+    pub fn is_unreachable(&self) -> bool {
+        matches!(
+            self,
+            Self::Cancelled | Self::Preempted | Self::WouldOverrun(_) | Self::CircuitOpen(_)
+        )
+    }
+}
+
+impl From<reqwest::Error> for ScrapeErr {
+    fn from(e: reqwest::Error) -> Self {
+        Self::HttpErr(Arc::new(e))
+    }
+}
+
+impl From<TemplateError> for ScrapeErr {
+    fn from(e: TemplateError) -> Self {
+        Self::Template(Arc::new(e))
+    }
+}
+
+impl From<Elapsed> for ScrapeErr {
+    fn from(e: Elapsed) -> Self {
+        Self::Timeout(Arc::new(e))
+    }
+}
+
+impl From<io::Error> for ScrapeErr {
+    fn from(e: io::Error) -> Self {
+        Self::IoErr(Arc::new(e))
+    }
 }
 
 pub struct Timeout<T> {
     inner: T,
     timeout: Duration,
+    unscheduled_timeout: Option<Duration>,
     cancel: Option<Receiver<()>>,
 }
 
@@ -71,6 +199,7 @@ impl<T> Timeout<T> {
         Self {
             inner,
             timeout,
+            unscheduled_timeout: None,
             cancel: None,
         }
     }
@@ -79,29 +208,246 @@ impl<T> Timeout<T> {
         Self {
             inner,
             timeout,
+            unscheduled_timeout: None,
             cancel: Some(cancel),
         }
     }
+
+    /// The timeout of unscheduled calls. Defaults to the regular timeout.
+    pub fn with_unscheduled_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.unscheduled_timeout = timeout;
+        self
+    }
 }
 
-impl<T> ScrapeService for Timeout<T>
+impl<T> Timeout<T>
 where
     T: ScrapeService,
 {
-    type Response = T::Response;
-    fn call(&mut self) -> FutureScrapeResult<Self::Response> {
-        let timeout = self.timeout;
-        let call = self.inner.call();
+    fn with_timeout(
+        &self,
+        timeout: Duration,
+        call: FutureScrapeResult<T::Response>,
+    ) -> FutureScrapeResult<T::Response> {
         if let Some(cancel) = &self.cancel {
             let mut cancel = cancel.clone();
             return Box::pin(async move {
                 tokio::select! {
-                    r = tokio::time::timeout(timeout, call) => r?,
+                    r = tokio::time::timeout(timeout, call) => r.map_err(|e| timed_out(timeout, e))?,
                     _ = cancel.changed() => Err(ScrapeErr::Cancelled)
                 }
             });
         }
-        Box::pin(async move { tokio::time::timeout(timeout, call).await? })
+        Box::pin(async move {
+            tokio::time::timeout(timeout, call)
+                .await
+                .map_err(|e| timed_out(timeout, e))?
+        })
+    }
+}
+
+impl<T> ScrapeService for Timeout<T>
+where
+    T: ScrapeService,
+{
+    type Response = T::Response;
+    fn call(&mut self) -> FutureScrapeResult<Self::Response> {
+        let call = self.inner.call();
+        self.with_timeout(self.timeout, call)
+    }
+
+    fn call_unscheduled(&mut self) -> FutureScrapeResult<Self::Response> {
+        let timeout = self.unscheduled_timeout.unwrap_or(self.timeout);
+        let call = self.inner.call_unscheduled();
+        self.with_timeout(timeout, call)
+    }
+}
+
+fn timed_out(timeout: Duration, e: Elapsed) -> Elapsed {
+    tracing::debug!(?timeout, "scrape timed out");
+    e
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls pass.
+    Closed,
+    /// Calls fail without calling the inner service.
+    Open,
+    /// The next call passes, to probe whether the inner service recovered.
+    HalfOpen,
+}
+
+/// Stops calling the inner service after a number of consecutive failed
+/// calls, such that a struggling service is not hammered any further. While
+/// the circuit is open, calls fail with [ScrapeErr::CircuitOpen]. Once
+/// `open_for` has passed, the next call is let through: if it succeeds, the
+/// circuit closes, otherwise it stays open for another `open_for`.
+///
+/// Cancelled calls are not counted as failures.
+pub struct CircuitBreaker<T> {
+    inner: T,
+    circuit: Arc<Circuit>,
+}
+
+struct Circuit {
+    failures: u32,
+    open_for: Duration,
+    state: StdMutex<CircuitCounters>,
+    observed: watch::Sender<CircuitState>,
+}
+
+#[derive(Default)]
+struct CircuitCounters {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl<T> CircuitBreaker<T> {
+    pub fn new(inner: T, failures: u32, open_for: Duration) -> Self {
+        Self {
+            inner,
+            circuit: Arc::new(Circuit {
+                failures,
+                open_for,
+                state: Default::default(),
+                observed: watch::channel(CircuitState::Closed).0,
+            }),
+        }
+    }
+
+    /// Observe the state of the circuit.
+    pub fn subscribe(&self) -> Receiver<CircuitState> {
+        self.circuit.observed.subscribe()
+    }
+}
+
+impl Circuit {
+    /// The error of a call that is rejected, if any.
+    fn reject(&self) -> Option<ScrapeErr> {
+        let state = self.state.lock().unwrap();
+        let open_until = state.open_until?;
+        let now = Instant::now();
+        if now < open_until {
+            return Some(ScrapeErr::CircuitOpen(open_until - now));
+        }
+        self.observed.send_replace(CircuitState::HalfOpen);
+        None
+    }
+
+    fn record<R>(&self, r: &ScrapeResult<R>) {
+        let mut state = self.state.lock().unwrap();
+        match r {
+            Ok(_) => {
+                *state = CircuitCounters::default();
+                self.observed.send_if_modified(|s| {
+                    std::mem::replace(s, CircuitState::Closed) != CircuitState::Closed
+                });
+            }
+            Err(ScrapeErr::Cancelled) => {}
+            Err(_) => {
+                state.consecutive_failures = state.consecutive_failures.saturating_add(1);
+                // A failed probe opens the circuit again right away.
+                if state.open_until.is_some() || state.consecutive_failures >= self.failures {
+                    tracing::warn!(
+                        failures = state.consecutive_failures,
+                        open_for = ?self.open_for,
+                        "opening circuit"
+                    );
+                    state.open_until = Some(Instant::now() + self.open_for);
+                    self.observed.send_replace(CircuitState::Open);
+                }
+            }
+        }
+    }
+}
+
+impl<T> CircuitBreaker<T>
+where
+    T: ScrapeService,
+{
+    fn guarded(
+        &mut self,
+        call: impl FnOnce(&mut T) -> FutureScrapeResult<T::Response>,
+    ) -> FutureScrapeResult<T::Response> {
+        if let Some(e) = self.circuit.reject() {
+            return Box::pin(async move { Err(e) });
+        }
+        let call = call(&mut self.inner);
+        let circuit = self.circuit.clone();
+        Box::pin(async move {
+            let r = call.await;
+            circuit.record(&r);
+            r
+        })
+    }
+}
+
+impl<T> ScrapeService for CircuitBreaker<T>
+where
+    T: ScrapeService,
+{
+    type Response = T::Response;
+
+    fn call(&mut self) -> FutureScrapeResult<Self::Response> {
+        self.guarded(T::call)
+    }
+
+    fn call_unscheduled(&mut self) -> FutureScrapeResult<Self::Response> {
+        self.guarded(T::call_unscheduled)
+    }
+}
+
+/// Shares the result of a call with all calls that are made while it is in
+/// flight or less than `ttl` after it completed, instead of calling the inner
+/// service again. Wrapped around an [UnscheduledScrapeTarget], this coalesces
+/// storms of unscheduled calls, e.g. several alerts firing at once, into a
+/// single execution of the action.
+///
+/// If the call that executes the inner call is dropped, one of the calls
+/// waiting for its result takes over.
+pub struct Memoized<T: ScrapeService> {
+    inner: T,
+    ttl: Duration,
+    last: Option<Arc<MemoizedCall<T::Response>>>,
+}
+
+/// The result of a call and when it completed.
+type MemoizedCall<R> = tokio::sync::OnceCell<(Instant, ScrapeResult<R>)>;
+
+impl<T: ScrapeService> Memoized<T> {
+    pub fn new(inner: T, ttl: Duration) -> Self {
+        Self {
+            inner,
+            ttl,
+            last: None,
+        }
+    }
+}
+
+impl<T> ScrapeService for Memoized<T>
+where
+    T: ScrapeService,
+    T::Response: Clone + Sync,
+{
+    type Response = T::Response;
+
+    fn call(&mut self) -> FutureScrapeResult<Self::Response> {
+        let ttl = self.ttl;
+        let last = self
+            .last
+            .take()
+            .filter(|last| last.get().map_or(true, |(done, _)| done.elapsed() < ttl));
+        let shared = last.unwrap_or_default();
+        self.last = Some(shared.clone());
+        // Only one of the futures of the inner service is driven.
+        let call = self.inner.call();
+        Box::pin(async move {
+            let (_, r) = shared
+                .get_or_init(|| async move { (Instant::now(), call.await) })
+                .await;
+            r.clone()
+        })
     }
 }
 
@@ -141,33 +487,207 @@ impl<T> ScrapeTarget<T> {
             inner,
             wakeup: Instant::now(),
             interval,
+            max_interval: None,
+            overruns: None,
+            profiles: Vec::new(),
+            base_interval: (interval, None),
+            failures: 0,
         }));
 
+        let preempt = Arc::new(Notify::new());
+
         Self {
             scheduled: ScheduledScrapeTarget {
                 inner: inner.clone(),
                 cancel,
+                preempt: preempt.clone(),
+                rate_limit: None,
+            },
+            unscheduled: UnscheduledScrapeTarget {
+                inner,
+                preempt,
+                preempting: false,
             },
-            unscheduled: UnscheduledScrapeTarget { inner },
             cancel_signal: None,
         }
     }
+
+    /// Instead of following a fixed schedule, wait a uniformly random time
+    /// between the interval and `max` after the start of each scheduled call,
+    /// such that the calls do not synchronize with periodic behavior of the
+    /// observed system.
+    pub fn random_interval(self, max: Duration) -> Self {
+        self.scheduled
+            .inner
+            .try_lock()
+            .expect("a new target is not shared")
+            .max_interval = Some(max);
+        self
+    }
+
+    /// Use the interval of the first profile whose window contains the
+    /// current time of day, if any, when scheduling the next call. Call this
+    /// after [Self::random_interval].
+    pub fn interval_profiles(self, profiles: Vec<IntervalProfile>) -> Self {
+        {
+            let mut inner = self
+                .scheduled
+                .inner
+                .try_lock()
+                .expect("a new target is not shared");
+            inner.base_interval = (inner.interval, inner.max_interval);
+            inner.profiles = profiles;
+            inner.apply_profile();
+        }
+        self
+    }
+
+    /// Skip scheduled calls that, based on the durations of recent calls,
+    /// cannot complete before the next one is due. Such calls resolve with
+    /// [ScrapeErr::WouldOverrun] instead of sliding the schedule. A call is
+    /// never skipped twice in a row, such that the estimate stays current.
+    pub fn skip_overruns(self) -> Self {
+        self.scheduled
+            .inner
+            .try_lock()
+            .expect("a new target is not shared")
+            .overruns = Some(Overruns::default());
+        self
+    }
+
+    /// Start scheduled calls only as allowed by `limiter`, which may be shared
+    /// with other targets. Unscheduled calls are not limited.
+    pub fn rate_limited(mut self, limiter: RateLimiter) -> Self {
+        self.scheduled.rate_limit = Some(limiter);
+        self
+    }
+
+    /// Let the first scheduled call resolve `offset` after the start instead of
+    /// immediately.
+    pub fn delay_first_call(self, offset: Duration) -> Self {
+        self.scheduled
+            .inner
+            .try_lock()
+            .expect("a new target is not shared")
+            .wakeup = Instant::now() + offset;
+        self
+    }
+
+    /// Continue a schedule whose last scrape happened at `last_run`, e.g.
+    /// before a restart. The first scheduled call resolves one interval after
+    /// `last_run`, or immediately if that is in the past.
+    pub fn resume_from(self, last_run: SystemTime) -> Self {
+        {
+            let mut inner = self
+                .scheduled
+                .inner
+                .try_lock()
+                .expect("a new target is not shared");
+            let elapsed = last_run.elapsed().unwrap_or_default();
+            inner.wakeup = Instant::now() + inner.interval.saturating_sub(elapsed);
+        }
+        self
+    }
 }
 
+/// Spaces out the scheduled calls of all targets sharing it, such that at most
+/// `per_second` calls start per second. Due calls get their turn in the order
+/// in which they asked for it, so a busy target cannot starve the others.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    spacing: Duration,
+    next: Arc<StdMutex<Instant>>,
+}
+
+impl RateLimiter {
+    /// # Panics
+    ///
+    /// If `per_second` is not positive.
+    pub fn new(per_second: f64) -> Self {
+        assert!(per_second > 0.0, "the rate limit must be positive");
+        Self {
+            spacing: Duration::from_secs_f64(1.0 / per_second),
+            next: Arc::new(StdMutex::new(Instant::now())),
+        }
+    }
+
+    /// Reserve the next free slot and return when it starts.
+    fn reserve(&self) -> Instant {
+        let mut next = self.next.lock().unwrap();
+        let slot = (*next).max(Instant::now());
+        *next = slot + self.spacing;
+        slot
+    }
+}
+
+/// The delay after the first failed call of a continuous target, see
+/// [SyncedService::back_off].
+const MIN_CONTINUOUS_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_CONTINUOUS_BACKOFF: Duration = Duration::from_secs(30);
+
 struct SyncedService<T> {
     inner: T,
     wakeup: Instant,
     interval: Duration,
+    /// Set if the interval is picked randomly up to this value.
+    max_interval: Option<Duration>,
+    overruns: Option<Overruns>,
+    profiles: Vec<IntervalProfile>,
+    /// The interval and maximum interval outside of all profiles.
+    base_interval: (Duration, Option<Duration>),
+    /// Consecutive failed calls, counted for continuous targets only.
+    failures: u32,
+}
+
+/// The durations of the recent scheduled calls.
+#[derive(Default)]
+struct Overruns {
+    durations: VecDeque<Duration>,
+    skipped: bool,
+}
+
+impl Overruns {
+    const WINDOW: usize = 5;
+
+    fn record(&mut self, d: Duration) {
+        if self.durations.len() == Self::WINDOW {
+            self.durations.pop_front();
+        }
+        self.durations.push_back(d);
+        self.skipped = false;
+    }
+
+    /// The expected duration of a call that must finish within `remaining`,
+    /// if it is to be skipped.
+    fn should_skip(&mut self, remaining: Duration) -> Option<Duration> {
+        if self.skipped || self.durations.is_empty() {
+            return None;
+        }
+        let expected = self.durations.iter().sum::<Duration>() / self.durations.len() as u32;
+        self.skipped = expected > remaining;
+        self.skipped.then_some(expected)
+    }
 }
 
 impl<T> SyncedService<T> {
     /// Sets the wakeup time to the first point in the future that is a multiple
-    /// of the current interval using the current schedule.
+    /// of the current interval using the current schedule. With a zero
+    /// interval, the next call is due right away.
     fn set_next_wake_up_time(&mut self) {
         let now = Instant::now();
         if now < self.wakeup {
             return;
         }
+        self.apply_profile();
+        if let Some(max) = self.max_interval {
+            let wait = self.interval + (max.saturating_sub(self.interval)).mul_f64(fastrand::f64());
+            self.wakeup = (self.wakeup + wait).max(now);
+            return;
+        }
+        if self.interval.is_zero() {
+            self.wakeup = now;
+            return;
+        }
 
         let delta = now - self.wakeup;
         let ival_nanos = self.interval.as_nanos();
@@ -176,6 +696,44 @@ impl<T> SyncedService<T> {
         self.wakeup += self.interval * f;
     }
 
+    /// Delay the next call of a continuous target after a failed call, such
+    /// that e.g. a refused connection is not retried in a tight loop. The
+    /// delay doubles with every further failure, up to
+    /// [MAX_CONTINUOUS_BACKOFF].
+    fn back_off<R>(&mut self, r: &ScrapeResult<R>) {
+        if !self.interval.is_zero() || self.max_interval.is_some() {
+            return;
+        }
+        match r {
+            Err(e) if !e.is_unreachable() => {
+                let delay = MIN_CONTINUOUS_BACKOFF * 2u32.saturating_pow(self.failures.min(16));
+                self.wakeup = Instant::now() + delay.min(MAX_CONTINUOUS_BACKOFF);
+                self.failures += 1;
+            }
+            Err(_) => {}
+            Ok(_) => self.failures = 0,
+        }
+    }
+
+    /// Switch to the interval of the profile containing the current time.
+    fn apply_profile(&mut self) {
+        if self.profiles.is_empty() {
+            return;
+        }
+        let now = SystemTime::now();
+        (self.interval, self.max_interval) = self
+            .profiles
+            .iter()
+            .find(|p| p.contains_time(now))
+            .map(|p| {
+                (
+                    p.interval.min,
+                    Some(p.interval.max).filter(|_| p.interval.is_random()),
+                )
+            })
+            .unwrap_or(self.base_interval);
+    }
+
     /// Resets the schedule to the current point in time. As a result, the next
     /// scheduled scrape is happening one interval from now.
     fn reset_interval(&mut self) {
@@ -198,6 +756,21 @@ impl<T> SyncedService<T> {
 pub struct ScheduledScrapeTarget<T> {
     inner: Arc<Mutex<SyncedService<T>>>,
     cancel: Option<Receiver<()>>,
+    /// Notified by preempting unscheduled calls.
+    preempt: Arc<Notify>,
+    rate_limit: Option<RateLimiter>,
+}
+
+/// Clones share the schedule, i.e. they are interchangeable.
+impl<T> Clone for ScheduledScrapeTarget<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            cancel: self.cancel.clone(),
+            preempt: self.preempt.clone(),
+            rate_limit: self.rate_limit.clone(),
+        }
+    }
 }
 
 impl<T> ScrapeService for ScheduledScrapeTarget<T>
@@ -208,17 +781,57 @@ where
     fn call(&mut self) -> FutureScrapeResult<Self::Response> {
         let inner = self.inner.clone();
         let mut cancel = self.cancel.clone();
+        let preempt = self.preempt.clone();
+        let rate_limit = self.rate_limit.clone();
         Box::pin(async move {
+            // Taken once the call is due, such that it waits for a slot once.
+            let mut limit = rate_limit.clone();
             loop {
                 let wakeup = {
                     // critical section
                     let mut lockguard = inner.lock().await;
-                    if lockguard.is_due() {
-                        let res = lockguard.inner.call().await;
+                    let slot = lockguard
+                        .is_due()
+                        .then(|| limit.take().map(|l| l.reserve()))
+                        .flatten()
+                        .filter(|slot| *slot > Instant::now());
+                    if let Some(slot) = slot {
+                        slot
+                    } else if lockguard.is_due() {
+                        let next = lockguard.wakeup + lockguard.interval;
+                        let remaining = next.saturating_duration_since(Instant::now());
+                        // Back-to-back calls cannot overrun.
+                        let continuous = lockguard.interval.is_zero();
+                        if let Some(expected) = lockguard
+                            .overruns
+                            .as_mut()
+                            .filter(|_| !continuous)
+                            .and_then(|o| o.should_skip(remaining))
+                        {
+                            lockguard.set_next_wake_up_time();
+                            break Err(ScrapeErr::WouldOverrun(expected));
+                        }
+                        let start = Instant::now();
+                        let preempted = preempt.notified();
+                        // Dropping an in-flight call aborts it, e.g. commands
+                        // are terminated.
+                        let res = tokio::select! {
+                            r = lockguard.inner.call() => r,
+                            _ = preempted => Err(ScrapeErr::Preempted),
+                            _ = cancelled(&mut cancel) => Err(ScrapeErr::Cancelled),
+                        };
+                        if let Some(o) = &mut lockguard.overruns {
+                            o.record(start.elapsed());
+                        }
                         lockguard.set_next_wake_up_time();
+                        lockguard.back_off(&res);
                         break res;
+                    } else {
+                        // Not due (anymore), e.g. because an unscheduled call
+                        // reset the schedule.
+                        limit.clone_from(&rate_limit);
+                        lockguard.wakeup
                     }
-                    lockguard.wakeup
                 };
                 if let Some(ref mut cancel) = cancel {
                     tokio::select! {
@@ -232,8 +845,40 @@ where
     }
 }
 
+/// Resolves once `cancel` fires, or never if there is no signal.
+async fn cancelled(cancel: &mut Option<Receiver<()>>) {
+    match cancel {
+        Some(cancel) => {
+            let _ = cancel.changed().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
 pub struct UnscheduledScrapeTarget<T> {
     inner: Arc<Mutex<SyncedService<T>>>,
+    preempt: Arc<Notify>,
+    preempting: bool,
+}
+
+impl<T> Clone for UnscheduledScrapeTarget<T> {
+    /// The clone calls the same target, sharing its schedule.
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            preempt: self.preempt.clone(),
+            preempting: self.preempting,
+        }
+    }
+}
+
+impl<T> UnscheduledScrapeTarget<T> {
+    /// Instead of waiting for an in-flight scheduled call to finish, cancel
+    /// it. The scheduled call resolves with [ScrapeErr::Preempted].
+    pub fn preempting(mut self) -> Self {
+        self.preempting = true;
+        self
+    }
 }
 
 impl<T> ScrapeService for UnscheduledScrapeTarget<T>
@@ -243,9 +888,14 @@ where
     type Response = T::Response;
     fn call(&mut self) -> FutureScrapeResult<Self::Response> {
         let inner = self.inner.clone();
+        let preempt = self.preempting.then(|| self.preempt.clone());
         Box::pin(async move {
+            if let Some(preempt) = preempt {
+                // Only wakes a scheduled call that is in flight.
+                preempt.notify_waiters();
+            }
             let mut lockguard = inner.lock().await;
-            let res = lockguard.inner.call().await;
+            let res = lockguard.inner.call_unscheduled().await;
             lockguard.reset_interval();
             res
         })
@@ -254,7 +904,10 @@ where
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
     use super::*;
+    use crate::config::TimeOfDay;
 
     #[tokio::test]
     async fn synchronized_timeout_service() {
@@ -278,6 +931,199 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn unscheduled_calls_have_their_own_timeout() {
+        let service = Timeout::new(Counter(10), Duration::from_millis(30))
+            .with_unscheduled_timeout(Some(Duration::from_millis(100)));
+        let mut st = ScrapeTarget::new(service, Duration::from_millis(50));
+        assert_eq!(10, st.unscheduled.call().await.unwrap());
+        let mut service = Timeout::new(Counter(10), Duration::from_millis(30));
+        assert!(matches!(
+            service.call().await,
+            ScrapeResult::Err(ScrapeErr::Timeout(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn unscheduled_calls_preempt_scheduled_calls() {
+        let st = ScrapeTarget::new(Counter(10), Duration::from_secs(1));
+        let mut scheduled = st.scheduled;
+        let mut unscheduled = st.unscheduled.preempting();
+        let scheduled = tokio::spawn(async move { scheduled.call().await });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert_eq!(11, unscheduled.call().await.unwrap());
+        assert!(matches!(
+            scheduled.await.unwrap(),
+            ScrapeResult::Err(ScrapeErr::Preempted)
+        ));
+    }
+
+    #[tokio::test]
+    async fn zero_interval_calls_back_to_back() {
+        let mut st = ScrapeTarget::new(Counter(0), Duration::ZERO).skip_overruns();
+        let start = Instant::now();
+        for i in 0..5 {
+            assert_eq!(i, st.scheduled.call().await.unwrap());
+        }
+        assert!(start.elapsed() < Duration::from_millis(150));
+        assert_eq!(5, st.unscheduled.call().await.unwrap());
+        assert_eq!(6, st.scheduled.call().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn failing_continuous_targets_back_off() {
+        let fail = Arc::new(AtomicBool::new(true));
+        let mut st = ScrapeTarget::new(Failing(fail.clone()), Duration::ZERO);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(st.scheduled.call().await.is_err());
+        }
+        // No delay before the first call, then 100ms and 200ms.
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        fail.store(false, Ordering::Relaxed);
+        st.scheduled.call().await.unwrap();
+        let start = Instant::now();
+        st.scheduled.call().await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn random_intervals_stay_within_range() {
+        let (min, max) = (Duration::from_millis(60), Duration::from_millis(120));
+        let mut st = ScrapeTarget::new(Counter(0), min).random_interval(max);
+        st.scheduled.call().await.unwrap();
+        let mut last = Instant::now();
+        for _ in 0..4 {
+            st.scheduled.call().await.unwrap();
+            // Calls take 20ms, i.e. the wait starts 20ms before `last`.
+            let elapsed = last.elapsed();
+            assert!(elapsed >= min - Duration::from_millis(20), "{elapsed:?}");
+            assert!(elapsed <= max + Duration::from_millis(20), "{elapsed:?}");
+            last = Instant::now();
+        }
+    }
+
+    #[tokio::test]
+    async fn interval_profiles_override_the_interval() {
+        let midnight = TimeOfDay::new(0, 0);
+        let all_day = IntervalProfile::new(midnight, midnight, Duration::from_millis(30));
+        let mut st = ScrapeTarget::new(Counter(0), Duration::from_secs(3600))
+            .interval_profiles(vec![all_day]);
+        let start = Instant::now();
+        for i in 0..3 {
+            assert_eq!(i, st.scheduled.call().await.unwrap());
+        }
+        assert!(start.elapsed() < Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn overrunning_calls_are_skipped() {
+        // The first call takes 50ms, the following ones 20ms.
+        let mut st = ScrapeTarget::new(Counter(10), Duration::from_millis(30)).skip_overruns();
+        assert_eq!(10, st.scheduled.call().await.unwrap());
+        assert!(matches!(
+            st.scheduled.call().await,
+            ScrapeResult::Err(ScrapeErr::WouldOverrun(_))
+        ));
+        // Never skipped twice in a row.
+        assert_eq!(11, st.scheduled.call().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn due_calls_share_the_rate_limit() {
+        let limiter = RateLimiter::new(20.0);
+        let executions = Arc::new(AtomicUsize::new(0));
+        let start = Instant::now();
+        let calls: Vec<_> = (0..3)
+            .map(|_| {
+                let service = Executions(executions.clone());
+                let st = ScrapeTarget::new(service, Duration::from_secs(3600))
+                    .rate_limited(limiter.clone());
+                let mut scheduled = st.scheduled;
+                tokio::spawn(async move { scheduled.call().await })
+            })
+            .collect();
+        for call in calls {
+            call.await.unwrap().unwrap();
+        }
+        // The third call starts 100ms after the first one.
+        assert!(start.elapsed() >= Duration::from_millis(120));
+    }
+
+    #[tokio::test]
+    async fn circuit_opens_after_failures() {
+        let fail = Arc::new(AtomicBool::new(true));
+        let mut service = CircuitBreaker::new(Failing(fail.clone()), 2, Duration::from_millis(50));
+        let state = service.subscribe();
+        for _ in 0..2 {
+            assert!(matches!(service.call().await, Err(ScrapeErr::IoErr(_))));
+        }
+        assert_eq!(CircuitState::Open, *state.borrow());
+        assert!(matches!(
+            service.call().await,
+            Err(ScrapeErr::CircuitOpen(_))
+        ));
+
+        // A failed probe opens the circuit again.
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(matches!(service.call().await, Err(ScrapeErr::IoErr(_))));
+        assert!(matches!(
+            service.call_unscheduled().await,
+            Err(ScrapeErr::CircuitOpen(_))
+        ));
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        fail.store(false, Ordering::Relaxed);
+        assert!(service.call().await.is_ok());
+        assert_eq!(CircuitState::Closed, *state.borrow());
+    }
+
+    #[tokio::test]
+    async fn concurrent_calls_are_coalesced() {
+        let executions = Arc::new(AtomicUsize::new(0));
+        let mut service = Memoized::new(Executions(executions.clone()), Duration::from_millis(30));
+        let (a, b) = tokio::join!(service.call(), service.call());
+        assert_eq!((1, 1), (a.unwrap(), b.unwrap()));
+        assert_eq!(1, service.call().await.unwrap());
+        tokio::time::sleep(Duration::from_millis(40)).await;
+        assert_eq!(2, service.call().await.unwrap());
+        assert_eq!(2, executions.load(Ordering::Relaxed));
+    }
+
+    /// Takes 20ms and yields the number of executions so far.
+    struct Executions(Arc<AtomicUsize>);
+
+    impl ScrapeService for Executions {
+        type Response = usize;
+
+        fn call(&mut self) -> FutureScrapeResult<Self::Response> {
+            let executions = self.0.clone();
+            Box::pin(async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                Ok(executions.fetch_add(1, Ordering::Relaxed) + 1)
+            })
+        }
+    }
+
+    /// Fails as long as the flag is set.
+    struct Failing(Arc<AtomicBool>);
+
+    impl ScrapeService for Failing {
+        type Response = ();
+
+        fn call(&mut self) -> FutureScrapeResult<Self::Response> {
+            let fail = self.0.load(Ordering::Relaxed);
+            Box::pin(async move {
+                if fail {
+                    return Err(io::Error::other("down").into());
+                }
+                Ok(())
+            })
+        }
+    }
+
     struct Counter(usize);
 
     impl ScrapeService for Counter {
@@ -0,0 +1,176 @@
+//! Signatures of output records.
+//!
+//! If a [RecordSigner] is configured, every record (the meta record of a
+//! scrape as well as each chunk record) is signed. The signature is appended
+//! as last field of the JSON object, e.g.
+//!
+//! ```text
+//! {"id":"…","remaining":0,"data":"…","signature":"ed25519:…"}
+//! ```
+//!
+//! The signature covers the record without the signature field, i.e. the
+//! bytes up to the `,"signature"` suffix followed by the closing brace. Use
+//! [RecordVerifier::verify] to check a record.
+//!
+//! HMAC-SHA256 requires the decoder to know the secret key, whereas Ed25519
+//! allows the decoder to verify records with the public key of the host.
+
+use std::{fmt::Debug, io, path::Path};
+
+use ed25519_dalek::{Signer, Verifier};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use thiserror::Error;
+
+const SIGNATURE_FIELD: &[u8] = b",\"signature\":\"";
+
+#[derive(Clone)]
+pub enum RecordSigner {
+    HmacSha256(Vec<u8>),
+    Ed25519(ed25519_dalek::SigningKey),
+}
+
+#[derive(Clone)]
+pub enum RecordVerifier {
+    HmacSha256(Vec<u8>),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+impl Debug for RecordSigner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::HmacSha256(_) => "RecordSigner::HmacSha256",
+            Self::Ed25519(_) => "RecordSigner::Ed25519",
+        })
+    }
+}
+
+impl RecordSigner {
+    /// Read a hex-encoded HMAC key from a file.
+    pub fn hmac_from_file<P: AsRef<Path>>(path: P) -> Result<Self, SigningError> {
+        Ok(Self::HmacSha256(read_hex(path)?))
+    }
+
+    /// Read a hex-encoded 32-byte Ed25519 secret key from a file.
+    pub fn ed25519_from_file<P: AsRef<Path>>(path: P) -> Result<Self, SigningError> {
+        let key = read_hex(path)?
+            .try_into()
+            .map_err(|_| SigningError::InvalidKey)?;
+        Ok(Self::Ed25519(ed25519_dalek::SigningKey::from_bytes(&key)))
+    }
+
+    /// The verifier for records signed by this signer.
+    pub fn verifier(&self) -> RecordVerifier {
+        match self {
+            Self::HmacSha256(key) => RecordVerifier::HmacSha256(key.clone()),
+            Self::Ed25519(key) => RecordVerifier::Ed25519(key.verifying_key()),
+        }
+    }
+
+    /// Append the signature to the JSON object `record`.
+    pub fn sign_record(&self, record: &mut Vec<u8>) {
+        debug_assert_eq!(Some(&b'}'), record.last());
+        let signature = match self {
+            Self::HmacSha256(key) => {
+                format!(
+                    "hmac-sha256:{}",
+                    hex::encode(hmac(key, record).finalize().into_bytes())
+                )
+            }
+            Self::Ed25519(key) => format!("ed25519:{}", hex::encode(key.sign(record).to_bytes())),
+        };
+        record.pop();
+        // An empty object has no field to separate the signature from.
+        if record.last() == Some(&b'{') {
+            record.extend_from_slice(&SIGNATURE_FIELD[1..]);
+        } else {
+            record.extend_from_slice(SIGNATURE_FIELD);
+        }
+        record.extend_from_slice(signature.as_bytes());
+        record.extend_from_slice(b"\"}");
+    }
+}
+
+impl RecordVerifier {
+    /// Check the signature of a record as written by [RecordSigner::sign_record].
+    pub fn verify(&self, record: &[u8]) -> Result<(), SigningError> {
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        let start = record
+            .windows(SIGNATURE_FIELD.len())
+            .rposition(|w| w == SIGNATURE_FIELD)
+            .ok_or(SigningError::Missing)?;
+        let signature = record[start + SIGNATURE_FIELD.len()..]
+            .strip_suffix(b"\"}")
+            .and_then(|s| std::str::from_utf8(s).ok())
+            .ok_or(SigningError::Missing)?;
+        let mut signed = record[..start].to_vec();
+        signed.push(b'}');
+
+        match (self, signature.split_once(':')) {
+            (Self::HmacSha256(key), Some(("hmac-sha256", s))) => {
+                let s = hex::decode(s).map_err(|_| SigningError::Invalid)?;
+                hmac(key, &signed)
+                    .verify_slice(&s)
+                    .map_err(|_| SigningError::Invalid)
+            }
+            (Self::Ed25519(key), Some(("ed25519", s))) => {
+                let mut bytes = [0u8; 64];
+                hex::decode_to_slice(s, &mut bytes).map_err(|_| SigningError::Invalid)?;
+                key.verify(&signed, &ed25519_dalek::Signature::from_bytes(&bytes))
+                    .map_err(|_| SigningError::Invalid)
+            }
+            _ => Err(SigningError::Invalid),
+        }
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac
+}
+
+fn read_hex<P: AsRef<Path>>(path: P) -> Result<Vec<u8>, SigningError> {
+    let s = std::fs::read_to_string(path)?;
+    hex::decode(s.trim()).map_err(|_| SigningError::InvalidKey)
+}
+
+#[derive(Debug, Error)]
+pub enum SigningError {
+    #[error("Could not read key file")]
+    Io(#[from] io::Error),
+    #[error("Invalid key")]
+    InvalidKey,
+    #[error("Record is not signed")]
+    Missing,
+    #[error("Invalid signature")]
+    Invalid,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tampered_records_are_detected() {
+        let signers = [
+            RecordSigner::HmacSha256(b"secret".to_vec()),
+            RecordSigner::Ed25519(ed25519_dalek::SigningKey::from_bytes(&[3; 32])),
+        ];
+        for signer in signers {
+            let mut record = br#"{"id":"abc","remaining":0}"#.to_vec();
+            signer.sign_record(&mut record);
+            let verifier = signer.verifier();
+            assert!(verifier.verify(&record).is_ok());
+            assert!(serde_json::from_slice::<serde_json::Value>(&record).is_ok());
+
+            let tampered = String::from_utf8(record)
+                .unwrap()
+                .replace("\"remaining\":0", "\"remaining\":1");
+            assert!(matches!(
+                verifier.verify(tampered.as_bytes()),
+                Err(SigningError::Invalid)
+            ));
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Discovering targets from DNS SRV records.
+//!
+//! A [SrvSd] looks up the SRV records of a name like `_admin._tcp.myservice`
+//! and instantiates a template target for each of them, see
+//! [DiscoverySource::Srv](crate::discovery::DiscoverySource::Srv). That way,
+//! every replica of a service is scraped without maintaining a list of them.
+//! Requires the `dns-sd` feature.
+//!
+//! The placeholders `{srv_host}` and `{srv_port}` are replaced in all strings
+//! of the action of the template, e.g. in the URL
+//! `http://{srv_host}:{srv_port}/status`. Other placeholders are left for
+//! [crate::template]. The instances are named `<template name>/<host>:<port>`
+//! and carry an `instance` label of the form `<host>:<port>`.
+//!
+//! The resolver is configured like the one of the system, e.g. from
+//! `/etc/resolv.conf`, which is read again on every lookup.
+
+use hickory_resolver::TokioAsyncResolver;
+use serde_json::Value;
+
+use crate::{config::ScrapeTargetConfig, discovery::DiscoveryError};
+
+/// Instantiates a template for each SRV record of a name. See the
+/// [module docs](self).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvSd {
+    name: String,
+    template: Box<ScrapeTargetConfig>,
+}
+
+impl SrvSd {
+    pub fn new<S: ToString>(name: S, template: ScrapeTargetConfig) -> Self {
+        Self {
+            name: name.to_string(),
+            template: Box::new(template),
+        }
+    }
+
+    /// Look up the records and instantiate the template for each of them.
+    /// The instances are ordered by host and port.
+    pub async fn discover(&self) -> Result<Vec<ScrapeTargetConfig>, DiscoveryError> {
+        let lookup = async {
+            TokioAsyncResolver::tokio_from_system_conf()?
+                .srv_lookup(self.name.as_str())
+                .await
+        };
+        let records = lookup
+            .await
+            .map_err(|e| DiscoveryError::Srv(self.name.clone(), e))?;
+        let mut endpoints: Vec<_> = records
+            .iter()
+            .map(|r| {
+                let host = r.target().to_utf8();
+                (host.trim_end_matches('.').to_string(), r.port())
+            })
+            .collect();
+        endpoints.sort();
+        endpoints.dedup();
+        Ok(endpoints
+            .iter()
+            .filter_map(|(host, port)| self.instantiate(host, *port))
+            .collect())
+    }
+
+    /// The template for the given endpoint, if the action is still valid
+    /// after replacing the placeholders.
+    fn instantiate(&self, host: &str, port: u16) -> Option<ScrapeTargetConfig> {
+        let mut action = serde_json::to_value(&self.template.action).ok()?;
+        replace_placeholders(&mut action, host, &port.to_string());
+        let mut t = (*self.template).clone();
+        t.action = match serde_json::from_value(action) {
+            Ok(action) => action,
+            Err(e) => {
+                tracing::warn!(host, port, error = %e, "invalid SRV target");
+                return None;
+            }
+        };
+        let instance = format!("{host}:{port}");
+        t.name = Some(match &self.template.name {
+            Some(name) => format!("{name}/{instance}"),
+            None => instance.clone(),
+        });
+        t.labels.insert("instance".to_string(), instance);
+        Some(t)
+    }
+}
+
+fn replace_placeholders(v: &mut Value, host: &str, port: &str) {
+    match v {
+        Value::String(s) => *s = s.replace("{srv_host}", host).replace("{srv_port}", port),
+        Value::Array(vs) => vs
+            .iter_mut()
+            .for_each(|v| replace_placeholders(v, host, port)),
+        Value::Object(fields) => fields
+            .values_mut()
+            .for_each(|v| replace_placeholders(v, host, port)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        config::{Action, ScrapeTargetBuilder},
+        template::UrlTemplate,
+    };
+
+    #[test]
+    fn templates_are_instantiated() {
+        let template = ScrapeTargetBuilder::new()
+            .name("admin")
+            .interval(Duration::from_secs(10))
+            .action(Action::http(UrlTemplate::new(
+                "http://{srv_host}:{srv_port}/{path}",
+            )))
+            .label("app", "myservice")
+            .build();
+        let sd = SrvSd::new("_admin._tcp.myservice", template);
+
+        let t = sd.instantiate("replica-1.myservice", 8081).unwrap();
+        assert_eq!(Some("admin/replica-1.myservice:8081"), t.name.as_deref());
+        assert_eq!(
+            Action::http(UrlTemplate::new("http://replica-1.myservice:8081/{path}")),
+            t.action
+        );
+        assert_eq!("replica-1.myservice:8081", t.labels["instance"]);
+        assert_eq!("myservice", t.labels["app"]);
+    }
+}
@@ -0,0 +1,182 @@
+//! Schedule state that survives restarts.
+//!
+//! A [StateStore] keeps the time of the last scheduled scrape of every named
+//! target, as well as the cursor of incremental actions (the previous output
+//! of commands with `only_new_output`). Scheduled scrapes only update the
+//! state in memory. It is written to a JSON file every [SAVE_INTERVAL] if it
+//! has changed, and on shutdown, see
+//! [DebugBunny::await_shutdown](crate::debugbunny::DebugBunny::await_shutdown).
+//! When debugbunny is restarted with the same file, targets continue their
+//! schedule instead of all firing at once, and incremental actions only emit
+//! output that is new since the last run. After a crash, the changes of the
+//! last few seconds are lost, so a target may be called early once and an
+//! incremental action may emit some output again.
+//!
+//! Unnamed targets are not persisted, as they cannot be told apart across
+//! restarts.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use serde_with::{
+    base64::{Base64, Standard},
+    formats::Padded,
+    serde_as, TimestampSecondsWithFrac,
+};
+
+/// How often a changed state is written to the file.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[serde_as]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TargetState {
+    #[serde_as(as = "Option<TimestampSecondsWithFrac<f64>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_run: Option<SystemTime>,
+    #[serde_as(as = "Option<Base64<Standard, Padded>>")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<Vec<u8>>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum StateError {
+    #[error("Could not access state file")]
+    Io(#[from] io::Error),
+    #[error("Invalid state file")]
+    Parse(#[from] serde_json::Error),
+}
+
+/// The persisted state of all targets, keyed by target name. Clones share the
+/// state.
+#[derive(Debug, Clone)]
+pub struct StateStore {
+    path: Arc<PathBuf>,
+    targets: Arc<Mutex<BTreeMap<String, TargetState>>>,
+    /// Whether the state has changed since it was last written.
+    dirty: Arc<AtomicBool>,
+    /// Serializes writes of the file.
+    saving: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl StateStore {
+    /// Load the state from `path`. A missing file yields an empty state.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, StateError> {
+        let path = path.as_ref().to_path_buf();
+        let targets = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self {
+            path: Arc::new(path),
+            targets: Arc::new(Mutex::new(targets)),
+            dirty: Default::default(),
+            saving: Default::default(),
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<TargetState> {
+        self.targets.lock().unwrap().get(name).cloned()
+    }
+
+    /// Update the state of target `name` in memory. The change is written by
+    /// the next [Self::save_if_dirty] or [Self::save].
+    pub fn set(&self, name: &str, state: TargetState) {
+        self.targets.lock().unwrap().insert(name.to_string(), state);
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// Write the state to the file if it has changed since it was last
+    /// written. Returns whether it was written.
+    pub async fn save_if_dirty(&self) -> Result<bool, StateError> {
+        if !self.dirty.load(Ordering::Acquire) {
+            return Ok(false);
+        }
+        self.save().await?;
+        Ok(true)
+    }
+
+    /// Call [Self::save_if_dirty] every [SAVE_INTERVAL], forever.
+    pub async fn save_periodically(&self) {
+        let mut ticks = tokio::time::interval(SAVE_INTERVAL);
+        ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            if let Err(e) = self.save_if_dirty().await {
+                tracing::warn!(error = %e, "could not save schedule state");
+            }
+        }
+    }
+
+    /// Write the state to the file. The file is replaced atomically, such
+    /// that a crash does not leave a truncated file behind.
+    pub async fn save(&self) -> Result<(), StateError> {
+        let _saving = self.saving.lock().await;
+        // Changes made while writing are written the next time.
+        self.dirty.store(false, Ordering::Release);
+        let data = serde_json::to_vec(&*self.targets.lock().unwrap())?;
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let written = async {
+            tokio::fs::write(&tmp, data).await?;
+            tokio::fs::rename(&tmp, &*self.path).await
+        };
+        if let Err(e) = written.await {
+            self.dirty.store(true, Ordering::Release);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn state_is_restored() {
+        let path = std::env::temp_dir().join(format!("debugbunny-state-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = StateStore::open(&path).unwrap();
+        assert_eq!(None, store.get("t"));
+
+        let state = TargetState {
+            last_run: Some(SystemTime::UNIX_EPOCH + Duration::from_millis(1500)),
+            cursor: Some(b"a\nb\n".to_vec()),
+        };
+        store.set("t", state.clone());
+        store.save().await.unwrap();
+
+        let restored = StateStore::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(Some(state), restored.get("t"));
+    }
+
+    #[tokio::test]
+    async fn only_changed_state_is_saved() {
+        let path =
+            std::env::temp_dir().join(format!("debugbunny-state-dirty-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = StateStore::open(&path).unwrap();
+        assert!(!store.save_if_dirty().await.unwrap());
+        assert!(!path.exists());
+
+        store.set("t", TargetState::default());
+        assert!(!path.exists());
+        assert!(store.save_if_dirty().await.unwrap());
+        assert!(path.exists());
+        assert!(!store.save_if_dirty().await.unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,233 @@
+//! Periodic statistics of all targets.
+//!
+//! A [crate::config::Action::Summary] target emits, for every target, the
+//! number of successful and failed calls, the median and 95th percentile of
+//! their durations and the number of bytes of output since the previous
+//! summary. Scheduled every few minutes, it makes the long-term health of the
+//! targets visible without processing every record.
+//!
+//! The statistics are collected by [Summaries], a [ScrapeObserver] that is
+//! registered automatically for each summary target, such that several
+//! summary targets, e.g. with different intervals, do not take each other's
+//! statistics. Durations are measured like for other observers, see
+//! [crate::observer]. The percentiles are estimated from a uniform sample of
+//! up to [MAX_DURATIONS] durations per target. The output of a call is the
+//! body its derived fields are evaluated against, see [crate::derive].
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::{
+    config::ScrapeTargetConfig,
+    derive::body,
+    observer::ScrapeObserver,
+    result_processor::target_key,
+    scrape_target::{FutureScrapeResult, ScrapeOk, ScrapeResult, ScrapeService},
+};
+
+/// The number of durations kept per target until they are summarized.
+pub const MAX_DURATIONS: usize = 1024;
+
+/// Collects the statistics of each target until they are taken by a summary.
+/// Clones share their state.
+#[derive(Debug, Clone)]
+pub struct Summaries {
+    since: Arc<Mutex<Instant>>,
+    targets: Arc<Mutex<BTreeMap<String, TargetSummary>>>,
+}
+
+#[derive(Debug, Default)]
+struct TargetSummary {
+    name: Option<String>,
+    group: Option<String>,
+    successes: u64,
+    failures: u64,
+    /// A uniform sample of the durations of the calls.
+    durations: Vec<Duration>,
+    bytes: u64,
+}
+
+impl TargetSummary {
+    /// Add the duration of a call to the sample, replacing a random one once
+    /// the sample is full.
+    fn sample(&mut self, duration: Duration) {
+        let calls = (self.successes + self.failures) as usize;
+        if self.durations.len() < MAX_DURATIONS {
+            self.durations.push(duration);
+        } else if let Some(d) = self.durations.get_mut(fastrand::usize(..calls)) {
+            *d = duration;
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    /// The time covered by the summary.
+    pub period_secs: f64,
+    /// Only targets that were called within the period are listed.
+    pub targets: Vec<TargetStatistics>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TargetStatistics {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    pub successes: u64,
+    pub failures: u64,
+    pub p50_secs: f64,
+    pub p95_secs: f64,
+    /// The size of the output of the successful calls.
+    pub bytes: u64,
+}
+
+impl Default for Summaries {
+    fn default() -> Self {
+        Self {
+            since: Arc::new(Mutex::new(Instant::now())),
+            targets: Default::default(),
+        }
+    }
+}
+
+impl Summaries {
+    /// Take the statistics collected since the previous summary.
+    pub fn take(&self) -> Summary {
+        let targets = std::mem::take(&mut *self.targets.lock().unwrap());
+        let since = std::mem::replace(&mut *self.since.lock().unwrap(), Instant::now());
+        Summary {
+            period_secs: since.elapsed().as_secs_f64(),
+            targets: targets
+                .into_values()
+                .map(|mut t| {
+                    t.durations.sort_unstable();
+                    TargetStatistics {
+                        p50_secs: percentile(&t.durations, 0.5).as_secs_f64(),
+                        p95_secs: percentile(&t.durations, 0.95).as_secs_f64(),
+                        name: t.name,
+                        group: t.group,
+                        successes: t.successes,
+                        failures: t.failures,
+                        bytes: t.bytes,
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// The nearest-rank percentile of the sorted `durations`.
+fn percentile(durations: &[Duration], p: f64) -> Duration {
+    let rank = (p * durations.len() as f64).ceil() as usize;
+    durations
+        .get(rank.saturating_sub(1))
+        .copied()
+        .unwrap_or_default()
+}
+
+impl ScrapeObserver for Summaries {
+    fn on_finish(
+        &self,
+        config: &ScrapeTargetConfig,
+        duration: Duration,
+        result: &ScrapeResult<ScrapeOk>,
+    ) {
+        // Encoding structured output takes a while, so do it before locking.
+        let bytes = result.as_ref().map(|ok| body(ok).len() as u64);
+        let mut targets = self.targets.lock().unwrap();
+        let t = targets
+            .entry(target_key(config))
+            .or_insert_with(|| TargetSummary {
+                name: config.name.clone(),
+                group: config.group.clone(),
+                ..Default::default()
+            });
+        match bytes {
+            Ok(bytes) => {
+                t.successes += 1;
+                t.bytes += bytes;
+            }
+            Err(_) => t.failures += 1,
+        }
+        t.sample(duration);
+    }
+}
+
+/// Emits the statistics collected by a [Summaries].
+pub struct SummaryService(pub Summaries);
+
+impl ScrapeService for SummaryService {
+    type Response = ScrapeOk;
+
+    fn call(&mut self) -> FutureScrapeResult<ScrapeOk> {
+        let summary = self.0.take();
+        Box::pin(async move {
+            let v = serde_json::to_value(summary).expect("can't fail");
+            Ok(ScrapeOk::Structured(v))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{Action, ScrapeTargetBuilder},
+        scrape_target::ScrapeErr,
+    };
+
+    #[tokio::test]
+    async fn statistics_are_reset_by_each_summary() {
+        let config = ScrapeTargetBuilder::new()
+            .name("t")
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let summaries = Summaries::default();
+        let ok = Ok(ScrapeOk::Structured(serde_json::json!([1, 2])));
+        for ms in 1..=20 {
+            summaries.on_finish(&config, Duration::from_millis(ms), &ok);
+        }
+        summaries.on_finish(&config, Duration::from_secs(1), &Err(ScrapeErr::Cancelled));
+
+        let Ok(ScrapeOk::Structured(v)) = SummaryService(summaries.clone()).call().await else {
+            panic!("expected structured output");
+        };
+        let t = &v["targets"][0];
+        assert_eq!("t", t["name"]);
+        assert_eq!(20, t["successes"]);
+        assert_eq!(1, t["failures"]);
+        assert_eq!(0.011, t["p50_secs"]);
+        assert_eq!(0.02, t["p95_secs"]);
+        assert_eq!(20 * 5, t["bytes"]);
+        assert!(summaries.take().targets.is_empty());
+    }
+
+    #[test]
+    fn durations_are_sampled() {
+        let config = ScrapeTargetBuilder::new()
+            .name("t")
+            .interval(Duration::from_secs(1))
+            .action(Action::SelfStatus)
+            .build();
+        let summaries = Summaries::default();
+        let ok = Ok(ScrapeOk::Structured(serde_json::json!(null)));
+        for ms in 0..4 * MAX_DURATIONS as u64 {
+            summaries.on_finish(&config, Duration::from_millis(ms), &ok);
+        }
+        let durations = summaries.targets.lock().unwrap()["t"].durations.len();
+        assert_eq!(MAX_DURATIONS, durations);
+
+        let t = &summaries.take().targets[0];
+        assert_eq!(4 * MAX_DURATIONS as u64, t.successes);
+        // The median of the sample is close to the one of all durations.
+        assert!((t.p50_secs - 2.048).abs() < 0.4, "{}", t.p50_secs);
+    }
+}
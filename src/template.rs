@@ -0,0 +1,156 @@
+//! Templated URLs with runtime variables.
+//!
+//! A [UrlTemplate] may contain placeholders like `{hostname}` or `{pod_ip}`.
+//! They are expanded each time a target is called. A placeholder is looked up
+//! in the [Variables] map first. If the map does not contain it, the
+//! environment variable of the same name is used, and finally the environment
+//! variable with the upper-cased name. That way, a single config artifact can
+//! be deployed across a fleet.
+
+use std::{collections::BTreeMap, fmt::Display, str::FromStr};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use url::Url;
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(transparent)]
+pub struct Variables(BTreeMap<String, String>);
+
+impl Variables {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn insert<K: ToString, V: ToString>(&mut self, name: K, value: V) {
+        self.0.insert(name.to_string(), value.to_string());
+    }
+
+    /// Insert the values of `other`, replacing the ones of the same names.
+    pub fn merge(&mut self, other: Variables) {
+        self.0.extend(other.0);
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<String> {
+        self.0
+            .get(name)
+            .cloned()
+            .or_else(|| std::env::var(name).ok())
+            .or_else(|| std::env::var(name.to_uppercase()).ok())
+    }
+
+    /// Replace all placeholders in `s`. Braces that do not enclose a valid
+    /// variable name (alphanumerics and `_`) are copied verbatim.
+    pub fn expand(&self, s: &str) -> Result<String, TemplateError> {
+        let mut res = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            res.push_str(&rest[..start]);
+            let tail = &rest[start + 1..];
+            let name_len = tail
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(tail.len());
+            if name_len > 0 && tail[name_len..].starts_with('}') {
+                let name = &tail[..name_len];
+                let value = self
+                    .lookup(name)
+                    .ok_or_else(|| TemplateError::UnknownVariable(name.to_string()))?;
+                res.push_str(&value);
+                rest = &tail[name_len + 1..];
+            } else {
+                res.push('{');
+                rest = tail;
+            }
+        }
+        res.push_str(rest);
+        Ok(res)
+    }
+}
+
+impl FromIterator<(String, String)> for Variables {
+    fn from_iter<T: IntoIterator<Item = (String, String)>>(iter: T) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+/// A URL that may contain `{name}`-placeholders.
+#[derive(
+    Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, JsonSchema,
+)]
+#[serde(transparent)]
+pub struct UrlTemplate(String);
+
+impl UrlTemplate {
+    pub fn new<S: ToString>(s: S) -> Self {
+        Self(s.to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn expand(&self, vars: &Variables) -> Result<Url, TemplateError> {
+        let s = vars.expand(&self.0)?;
+        Url::parse(&s).map_err(|e| TemplateError::InvalidUrl(s, e))
+    }
+}
+
+impl From<Url> for UrlTemplate {
+    fn from(value: Url) -> Self {
+        Self(value.into())
+    }
+}
+
+impl FromStr for UrlTemplate {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self::new(s))
+    }
+}
+
+impl Display for UrlTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// A template without placeholders equals the URL it parses to.
+impl PartialEq<Url> for UrlTemplate {
+    fn eq(&self, other: &Url) -> bool {
+        Url::parse(&self.0).is_ok_and(|u| u == *other)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum TemplateError {
+    #[error("Unknown variable: {0}")]
+    UnknownVariable(String),
+    #[error("Expanded template is not a valid URL: {0}")]
+    InvalidUrl(String, #[source] url::ParseError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_are_expanded() {
+        let mut vars = Variables::new();
+        vars.insert("pod_ip", "10.0.0.1");
+        let t = UrlTemplate::new("http://{pod_ip}:9090/q?x={}&y={ no}");
+        assert_eq!(
+            "http://10.0.0.1:9090/q?x={}&y={%20no}",
+            t.expand(&vars).unwrap().as_str()
+        );
+        assert!(matches!(
+            UrlTemplate::new("http://{debugbunny_unset}/").expand(&vars),
+            Err(TemplateError::UnknownVariable(_))
+        ));
+    }
+}
@@ -0,0 +1,111 @@
+//! Probing of TLS endpoints.
+//!
+//! A [TlsHandshakeTarget] connects to an endpoint and performs a TLS handshake
+//! without sending any request. It records how long connecting and the
+//! handshake took, what was negotiated and the fingerprint of the certificate
+//! presented by the server. Tracked over time, this helps to pin down issues
+//! of TLS termination, e.g. slow handshakes or unexpected certificates.
+//!
+//! Certificates are verified against the Mozilla root certificates, so a
+//! handshake with an untrusted certificate fails.
+
+use std::{io, sync::Arc, time::Duration};
+
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tokio::{net::TcpStream, time::Instant};
+use tokio_rustls::{
+    rustls::{self, pki_types::ServerName, ClientConfig, RootCertStore},
+    TlsConnector,
+};
+use tracing::debug;
+
+use crate::scrape_target::{FutureScrapeResult, ScrapeOk, ScrapeService};
+
+pub struct TlsHandshakeTarget {
+    host: String,
+    port: u16,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+}
+
+impl TlsHandshakeTarget {
+    /// `server_name` is sent via SNI and verified against the certificate.
+    /// Defaults to `host`.
+    pub fn new(host: String, port: u16, server_name: Option<String>) -> io::Result<Self> {
+        let server_name = ServerName::try_from(server_name.unwrap_or_else(|| host.clone()))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        // The provider is passed explicitly, such that we do not depend on a
+        // process-wide default.
+        let config =
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .map_err(io::Error::other)?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+        Ok(Self {
+            host,
+            port,
+            server_name,
+            connector: TlsConnector::from(Arc::new(config)),
+        })
+    }
+}
+
+impl ScrapeService for TlsHandshakeTarget {
+    type Response = ScrapeOk;
+
+    fn call(&mut self) -> FutureScrapeResult<ScrapeOk> {
+        let host = self.host.clone();
+        let port = self.port;
+        let server_name = self.server_name.clone();
+        let connector = self.connector.clone();
+        Box::pin(async move {
+            debug!(host, port, "connecting");
+            let start = Instant::now();
+            let tcp = TcpStream::connect((host.as_str(), port)).await?;
+            let connected = start.elapsed();
+            let tls = connector.connect(server_name, tcp).await?;
+            let handshake = start.elapsed() - connected;
+            let (_, conn) = tls.get_ref();
+            let fingerprint = conn
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .map(|cert| hex::encode(Sha256::digest(cert)));
+            debug!(?handshake, "handshake completed");
+            Ok(ScrapeOk::Structured(json!({
+                "connect_ms": millis(connected),
+                "handshake_ms": millis(handshake),
+                "protocol": conn.protocol_version().map(|v| format!("{v:?}")),
+                "cipher_suite": conn.negotiated_cipher_suite().map(|s| format!("{:?}", s.suite())),
+                "peer_certificate_sha256": fingerprint,
+            })))
+        })
+    }
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn handshake_with_non_tls_server_fails() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut s, _) = listener.accept().await.unwrap();
+            let _ = s.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await;
+        });
+
+        let mut t = TlsHandshakeTarget::new("127.0.0.1".to_string(), port, None).unwrap();
+        assert!(t.call().await.is_err());
+    }
+}
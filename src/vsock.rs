@@ -0,0 +1,183 @@
+//! HTTP over AF_VSOCK, the socket family between a hypervisor host and its
+//! guests. Guest VMs and enclaves often expose their debug APIs on a vsock
+//! port only, see [VsockAddr]. Linux only.
+
+use std::{
+    fs::File,
+    io::{self, Read, Write},
+    mem,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::Bytes;
+use http_body_util::Empty;
+use hyper_util::rt::TokioIo;
+use tokio::io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf};
+use tracing::debug;
+use url::{Position, Url};
+
+use crate::config::VsockAddr;
+
+/// A connected vsock stream socket.
+pub struct VsockStream(AsyncFd<File>);
+
+impl VsockStream {
+    pub async fn connect(addr: VsockAddr) -> io::Result<Self> {
+        // SAFETY: plain syscall, the result is checked below.
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_VSOCK,
+                libc::SOCK_STREAM | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                0,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: the descriptor was just created and is owned by nobody else.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        // SAFETY: all-zeros is a valid `sockaddr_vm`.
+        let mut sa: libc::sockaddr_vm = unsafe { mem::zeroed() };
+        sa.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+        sa.svm_cid = addr.cid;
+        sa.svm_port = addr.port;
+        // SAFETY: the pointer and length describe `sa`.
+        let r = unsafe {
+            libc::connect(
+                fd.as_raw_fd(),
+                &sa as *const libc::sockaddr_vm as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            )
+        };
+        // Registering the descriptor may overwrite `errno`.
+        let connect_err = (r < 0).then(io::Error::last_os_error);
+        let fd = AsyncFd::new(File::from(fd))?;
+        if let Some(e) = connect_err {
+            if e.raw_os_error() != Some(libc::EINPROGRESS) {
+                return Err(e);
+            }
+            fd.writable().await?.retain_ready();
+            let mut err: libc::c_int = 0;
+            let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+            // SAFETY: the pointers describe `err`.
+            let r = unsafe {
+                libc::getsockopt(
+                    fd.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_ERROR,
+                    &mut err as *mut libc::c_int as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            if r < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if err != 0 {
+                return Err(io::Error::from_raw_os_error(err));
+            }
+        }
+        Ok(Self(fd))
+    }
+}
+
+impl AsyncRead for VsockStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|f| f.get_ref().read(unfilled)) {
+                Ok(Ok(n)) => {
+                    buf.advance(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for VsockStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        data: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.0.poll_write_ready(cx))?;
+            match guard.try_io(|f| f.get_ref().write(data)) {
+                Ok(r) => return Poll::Ready(r),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // SAFETY: plain syscall on a descriptor we own.
+        let r = unsafe { libc::shutdown(self.0.as_raw_fd(), libc::SHUT_WR) };
+        if r < 0 {
+            return Poll::Ready(Err(io::Error::last_os_error()));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Send a request for `url` to `addr`. The host of the URL is only used for
+/// the `Host` header. Unlike requests sent with reqwest, the body is not
+/// decompressed.
+pub(crate) async fn request(
+    addr: VsockAddr,
+    method: http::Method,
+    url: &Url,
+    accept_encoding: Option<&str>,
+) -> io::Result<http::Response<reqwest::Body>> {
+    let stream = VsockStream::connect(addr).await?;
+    let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(io::Error::other)?;
+    tokio::spawn(async move {
+        if let Err(e) = conn.await {
+            debug!(error = %e, "vsock connection failed");
+        }
+    });
+    let mut req = http::Request::builder()
+        .method(method)
+        .uri(&url[Position::BeforePath..])
+        .header(
+            http::header::HOST,
+            &url[Position::BeforeHost..Position::AfterPort],
+        );
+    if let Some(ae) = accept_encoding {
+        req = req.header(http::header::ACCEPT_ENCODING, ae);
+    }
+    let req = req.body(Empty::<Bytes>::new()).map_err(io::Error::other)?;
+    let resp = sender.send_request(req).await.map_err(io::Error::other)?;
+    Ok(resp.map(reqwest::Body::wrap))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn unreachable_ports_fail_to_connect() {
+        // Without the loopback transport (`vsock_loopback`), connecting fails
+        // right away; with it, nothing listens on the port.
+        let addr = VsockAddr {
+            cid: libc::VMADDR_CID_LOCAL,
+            port: 9,
+        };
+        let url = Url::parse("http://guest/metrics").unwrap();
+        assert!(request(addr, http::Method::GET, &url, None).await.is_err());
+    }
+}
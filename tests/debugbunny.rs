@@ -1,15 +1,28 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use debugbunny::{
     config::{Action, Config, ScrapeTargetBuilder, ScrapeTargetConfig},
-    debugbunny::DebugBunny,
+    debugbunny::{DebugBunny, HttpClientPolicy, ProcessorErrorPolicy, StartError, TargetId},
+    health::{Health, HealthPolicy, Transition},
+    observer::ScrapeObserver,
+    preflight::Problem,
     result_processor::ScrapeResultProcessor,
-    scrape_target::{ScrapeOk, ScrapeResult},
+    scrape_target::{ScrapeErr, ScrapeOk, ScrapeResult},
 };
 use httptest::{matchers::*, responders::*, Expectation, Server};
+use reqwest::StatusCode;
 use tokio::sync::Mutex;
+use tokio_stream::StreamExt;
 use url::Url;
 
+// `echo` is a builtin of the shell on Windows, see `shell_commands_are_run`.
+#[cfg(unix)]
 #[tokio::test]
 async fn two_http_and_one_command() {
     let server = Server::run();
@@ -66,10 +79,15 @@ async fn two_http_and_one_command() {
     );
 
     let collector = ResultCollector::default();
-    let debugbunny =
-        DebugBunny::start_scraping(config.clone().scrape_targets, collector.clone()).await;
+    let debugbunny = DebugBunny::start_scraping(config.clone().scrape_targets, collector.clone())
+        .await
+        .unwrap();
 
-    tokio::time::sleep(Duration::from_millis(250)).await;
+    assert!(
+        debugbunny
+            .wait_for_first_results(Duration::from_secs(5))
+            .await
+    );
     debugbunny.stop();
     debugbunny.await_shutdown().await;
 
@@ -100,6 +118,745 @@ async fn two_http_and_one_command() {
             Ok(ScrapeOk::CommandResponse(out))) if out.stdout.windows(command_out.len()).any(|w| w == command_out.as_bytes()))));
 }
 
+#[tokio::test]
+async fn shell_commands_are_run() {
+    let targets = vec![ScrapeTargetBuilder::new()
+        .name("shell")
+        .interval(Duration::from_secs(3600))
+        .action(Action::shell("echo hello && echo world"))
+        .build()];
+
+    let collector = ResultCollector::default();
+    let debugbunny = DebugBunny::start_scraping(targets, collector.clone())
+        .await
+        .unwrap();
+    assert!(
+        debugbunny
+            .wait_for_first_results(Duration::from_secs(5))
+            .await
+    );
+    debugbunny.stop();
+    debugbunny.await_shutdown().await;
+
+    let results = collector.results.lock().await;
+    let Some((_, Ok(ScrapeOk::CommandResponse(out)))) = results.first() else {
+        panic!("unexpected result");
+    };
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert_eq!(
+        vec!["hello", "world"],
+        stdout.lines().map(str::trim).collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test]
+async fn results_are_routed_to_sinks() {
+    let hour = Duration::from_secs(3600);
+    let targets = vec![
+        ScrapeTargetBuilder::new()
+            .name("default")
+            .interval(hour)
+            .action(Action::shell("echo default"))
+            .build(),
+        ScrapeTargetBuilder::new()
+            .name("spooled")
+            .interval(hour)
+            .action(Action::shell("echo spooled"))
+            .sink("spool")
+            .build(),
+    ];
+
+    let default = ResultCollector::default();
+    let spool = ResultCollector::default();
+    let debugbunny = DebugBunny::builder()
+        .sink("spool", spool.clone())
+        .start_scraping(targets, default.clone())
+        .await
+        .unwrap();
+    debugbunny.unscheduled_call(default.clone()).await;
+    debugbunny.stop();
+    debugbunny.await_shutdown().await;
+
+    let names = |c: &ResultCollector| {
+        let results = c.results.try_lock().unwrap();
+        let mut names: Vec<_> = results.iter().filter_map(|(c, _)| c.name.clone()).collect();
+        names.dedup();
+        names
+    };
+    assert_eq!(vec!["default"], names(&default));
+    assert_eq!(vec!["spooled"], names(&spool));
+}
+
+#[tokio::test]
+async fn unknown_sinks_are_rejected() {
+    let targets = vec![ScrapeTargetBuilder::new()
+        .interval(Duration::from_secs(3600))
+        .action(Action::SelfStatus)
+        .sink("spool")
+        .build()];
+
+    let started = DebugBunny::start_scraping(targets, ResultCollector::default()).await;
+    assert!(matches!(started, Err(StartError::UnknownSink(s)) if s == "spool"));
+
+    let started = DebugBunny::builder()
+        .processor_error_policy(ProcessorErrorPolicy::Fallback {
+            sink: "spool".to_string(),
+        })
+        .start_scraping(vec![], ResultCollector::default())
+        .await;
+    assert!(matches!(started, Err(StartError::UnknownFallbackSink(s)) if s == "spool"));
+}
+
+#[tokio::test]
+async fn invalid_http_clients_are_rejected() {
+    let action = serde_json::json!({
+        "type": "Http",
+        "url": "http://localhost/",
+        "client": { "user_agent": "no\nnewlines" }
+    });
+    let targets = vec![ScrapeTargetBuilder::new()
+        .name("agent")
+        .interval(Duration::from_secs(3600))
+        .action(serde_json::from_value(action).unwrap())
+        .build()];
+
+    let started = DebugBunny::start_scraping(targets, ResultCollector::default()).await;
+    assert!(matches!(started, Err(StartError::HttpClient(name, _)) if name == "agent"));
+}
+
+#[tokio::test]
+async fn processor_errors_stop_target() {
+    let targets = vec![ScrapeTargetBuilder::new()
+        .name("failing")
+        .interval(Duration::from_millis(50))
+        .action(Action::shell("echo x"))
+        .build()];
+
+    let debugbunny = DebugBunny::builder()
+        .processor_error_policy(ProcessorErrorPolicy::StopTarget)
+        .start_scraping(targets, FailingProcessor)
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let status = debugbunny.target_status();
+    assert!(status[0].stopped);
+    assert_eq!(1, status[0].processor_errors);
+    debugbunny.stop();
+    debugbunny.await_shutdown().await;
+}
+
+#[tokio::test]
+async fn processor_error_policies_apply_per_target() {
+    let target = |name: &str| {
+        ScrapeTargetBuilder::new()
+            .name(name)
+            .interval(Duration::from_millis(50))
+            .action(Action::shell("echo x"))
+    };
+    let targets = vec![
+        target("dropping").build(),
+        target("stopping")
+            .processor_error_policy(ProcessorErrorPolicy::StopTarget)
+            .build(),
+    ];
+
+    let debugbunny = DebugBunny::start_scraping(targets, FailingProcessor)
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let status = debugbunny.target_status();
+    assert!(!status[0].stopped);
+    assert!(status[0].processor_errors > 1);
+    assert!(status[1].stopped);
+    assert_eq!(1, status[1].processor_errors);
+    debugbunny.stop();
+    debugbunny.await_shutdown().await;
+}
+
+#[tokio::test]
+async fn processor_error_policies_apply_per_sink() {
+    let targets = vec![
+        ScrapeTargetBuilder::new()
+            .name("diverted")
+            .interval(Duration::from_millis(50))
+            .action(Action::shell("echo x"))
+            .sink("primary")
+            .build(),
+        ScrapeTargetBuilder::new()
+            .name("stopped")
+            .interval(Duration::from_millis(50))
+            .action(Action::shell("echo x"))
+            .sink("fragile")
+            .build(),
+    ];
+    let backup = ResultCollector::default();
+    let stopped = StoppedTargets::default();
+
+    let debugbunny = DebugBunny::builder()
+        .sink("primary", FailingProcessor)
+        .sink("backup", backup.clone())
+        .sink("fragile", FailingProcessor)
+        .sink_error_policy(
+            "primary",
+            ProcessorErrorPolicy::Fallback {
+                sink: "backup".to_string(),
+            },
+        )
+        .sink_error_policy("fragile", ProcessorErrorPolicy::StopTarget)
+        .observer(stopped.clone())
+        .start_scraping(targets, ResultCollector::default())
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let status = debugbunny.target_status();
+    assert!(!status[0].stopped);
+    assert!(status[1].stopped);
+    debugbunny.stop();
+    debugbunny.await_shutdown().await;
+
+    let diverted = backup.results.lock().await;
+    assert!(diverted.len() > 1);
+    assert!(diverted
+        .iter()
+        .all(|(c, _)| c.name.as_deref() == Some("diverted")));
+    assert_eq!(vec!["stopped"], *stopped.0.lock().unwrap());
+}
+
+#[tokio::test]
+async fn failing_targets_are_disabled() {
+    let targets = vec![ScrapeTargetBuilder::new()
+        .name("broken")
+        .interval(Duration::from_millis(20))
+        .action(Action::http(Url::parse("http://127.0.0.1:1/").unwrap()))
+        .max_consecutive_failures(3)
+        .build()];
+
+    let collector = ResultCollector::default();
+    let debugbunny = DebugBunny::start_scraping(targets, collector.clone())
+        .await
+        .unwrap();
+    let mut events = Box::pin(debugbunny.results());
+    let disabled = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            if let (_, _, Err(ScrapeErr::Disabled(n))) = events.next().await.unwrap() {
+                return n;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(3, disabled);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert!(debugbunny.target_status()[0].stopped);
+    debugbunny.stop();
+    debugbunny.await_shutdown().await;
+    let results = collector.results.lock().await;
+    assert_eq!(4, results.len());
+    assert!(matches!(results[3].1, Err(ScrapeErr::Disabled(3))));
+}
+
+#[tokio::test]
+async fn health_transitions_are_processed() {
+    let targets = vec![ScrapeTargetBuilder::new()
+        .name("unreachable")
+        .interval(Duration::from_millis(20))
+        .action(Action::http(Url::parse("http://127.0.0.1:1/").unwrap()))
+        .build()];
+
+    let transitions = Transitions::default();
+    let debugbunny = DebugBunny::builder()
+        .track_health(HealthPolicy::new().down_after(3))
+        .start_scraping(targets, transitions.clone())
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    debugbunny.stop();
+    debugbunny.await_shutdown().await;
+
+    let transitions = transitions.0.lock().await;
+    assert_eq!(1, transitions.len());
+    assert_eq!(Health::Failing, transitions[0].health);
+    assert_eq!(3, transitions[0].after);
+}
+
+#[tokio::test]
+async fn missing_commands_are_reported_on_start() {
+    let targets = vec![ScrapeTargetBuilder::new()
+        .name("missing")
+        .interval(Duration::from_millis(50))
+        .action(Action::command(
+            "no-such-command-for-debugbunny".to_string(),
+        ))
+        .build()];
+
+    let collector = ResultCollector::default();
+    let debugbunny = DebugBunny::start_scraping(targets, collector.clone())
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    assert!(debugbunny.target_status()[0].stopped);
+    let results = collector.results.lock().await;
+    assert_eq!(1, results.len());
+    assert!(matches!(results[0].1, Err(ScrapeErr::CommandNotFound(_))));
+    drop(results);
+    debugbunny.stop();
+    debugbunny.await_shutdown().await;
+}
+
+#[tokio::test]
+async fn preflight_reports_problems() {
+    let server = Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/")).respond_with(status_code(401)),
+    );
+    let hour = Duration::from_secs(3600);
+    let mut config = Config::new();
+    config.add_target(
+        ScrapeTargetBuilder::new()
+            .interval(hour)
+            .name("ok")
+            .action(Action::shell("echo ok"))
+            .build(),
+    );
+    config.add_target(
+        ScrapeTargetBuilder::new()
+            .interval(hour)
+            .name("missing")
+            .action(Action::command(
+                "no-such-command-for-debugbunny".to_string(),
+            ))
+            .build(),
+    );
+    config.add_target(
+        ScrapeTargetBuilder::new()
+            .interval(hour)
+            .name("denied")
+            .action(Action::http(
+                Url::parse(&server.url("/").to_string()).unwrap(),
+            ))
+            .build(),
+    );
+
+    let report = config.preflight().await;
+    assert!(!report.is_ok());
+    let problems: Vec<_> = report.checks.iter().map(|c| c.problem.clone()).collect();
+    assert_eq!(
+        vec![
+            None,
+            Some(Problem::MissingBinary(
+                "no-such-command-for-debugbunny".to_string()
+            )),
+            Some(Problem::Unauthorized(StatusCode::UNAUTHORIZED)),
+        ],
+        problems
+    );
+}
+
+#[tokio::test]
+async fn unscheduled_calls_are_cancelled_after_deadline() {
+    let hour = Duration::from_secs(3600);
+    let targets = vec![
+        ScrapeTargetBuilder::new()
+            .name("fast")
+            .interval(hour)
+            .action(Action::shell("echo x"))
+            .build(),
+        ScrapeTargetBuilder::new()
+            .name("slow")
+            .interval(hour)
+            .timeout(Duration::from_secs(10))
+            .action(Action::shell("sleep 5"))
+            .build(),
+    ];
+
+    let collector = ResultCollector::default();
+    let debugbunny = DebugBunny::start_scraping(targets, ResultCollector::default())
+        .await
+        .unwrap();
+    let started = std::time::Instant::now();
+    let cancelled = debugbunny
+        .unscheduled_call_with_deadline(Duration::from_millis(300), collector.clone())
+        .await;
+    assert!(started.elapsed() < Duration::from_secs(2));
+    assert_eq!(vec![TargetId(1)], cancelled);
+    debugbunny.stop();
+
+    let results = collector.results.lock().await;
+    assert_eq!(2, results.len());
+    for (c, r) in results.iter() {
+        match c.name.as_deref() {
+            Some("fast") => assert!(r.is_ok()),
+            _ => assert!(matches!(r, Err(ScrapeErr::DeadlineExceeded(_)))),
+        }
+    }
+}
+
+#[tokio::test]
+async fn self_status_reports_all_targets() {
+    let hour = Duration::from_secs(3600);
+    let targets = vec![
+        ScrapeTargetBuilder::new()
+            .name("self")
+            .interval(hour)
+            .action(Action::SelfStatus)
+            .build(),
+        ScrapeTargetBuilder::in_group("g", &Default::default())
+            .name("other")
+            .interval(hour)
+            .action(Action::shell("echo x"))
+            .build(),
+    ];
+
+    let collector = ResultCollector::default();
+    let debugbunny = DebugBunny::start_scraping(targets, collector.clone())
+        .await
+        .unwrap();
+    debugbunny.pause_group("g");
+    debugbunny.unscheduled_call(collector.clone()).await;
+    debugbunny.stop();
+    debugbunny.await_shutdown().await;
+
+    let results = collector.results.lock().await;
+    let status = results
+        .iter()
+        .find_map(|(_, r)| match r {
+            Ok(ScrapeOk::Structured(v)) => Some(v.clone()),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!("other", status["targets"][1]["name"]);
+    assert_eq!(true, status["targets"][1]["paused"]);
+    assert!(status["uptime_secs"].is_f64());
+}
+
+#[tokio::test]
+async fn schedule_state_survives_restarts() {
+    let path = std::env::temp_dir().join(format!("debugbunny-it-state-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let targets = || {
+        vec![ScrapeTargetBuilder::new()
+            .name("incremental")
+            .interval(Duration::from_secs(3600))
+            .action(Action::shell("echo a").only_new_output())
+            .build()]
+    };
+    let stdout = |c: &ResultCollector| {
+        let results = c.results.try_lock().unwrap();
+        results
+            .iter()
+            // Stopping cancels the pending scheduled call.
+            .filter_map(|(_, r)| match r {
+                Ok(ScrapeOk::CommandResponse(out)) => Some(out.stdout.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let first = ResultCollector::default();
+    let debugbunny = DebugBunny::builder()
+        .state_file(&path)
+        .start_scraping(targets(), first.clone())
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    debugbunny.stop();
+    debugbunny.await_shutdown().await;
+    assert_eq!(vec![b"a\n".to_vec()], stdout(&first));
+
+    // The target is not due yet and the output has been seen already.
+    let second = ResultCollector::default();
+    let debugbunny = DebugBunny::builder()
+        .state_file(&path)
+        .start_scraping(targets(), second.clone())
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    debugbunny.unscheduled_call(second.clone()).await;
+    debugbunny.stop();
+    debugbunny.await_shutdown().await;
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(vec![Vec::<u8>::new()], stdout(&second));
+}
+
+#[tokio::test]
+async fn burst_calls_target_repeatedly() {
+    let targets = vec![ScrapeTargetBuilder::new()
+        .name("spiky")
+        .interval(Duration::from_secs(3600))
+        .action(Action::shell("echo x"))
+        .build()];
+
+    let collector = ResultCollector::default();
+    let debugbunny = DebugBunny::start_scraping(targets, collector.clone())
+        .await
+        .unwrap();
+    // Let the first scheduled call finish.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!debugbunny.burst("unknown", 3, Duration::ZERO).await);
+    assert!(
+        debugbunny
+            .burst("spiky", 3, Duration::from_millis(10))
+            .await
+    );
+    assert!(debugbunny.burst("spiky", 2, Duration::ZERO).await);
+    debugbunny.stop();
+    debugbunny.await_shutdown().await;
+
+    let results = collector.results.lock().await;
+    let ok = results.iter().filter(|(_, r)| r.is_ok()).count();
+    // The first scheduled call and the bursts.
+    assert_eq!(6, ok);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn memory_budget_caps_concurrent_bodies() {
+    const BODY: usize = 64 * 1024;
+    let server = Server::run();
+    server.expect(
+        Expectation::matching(request::method_path("GET", "/large"))
+            .times(1..)
+            .respond_with(status_code(200).body(vec![b'x'; BODY])),
+    );
+    let targets = (0..6)
+        .map(|i| {
+            ScrapeTargetBuilder::new()
+                .name(format!("large-{i}"))
+                .interval(Duration::from_secs(3600))
+                .action(Action::http(
+                    Url::parse(&server.url("/large").to_string()).unwrap(),
+                ))
+                .build()
+        })
+        .collect();
+
+    // Two bodies do not fit at once.
+    let bodies = HeldBodies::default();
+    let debugbunny = DebugBunny::builder()
+        .max_in_flight_bytes(BODY * 3 / 2)
+        .start_scraping(targets, bodies.clone())
+        .await
+        .unwrap();
+    // Once the size of its body is known, a call waits for its turn.
+    for _ in 0..2 {
+        assert_eq!(6, debugbunny.trigger(|_| true).await);
+    }
+    debugbunny.stop();
+    debugbunny.await_shutdown().await;
+
+    // The first scheduled calls are staggered, and those still pending are
+    // cancelled on stop.
+    let mut results = bodies.results.lock().unwrap();
+    results.retain(|r| !matches!(r, Err(ScrapeErr::Cancelled)));
+    assert_eq!(1, bodies.max_held.load(Ordering::SeqCst));
+    for r in &results[..] {
+        match r {
+            Ok(len) => assert_eq!(BODY, *len),
+            Err(e) => assert!(matches!(e, ScrapeErr::MemoryExhausted { .. }), "{e}"),
+        }
+    }
+    assert!(results.len() >= 12);
+    assert!(results.iter().any(Result::is_ok));
+}
+
+#[tokio::test]
+async fn bursts_and_triggers_are_not_coalesced() {
+    let path = std::env::temp_dir().join(format!("debugbunny-it-calls-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let targets = vec![ScrapeTargetBuilder::new()
+        .name("counted")
+        .interval(Duration::from_secs(3600))
+        .action(Action::shell(format!("echo x >> {}", path.display())))
+        .build()];
+
+    let collector = ResultCollector::default();
+    let debugbunny = DebugBunny::builder()
+        .coalesce_unscheduled_calls(Duration::from_secs(3600))
+        .start_scraping(targets, collector.clone())
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    debugbunny
+        .burst("counted", 3, Duration::from_millis(10))
+        .await;
+    assert_eq!(1, debugbunny.trigger(|_| true).await);
+    // Only these share a result.
+    debugbunny.unscheduled_call(collector.clone()).await;
+    debugbunny.unscheduled_call(collector.clone()).await;
+    debugbunny.stop();
+    debugbunny.await_shutdown().await;
+
+    let calls = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    // The first scheduled call, the burst, the trigger and one unscheduled call.
+    assert_eq!(6, calls.lines().count());
+}
+
+#[tokio::test]
+async fn summaries_do_not_take_each_others_statistics() {
+    let hour = Duration::from_secs(3600);
+    let summary = |name: &str| {
+        ScrapeTargetBuilder::new()
+            .name(name)
+            .interval(hour)
+            .action(Action::Summary)
+            .build()
+    };
+    let targets = vec![
+        ScrapeTargetBuilder::new()
+            .name("observed")
+            .interval(hour)
+            .action(Action::shell("echo x"))
+            .build(),
+        summary("hourly"),
+        summary("daily"),
+    ];
+
+    let collector = ResultCollector::default();
+    let debugbunny = DebugBunny::start_scraping(targets, collector.clone())
+        .await
+        .unwrap();
+    debugbunny
+        .trigger(|c| c.name.as_deref() == Some("observed"))
+        .await;
+    assert_eq!(
+        2,
+        debugbunny
+            .trigger(|c| matches!(c.action, Action::Summary))
+            .await
+    );
+    debugbunny.stop();
+    debugbunny.await_shutdown().await;
+
+    let results = collector.results.lock().await;
+    let observed: Vec<_> = results
+        .iter()
+        .filter(|(c, _)| matches!(c.action, Action::Summary))
+        .filter_map(|(c, r)| match r {
+            Ok(ScrapeOk::Structured(v)) => Some((c.name.clone().unwrap(), v.clone())),
+            _ => None,
+        })
+        .map(|(name, v)| {
+            let targets = v["targets"].as_array().unwrap().clone();
+            let observed = targets.into_iter().find(|t| t["name"] == "observed");
+            (
+                name,
+                observed.map_or(0, |t| t["successes"].as_u64().unwrap()),
+            )
+        })
+        .collect();
+    // The triggered summaries both saw the triggered call.
+    for name in ["hourly", "daily"] {
+        let last = observed.iter().rev().find(|(n, _)| n == name).unwrap();
+        assert!(last.1 >= 1, "{observed:?}");
+    }
+}
+
+#[tokio::test]
+async fn results_are_streamed() {
+    let targets = vec![
+        ScrapeTargetBuilder::new()
+            .name("first")
+            .interval(Duration::from_secs(3600))
+            .action(Action::shell("echo first"))
+            .build(),
+        ScrapeTargetBuilder::new()
+            .name("second")
+            .interval(Duration::from_secs(3600))
+            .action(Action::shell("echo second"))
+            .build(),
+    ];
+
+    let collector = ResultCollector::default();
+    let debugbunny = DebugBunny::start_scraping(targets, collector.clone())
+        .await
+        .unwrap();
+    let results = debugbunny.results();
+    debugbunny
+        .burst("second", 1, Duration::from_millis(1))
+        .await;
+
+    // The first scheduled calls may show up as well.
+    let mut unscheduled = Box::pin(results.filter(|(_, meta, _)| meta.unscheduled));
+    let (id, meta, r) = tokio::time::timeout(Duration::from_secs(5), unscheduled.next())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(TargetId(1), id);
+    assert_eq!(Some("second"), meta.config.name.as_deref());
+    let Ok(ScrapeOk::CommandResponse(out)) = r else {
+        panic!("unexpected result");
+    };
+    assert_eq!(b"second\n", &out.stdout[..]);
+    debugbunny.stop();
+}
+
+#[tokio::test]
+async fn custom_http_client_is_used() {
+    let server = Server::run();
+    server.expect(
+        Expectation::matching(all_of![
+            request::method_path("GET", "/debug"),
+            request::headers(contains(("x-debug-token", "secret"))),
+        ])
+        .times(1..)
+        .respond_with(status_code(200)),
+    );
+    let url = Url::parse(&server.url("/debug").to_string()).unwrap();
+    let mut headers = http::HeaderMap::new();
+    headers.insert("x-debug-token", "secret".parse().unwrap());
+    let client = reqwest::Client::builder()
+        .default_headers(headers)
+        .build()
+        .unwrap();
+    let targets = vec![ScrapeTargetBuilder::new()
+        .interval(Duration::from_secs(3600))
+        .action(Action::http(url))
+        .build()];
+
+    let collector = ResultCollector::default();
+    let debugbunny = DebugBunny::builder()
+        .http_client_policy(HttpClientPolicy::Custom(client))
+        .start_scraping(targets, collector.clone())
+        .await
+        .unwrap();
+    debugbunny.unscheduled_call(collector.clone()).await;
+    debugbunny.stop();
+    debugbunny.await_shutdown().await;
+
+    let results = collector.results.lock().await;
+    assert!(results.iter().any(|(_, r)| r.is_ok()));
+}
+
+#[tokio::test]
+async fn processors_are_flushed_on_shutdown() {
+    let targets = vec![ScrapeTargetBuilder::new()
+        .interval(Duration::from_secs(3600))
+        .action(Action::shell("echo x"))
+        .build()];
+
+    let buffered = Buffered::default();
+    let debugbunny = DebugBunny::start_scraping(targets, buffered.clone())
+        .await
+        .unwrap();
+    assert!(
+        debugbunny
+            .wait_for_first_results(Duration::from_secs(5))
+            .await
+    );
+    assert!(buffered.0.lock().await.is_empty());
+    debugbunny.stop();
+    debugbunny.await_shutdown().await;
+    let done = buffered.0.lock().await;
+    let (results, lifecycle) = done.split_at(done.len() - 2);
+    assert!(!results.is_empty() && results.iter().all(|r| *r == "result"));
+    assert_eq!(["flush", "shutdown"], lifecycle);
+}
+
 type SharedResults = Arc<Mutex<Vec<(ScrapeTargetConfig, ScrapeResult<ScrapeOk>)>>>;
 
 #[derive(Default, Clone)]
@@ -118,3 +875,110 @@ impl ScrapeResultProcessor for ResultCollector {
         Ok(())
     }
 }
+
+/// Records the body sizes and how many bodies were held at once, holding each
+/// of them for a while.
+#[derive(Default, Clone)]
+struct HeldBodies {
+    results: Arc<std::sync::Mutex<Vec<Result<usize, ScrapeErr>>>>,
+    held: Arc<AtomicUsize>,
+    max_held: Arc<AtomicUsize>,
+}
+
+impl ScrapeResultProcessor for HeldBodies {
+    async fn process(
+        &self,
+        _config: &ScrapeTargetConfig,
+        result: ScrapeResult<ScrapeOk>,
+    ) -> std::io::Result<()> {
+        let len = result.map(|ok| match ok {
+            ScrapeOk::HttpResponse(r) => r.body().len(),
+            _ => panic!("expected an HTTP response"),
+        });
+        let ok = len.is_ok();
+        self.results.lock().unwrap().push(len);
+        if ok {
+            let held = self.held.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_held.fetch_max(held, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            self.held.fetch_sub(1, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct FailingProcessor;
+
+impl ScrapeResultProcessor for FailingProcessor {
+    async fn process(
+        &self,
+        _config: &ScrapeTargetConfig,
+        _result: ScrapeResult<ScrapeOk>,
+    ) -> std::io::Result<()> {
+        Err(std::io::Error::other("sink unavailable"))
+    }
+}
+
+/// Records the names of the targets stopped due to processor errors.
+#[derive(Clone, Default)]
+struct StoppedTargets(Arc<std::sync::Mutex<Vec<String>>>);
+
+impl ScrapeObserver for StoppedTargets {
+    fn on_target_stopped(&self, config: &ScrapeTargetConfig, _error: &std::io::Error) {
+        let name = config.name.clone().unwrap_or_default();
+        self.0.lock().unwrap().push(name);
+    }
+}
+
+/// Records health transitions and ignores results.
+#[derive(Clone, Default)]
+struct Transitions(Arc<Mutex<Vec<Transition>>>);
+
+impl ScrapeResultProcessor for Transitions {
+    async fn process(
+        &self,
+        _config: &ScrapeTargetConfig,
+        _result: ScrapeResult<ScrapeOk>,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    async fn process_transition(
+        &self,
+        _config: &ScrapeTargetConfig,
+        transition: &Transition,
+    ) -> std::io::Result<()> {
+        self.0.lock().await.push(transition.clone());
+        Ok(())
+    }
+}
+
+/// Holds back results until flushed.
+#[derive(Clone, Default)]
+struct Buffered(Arc<Mutex<Vec<&'static str>>>, Arc<Mutex<usize>>);
+
+impl ScrapeResultProcessor for Buffered {
+    async fn process(
+        &self,
+        _config: &ScrapeTargetConfig,
+        _result: ScrapeResult<ScrapeOk>,
+    ) -> std::io::Result<()> {
+        *self.1.lock().await += 1;
+        Ok(())
+    }
+
+    async fn flush(&self) -> std::io::Result<()> {
+        let mut pending = self.1.lock().await;
+        let mut done = self.0.lock().await;
+        done.extend(std::iter::repeat("result").take(*pending));
+        done.push("flush");
+        *pending = 0;
+        Ok(())
+    }
+
+    async fn shutdown(&self) -> std::io::Result<()> {
+        self.0.lock().await.push("shutdown");
+        Ok(())
+    }
+}